@@ -0,0 +1,88 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::llm::LLMEndpointError;
+use crate::resident::ResidentModel;
+
+/// How the embeddings of the tokens of an input are pooled into a single embedding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    /// The mean of the embeddings of every token.
+    Mean,
+    /// The embedding of the first token, e.g. `[CLS]` in BERT models.
+    Cls,
+    /// The embedding of the last token.
+    Last,
+}
+
+/// A request to generate embeddings for the provided inputs.
+#[derive(Debug, Default)]
+pub struct EmbeddingsArgs {
+    /// The texts to embed.
+    pub inputs: Vec<String>,
+
+    /// How the embeddings of the tokens of each input are pooled. If `None`, the pooling the model
+    /// was trained with is used.
+    pub pooling: Option<Pooling>,
+
+    /// Whether the embeddings are normalised to unit length.
+    pub normalize: bool,
+}
+
+/// An embeddings endpoint, that is, an object that turns text into vectors with dedicated
+/// embedding models, which, unlike large language models, do not generate text.
+#[async_trait::async_trait]
+pub trait EmbeddingsEndpoint {
+    /// Given several inputs, return an embedding for every input, in the same order.
+    async fn embeddings(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: EmbeddingsArgs,
+    ) -> Result<Vec<Vec<f32>>, LLMEndpointError>;
+
+    /// Returns the models currently loaded into memory.
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        Vec::new()
+    }
+
+    /// Unloads everything from memory.
+    fn reset(&self);
+}
+
+/// Normalises an embedding vector to unit length.
+pub fn normalize(embedding: &mut [f32]) {
+    let norm = embedding.iter().map(move |v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(move |v| *v /= norm);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized() {
+        let mut embedding = [3.0f32, 4.0];
+        normalize(&mut embedding);
+        assert_eq!(embedding, [0.6, 0.8]);
+
+        let mut zero = [0.0f32; 3];
+        normalize(&mut zero);
+        assert_eq!(zero, [0.0; 3]);
+    }
+}
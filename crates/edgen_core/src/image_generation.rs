@@ -1,7 +1,10 @@
 use serde::Serialize;
 use std::path::PathBuf;
+use std::time::Duration;
 use thiserror::Error;
 
+use crate::resident::ResidentModel;
+
 pub struct ImageGenerationArgs {
     pub prompt: String,
     pub uncond_prompt: String,
@@ -9,17 +12,52 @@ pub struct ImageGenerationArgs {
     pub height: Option<usize>,
     pub steps: usize,
     pub images: u32,
+    /// The seed of the first image. Every following image is seeded with the previous seed plus
+    /// one, so that each image can be reproduced on its own.
+    ///
+    /// If `None`, every image gets a random seed.
     pub seed: Option<u64>,
     pub guidance_scale: f64,
     pub vae_scale: f64,
 }
 
+/// The architecture of a stable diffusion model.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StableDiffusionVersion {
+    /// Stable Diffusion 2.1.
+    V2_1,
+    /// Stable Diffusion XL.
+    Xl,
+    /// SDXL-Turbo, a distilled version of SDXL that generates images in very few (1 to 4) steps.
+    Turbo,
+    /// A latent consistency model (LCM) distilled from SDXL, which generates images in 2 to 8 steps
+    /// using the LCM scheduler.
+    XlLcm,
+}
+
 pub struct ModelFiles {
+    pub version: StableDiffusionVersion,
     pub tokenizer: PathBuf,
     pub clip_weights: PathBuf,
     pub clip2_weights: Option<PathBuf>,
     pub vae_weights: PathBuf,
     pub unet_weights: PathBuf,
+    /// The weights of the safety checker used to detect NSFW images. If `None`, generated images
+    /// are not checked.
+    pub safety_checker: Option<PathBuf>,
+}
+
+/// An image generated by an [`ImageGenerationEndpoint`].
+pub struct GeneratedImage {
+    /// The encoded PNG data of the image.
+    pub data: Vec<u8>,
+
+    /// The seed used to generate this image.
+    pub seed: u64,
+
+    /// Whether the safety checker classified this image as NSFW. Always **`false`** if no safety
+    /// checker was provided.
+    pub nsfw: bool,
 }
 
 #[derive(Serialize, Error, Debug)]
@@ -40,5 +78,17 @@ pub trait ImageGenerationEndpoint {
         &self,
         model: ModelFiles,
         args: ImageGenerationArgs,
-    ) -> Result<Vec<Vec<u8>>, ImageGenerationEndpointError>;
+    ) -> Result<Vec<GeneratedImage>, ImageGenerationEndpointError>;
+
+    /// Returns the models currently loaded into memory.
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        Vec::new()
+    }
+}
+
+/// Return the [`Duration`] for which an image generation model lives while not being used before
+/// being unloaded from memory.
+pub fn inactive_image_generation_ttl() -> Duration {
+    // TODO this should come from the settings
+    Duration::from_secs(5 * 60)
 }
@@ -0,0 +1,108 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Background tasks that are only spawned once they are needed.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+
+/// A background task that is spawned the first time it is [started](LazyTask::start) within a
+/// Tokio runtime, rather than when it is created.
+///
+/// This lets the owner of the task be created outside of a runtime, for example in a `once_cell`
+/// initializer triggered by a thread that does not run one. The task is aborted when this is
+/// dropped.
+pub struct LazyTask {
+    state: Mutex<LazyTaskState>,
+}
+
+enum LazyTaskState {
+    /// The task has not been spawned yet.
+    Pending(Pin<Box<dyn Future<Output = ()> + Send + 'static>>),
+
+    /// The task has been spawned.
+    Running(JoinHandle<()>),
+}
+
+impl LazyTask {
+    /// Creates a new task running `future`, without spawning it.
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self {
+            state: Mutex::new(LazyTaskState::Pending(Box::pin(future))),
+        }
+    }
+
+    /// Spawns the task on the current Tokio runtime, unless it has already been spawned.
+    ///
+    /// Outside of a runtime, this does nothing, and the task is spawned the next time this is
+    /// called within one.
+    pub fn start(&self) {
+        if let Ok(handle) = Handle::try_current() {
+            self.start_on(&handle);
+        }
+    }
+
+    /// Spawns the task on the runtime of `handle`, unless it has already been spawned.
+    pub fn start_on(&self, handle: &Handle) {
+        let mut state = self.state.lock().unwrap();
+        if let LazyTaskState::Pending(future) = &mut *state {
+            let future = std::mem::replace(future, Box::pin(async {}));
+            *state = LazyTaskState::Running(handle.spawn(future));
+        }
+    }
+
+    /// Returns `true` if the task has been spawned.
+    pub fn is_started(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), LazyTaskState::Running(_))
+    }
+}
+
+impl Drop for LazyTask {
+    fn drop(&mut self) {
+        if let LazyTaskState::Running(handle) = &*self.state.lock().unwrap() {
+            handle.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn starts_within_runtime() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let ran_clone = ran.clone();
+
+        // Created outside of any runtime
+        let task = LazyTask::new(async move { ran_clone.store(true, Ordering::SeqCst) });
+        task.start();
+        assert!(!task.is_started());
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            task.start();
+            task.start();
+            while !ran.load(Ordering::SeqCst) {
+                tokio::task::yield_now().await;
+            }
+        });
+        assert!(task.is_started());
+    }
+}
@@ -20,13 +20,16 @@ extern crate alloc;
 
 use std::time::Duration;
 
+pub mod embeddings;
 pub mod llm;
 pub mod whisper;
 
 pub mod settings;
 
 pub mod image_generation;
+pub mod lazy_task;
 pub mod perishable;
+pub mod resident;
 
 /// Return the [`Duration`] that cleanup threads should wait before looking for and freeing unused
 /// resources, after last doing so.
@@ -12,14 +12,21 @@
 
 use core::fmt::{Display, Formatter};
 use core::time::Duration;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use derive_more::{Deref, DerefMut, From};
 use either::Either;
 use futures::Stream;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::resident::ResidentModel;
+use crate::settings::SamplerOptions;
 
 /// The context tag marking the start of generated dialogue.
 pub const ASSISTANT_TAG: &str = "<|ASSISTANT|>";
@@ -41,10 +48,20 @@ pub enum LLMEndpointError {
     Load(String),
     #[error("failed to create a new session: {0}")]
     SessionCreationFailed(String),
+    #[error("no matching session found")]
+    SessionNotFound,
     #[error("failed to create embeddings: {0}")]
     Embeddings(String), // Embeddings may involve session creation, advancing, and other things, so it should have its own error
     #[error("unsuitable endpoint for model: {0}")]
     UnsuitableEndpoint(String),
+    #[error("input {index} is too large: {tokens} tokens > max ({max})")]
+    InputTooLarge {
+        index: usize,
+        tokens: usize,
+        max: usize,
+    },
+    #[error("prompt is too large: {tokens} tokens > max context ({max})")]
+    ContextTooLarge { tokens: usize, max: usize },
 }
 
 /// The plaintext or image content of a [`ChatMessage`] within a [`CreateChatCompletionRequest`].
@@ -206,6 +223,28 @@ pub struct ChatMessages(
     pub Vec<ChatMessage>,
 );
 
+impl ChatMessages {
+    /// Renders the leading system messages, that is the system prompt the rendered messages start
+    /// with, or an empty string if they do not start with a system message.
+    pub fn system_prompt(&self) -> String {
+        let mut prompt = String::new();
+        for message in &self.0 {
+            match message {
+                ChatMessage::System {
+                    content: Some(data),
+                    ..
+                } => {
+                    prompt.push_str(SYSTEM_TAG);
+                    prompt.push_str(data);
+                }
+                ChatMessage::System { content: None, .. } => {}
+                _ => break,
+            }
+        }
+        prompt
+    }
+}
+
 impl Display for ChatMessages {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         for message in &self.0 {
@@ -298,6 +337,25 @@ pub struct CompletionArgs {
     /// sampling, preventing sampling of very low-probability tokens.
     pub top_p: Option<f32>,
 
+    /// Min-p sampling. Tokens whose probability is less than this fraction of the probability of
+    /// the most likely token are not sampled.
+    pub min_p: Option<f32>,
+
+    /// Locally typical sampling. Only the most typical tokens, up to this cumulative probability,
+    /// are sampled.
+    pub typical_p: Option<f32>,
+
+    /// Mirostat sampling, which keeps the perplexity of the output close to `mirostat_tau`: `1`
+    /// for Mirostat, `2` for Mirostat 2.0, or `0` to disable it. When enabled, replaces top-p,
+    /// min-p and typical sampling.
+    pub mirostat: Option<u8>,
+
+    /// The target entropy of Mirostat.
+    pub mirostat_tau: Option<f32>,
+
+    /// The learning rate of Mirostat.
+    pub mirostat_eta: Option<f32>,
+
     /// A list of tools made available to the model.
     // pub tools: Option<Vec<ToolStub<'a>>>,
 
@@ -320,6 +378,272 @@ pub struct CompletionArgs {
     /// An unsound hint may severely drop performance and/or inference quality, and in some cases even cause Edgen
     /// to crash. Do not set this value unless you know what you are doing.
     pub context_hint: Option<u32>,
+
+    /// Keep the prompt up to the last user message loaded in a pinned session, so that the next
+    /// one-shot requests starting with the same messages only process what follows them.
+    /// Default: `false`
+    pub cache_prompt: Option<bool>,
+
+    /// The name of the pinned session holding the cached prompt. Implies `cache_prompt`.
+    ///
+    /// A request with the same key but a different prompt replaces the cached prompt.
+    pub cache_key: Option<String>,
+
+    /// Create a new session holding the context of the conversation, identified by `session`, so
+    /// that the next requests can continue it explicitly. Default: `false`
+    pub create_session: bool,
+
+    /// The [`Uuid`] of the session holding the context of the conversation. The messages of the
+    /// previous requests of the session are already in its context, so only the messages following
+    /// the last assistant message are processed.
+    ///
+    /// If `None`, the session is found from the messages of the prompt. `one_shot` is ignored if
+    /// this is set.
+    pub session: Option<Uuid>,
+
+    /// If set, the tokens of a streamed completion are recorded in this log as they get
+    /// generated. Only used by streamed completions.
+    pub token_log: Option<TokenLog>,
+
+    /// If set, the timings of the completion are recorded in it as it gets generated.
+    pub timings: Option<TimingsRecorder>,
+
+    /// Keep the KV cache of the session in host memory, even if the weights of the model are
+    /// offloaded to a device, which allows for larger contexts on devices with little memory.
+    pub no_kv_offload: bool,
+
+    /// The largest context the model may be given, in tokens. `context_hint` is clamped to it, and
+    /// prompts larger than it are refused.
+    pub max_context: Option<u32>,
+
+    /// Keep the system prompt at the start of the context, as llama.cpp's `n_keep`, when the
+    /// oldest tokens of a full context are evicted to make room for new ones. If `false`, a
+    /// context too small for the conversation fails the completion instead.
+    pub keep_system_prompt: bool,
+}
+
+impl CompletionArgs {
+    /// Sets the sampling options that were not provided to those of `defaults`.
+    pub fn with_sampler_defaults(mut self, defaults: &SamplerOptions) -> Self {
+        self.temperature = self.temperature.or(defaults.temperature);
+        self.top_p = self.top_p.or(defaults.top_p);
+        self.min_p = self.min_p.or(defaults.min_p);
+        self.typical_p = self.typical_p.or(defaults.typical_p);
+        self.mirostat = self.mirostat.or(defaults.mirostat);
+        self.mirostat_tau = self.mirostat_tau.or(defaults.mirostat_tau);
+        self.mirostat_eta = self.mirostat_eta.or(defaults.mirostat_eta);
+        self
+    }
+}
+
+/// A token generated by a large language model, as recorded in a [`TokenLog`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoggedToken {
+    /// The ID of the token in the vocabulary of the model.
+    pub id: u32,
+
+    /// The length, in bytes, of the text of the token.
+    pub len: usize,
+}
+
+/// A log of the tokens of a streamed completion, recorded as they get generated.
+///
+/// The chunks of a stream are only emitted once all the tokens they are made of have been
+/// generated, so the tokens of a chunk are those recorded since the previous chunk.
+#[derive(Debug, Clone, Default)]
+pub struct TokenLog(Arc<Mutex<Vec<LoggedToken>>>);
+
+impl TokenLog {
+    /// Records a generated token.
+    pub fn push(&self, id: u32, len: usize) {
+        self.0.lock().unwrap().push(LoggedToken { id, len });
+    }
+
+    /// Takes the tokens recorded since the last call.
+    pub fn take(&self) -> Vec<LoggedToken> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+/// The timings of a chat completion, as recorded by the runtime generating it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CompletionTimings {
+    /// The time spent loading the model into memory, or waiting for it to be loaded.
+    pub load: Duration,
+
+    /// When the runtime started processing the prompt, once the model was loaded.
+    pub prompt_start: Option<Instant>,
+
+    /// The time spent processing the prompt.
+    pub prompt_eval: Duration,
+
+    /// When the first token started being generated, once the prompt was processed.
+    pub generation_start: Option<Instant>,
+
+    /// When the last token was generated.
+    pub last_token: Option<Instant>,
+
+    /// The number of generated tokens.
+    pub tokens: usize,
+}
+
+impl CompletionTimings {
+    /// Returns the time spent generating tokens.
+    pub fn generation(&self) -> Duration {
+        match (self.generation_start, self.last_token) {
+            (Some(start), Some(end)) => end.saturating_duration_since(start),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Returns the number of tokens generated every second.
+    pub fn tokens_per_second(&self) -> f64 {
+        let secs = self.generation().as_secs_f64();
+        if secs > 0.0 {
+            self.tokens as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Records the [`CompletionTimings`] of a chat completion as it gets generated, so that they can
+/// be read while it is streamed.
+#[derive(Debug, Clone, Default)]
+pub struct TimingsRecorder(Arc<Mutex<CompletionTimings>>);
+
+impl TimingsRecorder {
+    /// Records the time spent loading the model.
+    pub fn record_load(&self, load: Duration) {
+        self.0.lock().unwrap().load += load;
+    }
+
+    /// Records that the prompt started being processed.
+    pub fn start_prompt(&self) {
+        self.0.lock().unwrap().prompt_start = Some(Instant::now());
+    }
+
+    /// Records that the prompt was processed, and that tokens started being generated.
+    pub fn end_prompt(&self) {
+        let mut timings = self.0.lock().unwrap();
+        let now = Instant::now();
+        if let Some(start) = timings.prompt_start {
+            timings.prompt_eval = now.saturating_duration_since(start);
+        }
+        timings.generation_start = Some(now);
+    }
+
+    /// Records a generated token.
+    pub fn record_token(&self) {
+        let mut timings = self.0.lock().unwrap();
+        timings.tokens += 1;
+        timings.last_token = Some(Instant::now());
+    }
+
+    /// Returns the timings recorded so far.
+    pub fn get(&self) -> CompletionTimings {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// The window over which [`ThroughputMeter`] computes the rate of generated tokens.
+pub const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+/// The live generation throughput of an endpoint.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    /// The number of tokens generated every second, over the last [`THROUGHPUT_WINDOW`], across
+    /// every completion.
+    pub tokens_per_second: f64,
+
+    /// The number of completions currently being streamed.
+    pub active_streams: usize,
+}
+
+/// The estimated cost of the prompt of a chat completion, as computed by
+/// [`LLMEndpoint::estimate_prompt`].
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PromptEstimate {
+    /// The number of tokens of the rendered prompt.
+    pub prompt_tokens: usize,
+
+    /// The size of the context the prompt would be processed in, in tokens.
+    pub context_size: usize,
+
+    /// The memory, in bytes, the session processing the prompt would take, besides the weights of
+    /// the model.
+    pub session_memory: usize,
+}
+
+impl PromptEstimate {
+    /// Returns the number of tokens left in the context for the completion.
+    pub fn remaining_tokens(&self) -> usize {
+        self.context_size.saturating_sub(self.prompt_tokens)
+    }
+}
+
+/// Measures the [`Throughput`] of an endpoint, as tokens get generated by all of its completions.
+#[derive(Debug, Clone, Default)]
+pub struct ThroughputMeter(Arc<Mutex<ThroughputSamples>>);
+
+#[derive(Debug, Default)]
+struct ThroughputSamples {
+    /// When each token of the last [`THROUGHPUT_WINDOW`] was generated, oldest first.
+    tokens: VecDeque<Instant>,
+
+    /// The number of completions currently being streamed.
+    active_streams: usize,
+}
+
+impl ThroughputSamples {
+    /// Forgets the tokens generated before the last [`THROUGHPUT_WINDOW`].
+    fn prune(&mut self, now: Instant) {
+        while let Some(&oldest) = self.tokens.front() {
+            if now.saturating_duration_since(oldest) <= THROUGHPUT_WINDOW {
+                break;
+            }
+            self.tokens.pop_front();
+        }
+    }
+}
+
+impl ThroughputMeter {
+    /// Records a generated token.
+    pub fn record_token(&self) {
+        let now = Instant::now();
+        let mut samples = self.0.lock().unwrap();
+        samples.prune(now);
+        samples.tokens.push_back(now);
+    }
+
+    /// Records that a completion started being streamed, until the returned [`ActiveStream`] is
+    /// dropped.
+    pub fn start_stream(&self) -> ActiveStream {
+        self.0.lock().unwrap().active_streams += 1;
+        ActiveStream(self.clone())
+    }
+
+    /// Returns the current throughput.
+    pub fn sample(&self) -> Throughput {
+        let mut samples = self.0.lock().unwrap();
+        samples.prune(Instant::now());
+        Throughput {
+            tokens_per_second: samples.tokens.len() as f64 / THROUGHPUT_WINDOW.as_secs_f64(),
+            active_streams: samples.active_streams,
+        }
+    }
+}
+
+/// A completion being streamed, counted by the [`ThroughputMeter`] it was started from while it
+/// is alive.
+#[derive(Debug)]
+pub struct ActiveStream(ThroughputMeter);
+
+impl Drop for ActiveStream {
+    fn drop(&mut self) {
+        let mut samples = self.0 .0.lock().unwrap();
+        samples.active_streams = samples.active_streams.saturating_sub(1);
+    }
 }
 
 /// A large language model endpoint, that is, an object that provides various ways to interact with
@@ -341,11 +665,27 @@ pub trait LLMEndpoint {
         args: CompletionArgs,
     ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError>;
 
-    async fn embeddings(
+    /// Renders and tokenizes the prompt of a completion, estimating its cost without generating
+    /// anything.
+    async fn estimate_prompt(
         &self,
-        model_path: impl AsRef<Path> + Send,
-        inputs: Vec<String>,
-    ) -> Result<Vec<Vec<f32>>, LLMEndpointError>;
+        _model_path: impl AsRef<Path> + Send,
+        _args: CompletionArgs,
+    ) -> Result<PromptEstimate, LLMEndpointError> {
+        Err(LLMEndpointError::UnsuitableEndpoint(
+            "prompts cannot be estimated by this endpoint".to_string(),
+        ))
+    }
+
+    /// Returns the models currently loaded into memory.
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        Vec::new()
+    }
+
+    /// Returns the live generation throughput of the endpoint.
+    fn throughput(&self) -> Throughput {
+        Throughput::default()
+    }
 
     /// Unloads everything from memory.
     fn reset(&self);
@@ -364,3 +704,67 @@ pub fn inactive_llm_session_ttl() -> Duration {
     // TODO this should come from the settings
     Duration::from_secs(2 * 60)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throughput() {
+        let meter = ThroughputMeter::default();
+        assert_eq!(meter.sample(), Throughput::default());
+
+        let stream = meter.start_stream();
+        for _ in 0..10 {
+            meter.record_token();
+        }
+        let throughput = meter.sample();
+        assert_eq!(throughput.active_streams, 1);
+        assert_eq!(
+            throughput.tokens_per_second,
+            10.0 / THROUGHPUT_WINDOW.as_secs_f64()
+        );
+
+        drop(stream);
+        assert_eq!(meter.sample().active_streams, 0);
+    }
+
+    #[test]
+    fn system_prompt() {
+        let system = |content: &str| ChatMessage::System {
+            content: Some(content.to_string()),
+            name: None,
+        };
+        let user = ChatMessage::User {
+            content: Either::Left("Hello".to_string()),
+            name: None,
+        };
+
+        let messages = ChatMessages(vec![system("Be brief."), system("Be kind."), user]);
+        let prompt = messages.system_prompt();
+        assert_eq!(prompt, format!("{SYSTEM_TAG}Be brief.{SYSTEM_TAG}Be kind."));
+        assert!(messages.to_string().starts_with(&prompt));
+
+        let messages = ChatMessages(vec![ChatMessage::User {
+            content: Either::Left("Hello".to_string()),
+            name: None,
+        }]);
+        assert_eq!(messages.system_prompt(), "");
+    }
+
+    #[test]
+    fn remaining_tokens() {
+        let estimate = PromptEstimate {
+            prompt_tokens: 1000,
+            context_size: 4096,
+            session_memory: 0,
+        };
+        assert_eq!(estimate.remaining_tokens(), 3096);
+
+        let overflowing = PromptEstimate {
+            prompt_tokens: 5000,
+            ..estimate
+        };
+        assert_eq!(overflowing.remaining_tokens(), 0);
+    }
+}
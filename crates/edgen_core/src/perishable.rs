@@ -14,11 +14,14 @@
 
 use core::time::Duration;
 use std::future::Future;
+use std::hash::Hash;
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
+use dashmap::DashMap;
 use futures::executor::block_on;
 use tokio::select;
 use tokio::sync::{oneshot, RwLock, RwLockReadGuard, RwLockWriteGuard};
@@ -44,11 +47,35 @@ struct PerishableInner<T> {
     current_value: RwLock<Option<T>>,
     active_signal: ActiveSignal,
     state: Arc<RwLock<PerishableState>>,
+    /// Whether the value was pinned through [`Perishable::pin`].
+    pinned: AtomicBool,
+    /// The number of times the value has perished.
+    expirations: AtomicUsize,
 }
 
 struct PerishableState {
     active: bool,
     last_accessed: Instant,
+    /// The instant before which the value does not perish, regardless of its TTL, if it was
+    /// extended through [`Perishable::extend`].
+    extended_until: Option<Instant>,
+}
+
+impl PerishableState {
+    /// Returns `true` if the value is currently kept alive by an extension.
+    fn is_extended(&self) -> bool {
+        self.extended_until
+            .map_or(false, move |until| until > Instant::now())
+    }
+}
+
+/// The number of times any [`Perishable`] value has perished.
+static EXPIRATIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of times any [`Perishable`] value has perished since the start of the
+/// process.
+pub fn total_expirations() -> usize {
+    EXPIRATIONS.load(Ordering::Relaxed)
 }
 
 /// An asynchronous constructor for a [`Perishable`] value.
@@ -118,11 +145,22 @@ where
     /// `constructor` will be invoked to create the value if it is not already initialized, or
     /// if it has been intermittently de-initialized after `ttl` seconds of inactivity.
     pub fn with_ttl(ttl: Duration) -> Self {
+        Self::with_ttl_and_pin(ttl, move || false)
+    }
+
+    /// Creates a new, lazily-initialized, perishable value, which does not perish while `pinned`
+    /// returns `true`.
+    ///
+    /// `pinned` is checked whenever the value would otherwise perish, so pinning or unpinning the
+    /// value takes effect without touching it. The value can also be pinned explicitly with
+    /// [`Perishable::pin`].
+    pub fn with_ttl_and_pin(ttl: Duration, pinned: impl Fn() -> bool + Send + 'static) -> Self {
         let (drop_tx, drop_rx) = oneshot::channel();
 
         let state = Arc::new(RwLock::new(PerishableState {
             active: false,
             last_accessed: Instant::now(),
+            extended_until: None,
         }));
         let state_clone0 = state.clone();
         let state_clone1 = state.clone();
@@ -142,6 +180,8 @@ where
                 },
             ),
             state,
+            pinned: AtomicBool::new(false),
+            expirations: AtomicUsize::new(0),
         });
 
         let watched_inner = Arc::clone(&inner);
@@ -155,17 +195,22 @@ where
             let watched_inner = watched_inner;
 
             loop {
-                let accessed = {
+                let (accessed, expiry) = {
                     let locked = watched_inner.state.read().await;
-                    if locked.active {
+                    let accessed = if locked.active {
                         Instant::now()
                     } else {
                         locked.last_accessed
-                    }
+                    };
+                    let expiry = match locked.extended_until {
+                        Some(until) => until.max(accessed + ttl),
+                        None => accessed + ttl,
+                    };
+                    (accessed, expiry)
                 };
 
-                let check_date = if accessed + ttl > Instant::now() {
-                    accessed + ttl
+                let check_date = if expiry > Instant::now() {
+                    expiry
                 } else {
                     Instant::now() + Duration::from_secs(5)
                 };
@@ -173,7 +218,16 @@ where
                 select! {
                     _ = &mut drop_rx => break,
                     _ = yield_until(check_date) => {
-                        if watched_inner.state.read().await.last_accessed == accessed && watched_inner.current_value.write().await.take().is_some() {
+                        if pinned() || watched_inner.pinned.load(Ordering::SeqCst) {
+                            continue;
+                        }
+                        let expired = {
+                            let locked = watched_inner.state.read().await;
+                            locked.last_accessed == accessed && !locked.is_extended()
+                        };
+                        if expired && watched_inner.current_value.write().await.take().is_some() {
+                            watched_inner.expirations.fetch_add(1, Ordering::Relaxed);
+                            EXPIRATIONS.fetch_add(1, Ordering::Relaxed);
                             info!("A {} has perished", std::any::type_name::<T>());
                         }
                     }
@@ -189,17 +243,79 @@ where
 }
 
 impl<T: 'static> Perishable<T> {
-    /// Indicates that the value has been accessed recently.
-    fn touch(&self) -> ActiveSignal {
+    /// Indicates that the value is being accessed, until the returned signal is dropped.
+    fn signal(&self) -> ActiveSignal {
         self.inner.active_signal.clone()
     }
 
+    /// Refreshes the TTL of the value, as if it had just been accessed, without taking a guard
+    /// to it.
+    pub async fn touch(&self) {
+        self.inner.state.write().await.last_accessed = Instant::now();
+    }
+
+    /// Keeps the value from perishing for at least `duration` from now, even if that is longer
+    /// than its TTL. Extending the value for a shorter time than a previous extension has no
+    /// effect.
+    pub async fn extend(&self, duration: Duration) {
+        let until = Instant::now() + duration;
+        let mut locked = self.inner.state.write().await;
+        locked.extended_until = Some(match locked.extended_until {
+            Some(previous) => previous.max(until),
+            None => until,
+        });
+    }
+
+    /// Pins the value, which then never perishes until it is unpinned.
+    pub fn pin(&self) {
+        self.inner.pinned.store(true, Ordering::SeqCst);
+    }
+
+    /// Unpins the value, which then perishes after its TTL of inactivity, as usual.
+    ///
+    /// This has no effect on the pinning checked by the closure passed to
+    /// [`Perishable::with_ttl_and_pin`].
+    pub fn unpin(&self) {
+        self.inner.pinned.store(false, Ordering::SeqCst);
+    }
+
+    /// Returns `true` if the value was pinned with [`Perishable::pin`].
+    pub fn is_pinned(&self) -> bool {
+        self.inner.pinned.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of times the value has perished.
+    pub fn expirations(&self) -> usize {
+        self.inner.expirations.load(Ordering::Relaxed)
+    }
+
     /// Returns `true` if the value inside of this wrapper is currently live (i.e., has been
     /// initialized and has not expired).
     pub async fn is_alive(&self) -> bool {
         self.inner.current_value.read().await.is_some()
     }
 
+    /// Returns `true` if the value is currently initialized, without waiting for it to be
+    /// accessible. A value that is being initialized or is perishing is not considered resident.
+    pub fn is_resident(&self) -> bool {
+        self.inner
+            .current_value
+            .try_read()
+            .map_or(false, move |value| value.is_some())
+    }
+
+    /// Returns a handle to check whether the value is live, which outlives any borrow of this
+    /// wrapper.
+    pub fn liveness(&self) -> Liveness<T> {
+        Liveness(self.inner.clone())
+    }
+
+    /// Returns the number of users currently accessing the value, i.e. how many [`ActiveSignal`]s
+    /// of it are held outside of this wrapper.
+    pub fn users(&self) -> usize {
+        self.inner.active_signal.refs().saturating_sub(1)
+    }
+
     /// Gets a RAII read guard for the value, possibly initializing it.
     ///
     /// If the value is not initialized, it will be initialized, which may be expensive.
@@ -207,7 +323,7 @@ impl<T: 'static> Perishable<T> {
         &self,
         constructor: F,
     ) -> (ActiveSignal, PerishableReadGuard<'_, T>) {
-        let signal = self.touch();
+        let signal = self.signal();
 
         {
             let guard = self.inner.current_value.read().await;
@@ -217,11 +333,14 @@ impl<T: 'static> Perishable<T> {
             }
         }
 
-        // Value isn't initialized. Acquire a write lock and initialize it.
+        // Value isn't initialized. Acquire a write lock and initialize it, unless a concurrent
+        // caller holding the lock before us already did, so that a value is only loaded once.
         let mut guard = self.inner.current_value.write().await;
 
-        info!("(Re)Creating a new {}", std::any::type_name::<T>());
-        *guard = Some(constructor.construct().await);
+        if guard.is_none() {
+            info!("(Re)Creating a new {}", std::any::type_name::<T>());
+            *guard = Some(constructor.construct().await);
+        }
 
         (signal, PerishableReadGuard(guard.downgrade()))
     }
@@ -234,7 +353,7 @@ impl<T: 'static> Perishable<T> {
         &self,
         constructor: F,
     ) -> Result<(ActiveSignal, PerishableReadGuard<'_, T>), E> {
-        let signal = self.touch();
+        let signal = self.signal();
 
         {
             let guard = self.inner.current_value.read().await;
@@ -244,11 +363,14 @@ impl<T: 'static> Perishable<T> {
             }
         }
 
-        // Value isn't initialized. Acquire a write lock and initialize it.
+        // Value isn't initialized. Acquire a write lock and initialize it, unless a concurrent
+        // caller holding the lock before us already did, so that a value is only loaded once.
         let mut guard = self.inner.current_value.write().await;
 
-        info!("(Re)Creating a new {}", std::any::type_name::<T>());
-        *guard = Some(constructor.construct().await?);
+        if guard.is_none() {
+            info!("(Re)Creating a new {}", std::any::type_name::<T>());
+            *guard = Some(constructor.construct().await?);
+        }
 
         Ok((signal, PerishableReadGuard(guard.downgrade())))
     }
@@ -260,7 +382,7 @@ impl<T: 'static> Perishable<T> {
         &self,
         constructor: F,
     ) -> (ActiveSignal, PerishableWriteGuard<'_, T>) {
-        let signal = self.touch();
+        let signal = self.signal();
         let mut guard = self.inner.current_value.write().await;
 
         if guard.is_none() {
@@ -279,7 +401,7 @@ impl<T: 'static> Perishable<T> {
         &self,
         constructor: F,
     ) -> Result<(ActiveSignal, PerishableWriteGuard<'_, T>), E> {
-        let signal = self.touch();
+        let signal = self.signal();
         let mut guard = self.inner.current_value.write().await;
 
         if guard.is_none() {
@@ -338,6 +460,11 @@ impl ActiveSignal {
             })),
         }
     }
+
+    /// Returns the current number of references to this signal.
+    fn refs(&self) -> usize {
+        self.inner.lock().map_or(0, move |locked| locked.refs)
+    }
 }
 
 impl Clone for ActiveSignal {
@@ -375,6 +502,56 @@ pub async fn yield_until(t: Instant) {
     }
 }
 
+/// A handle to check whether the value of a [`Perishable`] is live, obtained through
+/// [`Perishable::liveness`].
+///
+/// Unlike a reference to the [`Perishable`], this can be held across `.await` points without
+/// holding onto the map, or the lock, the [`Perishable`] is stored in.
+pub struct Liveness<T>(Arc<PerishableInner<T>>);
+
+impl<T> Liveness<T> {
+    /// Returns `true` if the value is currently live, waiting for it to be accessible.
+    pub async fn is_alive(&self) -> bool {
+        self.0.current_value.read().await.is_some()
+    }
+
+    /// Returns whether the value is currently live, or `None` if it is being initialized or is
+    /// perishing.
+    pub fn try_is_alive(&self) -> Option<bool> {
+        self.0
+            .current_value
+            .try_read()
+            .ok()
+            .map(move |value| value.is_some())
+    }
+}
+
+/// Removes the entries of `map` whose [`Perishable`] value is not live, as returned by
+/// `liveness`.
+///
+/// Unlike calling [`DashMap::retain`] with [`Perishable::is_alive`], this never blocks the
+/// current thread: the liveness of every entry is awaited without holding any shard lock, and an
+/// entry is only removed if it is still dead once its shard is locked again, so that an entry
+/// being initialized, or inserted in the meantime, is kept.
+pub async fn retain_alive<K, V, T, F>(map: &DashMap<K, V>, liveness: F)
+where
+    K: Eq + Hash + Clone,
+    F: Fn(&V) -> Liveness<T>,
+{
+    let entries: Vec<(K, Liveness<T>)> = map
+        .iter()
+        .map(|entry| (entry.key().clone(), liveness(entry.value())))
+        .collect();
+
+    for (key, entry_liveness) in entries {
+        if !entry_liveness.is_alive().await {
+            map.remove_if(&key, |_, value| {
+                liveness(value).try_is_alive() == Some(false)
+            });
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -389,6 +566,30 @@ mod test {
         assert_eq!(*perishable.get_or_init(|| async { 0 }).await.1, 0);
     }
 
+    #[tokio::test]
+    async fn perishable_init_once() {
+        let perishable = Arc::new(Perishable::with_ttl(Duration::from_secs(5)));
+        let constructed = Arc::new(AtomicUsize::new(0));
+
+        let init = |perishable: Arc<Perishable<usize>>, constructed: Arc<AtomicUsize>| async move {
+            let (_signal, value) = perishable
+                .get_or_init(move || async move {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    constructed.fetch_add(1, Ordering::SeqCst)
+                })
+                .await;
+            *value
+        };
+
+        let (a, b) = tokio::join!(
+            init(perishable.clone(), constructed.clone()),
+            init(perishable.clone(), constructed.clone())
+        );
+
+        assert_eq!((a, b), (0, 0));
+        assert_eq!(constructed.load(Ordering::SeqCst), 1);
+    }
+
     #[tokio::test]
     async fn perishable_dies() {
         let perishable = Perishable::with_ttl(Duration::from_millis(100));
@@ -423,4 +624,82 @@ mod test {
         assert_eq!(*perishable.get_or_init(|| async { 0 }).await.1, 0);
         assert!(perishable.is_alive().await);
     }
+
+    #[tokio::test]
+    async fn perishable_pinned() {
+        let pinned = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let pinned_clone = pinned.clone();
+        let perishable = Perishable::with_ttl_and_pin(Duration::from_millis(100), move || {
+            pinned_clone.load(std::sync::atomic::Ordering::SeqCst)
+        });
+
+        assert_eq!(*perishable.get_or_init(|| async { 0 }).await.1, 0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(perishable.is_alive().await);
+
+        // Expired values are checked again every 5 seconds
+        pinned.store(false, std::sync::atomic::Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(5500)).await;
+        assert!(!perishable.is_alive().await);
+    }
+
+    #[tokio::test]
+    async fn perishable_explicit_touch() {
+        let perishable = Perishable::with_ttl(Duration::from_millis(200));
+
+        assert_eq!(*perishable.get_or_init(|| async { 0 }).await.1, 0);
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        perishable.touch().await;
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(perishable.is_alive().await);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!perishable.is_alive().await);
+        assert_eq!(perishable.expirations(), 1);
+    }
+
+    #[tokio::test]
+    async fn perishable_extended() {
+        let perishable = Perishable::with_ttl(Duration::from_millis(100));
+
+        assert_eq!(*perishable.get_or_init(|| async { 0 }).await.1, 0);
+        perishable.extend(Duration::from_millis(400)).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(perishable.is_alive().await);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!perishable.is_alive().await);
+    }
+
+    #[tokio::test]
+    async fn perishable_explicitly_pinned() {
+        let perishable = Perishable::with_ttl(Duration::from_millis(100));
+        perishable.pin();
+        assert!(perishable.is_pinned());
+
+        assert_eq!(*perishable.get_or_init(|| async { 0 }).await.1, 0);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(perishable.is_alive().await);
+        assert_eq!(perishable.expirations(), 0);
+
+        perishable.unpin();
+        tokio::time::sleep(Duration::from_millis(5500)).await;
+        assert!(!perishable.is_alive().await);
+        assert_eq!(perishable.expirations(), 1);
+        assert!(total_expirations() >= 1);
+    }
+
+    #[tokio::test]
+    async fn perishable_retain_alive() {
+        let map: DashMap<&str, Perishable<u32>> = DashMap::new();
+        map.insert("alive", Perishable::with_ttl(Duration::from_secs(5)));
+        map.insert("dead", Perishable::with_ttl(Duration::from_secs(5)));
+
+        map.get("alive").unwrap().get_or_init(|| async { 0 }).await;
+
+        retain_alive(&map, Perishable::liveness).await;
+
+        assert!(map.contains_key("alive"));
+        assert!(!map.contains_key("dead"));
+    }
 }
@@ -0,0 +1,114 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Introspection of the models that endpoints currently keep in memory, and of the models pinned
+//! in memory.
+//!
+//! A pinned model is never unloaded for being inactive, once loaded. Models are pinned by the
+//! path of their file, so that endpoints can check it without knowing how models are named.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::settings::DevicePolicy;
+
+/// A model currently loaded into memory by an endpoint.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct ResidentModel {
+    /// The path the model was loaded from.
+    pub path: String,
+
+    /// The device the model is loaded on, `cpu` or `device:<index>`.
+    pub device: String,
+
+    /// The size of the model file, in bytes.
+    pub size: u64,
+
+    /// The number of sessions kept for the model.
+    pub sessions: usize,
+
+    /// The number of requests the model is currently serving.
+    pub in_flight: usize,
+
+    /// Whether the model is pinned in memory.
+    pub pinned: bool,
+}
+
+impl ResidentModel {
+    /// Describes the model loaded from `path`, reading its size from the file.
+    pub fn new(path: impl AsRef<Path>, device: String, sessions: usize, in_flight: usize) -> Self {
+        let path = path.as_ref();
+        Self {
+            path: path.to_string_lossy().to_string(),
+            device,
+            size: std::fs::metadata(path)
+                .map(move |metadata| metadata.len())
+                .unwrap_or(0),
+            sessions,
+            in_flight,
+            pinned: is_pinned(path),
+        }
+    }
+}
+
+/// The paths of the files of the models pinned in memory.
+static PINNED: Lazy<RwLock<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// Pins, or unpins, the model loaded from `path` in memory.
+pub fn set_pinned(path: impl AsRef<Path>, pinned: bool) {
+    let path = path.as_ref().to_path_buf();
+    let mut paths = PINNED.write().unwrap();
+    if pinned {
+        paths.insert(path);
+    } else {
+        paths.remove(&path);
+    }
+}
+
+/// Returns `true` if the model loaded from `path` is pinned in memory.
+pub fn is_pinned(path: impl AsRef<Path>) -> bool {
+    PINNED.read().unwrap().contains(path.as_ref())
+}
+
+/// The device a model was actually loaded on, recorded by its endpoint when loading it, so that it
+/// is reported even if the `gpu_policy` of the settings changed since.
+#[derive(Debug, Clone, Default)]
+pub struct LoadedDevice(Arc<RwLock<Option<String>>>);
+
+impl LoadedDevice {
+    /// Records the name of the device the model was loaded on, `cpu` or `device:<index>`.
+    pub fn set(&self, device: String) {
+        *self.0.write().unwrap() = Some(device);
+    }
+
+    /// Returns the name of the device the model was loaded on, `cpu` if it was never loaded.
+    pub fn get(&self) -> String {
+        self.0
+            .read()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(move || "cpu".to_string())
+    }
+}
+
+/// Returns the name of the device models are loaded on under a [`DevicePolicy`].
+pub fn policy_device(policy: &DevicePolicy) -> String {
+    match policy {
+        DevicePolicy::AlwaysDevice { .. } => "device:0".to_string(),
+        _ => "cpu".to_string(),
+    }
+}
@@ -10,15 +10,18 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
+use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use dashmap::DashMap;
 use directories::ProjectDirs;
 use futures::executor::block_on;
 use notify::{Config, Event, EventHandler, EventKind, PollWatcher, RecursiveMode, Watcher};
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 use serde::{Deserialize, Serialize};
 use serde_yaml::{from_slice, to_string};
 use thiserror::Error;
@@ -38,6 +41,12 @@ pub static PROJECT_DIRS: Lazy<ProjectDirs> =
     Lazy::new(|| ProjectDirs::from("com", "EdgenAI", "Edgen").unwrap());
 pub static CONFIG_FILE: Lazy<PathBuf> = Lazy::new(build_config_file_path);
 
+/// The config file selected by [`select_config_file`], if any.
+static SELECTED_CONFIG_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// The directory, in a config directory, holding the config files of the named profiles.
+const PROFILES_DIR: &str = "profiles";
+
 /// Create project dirs if they don't exist
 pub async fn create_project_dirs() -> Result<(), std::io::Error> {
     let config_dir = PROJECT_DIRS.config_dir();
@@ -104,9 +113,70 @@ pub fn get_config_file_path() -> PathBuf {
 }
 
 fn build_config_file_path() -> PathBuf {
-    let config_dir = PROJECT_DIRS.config_dir();
-    let filename = FILE_NAME.to_string() + FILE_EXTENSION;
-    config_dir.join(Path::new(&filename))
+    match SELECTED_CONFIG_FILE.get() {
+        Some(path) => path.clone(),
+        None => config_file_path(None, None),
+    }
+}
+
+/// Selects the config file the settings are loaded from, instead of the default one in
+/// [`PROJECT_DIRS`].
+///
+/// `config` is either a config file, or a directory holding `edgen.conf.yaml`. If `profile` is
+/// provided, the settings are instead loaded from `profiles/<profile>.yaml` in that directory,
+/// or in the default config directory if `config` is not provided.
+///
+/// This must be called before the settings are initialised.
+pub fn select_config_file(
+    config: Option<&Path>,
+    profile: Option<&str>,
+) -> Result<(), SettingsError> {
+    if config.is_none() && profile.is_none() {
+        return Ok(());
+    }
+    if let Some(profile) = profile {
+        if profile.is_empty() || profile.contains(['/', '\\']) || profile.starts_with('.') {
+            return Err(SettingsError::Profile(profile.to_string()));
+        }
+    }
+    if config.is_some_and(is_config_file) && profile.is_some() {
+        return Err(SettingsError::ProfileWithFile);
+    }
+
+    let path = config_file_path(config, profile);
+    if Lazy::get(&CONFIG_FILE).is_some_and(|selected| *selected != path)
+        || SELECTED_CONFIG_FILE.set(path).is_err()
+    {
+        return Err(SettingsError::AlreadyInitialised);
+    }
+
+    Ok(())
+}
+
+/// Returns the path of the config file selected by `config` and `profile`, as described in
+/// [`select_config_file`].
+fn config_file_path(config: Option<&Path>, profile: Option<&str>) -> PathBuf {
+    let dir = match config {
+        Some(file) if is_config_file(file) => return file.to_path_buf(),
+        Some(dir) => dir,
+        None => PROJECT_DIRS.config_dir(),
+    };
+
+    match profile {
+        Some(profile) => dir
+            .join(PROFILES_DIR)
+            .join(profile.to_string() + FILE_EXTENSION),
+        None => dir.join(FILE_NAME.to_string() + FILE_EXTENSION),
+    }
+}
+
+/// Returns `true` if `path` names a config file rather than a config directory.
+fn is_config_file(path: &Path) -> bool {
+    path.is_file()
+        || path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("yaml") || ext.eq_ignore_ascii_case("yml"))
 }
 
 /// Helper to get the chat completions model directory.
@@ -157,6 +227,371 @@ pub async fn image_generation_dir() -> String {
         .to_string()
 }
 
+/// Helper to get whether deterministic chat completions are cached.
+pub async fn chat_completions_cache() -> bool {
+    SETTINGS.read().await.read().await.chat_completions_cache
+}
+
+/// Helper to get the maximum size, in bytes, of the cached chat completions, if any.
+pub async fn chat_completions_cache_max_size() -> Option<u64> {
+    let size = SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .chat_completions_cache_max_size;
+    (size != 0).then_some(size)
+}
+
+/// Helper to get whether generated embeddings are cached.
+pub async fn embeddings_cache() -> bool {
+    SETTINGS.read().await.read().await.embeddings_cache
+}
+
+/// Helper to get the maximum size, in bytes, of the cached embeddings, if any.
+pub async fn embeddings_cache_max_size() -> Option<u64> {
+    let size = SETTINGS.read().await.read().await.embeddings_cache_max_size;
+    (size != 0).then_some(size)
+}
+
+/// Helper to get the URL of the upstream API chat completions requests fall back to, if any.
+pub async fn remote_fallback_url() -> Option<String> {
+    let url = SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .remote_fallback_url
+        .trim()
+        .to_string();
+    (!url.is_empty()).then_some(url)
+}
+
+/// Helper to get the API key sent to the upstream API of the remote fallback.
+pub async fn remote_fallback_api_key() -> String {
+    SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .remote_fallback_api_key
+        .clone()
+}
+
+/// Helper to get the model requested from the upstream API of the remote fallback, if it should
+/// replace the requested one.
+pub async fn remote_fallback_model() -> Option<String> {
+    let model = SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .remote_fallback_model
+        .trim()
+        .to_string();
+    (!model.is_empty()).then_some(model)
+}
+
+/// Helper to get how long a chat completions request may wait for the local runtime before falling
+/// back to the upstream API, if at all.
+pub async fn remote_fallback_max_wait() -> Option<Duration> {
+    let secs = SETTINGS.read().await.read().await.remote_fallback_max_wait;
+    (secs != 0).then(|| Duration::from_secs(secs))
+}
+
+/// Helper to get the time given to the server to shut down gracefully before it exits regardless.
+pub async fn shutdown_grace_period() -> Duration {
+    Duration::from_secs(SETTINGS.read().await.read().await.shutdown_grace_period)
+}
+
+/// Helper to get the webhooks notified of server events.
+pub async fn webhooks() -> Vec<WebhookSettings> {
+    SETTINGS.read().await.read().await.webhooks.clone()
+}
+
+/// Helper to get the path prefix every route is served under, either empty or starting with a `/`
+/// and without a trailing one, e.g. `/llm`.
+pub async fn base_path() -> String {
+    let settings = SETTINGS.read().await;
+    let path = settings
+        .read()
+        .await
+        .base_path
+        .trim()
+        .trim_matches('/')
+        .to_string();
+    if path.is_empty() {
+        path
+    } else {
+        format!("/{path}")
+    }
+}
+
+/// Helper to get the addresses of the reverse proxies whose forwarding headers are trusted.
+pub async fn trusted_proxies() -> Vec<IpAddr> {
+    SETTINGS.read().await.read().await.trusted_proxies.clone()
+}
+
+/// Helper to get the options of the listener bound to `uri`.
+pub async fn listener_settings(uri: &str) -> ListenerSettings {
+    SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .listeners
+        .iter()
+        .find(move |listener| listener.uri.trim() == uri)
+        .cloned()
+        .unwrap_or_else(move || ListenerSettings::open(uri))
+}
+
+/// Helper to get the base URLs of the worker instances requests are forwarded to in router mode.
+pub async fn router_workers() -> Vec<String> {
+    SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .router_workers
+        .iter()
+        .map(move |url| url.trim().to_string())
+        .filter(move |url| !url.is_empty())
+        .collect()
+}
+
+/// Helper to get where the chunks of the vector stores are kept.
+pub async fn vector_storage() -> VectorStorage {
+    SETTINGS.read().await.read().await.vector_storage.clone()
+}
+
+/// Helper to get the maximums of the parameters of requests.
+pub async fn request_caps() -> RequestCaps {
+    SETTINGS.read().await.read().await.request_caps.clone()
+}
+
+/// Helper to get how the tokens of streamed chat completions are coalesced.
+pub async fn stream_coalescing() -> StreamCoalescing {
+    SETTINGS.read().await.read().await.stream_coalescing
+}
+
+/// Helper to get whether invalid request parameters are refused instead of ignored.
+pub async fn strict_requests() -> bool {
+    SETTINGS.read().await.read().await.strict_requests
+}
+
+/// Helper to get the maximum size of the requests of each endpoint.
+pub async fn request_size_limits() -> RequestSizeLimits {
+    SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .request_size_limits
+        .clone()
+}
+
+/// Helper to get the runtime pinned to a model, trying each of the provided identifiers in order.
+pub async fn model_backend(ids: &[&str]) -> Option<ModelBackend> {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    ids.iter().find_map(move |id| {
+        settings
+            .model_backends
+            .iter()
+            .find(move |(model, _)| model.eq_ignore_ascii_case(id))
+            .map(move |(_, backend)| *backend)
+    })
+}
+
+/// Helper to check whether a model is pinned in memory, under any of the provided identifiers.
+pub async fn model_pinned(ids: &[&str]) -> bool {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    ids.iter().any(move |id| {
+        settings
+            .pinned_models
+            .iter()
+            .any(move |model| model.eq_ignore_ascii_case(id))
+    })
+}
+
+/// Helper to check whether a model is trusted by the `trusted_models` allowlist, under any of the
+/// provided identifiers. Every model is trusted if the allowlist is empty.
+///
+/// An entry of the allowlist trusts the models identified by it, and every model below it, so
+/// `owner` trusts all the repositories of `owner` and `owner/repo` all the files of `repo`.
+pub async fn model_trusted(ids: &[&str]) -> bool {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    if settings.trusted_models.is_empty() {
+        return true;
+    }
+
+    ids.iter().any(move |id| {
+        settings
+            .trusted_models
+            .iter()
+            .any(move |trusted| trusts(trusted, id))
+    })
+}
+
+/// Returns `true` if the `trusted_models` entry `trusted` trusts the model identified by `id`.
+fn trusts(trusted: &str, id: &str) -> bool {
+    let trusted = trusted.trim_end_matches('/');
+    let Some(prefix) = id.get(..trusted.len()) else {
+        return false;
+    };
+    prefix.eq_ignore_ascii_case(trusted)
+        && (id.len() == trusted.len() || id[trusted.len()..].starts_with('/'))
+}
+
+/// Helper to check whether a model file may be loaded by its absolute path, which is the case if
+/// it exists below one of the `local_model_dirs`.
+pub async fn local_model_allowed(path: &Path) -> bool {
+    let Ok(path) = std::fs::canonicalize(path) else {
+        return false;
+    };
+
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    settings
+        .local_model_dirs
+        .iter()
+        .filter_map(|dir| std::fs::canonicalize(dir.trim()).ok())
+        .any(move |dir| path.starts_with(dir))
+}
+
+/// Helper to get the expected SHA-256 hash of the file of a model, in hexadecimal, under any of
+/// the provided identifiers.
+pub async fn model_hash(ids: &[&str]) -> Option<String> {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    ids.iter().find_map(move |id| {
+        settings
+            .model_hashes
+            .iter()
+            .find(move |(model, _)| model.eq_ignore_ascii_case(id))
+            .map(move |(_, hash)| hash.trim().to_lowercase())
+    })
+}
+
+/// Helper to check whether the KV cache of a model is kept in host memory, under any of the
+/// provided identifiers.
+pub async fn model_no_kv_offload(ids: &[&str]) -> bool {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    ids.iter().any(move |id| {
+        settings
+            .no_kv_offload_models
+            .iter()
+            .any(move |model| model.eq_ignore_ascii_case(id))
+    })
+}
+
+/// Helper to get the largest context of a model, in tokens, trying each of the provided
+/// identifiers in order.
+pub async fn model_max_context(ids: &[&str]) -> Option<u32> {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    ids.iter().find_map(move |id| {
+        settings
+            .model_max_context
+            .iter()
+            .find(move |(model, _)| model.eq_ignore_ascii_case(id))
+            .map(move |(_, max)| *max)
+    })
+}
+
+/// Helper to get the default sampling options of a model, trying each of the provided identifiers
+/// in order.
+pub async fn sampler_defaults(ids: &[&str]) -> SamplerOptions {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    ids.iter()
+        .find_map(move |id| {
+            settings
+                .sampler_defaults
+                .iter()
+                .find(move |(model, _)| model.eq_ignore_ascii_case(id))
+                .map(move |(_, options)| *options)
+        })
+        .unwrap_or_default()
+}
+
+/// Helper to get the sampler profile named `name`, if any.
+pub async fn sampler_profile(name: &str) -> Option<SamplerOptions> {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    settings
+        .sampler_profiles
+        .iter()
+        .find(move |(profile, _)| profile.eq_ignore_ascii_case(name))
+        .map(move |(_, options)| *options)
+}
+
+/// Helper to get the name of the sampler profile assigned to a model, trying each of the provided
+/// identifiers in order.
+pub async fn model_sampler_profile(ids: &[&str]) -> Option<String> {
+    let settings = SETTINGS.read().await;
+    let settings = settings.read().await;
+    ids.iter().find_map(move |id| {
+        settings
+            .model_sampler_profiles
+            .iter()
+            .find(move |(model, _)| model.eq_ignore_ascii_case(id))
+            .map(move |(_, profile)| profile.clone())
+    })
+}
+
+/// Helper to get the path of the fixture file with the scripted responses of the chat faker, if
+/// any.
+pub async fn chat_faker_fixture() -> Option<PathBuf> {
+    let path = SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .chat_faker_fixture
+        .trim()
+        .to_string();
+    (!path.is_empty()).then(|| PathBuf::from(path))
+}
+
+/// Helper to get the path of the fixture file with the canned transcriptions of the whisper faker,
+/// if any.
+pub async fn whisper_faker_fixture() -> Option<PathBuf> {
+    let path = SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .whisper_faker_fixture
+        .trim()
+        .to_string();
+    (!path.is_empty()).then(|| PathBuf::from(path))
+}
+
+/// Helper to get the latency simulated by the chat faker when streaming.
+pub async fn chat_faker_latency() -> FakerLatency {
+    SETTINGS.read().await.read().await.chat_faker_latency
+}
+
+/// Helper to get the faults injected by the chat faker.
+pub async fn chat_faker_faults() -> FakerFaults {
+    SETTINGS.read().await.read().await.chat_faker_faults
+}
+
+/// Helper to get the image generation safety checker policy.
+pub async fn image_generation_safety_checker() -> SafetyCheckerPolicy {
+    SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .image_generation_safety_checker
+}
+
 /// Helper to get the chat completions model name.
 pub async fn chat_completions_name() -> String {
     SETTINGS
@@ -247,6 +682,10 @@ pub enum SettingsError {
     WatchFile(String),
     #[error("global settings have already been initialised")]
     AlreadyInitialised,
+    #[error("invalid profile name: {0}")]
+    Profile(String),
+    #[error("a profile cannot be selected along with a config file, only with a config directory")]
+    ProfileWithFile,
 }
 
 /// A device allocation/execution policy.
@@ -269,22 +708,499 @@ pub enum DevicePolicy {
     // TODO add other policies like: modelthreshold, devicememorythreshold, requestbased, etc
 }
 
+/// What is done with models that are not trusted by the `trusted_models` allowlist, or whose file
+/// does not match its hash in `model_hashes`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UntrustedModelPolicy {
+    /// The model is not loaded, and requests using it fail.
+    #[default]
+    Refuse,
+
+    /// The model is loaded anyway, and a warning is logged.
+    Warn,
+}
+
+/// What is done with generated images that the safety checker classifies as not safe for work.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SafetyCheckerPolicy {
+    /// Generated images are not checked.
+    #[default]
+    Disabled,
+
+    /// Generated images are checked and returned, flagged with the result of the check.
+    Flag,
+
+    /// Generated images are checked, and those that fail the check are not returned.
+    Block,
+}
+
+/// The latency the chat faker simulates while streaming, in milliseconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FakerLatency {
+    /// The delay before the first chunk.
+    pub first_token_ms: u64,
+
+    /// The delay between every chunk after the first.
+    pub token_delay_ms: u64,
+
+    /// The maximum random delay added to every chunk.
+    pub jitter_ms: u64,
+}
+
+/// A fault the chat faker can inject into its responses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FakerFault {
+    /// No fault is injected.
+    #[default]
+    None,
+
+    /// The request fails as if the model could not be loaded.
+    LoadError,
+
+    /// The request fails as if the context could not be advanced.
+    AdvanceError,
+
+    /// The request fails as if a session could not be created.
+    SessionError,
+
+    /// A garbage chunk is streamed. Only applies to streamed requests.
+    MalformedChunk,
+
+    /// The stream ends before the response is complete. Only applies to streamed requests.
+    Disconnect,
+}
+
+/// The faults the chat faker injects, and how often.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FakerFaults {
+    /// The fault that is injected.
+    pub fault: FakerFault,
+
+    /// The fault is injected in every request whose number is a multiple of this one, so `1`
+    /// injects it in every request, `3` in every third request, and so on.
+    pub every: u64,
+
+    /// The number of chunks streamed before a stream fault.
+    pub after_chunks: usize,
+}
+
+impl Default for FakerFaults {
+    fn default() -> Self {
+        Self {
+            fault: FakerFault::None,
+            every: 1,
+            after_chunks: 0,
+        }
+    }
+}
+
+/// A runtime that can be pinned to serve a model, regardless of the kind inferred from its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelBackend {
+    /// The `llama.cpp` runtime, for GGUF models.
+    LlamaCpp,
+
+    /// The `candle` runtime, for safetensors models.
+    Candle,
+
+    /// The chat faker, answering with canned responses.
+    Faker,
+
+    /// The upstream API of the remote fallback.
+    Remote,
+}
+
+/// The sampling options a model uses when a chat completions request does not set them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplerOptions {
+    /// The sampling temperature.
+    pub temperature: Option<f32>,
+
+    /// The cumulative probability of the most likely tokens kept by nucleus sampling.
+    pub top_p: Option<f32>,
+
+    /// The fraction of the probability of the most likely token below which tokens are discarded.
+    pub min_p: Option<f32>,
+
+    /// The cumulative probability of the most typical tokens kept by locally typical sampling.
+    pub typical_p: Option<f32>,
+
+    /// The Mirostat version used, `1` or `2`, or `0` to disable Mirostat.
+    pub mirostat: Option<u8>,
+
+    /// The target entropy of Mirostat.
+    pub mirostat_tau: Option<f32>,
+
+    /// The learning rate of Mirostat.
+    pub mirostat_eta: Option<f32>,
+}
+
+impl SamplerOptions {
+    /// Returns these options, with those that are not set taken from `fallback`.
+    pub fn or(self, fallback: &SamplerOptions) -> Self {
+        Self {
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            min_p: self.min_p.or(fallback.min_p),
+            typical_p: self.typical_p.or(fallback.typical_p),
+            mirostat: self.mirostat.or(fallback.mirostat),
+            mirostat_tau: self.mirostat_tau.or(fallback.mirostat_tau),
+            mirostat_eta: self.mirostat_eta.or(fallback.mirostat_eta),
+        }
+    }
+}
+
+/// The options of the listener bound to a URI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ListenerSettings {
+    /// The URI of the listener, as it is passed to `--uri` or set in `default_uri`.
+    pub uri: String,
+
+    /// The API keys of the listener, one of which requests must send as a bearer token. No key is
+    /// required if this is empty.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+
+    /// Whether the administration routes, `/v1/admin/...` and `/v1/requests/...`, are served.
+    #[serde(default = "default_listener_admin")]
+    pub admin: bool,
+
+    /// The CORS policy of the listener.
+    #[serde(default)]
+    pub cors: CorsPolicy,
+
+    /// The path prefixes of the routes served, e.g. `/v1/chat` or `/v1/embeddings`. Every route is
+    /// served if this is empty.
+    #[serde(default)]
+    pub allowed_endpoints: Vec<String>,
+}
+
+fn default_listener_admin() -> bool {
+    true
+}
+
+impl ListenerSettings {
+    /// Returns the options of a listener bound to `uri` that serves every route to anyone, which
+    /// are the options of listeners that are not configured.
+    pub fn open(uri: &str) -> Self {
+        Self {
+            uri: uri.to_string(),
+            api_keys: vec![],
+            admin: true,
+            cors: CorsPolicy::default(),
+            allowed_endpoints: vec![],
+        }
+    }
+}
+
+/// A webhook notified of server events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebhookSettings {
+    /// The URL the events are posted to.
+    pub url: String,
+
+    /// The secret the payloads are signed with, with HMAC-SHA256. The payloads are not signed if
+    /// this is empty.
+    #[serde(default)]
+    pub secret: String,
+
+    /// The types of the events posted, as in the `type` of the status events, e.g. `model_loaded`.
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<String>,
+}
+
+fn default_webhook_events() -> Vec<String> {
+    [
+        "download",
+        "model_loaded",
+        "model_unloaded",
+        "error",
+        "request_failed",
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+/// An API key of a listener, either the bare key, which has the [`KeyRole::Admin`] role, or the
+/// key along with its options.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ApiKey {
+    /// The key alone.
+    Key(String),
+
+    /// The key, with what the requests sending it are allowed to do, and the models they use when
+    /// they ask for `default`.
+    WithOptions {
+        /// The key.
+        key: String,
+
+        /// What the requests sending the key are allowed to do.
+        #[serde(default)]
+        role: KeyRole,
+
+        /// The models of the key.
+        #[serde(flatten)]
+        models: KeyModels,
+
+        /// The maximums of the parameters of the requests sending the key, on top of the
+        /// `request_caps` of the settings.
+        #[serde(default)]
+        caps: RequestCaps,
+    },
+}
+
+impl ApiKey {
+    /// Returns the key.
+    pub fn key(&self) -> &str {
+        match self {
+            ApiKey::Key(key) => key,
+            ApiKey::WithOptions { key, .. } => key,
+        }
+    }
+
+    /// Returns what the requests sending the key are allowed to do.
+    pub fn role(&self) -> KeyRole {
+        match self {
+            ApiKey::Key(_) => KeyRole::Admin,
+            ApiKey::WithOptions { role, .. } => *role,
+        }
+    }
+
+    /// Returns the models of the key, if any are set.
+    pub fn models(&self) -> Option<&KeyModels> {
+        match self {
+            ApiKey::Key(_) => None,
+            ApiKey::WithOptions { models, .. } => Some(models),
+        }
+    }
+
+    /// Returns the maximums of the parameters of the requests sending the key, if any are set.
+    pub fn caps(&self) -> Option<&RequestCaps> {
+        match self {
+            ApiKey::Key(_) => None,
+            ApiKey::WithOptions { caps, .. } => Some(caps),
+        }
+    }
+}
+
+/// What the requests sending an API key are allowed to do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyRole {
+    /// Every route the listener serves may be used.
+    #[default]
+    Admin,
+
+    /// Only the inference routes may be used, not the administration ones, which reconfigure the
+    /// server or delete its models.
+    Inference,
+}
+
+/// The models used instead of the configured ones by the requests sending an API key and asking
+/// for the `default` model, so that applications sharing a server can use different models.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyModels {
+    /// The chat completions model, like `chat_completions_model_name`, or the configured one if
+    /// `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_completions_model: Option<String>,
+
+    /// The embeddings model, like `embeddings_model_name`, or the configured one if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embeddings_model: Option<String>,
+}
+
+/// The CORS policy of a listener.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CorsPolicy {
+    /// Requests from any origin are allowed.
+    #[default]
+    Permissive,
+
+    /// No CORS headers are sent, so browsers only allow requests from the same origin.
+    Disabled,
+
+    /// Requests from the listed origins are allowed, e.g. `https://example.com`.
+    Origins(Vec<String>),
+}
+
+/// Where the chunks of the vector stores, and their embeddings, are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum VectorStorage {
+    /// The SQLite database in the data directory.
+    #[default]
+    Embedded,
+
+    /// A Qdrant server, with a collection per vector store.
+    Qdrant {
+        /// The base URL of the REST API of the server, e.g. `http://localhost:6333`.
+        url: String,
+
+        /// The API key of the server, if any.
+        #[serde(default)]
+        api_key: String,
+    },
+
+    /// A PostgreSQL database with the `pgvector` extension.
+    Pgvector {
+        /// The connection string of the database, e.g. `postgresql://user@localhost/edgen`. TLS is
+        /// used if the server supports it, and required with `sslmode=require`.
+        url: String,
+    },
+}
+
+/// The maximum size, in bytes, of the requests of each endpoint. Endpoints without a size use
+/// `max_request_size`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestSizeLimits {
+    /// The maximum size of chat completions requests.
+    pub chat_completions: Option<usize>,
+
+    /// The maximum size of embeddings and rerank requests.
+    pub embeddings: Option<usize>,
+
+    /// The maximum size of audio transcriptions requests, including the audio file.
+    pub audio_transcriptions: Option<usize>,
+
+    /// The maximum size of image generation requests.
+    pub image_generation: Option<usize>,
+
+    /// The maximum size of file uploads.
+    pub files: Option<usize>,
+}
+
+/// The maximums of the parameters of requests, protecting shared instances from abusive values.
+/// Parameters without a maximum are not capped.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestCaps {
+    /// The maximum `max_tokens` of chat completions, which is also the `max_tokens` of the
+    /// requests that do not set it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+
+    /// The maximum `context_hint` of chat completions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_hint: Option<u32>,
+
+    /// The maximum number of images generated by an image generation request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub images: Option<u32>,
+
+    /// The maximum duration, in seconds, of the audio of a transcription request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio_duration: Option<u64>,
+}
+
+impl RequestCaps {
+    /// Returns the tightest of these caps and `other`, for each parameter.
+    pub fn min(&self, other: &RequestCaps) -> Self {
+        fn min<T: Ord + Copy>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            }
+        }
+
+        Self {
+            max_tokens: min(self.max_tokens, other.max_tokens),
+            context_hint: min(self.context_hint, other.context_hint),
+            images: min(self.images, other.images),
+            audio_duration: min(self.audio_duration, other.audio_duration),
+        }
+    }
+}
+
+/// How the tokens of streamed chat completions are coalesced, so that fast models do not send an
+/// event per token to slow clients. Tokens are coalesced if `window_ms` is not `0`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamCoalescing {
+    /// The maximum time, in milliseconds, the first token of an event waits for the next ones.
+    pub window_ms: u64,
+
+    /// The maximum number of tokens of an event, or `0` for no maximum.
+    pub max_tokens: usize,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SettingsParams {
     // TODO make a different thread settings for each endpoint
     /// The number of threads each individual endpoint session can use.
     pub threads: u32,
 
+    /// The number of threads `llama.cpp` sessions use to process prompts, which are processed in
+    /// batches rather than token by token. Zero to use `threads`.
+    #[serde(default)]
+    pub threads_batch: u32,
+
+    /// The CPU cores every thread of the process is pinned to once a `llama.cpp` model is used,
+    /// e.g. the performance cores of a hybrid CPU. This includes the threads serving requests and
+    /// running other models, as the threads of `llama.cpp` cannot be told apart from them. Empty
+    /// to let the operating system schedule them on any core. Only supported on Linux.
+    #[serde(default)]
+    pub process_cpu_affinity: Vec<usize>,
+
     // TODO should this be a vector instead?
     /// The default URI that *Edgen* will receive requests in.
     pub default_uri: String,
 
+    /// The path prefix every route is served under, e.g. `/llm` to serve chat completions at
+    /// `/llm/v1/chat/completions` behind a reverse proxy. Routes are served at the root if this is
+    /// empty.
+    #[serde(default)]
+    pub base_path: String,
+
+    /// The addresses of the reverse proxies whose `Forwarded` and `X-Forwarded-*` headers are
+    /// trusted, e.g. `127.0.0.1`. These headers are ignored on requests from any other peer.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// The options of each listener, by URI, e.g. to require API keys and hide the administration
+    /// routes on a listener exposed to the local network. Listeners without options serve every
+    /// route to anyone.
+    #[serde(default)]
+    pub listeners: Vec<ListenerSettings>,
+
+    /// Whether the listeners reachable from the local network are announced via mDNS/DNS-SD, as
+    /// `_edgen._tcp` services, so that clients on the network can find them.
+    #[serde(default)]
+    pub mdns: bool,
+
+    /// The number of seconds the server is given to shut down gracefully, stopping its listeners
+    /// and running its shutdown hooks, before it exits regardless.
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+
+    /// The webhooks notified of server events, such as models being loaded or downloads finishing.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookSettings>,
+
     // TODO temporary, until the model parameter in incoming requests can be parsed into local paths
     pub chat_completions_models_dir: String,
     /// The chat completion model that Edgen will use when the user does not provide a model
     pub chat_completions_model_name: String,
     /// The chat completion model repo that Edgen will use for download
     pub chat_completions_model_repo: String,
+    /// Whether the completions of chat completions requests with a temperature of `0` are cached
+    /// in the data directory, so that repeating such a request returns the cached completion.
+    #[serde(default)]
+    pub chat_completions_cache: bool,
+    /// The maximum size, in bytes, of the cached chat completions, beyond which the least recently
+    /// used ones are removed. Zero for no limit.
+    #[serde(default = "default_chat_completions_cache_max_size")]
+    pub chat_completions_cache_max_size: u64,
 
     // TODO temporary, until the model parameter in incoming requests can be parsed into local paths
     pub audio_transcriptions_models_dir: String,
@@ -299,8 +1215,136 @@ pub struct SettingsParams {
     pub embeddings_model_name: String,
     /// The embeddings repo that Edgen will use for downloads
     pub embeddings_model_repo: String,
+    /// The maximum number of inputs that are embedded at once. Requests with more inputs are
+    /// processed in several batches.
+    #[serde(default = "default_embeddings_max_batch")]
+    pub embeddings_max_batch: usize,
+    /// The maximum number of tokens a single embeddings input can have.
+    #[serde(default = "default_embeddings_max_input_tokens")]
+    pub embeddings_max_input_tokens: usize,
+    /// Whether generated embeddings are cached in the data directory, so that embedding the same
+    /// input with the same model again returns the cached embedding.
+    #[serde(default)]
+    pub embeddings_cache: bool,
+    /// The maximum size, in bytes, of the cached embeddings, beyond which the least recently used
+    /// ones are removed. Zero for no limit.
+    #[serde(default = "default_embeddings_cache_max_size")]
+    pub embeddings_cache_max_size: u64,
 
     pub image_generation_models_dir: String,
+    /// Whether generated images go through a safety checker, and what happens to those that fail.
+    #[serde(default)]
+    pub image_generation_safety_checker: SafetyCheckerPolicy,
+
+    /// The URL of an upstream OpenAI-compatible API, such as `https://api.openai.com/v1`, chat
+    /// completions requests are forwarded to when they cannot be served locally. Empty to disable
+    /// the fallback.
+    #[serde(default)]
+    pub remote_fallback_url: String,
+    /// The API key sent as a bearer token to the upstream API.
+    #[serde(default)]
+    pub remote_fallback_api_key: String,
+    /// The model requested from the upstream API. Empty to request the same model as the original
+    /// request.
+    #[serde(default)]
+    pub remote_fallback_model: String,
+    /// The number of seconds a chat completions request may wait for the local runtime to start
+    /// processing its prompt before being forwarded to the upstream API. Requests whose prompt
+    /// started being processed are never forwarded. Zero to only fall back when the model is
+    /// unavailable.
+    #[serde(default)]
+    pub remote_fallback_max_wait: u64,
+
+    /// The base URLs of the worker **Edgen** instances requests are forwarded to, including their
+    /// `base_path` if they have one, e.g. `http://10.0.0.2:33322`. If any is set, the server runs
+    /// in router mode and does not run any model itself.
+    #[serde(default)]
+    pub router_workers: Vec<String>,
+
+    /// Where the chunks of the vector stores, and their embeddings, are kept. The vector stores
+    /// themselves and their files are always kept in the data directory.
+    #[serde(default)]
+    pub vector_storage: VectorStorage,
+
+    /// The path of a YAML or JSON fixture file with scripted responses for the chat faker. Empty
+    /// to use the built-in responses.
+    #[serde(default)]
+    pub chat_faker_fixture: String,
+    /// The latency the chat faker simulates while streaming.
+    #[serde(default)]
+    pub chat_faker_latency: FakerLatency,
+    /// The faults the chat faker injects into its responses, to exercise the error handling of
+    /// clients.
+    #[serde(default)]
+    pub chat_faker_faults: FakerFaults,
+
+    /// The path of a YAML or JSON fixture file with canned transcriptions for the whisper faker.
+    /// Empty to always return the same transcription.
+    #[serde(default)]
+    pub whisper_faker_fixture: String,
+
+    /// The runtime pinned to each model, overriding the one inferred from the model patterns.
+    /// Models are identified as they are requested, e.g. `owner/repo/model.gguf`, or by their file
+    /// name.
+    #[serde(default)]
+    pub model_backends: HashMap<String, ModelBackend>,
+
+    /// The default sampling options of each model, used when a request does not set them. Models
+    /// are identified like in `model_backends`.
+    #[serde(default)]
+    pub sampler_defaults: HashMap<String, SamplerOptions>,
+
+    /// Named sets of sampling options, which requests can select with `sampler_profile`.
+    #[serde(default = "default_sampler_profiles")]
+    pub sampler_profiles: HashMap<String, SamplerOptions>,
+
+    /// The sampler profile used by each model when a request does not select one. Models are
+    /// identified like in `model_backends`. The options in `sampler_defaults` take precedence.
+    #[serde(default)]
+    pub model_sampler_profiles: HashMap<String, String>,
+
+    /// The models pinned in memory, which are never unloaded for being inactive once loaded.
+    /// Models are identified like in `model_backends`.
+    #[serde(default)]
+    pub pinned_models: Vec<String>,
+
+    /// The models whose KV cache is kept in host memory, while their weights are offloaded to the
+    /// device. Models are identified like in `model_backends`.
+    #[serde(default)]
+    pub no_kv_offload_models: Vec<String>,
+
+    /// The largest context, in tokens, of each model. The `context_hint` of requests is clamped
+    /// to it, and prompts larger than it are refused. Models are identified like in
+    /// `model_backends`.
+    #[serde(default)]
+    pub model_max_context: HashMap<String, u32>,
+
+    /// Whether the upstream repositories of the configured models are checked for updated
+    /// revisions in the background, once a day. Available updates are reported in the status of
+    /// each endpoint, and are downloaded by `edgen models upgrade`.
+    #[serde(default)]
+    pub check_model_updates: bool,
+
+    /// The models that may be loaded, identified like in `model_backends`, or by their repository
+    /// or its owner, e.g. `owner/repo` or `owner`. Any model may be loaded if this is empty.
+    #[serde(default)]
+    pub trusted_models: Vec<String>,
+
+    /// The SHA-256 hash, in hexadecimal, the file of each model must have, by model identified like
+    /// in `model_backends`.
+    #[serde(default)]
+    pub model_hashes: HashMap<String, String>,
+
+    /// What is done with models outside of `trusted_models`, or not matching their hash in
+    /// `model_hashes`.
+    #[serde(default)]
+    pub untrusted_models: UntrustedModelPolicy,
+
+    /// The directories whose model files requests may load by their absolute path, e.g.
+    /// `/mnt/models/model.gguf`, without them being in the models directories. Models may not be
+    /// requested by path if this is empty.
+    #[serde(default)]
+    pub local_model_dirs: Vec<String>,
 
     /// The policy used to decided if models/session should be allocated and run on acceleration
     /// hardware.
@@ -309,6 +1353,24 @@ pub struct SettingsParams {
     /// The maximum size, in bytes, any request can have. This is most relevant in requests with files, such as audio
     /// transcriptions.
     pub max_request_size: usize,
+
+    /// The maximum size, in bytes, of the requests of specific endpoints, overriding
+    /// `max_request_size`.
+    #[serde(default)]
+    pub request_size_limits: RequestSizeLimits,
+
+    /// The maximums of the parameters of requests, which requests exceeding them are refused for.
+    #[serde(default)]
+    pub request_caps: RequestCaps,
+
+    /// How the tokens of streamed chat completions are coalesced into server-sent events.
+    #[serde(default)]
+    pub stream_coalescing: StreamCoalescing,
+
+    /// If `true`, requests with unknown parameters, parameters out of their range or conflicting
+    /// parameters are refused, instead of having them ignored.
+    #[serde(default)]
+    pub strict_requests: bool,
 }
 
 impl SettingsParams {
@@ -326,6 +1388,23 @@ impl SettingsParams {
             self.threads
         }
     }
+
+    /// Returns the number of threads used to process prompts in batches, which is the number
+    /// returned by [`SettingsParams::auto_threads`] unless `threads_batch` is set.
+    pub fn auto_threads_batch(&self, physical: bool) -> u32 {
+        let threads = self.auto_threads(physical);
+        let max_threads = if physical {
+            num_cpus::get_physical()
+        } else {
+            num_cpus::get()
+        };
+
+        if self.threads_batch == 0 {
+            threads
+        } else {
+            self.threads_batch.min(max_threads as u32)
+        }
+    }
 }
 
 impl Default for SettingsParams {
@@ -356,9 +1435,19 @@ impl Default for SettingsParams {
         // if changed, please update docs at docs/src/app/documentation/configuration/page.mdx
         Self {
             threads: threads as u32,
+            threads_batch: 0,
+            process_cpu_affinity: vec![],
             default_uri: "http://127.0.0.1:33322".to_string(),
+            base_path: String::new(),
+            trusted_proxies: vec![],
+            listeners: vec![],
+            mdns: false,
+            shutdown_grace_period: default_shutdown_grace_period(),
+            webhooks: vec![],
             chat_completions_model_name: "neural-chat-7b-v3-3.Q4_K_M.gguf".to_string(),
             chat_completions_model_repo: "TheBloke/neural-chat-7B-v3-3-GGUF".to_string(),
+            chat_completions_cache: false,
+            chat_completions_cache_max_size: default_chat_completions_cache_max_size(),
             chat_completions_models_dir: chat_completions_str,
             audio_transcriptions_model_name: "ggml-distil-small.en.bin".to_string(),
             audio_transcriptions_model_repo: "distil-whisper/distil-small.en".to_string(),
@@ -366,16 +1455,90 @@ impl Default for SettingsParams {
             embeddings_model_name: "nomic-embed-text-v1.5.f16.gguf".to_string(),
             embeddings_model_repo: "nomic-ai/nomic-embed-text-v1.5-GGUF".to_string(),
             embeddings_models_dir: embeddings_str,
+            embeddings_max_batch: default_embeddings_max_batch(),
+            embeddings_max_input_tokens: default_embeddings_max_input_tokens(),
+            embeddings_cache: false,
+            embeddings_cache_max_size: default_embeddings_cache_max_size(),
             image_generation_models_dir: image_generation_str,
+            image_generation_safety_checker: SafetyCheckerPolicy::Disabled,
+            remote_fallback_url: String::new(),
+            remote_fallback_api_key: String::new(),
+            remote_fallback_model: String::new(),
+            remote_fallback_max_wait: 0,
+            router_workers: vec![],
+            vector_storage: VectorStorage::Embedded,
+            model_backends: HashMap::new(),
+            sampler_defaults: HashMap::new(),
+            sampler_profiles: default_sampler_profiles(),
+            model_sampler_profiles: HashMap::new(),
+            pinned_models: vec![],
+            no_kv_offload_models: vec![],
+            model_max_context: HashMap::new(),
+            check_model_updates: false,
+            trusted_models: vec![],
+            model_hashes: HashMap::new(),
+            untrusted_models: UntrustedModelPolicy::Refuse,
+            local_model_dirs: vec![],
+            chat_faker_fixture: String::new(),
+            chat_faker_latency: FakerLatency::default(),
+            chat_faker_faults: FakerFaults::default(),
+            whisper_faker_fixture: String::new(),
             // TODO detect if the system has acceleration hardware to decide the default
             gpu_policy: DevicePolicy::AlwaysDevice {
                 overflow_to_cpu: true,
             },
             max_request_size: 1024 * 1014 * 100, // 100 MB
+            request_size_limits: RequestSizeLimits::default(),
+            request_caps: RequestCaps::default(),
+            stream_coalescing: StreamCoalescing::default(),
+            strict_requests: false,
         }
     }
 }
 
+fn default_shutdown_grace_period() -> u64 {
+    30
+}
+
+fn default_chat_completions_cache_max_size() -> u64 {
+    256 * 1024 * 1024 // 256 MiB
+}
+
+fn default_embeddings_cache_max_size() -> u64 {
+    256 * 1024 * 1024 // 256 MiB
+}
+
+fn default_embeddings_max_batch() -> usize {
+    32
+}
+
+fn default_embeddings_max_input_tokens() -> usize {
+    8192
+}
+
+fn default_sampler_profiles() -> HashMap<String, SamplerOptions> {
+    HashMap::from([
+        (
+            "precise".to_string(),
+            SamplerOptions {
+                temperature: Some(0.2),
+                top_p: Some(0.9),
+                min_p: Some(0.1),
+                ..SamplerOptions::default()
+            },
+        ),
+        (
+            "creative".to_string(),
+            SamplerOptions {
+                temperature: Some(1.1),
+                top_p: Some(0.98),
+                min_p: Some(0.02),
+                ..SamplerOptions::default()
+            },
+        ),
+    ])
+}
+
 fn join_path_components(comps: &[&str]) -> PathBuf {
     comps.iter().collect::<PathBuf>()
 }
@@ -392,15 +1555,19 @@ impl SettingsInner {
         name: &str,
     ) -> Result<(Self, bool), SettingsError> {
         let filename = name.to_string() + FILE_EXTENSION;
-        let path = directory.as_ref().join(filename);
+        Self::load_or_create_file(directory.as_ref().join(filename)).await
+    }
 
+    async fn load_or_create_file(path: PathBuf) -> Result<(Self, bool), SettingsError> {
         let is_new = !path.exists();
         let params = if is_new {
             info!("Creating new settings file: {}", path.to_string_lossy());
 
-            tokio::fs::create_dir_all(directory)
-                .await
-                .map_err(move |e| SettingsError::Write(e.to_string()))?;
+            if let Some(directory) = path.parent() {
+                tokio::fs::create_dir_all(directory)
+                    .await
+                    .map_err(move |e| SettingsError::Write(e.to_string()))?;
+            }
             tokio::fs::write(&path, "")
                 .await
                 .map_err(move |e| SettingsError::Write(e.to_string()))?;
@@ -472,7 +1639,14 @@ impl Settings {
         directory: impl AsRef<Path>,
         name: &str,
     ) -> Result<Self, SettingsError> {
-        let (inner, is_new) = SettingsInner::load_or_create(directory, name).await?;
+        let filename = name.to_string() + FILE_EXTENSION;
+        Self::load_or_create_file(directory.as_ref().join(filename)).await
+    }
+
+    /// Loads the settings from the file at `path`, creating it with the default settings if it
+    /// does not exist.
+    pub async fn load_or_create_file(path: PathBuf) -> Result<Self, SettingsError> {
+        let (inner, is_new) = SettingsInner::load_or_create_file(path).await?;
         let inner = Arc::new(RwLock::new(inner));
 
         let handler = UpdateHandler::new(inner.clone());
@@ -639,9 +1813,7 @@ pub struct StaticSettings {
 impl StaticSettings {
     pub async fn init(&mut self) -> Result<(), SettingsError> {
         if self.inner.is_none() {
-            let directory = PROJECT_DIRS.config_dir();
-            let name = FILE_NAME;
-            self.inner = Some(Settings::load_or_create(directory, name).await?);
+            self.inner = Some(Settings::load_or_create_file(get_config_file_path()).await?);
             Ok(())
         } else {
             Ok(())
@@ -718,6 +1890,22 @@ mod tests {
         }
     }
 
+    #[test]
+    fn trusted_models() {
+        assert!(trusts(
+            "TheBloke",
+            "thebloke/neural-chat-7B-v3-3-GGUF/model.gguf"
+        ));
+        assert!(trusts(
+            "TheBloke/neural-chat-7B-v3-3-GGUF/",
+            "TheBloke/neural-chat-7B-v3-3-GGUF"
+        ));
+        assert!(trusts("model.gguf", "model.gguf"));
+        assert!(!trusts("TheBloke", "TheBlokeFake/repo/model.gguf"));
+        assert!(!trusts("TheBloke/repo", "TheBloke/other/model.gguf"));
+        assert!(!trusts("model.gguf", "model"));
+    }
+
     // Trying to avoid doing too many disk writes in unit tests by performing every test using the
     // same file.
     #[tokio::test]
@@ -801,4 +1989,30 @@ mod tests {
             assert_eq!(1000000, settings.threads, "Settings were not saved");
         }
     }
+
+    #[test]
+    fn config_file_paths() {
+        let default_dir = PROJECT_DIRS.config_dir();
+        assert_eq!(
+            config_file_path(None, None),
+            default_dir.join("edgen.conf.yaml")
+        );
+        assert_eq!(
+            config_file_path(None, Some("benchmarking")),
+            default_dir.join("profiles").join("benchmarking.yaml")
+        );
+
+        let dir = Path::new("/etc/edgen");
+        assert_eq!(
+            config_file_path(Some(dir), None),
+            dir.join("edgen.conf.yaml")
+        );
+        assert_eq!(
+            config_file_path(Some(dir), Some("benchmarking")),
+            dir.join("profiles").join("benchmarking.yaml")
+        );
+
+        let file = Path::new("/etc/edgen/server.yml");
+        assert_eq!(config_file_path(Some(file), None), file);
+    }
 }
@@ -10,7 +10,8 @@
  * limitations under the License.
  */
 
-use std::path::Path;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use serde::Serialize;
@@ -18,6 +19,8 @@ use thiserror::Error;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
+use crate::resident::ResidentModel;
+
 #[derive(Serialize, Error, Debug)]
 pub enum WhisperEndpointError {
     #[error("failed to advance context: {0}")]
@@ -34,23 +37,89 @@ pub enum WhisperEndpointError {
     Audio(#[from] AudioError),
 }
 
+/// The audio file of a transcription request.
+#[derive(Debug, Clone)]
+pub enum AudioFile {
+    /// The contents of the file, held in memory.
+    Bytes(Vec<u8>),
+
+    /// A file on disk, such as an upload streamed to a temporary file, read as it is decoded.
+    Path(PathBuf),
+}
+
+impl AudioFile {
+    /// Returns the contents of the file, reading it into memory if it is on disk.
+    pub fn contents(&self) -> Result<Cow<[u8]>, AudioError> {
+        match self {
+            AudioFile::Bytes(bytes) => Ok(Cow::Borrowed(bytes)),
+            AudioFile::Path(path) => std::fs::read(path)
+                .map(Cow::Owned)
+                .map_err(move |e| AudioError::Read(e.to_string())),
+        }
+    }
+}
+
 pub struct TranscriptionArgs {
-    pub file: Vec<u8>,
+    pub file: AudioFile,
     pub language: Option<String>,
     pub prompt: Option<String>,
     pub temperature: Option<f32>,
     pub create_session: bool,
     pub session: Option<Uuid>,
+    /// If **`true`**, silent stretches of the audio are removed (see [`vad`]) before it is
+    /// transcribed.
+    pub vad: bool,
+}
+
+/// The transcription of an audio segment.
+#[derive(Debug, Default)]
+pub struct Transcription {
+    /// The transcribed text of the whole audio segment.
+    pub text: String,
+
+    /// The stretches of audio the text is made of, in order.
+    pub segments: Vec<TranscriptionSegment>,
+}
+
+impl Transcription {
+    /// Creates a transcription made of a single segment, spanning the first `duration` of the
+    /// audio.
+    pub fn single(text: String, duration: Duration) -> Self {
+        let segments = vec![TranscriptionSegment {
+            start: Duration::ZERO,
+            end: duration,
+            text: text.clone(),
+        }];
+        Self { text, segments }
+    }
+}
+
+/// A transcribed stretch of audio.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TranscriptionSegment {
+    /// The position of the start of this segment in the audio.
+    pub start: Duration,
+
+    /// The position of the end of this segment in the audio.
+    pub end: Duration,
+
+    /// The transcribed text of this segment.
+    pub text: String,
 }
 
 #[async_trait::async_trait]
 pub trait WhisperEndpoint {
-    /// Given an audio segment with several arguments, return a transcription in [`String`] form.
+    /// Given an audio segment with several arguments, return its [`Transcription`].
     async fn transcription(
         &self,
         model_path: impl AsRef<Path> + Send,
         args: TranscriptionArgs,
-    ) -> Result<(String, Option<Uuid>), WhisperEndpointError>;
+    ) -> Result<(Transcription, Option<Uuid>), WhisperEndpointError>;
+
+    /// Returns the models currently loaded into memory.
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        Vec::new()
+    }
 
     /// Unloads everything from memory.
     fn reset(&self);
@@ -72,6 +141,8 @@ pub fn inactive_whisper_session_ttl() -> Duration {
 
 #[derive(Serialize, Error, ToSchema, Debug)]
 pub enum AudioError {
+    #[error("failed to read the audio file: {0}")]
+    Read(String),
     #[error("failed to parse mime data: {0}")]
     Parse(String),
     #[error("failed to initialise resampler: {0}")]
@@ -84,28 +155,93 @@ pub mod parse {
     use rubato::{
         Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
     };
+    use std::time::Duration;
+
     use symphonia::core::audio::Signal;
     use symphonia::core::codecs::{CodecType, DecoderOptions, CODEC_TYPE_NULL};
     use symphonia::core::errors::Error;
     use symphonia::core::formats::{FormatOptions, Track};
-    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::io::{MediaSource, MediaSourceStream};
     use symphonia::core::meta::MetadataOptions;
     use symphonia::core::probe::Hint;
     use tracing::info;
 
-    use crate::whisper::AudioError;
+    use crate::whisper::{AudioError, AudioFile};
+
+    /// The optimal sample rate for whisper models.
+    const OPTIMAL_SAMPLE_RATE: u32 = 16000;
 
     /// Parse an audio file and convert it into a *PCM* audio segment, using the optimal sample rate
     /// for whisper models.
     pub fn pcm(audio_file: &[u8]) -> Result<Vec<f32>, AudioError> {
-        /// The optimal sample rate for whisper models.
-        const OPTIMAL_SAMPLE_RATE: u32 = 16000;
-
         info!("Parsing audio file ({} bytes)", audio_file.len());
 
+        decode(Box::new(std::io::Cursor::new(audio_file.to_vec())))
+    }
+
+    /// Parse an [`AudioFile`] and convert it into a *PCM* audio segment, like [`pcm`].
+    ///
+    /// Files on disk are read as they are decoded, instead of being loaded into memory first.
+    pub fn pcm_of(audio_file: &AudioFile) -> Result<Vec<f32>, AudioError> {
+        match audio_file {
+            AudioFile::Bytes(bytes) => pcm(bytes),
+            AudioFile::Path(path) => {
+                info!("Parsing audio file {}", path.to_string_lossy());
+
+                let file =
+                    std::fs::File::open(path).map_err(move |e| AudioError::Read(e.to_string()))?;
+                decode(Box::new(file))
+            }
+        }
+    }
+
+    /// Returns the duration of the audio of an [`AudioFile`].
+    ///
+    /// The duration is read from the headers of the file when they have it, otherwise the file is
+    /// decoded to measure it.
+    pub fn duration_of(audio_file: &AudioFile) -> Result<Duration, AudioError> {
+        let source: Box<dyn MediaSource> = match audio_file {
+            AudioFile::Bytes(bytes) => Box::new(std::io::Cursor::new(bytes.clone())),
+            AudioFile::Path(path) => Box::new(
+                std::fs::File::open(path).map_err(move |e| AudioError::Read(e.to_string()))?,
+            ),
+        };
+        let stream = MediaSourceStream::new(source, Default::default());
+
+        let probed = symphonia::default::get_probe()
+            .format(
+                &Hint::new(),
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .map_err(move |e| AudioError::Parse(format!("failed to probe audio data: {e}")))?;
+        let params = probed
+            .format
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(AudioError::Parse("codec is null".to_string()))?
+            .codec_params
+            .clone();
+
+        if let (Some(frames), Some(sample_rate)) = (params.n_frames, params.sample_rate) {
+            if sample_rate > 0 {
+                return Ok(Duration::from_secs_f64(frames as f64 / sample_rate as f64));
+            }
+        }
+
+        let pcm = pcm_of(audio_file)?;
+        Ok(Duration::from_secs_f64(
+            pcm.len() as f64 / OPTIMAL_SAMPLE_RATE as f64,
+        ))
+    }
+
+    /// Decodes the audio of a [`MediaSource`] into a *PCM* audio segment, resampled to the optimal
+    /// sample rate for whisper models.
+    fn decode(source: Box<dyn MediaSource>) -> Result<Vec<f32>, AudioError> {
         // Initialisation.
-        let cursor = std::io::Cursor::new(audio_file.to_vec());
-        let stream = MediaSourceStream::new(Box::new(cursor), Default::default());
+        let stream = MediaSourceStream::new(source, Default::default());
 
         let hint = Hint::new();
 
@@ -327,9 +463,216 @@ pub mod parse {
     }
 }
 
+/// Splitting of long audio segments into overlapping windows, and stitching of the resulting
+/// transcriptions back together.
+///
+/// Whisper models degrade considerably (and may exhaust memory) on very long inputs, so runtimes
+/// should transcribe long *PCM* segments window by window, carrying the context over from one
+/// window to the next.
+pub mod chunk {
+    use std::time::Duration;
+
+    use super::{Transcription, TranscriptionSegment};
+
+    /// The sample rate of the *PCM* segments produced by [`super::parse::pcm`].
+    pub const SAMPLE_RATE: usize = 16000;
+
+    /// The length of each window, in seconds.
+    pub const WINDOW_SECS: usize = 30;
+
+    /// The length of the overlap between two consecutive windows, in seconds.
+    pub const OVERLAP_SECS: usize = 2;
+
+    /// The maximum amount of words looked at when removing text duplicated by the overlap between
+    /// two windows.
+    const MAX_OVERLAP_WORDS: usize = 16;
+
+    /// A window of a larger *PCM* audio segment.
+    pub struct Chunk<'a> {
+        /// The position of the start of this window in the original audio segment, which the
+        /// timestamps of anything transcribed from this window are relative to.
+        pub offset: Duration,
+
+        /// The samples of this window.
+        pub pcm: &'a [f32],
+    }
+
+    impl Chunk<'_> {
+        /// Returns the length of this window.
+        pub fn duration(&self) -> Duration {
+            Duration::from_secs_f64(self.pcm.len() as f64 / SAMPLE_RATE as f64)
+        }
+    }
+
+    /// Splits the provided *PCM* segment into windows of [`WINDOW_SECS`] seconds, each overlapping
+    /// the previous one by [`OVERLAP_SECS`] seconds.
+    ///
+    /// Segments shorter than a window produce a single chunk.
+    pub fn chunks(pcm: &[f32]) -> Vec<Chunk> {
+        let window = WINDOW_SECS * SAMPLE_RATE;
+        let step = (WINDOW_SECS - OVERLAP_SECS) * SAMPLE_RATE;
+
+        let mut chunks = vec![];
+        let mut start = 0;
+        loop {
+            let end = usize::min(start + window, pcm.len());
+            chunks.push(Chunk {
+                offset: Duration::from_secs_f64(start as f64 / SAMPLE_RATE as f64),
+                pcm: &pcm[start..end],
+            });
+
+            if end == pcm.len() {
+                break;
+            }
+            start += step;
+        }
+
+        chunks
+    }
+
+    /// Appends the segments transcribed from a window to the transcription of all the previous
+    /// windows.
+    ///
+    /// The timestamps of `segments` are relative to the start of `window`, and are offset by
+    /// [`Chunk::offset`] so that they become relative to the start of the whole audio segment. The
+    /// words already transcribed as part of the overlap are dropped, along with the segments left
+    /// empty.
+    pub fn stitch_window(
+        transcription: &mut Transcription,
+        window: &Chunk,
+        segments: Vec<TranscriptionSegment>,
+    ) {
+        for segment in segments {
+            // A blank transcription is replaced rather than appended to
+            let len = if transcription.text.trim().is_empty() {
+                0
+            } else {
+                transcription.text.len()
+            };
+            stitch(&mut transcription.text, &segment.text);
+
+            let text = transcription.text[len..].trim();
+            if !text.is_empty() {
+                transcription.segments.push(TranscriptionSegment {
+                    start: window.offset + segment.start,
+                    end: window.offset + segment.end,
+                    text: text.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Appends the transcription of a window to the transcription of all the previous windows,
+    /// dropping the leading words of `next` that were already transcribed as part of the overlap.
+    pub fn stitch(transcription: &mut String, next: &str) {
+        let next = next.trim();
+        if next.is_empty() {
+            return;
+        }
+        if transcription.trim().is_empty() {
+            transcription.clear();
+            transcription.push_str(next);
+            return;
+        }
+
+        let previous_words: Vec<_> = transcription.split_whitespace().map(normalise).collect();
+        let next_words: Vec<_> = next.split_whitespace().collect();
+
+        let max = MAX_OVERLAP_WORDS
+            .min(previous_words.len())
+            .min(next_words.len());
+        let overlap = (1..=max)
+            .rev()
+            .find(|n| {
+                previous_words[previous_words.len() - n..]
+                    .iter()
+                    .zip(&next_words[..*n])
+                    .all(|(a, b)| *a == normalise(b))
+            })
+            .unwrap_or(0);
+
+        for word in &next_words[overlap..] {
+            transcription.push(' ');
+            transcription.push_str(word);
+        }
+    }
+
+    /// Strips a word of punctuation and casing, so that it can be compared against the same word
+    /// transcribed in a different window.
+    fn normalise(word: &str) -> String {
+        word.chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect()
+    }
+}
+
+/// A simple, energy based, voice activity detector.
+///
+/// Skipping long silent stretches before handing the audio to a whisper model both cuts the
+/// transcription time and stops the model from hallucinating text over silence (something common
+/// in podcasts and meeting recordings).
+pub mod vad {
+    use super::chunk::SAMPLE_RATE;
+
+    /// The length of each analysed frame, in milliseconds.
+    const FRAME_MS: usize = 30;
+
+    /// The amount of frames kept around detected speech, so that word onsets and endings are not
+    /// clipped.
+    const PADDING_FRAMES: usize = 10;
+
+    /// The RMS energy below which a frame is always considered silent (about -80 dBFS).
+    ///
+    /// This only catches digital silence: the threshold is otherwise relative to the noise floor of
+    /// the recording, so that quiet speech in a clean recording is not dropped.
+    const MIN_THRESHOLD: f32 = 0.0001;
+
+    /// How much louder than the estimated noise floor a frame needs to be to contain speech.
+    const NOISE_FACTOR: f32 = 3.0;
+
+    /// Returns the provided *PCM* segment without its silent stretches.
+    ///
+    /// If no speech is detected at all, an empty segment is returned.
+    pub fn remove_silence(pcm: &[f32]) -> Vec<f32> {
+        let frame_len = SAMPLE_RATE * FRAME_MS / 1000;
+        let energies: Vec<f32> = pcm
+            .chunks(frame_len)
+            .map(|frame| (frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32).sqrt())
+            .collect();
+
+        if energies.is_empty() {
+            return vec![];
+        }
+
+        // Estimate the noise floor from the quietest tenth of the frames.
+        let mut sorted = energies.clone();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let noise_floor = sorted[sorted.len() / 10];
+        let threshold = f32::max(MIN_THRESHOLD, noise_floor * NOISE_FACTOR);
+
+        let mut keep = vec![false; energies.len()];
+        for (i, energy) in energies.iter().enumerate() {
+            if *energy >= threshold {
+                let start = i.saturating_sub(PADDING_FRAMES);
+                let end = usize::min(i + PADDING_FRAMES + 1, energies.len());
+                keep[start..end].iter_mut().for_each(|k| *k = true);
+            }
+        }
+
+        pcm.chunks(frame_len)
+            .zip(keep)
+            .filter(|(_, keep)| *keep)
+            .flat_map(|(frame, _)| frame.iter().copied())
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse;
+    use std::time::Duration;
+
+    use super::{chunk, parse, vad, AudioFile, Transcription, TranscriptionSegment};
 
     #[test]
     fn parse_audio_succeeds() {
@@ -342,4 +685,134 @@ mod tests {
         let sound: Vec<u8> = vec![0, 1, 2, 3];
         assert!(parse::pcm(&sound).is_err(), "can parse non-audio file");
     }
+
+    #[test]
+    fn audio_duration() {
+        let sound = include_bytes!("../../edgen_server/resources/frost.wav");
+        let duration = parse::duration_of(&AudioFile::Bytes(sound.to_vec()))
+            .expect("cannot read the audio duration");
+        let decoded = parse::pcm(sound).unwrap().len() as f64 / 16000.0;
+        assert!((duration.as_secs_f64() - decoded).abs() < 0.1);
+    }
+
+    #[test]
+    fn chunks_overlap() {
+        let window = chunk::WINDOW_SECS * chunk::SAMPLE_RATE;
+        let step = (chunk::WINDOW_SECS - chunk::OVERLAP_SECS) * chunk::SAMPLE_RATE;
+
+        let short = vec![0.0; window / 2];
+        assert_eq!(chunk::chunks(&short).len(), 1);
+
+        let long = vec![0.0; step * 3 + window / 2];
+        let chunks = chunk::chunks(&long);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(
+            chunks[1].offset.as_secs() as usize,
+            chunk::WINDOW_SECS - chunk::OVERLAP_SECS
+        );
+        assert_eq!(chunks[0].pcm.len(), window);
+        assert_eq!(
+            chunks.last().unwrap().pcm.as_ptr_range().end,
+            long.as_ptr_range().end
+        );
+    }
+
+    #[test]
+    fn stitch_removes_overlap() {
+        let mut transcription = String::new();
+        chunk::stitch(&mut transcription, " The quick brown fox");
+        assert_eq!(transcription, "The quick brown fox");
+
+        chunk::stitch(&mut transcription, "brown fox, jumps over");
+        assert_eq!(transcription, "The quick brown fox jumps over");
+
+        chunk::stitch(&mut transcription, "the lazy dog.");
+        assert_eq!(
+            transcription,
+            "The quick brown fox jumps over the lazy dog."
+        );
+    }
+
+    #[test]
+    fn stitch_offsets_segments() {
+        let pcm = vec![0.0; (chunk::WINDOW_SECS * 2) * chunk::SAMPLE_RATE];
+        let chunks = chunk::chunks(&pcm);
+        let segment = |start: u64, end: u64, text: &str| TranscriptionSegment {
+            start: Duration::from_secs(start),
+            end: Duration::from_secs(end),
+            text: text.to_string(),
+        };
+
+        let mut transcription = Transcription::default();
+        chunk::stitch_window(
+            &mut transcription,
+            &chunks[0],
+            vec![segment(0, 10, "The quick"), segment(10, 30, "brown fox")],
+        );
+        chunk::stitch_window(
+            &mut transcription,
+            &chunks[1],
+            vec![segment(0, 2, "brown fox"), segment(2, 9, "jumps over")],
+        );
+
+        let offset = (chunk::WINDOW_SECS - chunk::OVERLAP_SECS) as u64;
+        assert_eq!(transcription.text, "The quick brown fox jumps over");
+        assert_eq!(
+            transcription.segments,
+            [
+                segment(0, 10, "The quick"),
+                segment(10, 30, "brown fox"),
+                segment(offset + 2, offset + 9, "jumps over"),
+            ]
+        );
+        assert_eq!(
+            chunks[1].duration(),
+            Duration::from_secs(chunk::WINDOW_SECS as u64)
+        );
+    }
+
+    #[test]
+    fn vad_removes_silence() {
+        let second = chunk::SAMPLE_RATE;
+        let tone: Vec<f32> = (0..second).map(|i| (i as f32 * 0.1).sin() * 0.5).collect();
+
+        let mut pcm = vec![0.0; second * 5];
+        pcm.extend_from_slice(&tone);
+        pcm.extend(vec![0.0; second * 5]);
+
+        let filtered = vad::remove_silence(&pcm);
+        assert!(filtered.len() >= tone.len());
+        assert!(filtered.len() < second * 2);
+
+        assert!(vad::remove_silence(&vec![0.0; second]).is_empty());
+    }
+
+    #[test]
+    fn vad_keeps_quiet_speech() {
+        let second = chunk::SAMPLE_RATE;
+        // Deterministic background noise, far below the speech
+        let mut state = 1u32;
+        let mut noise = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as f32 / u32::MAX as f32 - 0.5) * 0.0004
+        };
+        // Whispered speech, peaking at about -48 dBFS
+        let tone: Vec<f32> = (0..second)
+            .map(|i| (i as f32 * 0.1).sin() * 0.004 + noise())
+            .collect();
+
+        let mut pcm: Vec<f32> = (0..second * 5).map(|_| noise()).collect();
+        pcm.extend_from_slice(&tone);
+        pcm.extend((0..second * 5).map(|_| noise()));
+
+        let filtered = vad::remove_silence(&pcm);
+        assert!(filtered.len() >= tone.len());
+        assert!(filtered.len() < second * 2);
+
+        let mut silent = vec![0.0; second * 5];
+        silent.extend(tone.iter().map(|x| x * 0.5));
+        assert!(vad::remove_silence(&silent).len() >= tone.len());
+    }
 }
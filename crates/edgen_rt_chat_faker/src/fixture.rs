@@ -0,0 +1,140 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Scripted responses of the chat faker, loaded from a YAML or JSON fixture file.
+//!
+//! A fixture looks like this:
+//!
+//! ```yaml
+//! responses:
+//!   - prompt: capital of france
+//!     response: The capital of France is Paris.
+//!   - prompt: tell me a joke
+//!     chunks: ["Why did ", "the chicken ", "cross the road?"]
+//! default: I have no idea.
+//! ```
+//!
+//! The first response whose `prompt` is contained in the last user message of a request, ignoring
+//! case, is the one returned.
+
+use std::path::Path;
+
+use serde_derive::Deserialize;
+
+use edgen_core::llm::LLMEndpointError;
+
+/// A set of scripted responses.
+#[derive(Debug, Default, Deserialize)]
+pub struct Fixture {
+    /// The scripted responses, in order of precedence.
+    #[serde(default)]
+    pub responses: Vec<ScriptedResponse>,
+
+    /// The response returned when no scripted response matches, if any.
+    pub default: Option<String>,
+}
+
+/// A response returned for the prompts that contain a piece of text.
+#[derive(Debug, Deserialize)]
+pub struct ScriptedResponse {
+    /// The text a prompt must contain for this response to be returned.
+    pub prompt: String,
+
+    /// The complete response. If not provided, it is the concatenation of the `chunks`.
+    pub response: Option<String>,
+
+    /// The chunks the response is streamed in. If not provided, the response is streamed word by
+    /// word.
+    pub chunks: Option<Vec<String>>,
+}
+
+impl Fixture {
+    /// Loads a fixture from a YAML or JSON file.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Self, LLMEndpointError> {
+        let path = path.as_ref();
+        let contents = tokio::fs::read(path).await.map_err(move |e| {
+            LLMEndpointError::Load(format!("failed to read fixture {path:?}: {e}"))
+        })?;
+        Self::parse(&contents)
+            .map_err(move |e| LLMEndpointError::Load(format!("invalid fixture {path:?}: {e}")))
+    }
+
+    /// Parses a YAML or JSON fixture.
+    pub fn parse(contents: &[u8]) -> Result<Self, serde_yaml::Error> {
+        // JSON is valid YAML, so a single parser handles both
+        serde_yaml::from_slice(contents)
+    }
+
+    /// Returns the scripted response for the last user message of a request, if any.
+    pub fn response_for(&self, message: &str) -> Option<&ScriptedResponse> {
+        let message = message.to_lowercase();
+        self.responses
+            .iter()
+            .find(move |response| message.contains(&response.prompt.to_lowercase()))
+    }
+}
+
+impl ScriptedResponse {
+    /// Returns the complete response.
+    pub fn text(&self) -> String {
+        match (&self.response, &self.chunks) {
+            (Some(response), _) => response.clone(),
+            (None, Some(chunks)) => chunks.concat(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Returns the chunks the response is streamed in.
+    pub fn chunks(&self) -> Vec<String> {
+        match &self.chunks {
+            Some(chunks) => chunks.clone(),
+            None => crate::streamify(&self.text()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_responses() {
+        let fixture = Fixture::parse(
+            br#"
+            responses:
+              - prompt: Capital of France
+                response: Paris.
+              - prompt: joke
+                chunks: ["Why did ", "the chicken?"]
+            default: No idea.
+            "#,
+        )
+        .unwrap();
+
+        let paris = fixture
+            .response_for("What is the capital of france?")
+            .unwrap();
+        assert_eq!(paris.text(), "Paris.");
+        assert_eq!(paris.chunks(), ["Paris."]);
+
+        let joke = fixture.response_for("Tell me a joke").unwrap();
+        assert_eq!(joke.text(), "Why did the chicken?");
+        assert_eq!(joke.chunks(), ["Why did ", "the chicken?"]);
+
+        assert!(fixture.response_for("Hello").is_none());
+        assert_eq!(fixture.default.as_deref(), Some("No idea."));
+
+        let json =
+            Fixture::parse(br#"{"responses": [{"prompt": "hi", "response": "Hello!"}]}"#).unwrap();
+        assert_eq!(json.response_for("hi there").unwrap().text(), "Hello!");
+    }
+}
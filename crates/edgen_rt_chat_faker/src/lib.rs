@@ -13,30 +13,65 @@
 //! A fake model RT for chat completions that answers with predefined strings
 
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use dashmap::DashMap;
 use futures::Stream;
+use rand::Rng;
 use tracing::info;
 
-use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError};
+use edgen_core::llm::{ChatMessage, ChatMessages, CompletionArgs, LLMEndpoint, LLMEndpointError};
+use edgen_core::resident::ResidentModel;
+use edgen_core::settings;
+use edgen_core::settings::{FakerFault, FakerFaults, FakerLatency};
+
+use crate::fixture::Fixture;
+
+mod fixture;
 
 pub const CAPITAL: &str = "The capital of Canada is Ottawa.";
 pub const CAPITAL_OF_PORTUGAL: &str = "The capital of Portugal is Lisbon.";
 pub const DEFAULT_ANSWER: &str = "The answer is 42.";
 pub const LONG_ANSWER: &str = "Call me Ishmael. Some years ago—never mind how long precisely—having little or no money in my purse, and nothing particular to interest me on shore, I thought I would sail about a little and see the watery part of the world. It is a way I have of driving off the spleen and regulating circulation. Whenever I find myself growing grim about the mouth; whenever it is a damp, drizzly November in my soul; whenever I find myself involuntarily pausing before coffin warehouses, and bringing up the rear of every funeral I meet; and especially whenever my hypos get such an upper hand of me, that it requires a strong moral principle to prevent me from deliberately stepping into the street, and methodically knocking people’s hats off—then, I account it high time to get to sea as soon as I can. There is nothing surprising in this. If they but knew it, almost all men in their degree, some time or other, cherish very nearly the same feelings towards the ocean with me.";
 
-struct ChatFakerModel {}
+/// The chunk streamed by the [`FakerFault::MalformedChunk`] fault.
+pub const MALFORMED_CHUNK: &str = "\u{0}\u{1b}[0m\u{fffd}{\"choices\": [";
+
+struct ChatFakerModel {
+    /// The scripted responses configured in the settings, if any.
+    fixture: Option<Fixture>,
+
+    /// The number of requests served so far, to schedule the injected faults.
+    requests: AtomicU64,
+}
 
 impl ChatFakerModel {
-    async fn new(_path: impl AsRef<Path>) -> Self {
-        Self {}
+    async fn new(_path: impl AsRef<Path>) -> Result<Self, LLMEndpointError> {
+        let fixture = match settings::chat_faker_fixture().await {
+            Some(path) => Some(Fixture::load(path).await?),
+            None => None,
+        };
+        Ok(Self {
+            fixture,
+            requests: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns the faults to inject into the current request, if any.
+    async fn next_faults(&self) -> Option<FakerFaults> {
+        let faults = settings::chat_faker_faults().await;
+        let request = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        (faults.fault != FakerFault::None && request % faults.every.max(1) == 0).then_some(faults)
     }
 
     async fn chat_completions(&self, args: &CompletionArgs) -> Result<String, LLMEndpointError> {
         info!("faking chat completions");
-        let prompt = format!("{}<|ASSISTANT|>", args.messages);
-        Ok(completions_for(&prompt))
+        if let Some(e) = self.next_faults().await.and_then(|f| fault_error(f.fault)) {
+            return Err(e);
+        }
+        Ok(self.completions_for(&args.messages))
     }
 
     async fn stream_chat_completions(
@@ -44,19 +79,64 @@ impl ChatFakerModel {
         args: &CompletionArgs,
     ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
         info!("faking stream chat completions");
-        let prompt = format!("{}<|ASSISTANT|>", args.messages);
-        let msg = completions_for(&prompt);
-        let toks = streamify(&msg);
-        Ok(Box::new(futures::stream::iter(toks.into_iter())))
+        let faults = self.next_faults().await;
+        if let Some(e) = faults.and_then(|f| fault_error(f.fault)) {
+            return Err(e);
+        }
+
+        let message = last_user_message(&args.messages);
+        let mut toks = match self.fixture.as_ref().and_then(|f| f.response_for(&message)) {
+            Some(response) => response.chunks(),
+            None => streamify(&self.completions_for(&args.messages)),
+        };
+        if let Some(faults) = faults {
+            inject_stream_fault(&mut toks, &faults);
+        }
+        let latency = settings::chat_faker_latency().await;
+        if latency == FakerLatency::default() {
+            return Ok(Box::new(futures::stream::iter(toks.into_iter())));
+        }
+
+        Ok(Box::new(Box::pin(delayed(toks, latency))))
     }
+}
 
-    //TODO: implement
-    async fn embeddings(&self, _inputs: &[String]) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
-        info!("faking emeddings");
-        Ok(vec![])
+impl ChatFakerModel {
+    /// Returns the scripted response for the last user message, falling back to the fixture's
+    /// default and then to the built-in answers.
+    fn completions_for(&self, messages: &ChatMessages) -> String {
+        let prompt = format!("{messages}<|ASSISTANT|>");
+        match &self.fixture {
+            Some(fixture) => match fixture.response_for(&last_user_message(messages)) {
+                Some(response) => response.text(),
+                None => fixture
+                    .default
+                    .clone()
+                    .unwrap_or_else(|| completions_for(&prompt)),
+            },
+            None => completions_for(&prompt),
+        }
     }
 }
 
+/// Returns the content of the last user message, or an empty string if there is none.
+///
+/// Scripted responses are matched against this message only, so that a response is not returned
+/// again for every later turn of a conversation that once matched it.
+fn last_user_message(messages: &ChatMessages) -> String {
+    messages
+        .iter()
+        .rev()
+        .find_map(|message| match message {
+            ChatMessage::User { content, .. } => Some(content.as_ref().either(
+                |text| text.clone(),
+                |parts| parts.iter().map(|part| part.to_string()).collect(),
+            )),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
 fn completions_for(prompt: &str) -> String {
     let prompt = prompt.to_lowercase();
     if prompt.contains("capital") {
@@ -76,6 +156,49 @@ fn streamify(msg: &str) -> Vec<String> {
     msg.split_whitespace().map(|s| s.to_string()).collect()
 }
 
+/// Returns the error a fault makes a request fail with, if any.
+fn fault_error(fault: FakerFault) -> Option<LLMEndpointError> {
+    let reason = "fault injected by the chat faker".to_string();
+    match fault {
+        FakerFault::LoadError => Some(LLMEndpointError::Load(reason)),
+        FakerFault::AdvanceError => Some(LLMEndpointError::Advance(reason)),
+        FakerFault::SessionError => Some(LLMEndpointError::SessionCreationFailed(reason)),
+        _ => None,
+    }
+}
+
+/// Applies a stream fault to the chunks that are going to be streamed.
+fn inject_stream_fault(toks: &mut Vec<String>, faults: &FakerFaults) {
+    let at = faults.after_chunks.min(toks.len());
+    match faults.fault {
+        FakerFault::MalformedChunk => toks.insert(at, MALFORMED_CHUNK.to_string()),
+        FakerFault::Disconnect => toks.truncate(at),
+        _ => {}
+    }
+}
+
+/// Streams the chunks, waiting before each of them as configured by the [`FakerLatency`].
+fn delayed(toks: Vec<String>, latency: FakerLatency) -> impl Stream<Item = String> + Send {
+    futures::stream::unfold(
+        (toks.into_iter(), true),
+        move |(mut toks, first)| async move {
+            let tok = toks.next()?;
+            let base = if first {
+                latency.first_token_ms
+            } else {
+                latency.token_delay_ms
+            };
+            let jitter = if latency.jitter_ms > 0 {
+                rand::thread_rng().gen_range(0..=latency.jitter_ms)
+            } else {
+                0
+            };
+            tokio::time::sleep(Duration::from_millis(base + jitter)).await;
+            Some((tok, (toks, false)))
+        },
+    )
+}
+
 /// Faking a large language model endpoint, implementing [`LLMEndpoint`].
 pub struct ChatFakerEndpoint {
     /// A map of the models currently loaded into memory, with their path as the key.
@@ -88,16 +211,16 @@ impl ChatFakerEndpoint {
     async fn get(
         &self,
         model_path: impl AsRef<Path>,
-    ) -> dashmap::mapref::one::Ref<String, ChatFakerModel> {
+    ) -> Result<dashmap::mapref::one::Ref<String, ChatFakerModel>, LLMEndpointError> {
         let key = model_path.as_ref().to_string_lossy().to_string();
 
         if !self.models.contains_key(&key) {
-            let model = ChatFakerModel::new(model_path).await;
+            let model = ChatFakerModel::new(model_path).await?;
             self.models.insert(key.clone(), model);
         }
 
         // PANIC SAFETY: Just inserted the element if it isn't already inside the map, so must be present in the map
-        self.models.get(&key).unwrap()
+        Ok(self.models.get(&key).unwrap())
     }
 }
 
@@ -108,7 +231,7 @@ impl LLMEndpoint for ChatFakerEndpoint {
         model_path: impl AsRef<Path> + Send,
         args: CompletionArgs,
     ) -> Result<String, LLMEndpointError> {
-        let model = self.get(model_path).await;
+        let model = self.get(model_path).await?;
         model.chat_completions(&args).await
     }
 
@@ -117,17 +240,15 @@ impl LLMEndpoint for ChatFakerEndpoint {
         model_path: impl AsRef<Path> + Send,
         args: CompletionArgs,
     ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
-        let model = self.get(model_path).await;
+        let model = self.get(model_path).await?;
         model.stream_chat_completions(&args).await
     }
 
-    async fn embeddings(
-        &self,
-        model_path: impl AsRef<Path> + Send,
-        inputs: Vec<String>,
-    ) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
-        let model = self.get(model_path).await;
-        model.embeddings(&inputs).await
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        self.models
+            .iter()
+            .map(move |model| ResidentModel::new(model.key(), "cpu".to_string(), 0, 0))
+            .collect()
     }
 
     fn reset(&self) {
@@ -141,3 +262,60 @@ impl Default for ChatFakerEndpoint {
         Self { models }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use either::Either;
+
+    use super::*;
+
+    fn faults(fault: FakerFault, after_chunks: usize) -> FakerFaults {
+        FakerFaults {
+            fault,
+            every: 1,
+            after_chunks,
+        }
+    }
+
+    #[test]
+    fn stream_faults() {
+        let mut toks = streamify(DEFAULT_ANSWER);
+        inject_stream_fault(&mut toks, &faults(FakerFault::Disconnect, 2));
+        assert_eq!(toks, ["The", "answer"]);
+
+        let mut toks = streamify(DEFAULT_ANSWER);
+        inject_stream_fault(&mut toks, &faults(FakerFault::MalformedChunk, 1));
+        assert_eq!(toks, ["The", MALFORMED_CHUNK, "answer", "is", "42."]);
+
+        let mut toks = streamify(DEFAULT_ANSWER);
+        inject_stream_fault(&mut toks, &faults(FakerFault::AdvanceError, 1));
+        assert_eq!(toks, streamify(DEFAULT_ANSWER));
+        assert!(matches!(
+            fault_error(FakerFault::AdvanceError),
+            Some(LLMEndpointError::Advance(_))
+        ));
+        assert!(fault_error(FakerFault::Disconnect).is_none());
+    }
+
+    #[test]
+    fn last_user_messages() {
+        let user = |text: &str| ChatMessage::User {
+            content: Either::Left(text.to_string()),
+            name: None,
+        };
+        let messages = ChatMessages(vec![
+            user("Tell me a joke"),
+            ChatMessage::Assistant {
+                content: Some("Why did the chicken?".to_string()),
+                name: None,
+                tool_calls: None,
+            },
+            user("What is the capital of France?"),
+        ]);
+        assert_eq!(
+            last_user_message(&messages),
+            "What is the capital of France?"
+        );
+        assert_eq!(last_user_message(&ChatMessages::default()), "");
+    }
+}
@@ -1,22 +1,39 @@
 use std::io::BufWriter;
 use std::io::{Cursor, IntoInnerError};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use candle_core::backend::BackendDevice;
-use candle_core::{CudaDevice, DType, Device, IndexOp, Module, Tensor, D};
+use candle_core::{DType, Device, DeviceLocation, IndexOp, Module, Tensor, D};
+use candle_transformers::models::stable_diffusion::clip::ClipTextTransformer;
+use candle_transformers::models::stable_diffusion::unet_2d::UNet2DConditionModel;
 use candle_transformers::models::stable_diffusion::vae::AutoEncoderKL;
+use candle_transformers::models::stable_diffusion::StableDiffusionConfig;
 use candle_transformers::models::{stable_diffusion, wuerstchen};
+use dashmap::DashMap;
 use image::{ImageBuffer, ImageError, ImageFormat, Rgb};
-use rand::random;
+use rand::rngs::StdRng;
+use rand::{random, Rng, SeedableRng};
 use thiserror::Error;
 use tokenizers::Tokenizer;
+use tokio::time::{interval, MissedTickBehavior};
 use tracing::{debug, info, info_span, warn};
 
+use edgen_core::cleanup_interval;
 use edgen_core::image_generation::{
-    ImageGenerationArgs, ImageGenerationEndpoint, ImageGenerationEndpointError, ModelFiles,
+    inactive_image_generation_ttl, GeneratedImage, ImageGenerationArgs, ImageGenerationEndpoint,
+    ImageGenerationEndpointError, ModelFiles, StableDiffusionVersion,
 };
+use edgen_core::lazy_task::LazyTask;
+use edgen_core::perishable::{retain_alive, ActiveSignal, Perishable, PerishableReadGuard};
+use edgen_core::resident::ResidentModel;
 use edgen_core::settings::{DevicePolicy, SETTINGS};
 
+use crate::lcm::LcmScheduler;
+use crate::safety_checker::SafetyChecker;
+
+mod lcm;
+mod safety_checker;
+
 #[derive(Error, Debug)]
 enum CandleError {
     #[error("The prompt is too long, {len} > max-tokens ({max})")]
@@ -35,22 +52,103 @@ enum CandleError {
     EncodeProcessFailed(#[from] ImageError),
     #[error(transparent)]
     EncodeWriteFailed(#[from] IntoInnerError<BufWriter<Cursor<Vec<u8>>>>),
+    #[error("No acceleration device available: {0}")]
+    NoDevice(String),
+}
+
+/// A stable diffusion model, loaded into memory.
+struct StableDiffusionModel {
+    version: StableDiffusionVersion,
+    tokenizer: Tokenizer,
+    clip: ClipTextTransformer,
+    clip2: Option<ClipTextTransformer>,
+    vae: AutoEncoderKL,
+    unet: UNet2DConditionModel,
+    safety_checker: Option<SafetyChecker>,
+    dtype: DType,
+    /// The device the model is loaded on, which every tensor used with it must be on too.
+    device: Device,
+}
+
+impl StableDiffusionModel {
+    /// Loads every part of a stable diffusion model into the memory of the provided [`Device`].
+    fn load(files: &ModelFiles, device: &Device) -> Result<Self, CandleError> {
+        let _span = info_span!("sd_load", unet = ?files.unet_weights).entered();
+        let config = sd_config(files.version, None, None);
+        // Half precision is very slow (when supported at all) on most CPUs
+        let dtype = if device.is_cpu() {
+            DType::F32
+        } else {
+            DType::F16
+        };
+
+        let tokenizer = Tokenizer::from_file(&files.tokenizer)
+            .map_err(|e| CandleError::Tokenizer(e.to_string()))?;
+        let clip = stable_diffusion::build_clip_transformer(
+            &config.clip,
+            &files.clip_weights,
+            device,
+            DType::F32,
+        )?;
+        let clip2 = if let Some(clip2_weights) = &files.clip2_weights {
+            let clip2_config = config.clip2.as_ref().ok_or(CandleError::Clip2Unavailable)?;
+            Some(stable_diffusion::build_clip_transformer(
+                clip2_config,
+                clip2_weights,
+                device,
+                DType::F32,
+            )?)
+        } else {
+            None
+        };
+        let vae = config.build_vae(&files.vae_weights, device, dtype)?;
+        let unet = config.build_unet(&files.unet_weights, device, 4, false, dtype)?;
+        let safety_checker = if let Some(weights) = &files.safety_checker {
+            Some(SafetyChecker::load(weights, device)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            version: files.version,
+            tokenizer,
+            clip,
+            clip2,
+            vae,
+            unet,
+            safety_checker,
+            dtype,
+            device: device.clone(),
+        })
+    }
+}
+
+/// Returns the [`StableDiffusionConfig`] matching the provided model version and image size.
+fn sd_config(
+    version: StableDiffusionVersion,
+    height: Option<usize>,
+    width: Option<usize>,
+) -> StableDiffusionConfig {
+    match version {
+        StableDiffusionVersion::V2_1 => StableDiffusionConfig::v2_1(None, height, width),
+        StableDiffusionVersion::Xl | StableDiffusionVersion::XlLcm => {
+            StableDiffusionConfig::sdxl(None, height, width)
+        }
+        StableDiffusionVersion::Turbo => StableDiffusionConfig::sdxl_turbo(None, height, width),
+    }
 }
 
 fn sd_text_embeddings(
     prompt: &str,
     uncond_prompt: &str,
-    tokenizer: impl AsRef<Path>,
-    clip_weights: impl AsRef<Path>,
-    config: &stable_diffusion::StableDiffusionConfig,
+    tokenizer: &Tokenizer,
+    text_model: &ClipTextTransformer,
+    clip_config: &stable_diffusion::clip::Config,
     device: &Device,
     dtype: DType,
     use_guide_scale: bool,
-    first_clip: bool,
 ) -> Result<Tensor, CandleError> {
-    let tokenizer =
-        Tokenizer::from_file(tokenizer).map_err(|e| CandleError::Tokenizer(e.to_string()))?;
-    let pad_id = match &config.clip.pad_with {
+    let pad_id = match &clip_config.pad_with {
         Some(padding) => *tokenizer.get_vocab(true).get(padding.as_str()).unwrap(),
         None => *tokenizer.get_vocab(true).get("<|endoftext|>").unwrap(),
     };
@@ -60,24 +158,17 @@ fn sd_text_embeddings(
         .map_err(|e| CandleError::Tokenizer(e.to_string()))?
         .get_ids()
         .to_vec();
-    if tokens.len() > config.clip.max_position_embeddings {
+    if tokens.len() > clip_config.max_position_embeddings {
         return Err(CandleError::PromptTooLong {
             len: tokens.len(),
-            max: config.clip.max_position_embeddings,
+            max: clip_config.max_position_embeddings,
         });
     }
-    while tokens.len() < config.clip.max_position_embeddings {
+    while tokens.len() < clip_config.max_position_embeddings {
         tokens.push(pad_id)
     }
     let tokens = Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?;
 
-    let clip_config = if first_clip {
-        &config.clip
-    } else {
-        config.clip2.as_ref().ok_or(CandleError::Clip2Unavailable)?
-    };
-    let text_model =
-        stable_diffusion::build_clip_transformer(clip_config, clip_weights, device, DType::F32)?;
     let text_embeddings = text_model.forward(&tokens)?;
 
     let text_embeddings = if use_guide_scale {
@@ -86,13 +177,13 @@ fn sd_text_embeddings(
             .map_err(|e| CandleError::Tokenizer(e.to_string()))?
             .get_ids()
             .to_vec();
-        if uncond_tokens.len() > config.clip.max_position_embeddings {
+        if uncond_tokens.len() > clip_config.max_position_embeddings {
             return Err(CandleError::PromptTooLong {
                 len: uncond_tokens.len(),
-                max: config.clip.max_position_embeddings,
+                max: clip_config.max_position_embeddings,
             });
         }
-        while uncond_tokens.len() < config.clip.max_position_embeddings {
+        while uncond_tokens.len() < clip_config.max_position_embeddings {
             uncond_tokens.push(pad_id)
         }
 
@@ -106,12 +197,15 @@ fn sd_text_embeddings(
     Ok(text_embeddings)
 }
 
+/// Decodes the latents into PNG images, along with whether the safety checker (if any) classified
+/// each of them as NSFW.
 fn sd_to_bitmap(
     vae: &AutoEncoderKL,
+    safety_checker: Option<&SafetyChecker>,
     latents: &Tensor,
     vae_scale: f64,
     bsize: usize,
-) -> Result<Vec<Vec<u8>>, CandleError> {
+) -> Result<Vec<(Vec<u8>, bool)>, CandleError> {
     let images = vae.decode(&(latents / vae_scale)?)?;
     let images = ((images / 2.)? + 0.5)?.to_device(&Device::Cpu)?;
     let images = (images.clamp(0f32, 1.)? * 255.)?.to_dtype(DType::U8)?;
@@ -130,76 +224,112 @@ fn sd_to_bitmap(
         let pixels = img.to_vec1::<u8>()?;
         let buf = ImageBuffer::<Rgb<u8>, _>::from_vec(width as u32, height as u32, pixels)
             .ok_or(CandleError::BadOutput)?;
+        let nsfw = match safety_checker {
+            Some(checker) => checker.is_nsfw(&buf)?,
+            None => false,
+        };
         let mut encoded = BufWriter::new(Cursor::new(Vec::new()));
         buf.write_to(&mut encoded, ImageFormat::Png)?;
-        res.push(encoded.into_inner()?.into_inner());
+        res.push((encoded.into_inner()?.into_inner(), nsfw));
     }
     Ok(res)
 }
 
+/// Creates a tensor of the given shape, with values sampled from a standard normal distribution
+/// using a generator seeded with `seed`.
+fn seeded_randn(
+    seed: u64,
+    shape: (usize, usize, usize, usize),
+    device: &Device,
+) -> Result<Tensor, CandleError> {
+    if !device.is_cpu() {
+        device.set_seed(seed)?;
+        return Ok(Tensor::randn(0f32, 1f32, shape, device)?);
+    }
+
+    // The CPU backend cannot be seeded, so the samples are generated here instead (using the
+    // Box-Muller transform)
+    let mut rng = StdRng::seed_from_u64(seed);
+    let len = shape.0 * shape.1 * shape.2 * shape.3;
+    let mut samples = Vec::with_capacity(len);
+    while samples.len() < len {
+        let u1: f32 = 1.0 - rng.gen::<f32>();
+        let u2: f32 = rng.gen();
+        let radius = (-2.0 * u1.ln()).sqrt();
+        let theta = 2.0 * std::f32::consts::PI * u2;
+        samples.push(radius * theta.cos());
+        samples.push(radius * theta.sin());
+    }
+    samples.truncate(len);
+
+    Ok(Tensor::from_vec(samples, shape, device)?)
+}
+
 fn sd_generate_image(
-    model: ModelFiles,
+    model: &StableDiffusionModel,
     args: ImageGenerationArgs,
-    device: Device,
-) -> Result<Vec<Vec<u8>>, CandleError> {
+) -> Result<Vec<GeneratedImage>, CandleError> {
     let _span = info_span!("sd_gen_image", images = args.images, steps = args.steps).entered();
-    let config = stable_diffusion::StableDiffusionConfig::v2_1(None, args.height, args.width);
+    let config = sd_config(model.version, args.height, args.width);
     let scheduler = config.build_scheduler(args.steps)?;
+    let lcm_scheduler = if model.version == StableDiffusionVersion::XlLcm {
+        Some(LcmScheduler::new(args.steps))
+    } else {
+        None
+    };
     let use_guide_scale = args.guidance_scale > 1.0;
-    let dtype = DType::F16;
+    let dtype = model.dtype;
+    let device = &model.device;
     let bsize = 1;
 
-    device.set_seed(args.seed.unwrap_or(random::<u64>()))?;
-
-    let which = if model.clip2_weights.is_some() {
-        vec![true, false]
-    } else {
-        vec![true]
-    };
-    let text_embeddings = which
-        .iter()
-        .map(|first| {
-            let clip = if *first {
-                &model.clip_weights
-            } else {
-                model.clip2_weights.as_ref().unwrap()
-            };
-            sd_text_embeddings(
-                &args.prompt,
-                &args.uncond_prompt,
-                &model.tokenizer,
-                clip,
-                &config,
-                &device,
-                dtype,
-                use_guide_scale,
-                *first,
-            )
-        })
-        .collect::<Result<Vec<_>, CandleError>>()?;
+    let mut text_embeddings = vec![sd_text_embeddings(
+        &args.prompt,
+        &args.uncond_prompt,
+        &model.tokenizer,
+        &model.clip,
+        &config.clip,
+        device,
+        dtype,
+        use_guide_scale,
+    )?];
+    if let Some(clip2) = &model.clip2 {
+        let clip2_config = config.clip2.as_ref().ok_or(CandleError::Clip2Unavailable)?;
+        text_embeddings.push(sd_text_embeddings(
+            &args.prompt,
+            &args.uncond_prompt,
+            &model.tokenizer,
+            clip2,
+            clip2_config,
+            device,
+            dtype,
+            use_guide_scale,
+        )?);
+    }
 
     let text_embeddings = Tensor::cat(&text_embeddings, D::Minus1)?;
     let text_embeddings = text_embeddings.repeat((bsize, 1, 1))?;
 
-    let vae = config.build_vae(model.vae_weights, &device, dtype)?;
-    let unet = config.build_unet(model.unet_weights, &device, 4, false, dtype)?;
-
     // This would be used in image to image scenarios
     let t_start = 0;
 
     let mut images = vec![];
     images.reserve(args.images as usize);
     for idx in 0..args.images {
-        let _span = info_span!("image", image_index = idx).entered();
+        let seed = match args.seed {
+            Some(seed) => seed.wrapping_add(idx as u64),
+            None => random::<u64>(),
+        };
+        let _span = info_span!("image", image_index = idx, seed).entered();
         info!("Generating image");
-        let timesteps = scheduler.timesteps();
-        let latents = Tensor::randn(
-            0f32,
-            1f32,
-            (bsize, 4, config.height / 8, config.width / 8),
-            &device,
-        )? * scheduler.init_noise_sigma();
+        let shape = (bsize, 4, config.height / 8, config.width / 8);
+        let (timesteps, init_noise_sigma) = match &lcm_scheduler {
+            Some(lcm_scheduler) => (lcm_scheduler.timesteps(), 1.0),
+            None => (scheduler.timesteps(), scheduler.init_noise_sigma()),
+        };
+        let latents = seeded_randn(seed, shape, device)? * init_noise_sigma;
         let mut latents = latents?.to_dtype(dtype)?;
+        // Seeds the noise added back by the LCM scheduler, so that images remain reproducible
+        let mut noise_rng = StdRng::seed_from_u64(seed);
 
         for (timestep_index, &timestep) in timesteps.iter().enumerate() {
             debug!("Image generation step {timestep_index}");
@@ -212,9 +342,15 @@ fn sd_generate_image(
                 latents.clone()
             };
 
-            let latent_model_input = scheduler.scale_model_input(latent_model_input, timestep)?;
+            let latent_model_input = if lcm_scheduler.is_some() {
+                latent_model_input
+            } else {
+                scheduler.scale_model_input(latent_model_input, timestep)?
+            };
             let noise_pred =
-                unet.forward(&latent_model_input, timestep as f64, &text_embeddings)?;
+                model
+                    .unet
+                    .forward(&latent_model_input, timestep as f64, &text_embeddings)?;
 
             let noise_pred = if use_guide_scale {
                 let noise_pred = noise_pred.chunk(2, 0)?;
@@ -226,10 +362,26 @@ fn sd_generate_image(
                 noise_pred
             };
 
-            latents = scheduler.step(&noise_pred, timestep, &latents)?;
+            latents = match &lcm_scheduler {
+                Some(lcm_scheduler) => {
+                    let noise = seeded_randn(noise_rng.gen(), shape, device)?.to_dtype(dtype)?;
+                    lcm_scheduler.step(&noise_pred, timestep, &latents, &noise)?
+                }
+                None => scheduler.step(&noise_pred, timestep, &latents)?,
+            };
         }
 
-        images.extend(sd_to_bitmap(&vae, &latents, args.vae_scale, bsize)?)
+        images.extend(
+            sd_to_bitmap(
+                &model.vae,
+                model.safety_checker.as_ref(),
+                &latents,
+                args.vae_scale,
+                bsize,
+            )?
+            .into_iter()
+            .map(|(data, nsfw)| GeneratedImage { data, seed, nsfw }),
+        )
     }
 
     Ok(images)
@@ -440,7 +592,32 @@ fn ws_generate_image(
     Ok(res)
 }
 
-pub struct CandleImageGenerationEndpoint {}
+/// The key of a model in the cache of a [`CandleImageGenerationEndpoint`], made of the location of
+/// the device it is loaded on and its files.
+type ModelKey = (String, String);
+
+/// A model in the cache of a [`CandleImageGenerationEndpoint`].
+struct CachedModel {
+    /// The device the model is loaded on, created along with the cache entry. It is reused by
+    /// every generation, since **candle** tensors must be on the very same device instance to be
+    /// used together.
+    device: Device,
+    model: Perishable<StableDiffusionModel>,
+
+    /// The files of the model, the UNet weights first, as reported in [`ResidentModel`]s.
+    files: Vec<PathBuf>,
+}
+
+/// An image generation endpoint, implementing [`ImageGenerationEndpoint`] using a [`candle_core`]
+/// backend.
+pub struct CandleImageGenerationEndpoint {
+    /// A map of the models currently loaded into memory, with their device and files as the key.
+    models: Arc<DashMap<ModelKey, CachedModel>>,
+
+    /// A background thread that periodically removes models from the `models` collection, if they
+    /// are not loaded at the time. It is started along with the first model.
+    cleanup_thread: LazyTask,
+}
 
 #[async_trait::async_trait]
 impl ImageGenerationEndpoint for CandleImageGenerationEndpoint {
@@ -448,22 +625,218 @@ impl ImageGenerationEndpoint for CandleImageGenerationEndpoint {
         &self,
         model: ModelFiles,
         args: ImageGenerationArgs,
-    ) -> Result<Vec<Vec<u8>>, ImageGenerationEndpointError> {
-        let device = match SETTINGS.read().await.read().await.gpu_policy {
-            DevicePolicy::AlwaysCpu { .. } => Device::Cpu,
-            DevicePolicy::AlwaysDevice { .. } => {
-                Device::Cuda(CudaDevice::new(0).map_err(|e| CandleError::Candle(e))?)
-            }
-            _ => {
-                warn!("Unknown device policy, executing on CPU");
-                Device::Cpu
+    ) -> Result<Vec<GeneratedImage>, ImageGenerationEndpointError> {
+        let files = format!(
+            "{:?}:{:?}:{:?}:{:?}:{:?}:{:?}",
+            model.unet_weights,
+            model.vae_weights,
+            model.clip_weights,
+            model.clip2_weights,
+            model.tokenizer,
+            model.safety_checker
+        );
+        let policy = SETTINGS.read().await.read().await.gpu_policy.clone();
+        let paths: Vec<PathBuf> = [
+            Some(&model.unet_weights),
+            Some(&model.vae_weights),
+            Some(&model.clip_weights),
+            model.clip2_weights.as_ref(),
+            Some(&model.tokenizer),
+            model.safety_checker.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+
+        let key = match cached_key(&self.models, &policy, &files) {
+            Some(key) => key,
+            None => {
+                let device = pick_device(&policy, &self.models)?;
+                let key = (format!("{:?}", device.location()), files);
+                self.cleanup_thread.start();
+                self.models
+                    .entry(key.clone())
+                    .or_insert_with(move || CachedModel {
+                        device,
+                        model: Perishable::with_ttl(inactive_image_generation_ttl()),
+                        files: paths,
+                    });
+                key
             }
         };
 
-        Ok(sd_generate_image(model, args, device)?)
+        // PANIC SAFETY: Just inserted the element if it isn't already inside the map, so must be present in the map
+        let cached = self.models.get(&key).unwrap();
+        let (_model_signal, model_guard) =
+            get_or_init_model(&cached.model, model, cached.device.clone()).await?;
+
+        Ok(sd_generate_image(&model_guard, args)?)
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        self.models
+            .iter()
+            .filter(move |cached| cached.model.is_resident())
+            .map(move |cached| {
+                let mut resident = ResidentModel::new(
+                    &cached.files[0],
+                    device_name(&cached.device),
+                    0,
+                    cached.model.users(),
+                );
+                resident.size = cached
+                    .files
+                    .iter()
+                    .filter_map(move |path| std::fs::metadata(path).ok())
+                    .map(move |metadata| metadata.len())
+                    .sum();
+                resident
+            })
+            .collect()
+    }
+}
+
+/// Returns the name of a [`Device`], as reported in [`ResidentModel`]s.
+fn device_name(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } | DeviceLocation::Metal { gpu_id } => {
+            format!("device:{gpu_id}")
+        }
     }
 }
 
+impl Default for CandleImageGenerationEndpoint {
+    fn default() -> Self {
+        let models: Arc<DashMap<ModelKey, CachedModel>> = Default::default();
+        let models_clone = models.clone();
+        let cleanup_thread = LazyTask::new(async move {
+            let mut interval = interval(cleanup_interval());
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+                retain_alive(&models_clone, move |cached: &CachedModel| {
+                    cached.model.liveness()
+                })
+                .await;
+            }
+        });
+
+        Self {
+            models,
+            cleanup_thread,
+        }
+    }
+}
+
+/// Returns the key of the cached model with the provided files, if any is on a device allowed by
+/// the provided [`DevicePolicy`].
+///
+/// A model keeps running on the device it is loaded on, so that it is only ever loaded once.
+fn cached_key(
+    loaded: &DashMap<ModelKey, CachedModel>,
+    policy: &DevicePolicy,
+    files: &str,
+) -> Option<ModelKey> {
+    loaded
+        .iter()
+        .find(move |entry| {
+            entry.key().1 == files
+                && match policy {
+                    DevicePolicy::AlwaysDevice { overflow_to_cpu } => {
+                        *overflow_to_cpu || !entry.device.is_cpu()
+                    }
+                    _ => entry.device.is_cpu(),
+                }
+        })
+        .map(move |entry| entry.key().clone())
+}
+
+/// Picks the [`Device`] on which a model that is not loaded yet should be loaded and executed,
+/// according to the provided [`DevicePolicy`] and the models already `loaded`.
+fn pick_device(
+    policy: &DevicePolicy,
+    loaded: &DashMap<ModelKey, CachedModel>,
+) -> Result<Device, CandleError> {
+    match policy {
+        DevicePolicy::AlwaysCpu { .. } => Ok(Device::Cpu),
+        DevicePolicy::AlwaysDevice { overflow_to_cpu } => match accelerator(loaded) {
+            Ok(device) => Ok(device),
+            Err(e) if *overflow_to_cpu => {
+                warn!("{e}, executing on CPU");
+                Ok(Device::Cpu)
+            }
+            Err(e) => Err(e),
+        },
+        _ => {
+            warn!("Unknown device policy, executing on CPU");
+            Ok(Device::Cpu)
+        }
+    }
+}
+
+/// Returns the acceleration device a model that is not loaded yet should run on.
+///
+/// When several CUDA GPUs are available, the model is loaded on the one with the fewest models, so
+/// a model unloaded for inactivity may be loaded on another GPU next time.
+fn accelerator(loaded: &DashMap<ModelKey, CachedModel>) -> Result<Device, CandleError> {
+    #[cfg(feature = "cuda")]
+    {
+        let count = candle_core::cuda_backend::cudarc::driver::CudaDevice::count()
+            .map_err(|e| CandleError::NoDevice(e.to_string()))?;
+        if count < 1 {
+            return Err(CandleError::NoDevice("no CUDA devices found".to_string()));
+        }
+
+        let count = count as usize;
+
+        let ordinal = (0..count)
+            .min_by_key(move |&gpu_id| {
+                loaded
+                    .iter()
+                    .filter(|entry| entry.device.location() == DeviceLocation::Cuda { gpu_id })
+                    .count()
+            })
+            .unwrap_or_default();
+
+        return Device::new_cuda(ordinal).map_err(|e| CandleError::NoDevice(e.to_string()));
+    }
+
+    #[cfg(all(feature = "metal", not(feature = "cuda")))]
+    {
+        let _ = loaded;
+        return Device::new_metal(0).map_err(|e| CandleError::NoDevice(e.to_string()));
+    }
+
+    #[cfg(not(any(feature = "cuda", feature = "metal")))]
+    {
+        let _ = loaded;
+        Err(CandleError::NoDevice(
+            "Edgen was built without CUDA or Metal support".to_string(),
+        ))
+    }
+}
+
+/// Helper function to acquire a read guard to a [`StableDiffusionModel`] (and its associated
+/// [`ActiveSignal`]).
+async fn get_or_init_model(
+    model: &Perishable<StableDiffusionModel>,
+    files: ModelFiles,
+    device: Device,
+) -> Result<(ActiveSignal, PerishableReadGuard<StableDiffusionModel>), CandleError> {
+    model
+        .get_or_try_init(move || async move {
+            info!(
+                "Loading {} into memory",
+                files.unet_weights.to_string_lossy()
+            );
+            StableDiffusionModel::load(&files, &device)
+        })
+        .await
+}
+
 impl From<CandleError> for ImageGenerationEndpointError {
     fn from(value: CandleError) -> Self {
         match value {
@@ -483,6 +856,7 @@ impl From<CandleError> for ImageGenerationEndpointError {
             CandleError::EncodeWriteFailed(_) => {
                 ImageGenerationEndpointError::Encoding(value.to_string())
             }
+            CandleError::NoDevice(_) => ImageGenerationEndpointError::Load(value.to_string()),
         }
     }
 }
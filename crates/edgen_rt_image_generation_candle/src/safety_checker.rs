@@ -0,0 +1,157 @@
+use std::path::Path;
+
+use candle_core::{DType, Device, Module, Tensor, D};
+use candle_nn::{linear_no_bias, Linear, VarBuilder};
+use candle_transformers::models::clip::text_model::Activation;
+use candle_transformers::models::clip::vision_model::{ClipVisionConfig, ClipVisionTransformer};
+use image::imageops::{crop_imm, resize, FilterType};
+use image::RgbImage;
+
+/// The side length of the square images the safety checker classifies.
+const IMAGE_SIZE: usize = 224;
+
+/// The size of the embeddings the images are projected to before being compared to the concepts.
+const PROJECTION_DIM: usize = 768;
+
+/// The number of NSFW concepts the images are compared to.
+const CONCEPTS: usize = 17;
+
+/// The number of concepts that, when detected, make the check of every other concept stricter.
+const SPECIAL_CARE_CONCEPTS: usize = 3;
+
+/// The per channel mean used to normalise the images, as done by CLIP.
+const MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+
+/// The per channel standard deviation used to normalise the images, as done by CLIP.
+const STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// A port of the stable diffusion safety checker, which classifies images as NSFW by comparing
+/// their CLIP embeddings to those of a set of NSFW concepts.
+///
+/// The weights can be found at `CompVis/stable-diffusion-safety-checker`.
+pub struct SafetyChecker {
+    vision_model: ClipVisionTransformer,
+    visual_projection: Linear,
+    concept_embeds: Tensor,
+    concept_embeds_weights: Tensor,
+    special_care_embeds: Tensor,
+    special_care_embeds_weights: Tensor,
+    device: Device,
+}
+
+impl SafetyChecker {
+    /// Loads the safety checker weights into the memory of the provided [`Device`].
+    pub fn load(weights: &Path, device: &Device) -> candle_core::Result<Self> {
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DType::F32, device)? };
+        let config = vision_config();
+
+        let vision_model =
+            ClipVisionTransformer::new(vb.pp("vision_model").pp("vision_model"), &config)?;
+        let visual_projection =
+            linear_no_bias(config.embed_dim, PROJECTION_DIM, vb.pp("visual_projection"))?;
+
+        Ok(Self {
+            vision_model,
+            visual_projection,
+            concept_embeds: vb.get((CONCEPTS, PROJECTION_DIM), "concept_embeds")?,
+            concept_embeds_weights: vb.get(CONCEPTS, "concept_embeds_weights")?,
+            special_care_embeds: vb.get(
+                (SPECIAL_CARE_CONCEPTS, PROJECTION_DIM),
+                "special_care_embeds",
+            )?,
+            special_care_embeds_weights: vb
+                .get(SPECIAL_CARE_CONCEPTS, "special_care_embeds_weights")?,
+            device: device.clone(),
+        })
+    }
+
+    /// Returns **`true`** if the provided image is classified as NSFW.
+    pub fn is_nsfw(&self, image: &RgbImage) -> candle_core::Result<bool> {
+        let pixels = preprocess(image, &self.device)?;
+        let pooled = self.vision_model.forward(&pixels)?;
+        let embeds = self.visual_projection.forward(&pooled)?;
+
+        let special_scores = cosine_similarity(&embeds, &self.special_care_embeds)?
+            .broadcast_sub(&self.special_care_embeds_weights.unsqueeze(0)?)?;
+        let adjustment = if any_positive(&special_scores)? {
+            0.01
+        } else {
+            0.0
+        };
+
+        let concept_scores = (cosine_similarity(&embeds, &self.concept_embeds)?
+            .broadcast_sub(&self.concept_embeds_weights.unsqueeze(0)?)?
+            + adjustment)?;
+
+        any_positive(&concept_scores)
+    }
+}
+
+/// The configuration of the CLIP ViT-L/14 vision model used by the safety checker.
+fn vision_config() -> ClipVisionConfig {
+    ClipVisionConfig {
+        embed_dim: 1024,
+        activation: Activation::QuickGelu,
+        intermediate_size: 4096,
+        num_hidden_layers: 24,
+        num_attention_heads: 16,
+        projection_dim: PROJECTION_DIM,
+        num_channels: 3,
+        image_size: IMAGE_SIZE,
+        patch_size: 14,
+    }
+}
+
+/// Resizes, crops and normalises an image the same way the CLIP image processor does, returning a
+/// `(1, 3, IMAGE_SIZE, IMAGE_SIZE)` tensor.
+fn preprocess(image: &RgbImage, device: &Device) -> candle_core::Result<Tensor> {
+    let (width, height) = image.dimensions();
+    let scale = IMAGE_SIZE as f32 / width.min(height) as f32;
+    let width = ((width as f32 * scale).round() as u32).max(IMAGE_SIZE as u32);
+    let height = ((height as f32 * scale).round() as u32).max(IMAGE_SIZE as u32);
+
+    let resized = resize(image, width, height, FilterType::CatmullRom);
+    let cropped = crop_imm(
+        &resized,
+        (width - IMAGE_SIZE as u32) / 2,
+        (height - IMAGE_SIZE as u32) / 2,
+        IMAGE_SIZE as u32,
+        IMAGE_SIZE as u32,
+    )
+    .to_image();
+
+    let pixels = Tensor::from_vec(
+        cropped.into_raw(),
+        (IMAGE_SIZE, IMAGE_SIZE, 3),
+        &Device::Cpu,
+    )?
+    .permute((2, 0, 1))?
+    .to_dtype(DType::F32)?
+    .to_device(device)?;
+    let mean = Tensor::new(&MEAN, device)?.reshape((3, 1, 1))?;
+    let std = Tensor::new(&STD, device)?.reshape((3, 1, 1))?;
+
+    (pixels / 255.)?
+        .broadcast_sub(&mean)?
+        .broadcast_div(&std)?
+        .unsqueeze(0)
+}
+
+/// Returns the cosine similarity of every row of `a` with every row of `b`.
+fn cosine_similarity(a: &Tensor, b: &Tensor) -> candle_core::Result<Tensor> {
+    normalise(a)?.matmul(&normalise(b)?.t()?)
+}
+
+/// Divides every row of the tensor by its L2 norm.
+fn normalise(t: &Tensor) -> candle_core::Result<Tensor> {
+    t.broadcast_div(&t.sqr()?.sum_keepdim(D::Minus1)?.sqrt()?)
+}
+
+/// Returns **`true`** if any of the scores is above zero.
+fn any_positive(scores: &Tensor) -> candle_core::Result<bool> {
+    Ok(scores
+        .flatten_all()?
+        .to_vec1::<f32>()?
+        .into_iter()
+        .any(|score| score > 0.0))
+}
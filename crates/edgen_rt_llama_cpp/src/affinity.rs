@@ -0,0 +1,110 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Pinning of every thread of the process to a set of CPU cores.
+//!
+//! `llama.cpp` spawns its worker threads from the threads calling into it, which are in turn
+//! spawned by `llama_cpp` and `tokio`, and every thread inherits the affinity of the thread that
+//! spawned it. So the worker threads are pinned by pinning every thread of the process, which
+//! also pins the threads spawned later on. This is process-wide: the threads serving requests and
+//! running the models of other runtimes are pinned to the same cores.
+
+use std::sync::Mutex;
+
+use tracing::warn;
+
+/// The cores the threads were last pinned to.
+static PINNED_CORES: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+/// Pins every thread of this process to the CPU `cores`, or unpins them if `cores` is empty.
+///
+/// Nothing is done if the threads are already pinned to `cores`.
+pub fn pin_threads(cores: &[usize]) {
+    let mut pinned = PINNED_CORES.lock().unwrap();
+    if pinned.as_slice() == cores {
+        return;
+    }
+
+    if set_affinity(cores) {
+        *pinned = cores.to_vec();
+    }
+}
+
+/// Sets the affinity of every thread of this process, returning `true` on success.
+#[cfg(target_os = "linux")]
+fn set_affinity(cores: &[usize]) -> bool {
+    let max_cores = libc::CPU_SETSIZE as usize;
+
+    // SAFETY: a `cpu_set_t` is a plain bit mask, for which all zeroes is a valid (empty) value
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if cores.is_empty() {
+        // Allowing cores that do not exist is harmless, the kernel only schedules on online cores
+        for core in 0..max_cores {
+            // SAFETY: `core` is below `CPU_SETSIZE`, so it fits in the mask
+            unsafe { libc::CPU_SET(core, &mut set) };
+        }
+    } else {
+        for &core in cores {
+            if core >= max_cores {
+                warn!(
+                    "Ignoring CPU core {core} in process_cpu_affinity, which is above {max_cores}"
+                );
+                continue;
+            }
+            // SAFETY: `core` is below `CPU_SETSIZE`, so it fits in the mask
+            unsafe { libc::CPU_SET(core, &mut set) };
+        }
+    }
+
+    let tasks = match std::fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(e) => {
+            warn!("Failed to list the threads to pin to CPU cores: {e}");
+            return false;
+        }
+    };
+
+    let mut success = true;
+    for task in tasks.flatten() {
+        let Some(tid) = task
+            .file_name()
+            .to_str()
+            .and_then(move |tid| tid.parse::<libc::pid_t>().ok())
+        else {
+            continue;
+        };
+
+        // SAFETY: `set` is a valid mask of the provided size, which is only read
+        let result =
+            unsafe { libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set) };
+        if result != 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ESRCH) {
+                // The thread exited in the meantime
+                continue;
+            }
+            warn!("Failed to pin thread {tid} to CPU cores {cores:?}: {error}");
+            success = false;
+        }
+    }
+
+    success
+}
+
+/// Sets the affinity of every thread of this process, returning `true` on success.
+#[cfg(not(target_os = "linux"))]
+fn set_affinity(cores: &[usize]) -> bool {
+    if !cores.is_empty() {
+        warn!("Pinning threads to CPU cores is only supported on Linux, ignoring process_cpu_affinity");
+    }
+    true
+}
@@ -15,42 +15,68 @@ use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Instant;
 
 use blake3::Hasher;
 use dashmap::DashMap;
-use futures::executor::block_on;
-use futures::Stream;
-use llama_cpp::standard_sampler::StandardSampler;
+use futures::{Stream, StreamExt};
+use llama_cpp::standard_sampler::{SamplerStage, StandardSampler};
 use llama_cpp::{
     CompletionHandle, EmbeddingsParams, LlamaModel, LlamaParams, LlamaSession, SessionParams,
-    TokensToStrings,
 };
 use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
 use tokio::task::JoinHandle;
 use tokio::time::{interval, MissedTickBehavior};
 use tokio::{select, spawn};
 use tracing::{error, info};
+use uuid::Uuid;
 
 use edgen_core::cleanup_interval;
+use edgen_core::embeddings::{normalize, EmbeddingsArgs, EmbeddingsEndpoint};
+use edgen_core::lazy_task::LazyTask;
 use edgen_core::llm::{
-    inactive_llm_session_ttl, inactive_llm_ttl, CompletionArgs, LLMEndpoint, LLMEndpointError,
+    inactive_llm_session_ttl, inactive_llm_ttl, ActiveStream, CompletionArgs, LLMEndpoint,
+    LLMEndpointError, PromptEstimate, Throughput, ThroughputMeter, TimingsRecorder, TokenLog,
     ASSISTANT_TAG, SYSTEM_TAG, TOOL_TAG, USER_TAG,
 };
-use edgen_core::perishable::{ActiveSignal, Perishable, PerishableReadGuard, PerishableWriteGuard};
+use edgen_core::perishable::{
+    retain_alive, ActiveSignal, Liveness, Perishable, PerishableReadGuard, PerishableWriteGuard,
+};
+use edgen_core::resident::{is_pinned, LoadedDevice, ResidentModel};
 use edgen_core::settings::{DevicePolicy, SETTINGS};
 
+mod affinity;
+pub mod quantize;
+
 // TODO this should be in settings
 const SINGLE_MESSAGE_LIMIT: usize = 4096;
 const CONTEXT_SIZE: u32 = 4096;
 
+/// The sampling options used when a request does not set them, matching those of
+/// [`StandardSampler::default`].
+const DEFAULT_TEMPERATURE: f32 = 0.8;
+const DEFAULT_TOP_K: i32 = 40;
+const DEFAULT_TOP_P: f32 = 0.95;
+const DEFAULT_MIN_P: f32 = 0.05;
+const REPETITION_PENALTY: f32 = 1.1;
+const REPETITION_LAST_N: i32 = 64;
+
+/// The Mirostat options used when a request does not set them, matching those of `llama.cpp`.
+const DEFAULT_MIROSTAT_TAU: f32 = 5.0;
+const DEFAULT_MIROSTAT_ETA: f32 = 0.1;
+const MIROSTAT_M: i32 = 100;
+
 /// A large language model endpoint, implementing [`LLMEndpoint`] using a [`llama_cpp`] backend.
 pub struct LlamaCppEndpoint {
     /// A map of the models currently loaded into memory, with their path as the key.
     models: Arc<DashMap<String, UnloadingModel>>,
 
     /// A background thread that periodically removes models from the `models` collection, if they
-    /// are not loaded at the time.
-    cleanup_thread: JoinHandle<()>,
+    /// are not loaded at the time. It is started along with the first model.
+    cleanup_thread: LazyTask,
+
+    /// The meter of the tokens generated by every model.
+    meter: ThroughputMeter,
 }
 
 impl LlamaCppEndpoint {
@@ -63,7 +89,8 @@ impl LlamaCppEndpoint {
         let key = model_path.as_ref().to_string_lossy().to_string();
 
         if !self.models.contains_key(&key) {
-            let model = UnloadingModel::new(model_path).await;
+            self.cleanup_thread.start();
+            let model = UnloadingModel::new(model_path, self.meter.clone()).await;
             self.models.insert(key.clone(), model);
         }
 
@@ -92,13 +119,32 @@ impl LLMEndpoint for LlamaCppEndpoint {
         model.stream_chat_completions(args).await
     }
 
-    async fn embeddings(
+    async fn estimate_prompt(
         &self,
         model_path: impl AsRef<Path> + Send,
-        inputs: Vec<String>,
-    ) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+        args: CompletionArgs,
+    ) -> Result<PromptEstimate, LLMEndpointError> {
         let model = self.get(model_path).await;
-        model.embeddings(inputs).await
+        model.estimate_prompt(args).await
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        self.models
+            .iter()
+            .filter(move |model| model.model.is_resident())
+            .map(move |model| {
+                ResidentModel::new(
+                    &model.path,
+                    model.device.get(),
+                    model.sessions.len(),
+                    model.model.users(),
+                )
+            })
+            .collect()
+    }
+
+    fn throughput(&self) -> Throughput {
+        self.meter.sample()
     }
 
     fn reset(&self) {
@@ -110,26 +156,48 @@ impl Default for LlamaCppEndpoint {
     fn default() -> Self {
         let models: Arc<DashMap<String, UnloadingModel>> = Default::default();
         let models_clone = models.clone();
-        let cleanup_thread = spawn(async move {
+        let cleanup_thread = LazyTask::new(async move {
             let mut interval = interval(cleanup_interval());
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
             loop {
                 interval.tick().await;
-                models_clone.retain(move |_, model| block_on(model.loaded()));
+                retain_alive(&models_clone, UnloadingModel::liveness).await;
             }
         });
 
         Self {
             models,
             cleanup_thread,
+            meter: Default::default(),
         }
     }
 }
 
-impl Drop for LlamaCppEndpoint {
-    fn drop(&mut self) {
-        self.cleanup_thread.abort()
+/// An embeddings endpoint, implementing [`EmbeddingsEndpoint`] using a [`llama_cpp`] backend.
+///
+/// Its models are loaded separately from those of the [`LlamaCppEndpoint`] generating chat
+/// completions.
+#[derive(Default)]
+pub struct LlamaCppEmbeddingsEndpoint(LlamaCppEndpoint);
+
+#[async_trait::async_trait]
+impl EmbeddingsEndpoint for LlamaCppEmbeddingsEndpoint {
+    async fn embeddings(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: EmbeddingsArgs,
+    ) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+        let model = self.0.get(model_path).await;
+        model.embeddings(args).await
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        LLMEndpoint::resident_models(&self.0).await
+    }
+
+    fn reset(&self) {
+        LLMEndpoint::reset(&self.0)
     }
 }
 
@@ -138,28 +206,37 @@ impl Drop for LlamaCppEndpoint {
 struct UnloadingModel {
     model: Perishable<LlamaModel>,
     path: PathBuf,
-    sessions: Arc<DashMap<SessionId, Perishable<LlamaSession>>>,
+    device: LoadedDevice,
+    sessions: Arc<DashMap<SessionKey, Perishable<LlamaSession>>>,
+    pinned: Arc<DashMap<String, Arc<PinnedSession>>>,
     maintenance_thread: JoinHandle<()>,
-    finished_tx: UnboundedSender<(SessionId, Perishable<LlamaSession>)>,
+    finished_tx: UnboundedSender<(SessionKey, Perishable<LlamaSession>)>,
+    meter: ThroughputMeter,
 }
 
 impl UnloadingModel {
-    /// Creates a new instance of this model, provided it's [`Path`].
+    /// Creates a new instance of this model, provided it's [`Path`], recording its generated tokens
+    /// in `meter`.
     ///
     /// This function is lazy and does not actually load the model into system memory, the model must be accessed in
     /// order to be loaded.
-    async fn new(model_path: impl AsRef<Path>) -> Self {
-        let sessions: Arc<DashMap<SessionId, Perishable<LlamaSession>>> = Default::default();
+    async fn new(model_path: impl AsRef<Path>, meter: ThroughputMeter) -> Self {
+        let sessions: Arc<DashMap<SessionKey, Perishable<LlamaSession>>> = Default::default();
+        let pinned: Arc<DashMap<String, Arc<PinnedSession>>> = Default::default();
         let (tx, mut rx) = unbounded_channel();
 
         let sessions_clone = sessions.clone();
+        let pinned_clone = pinned.clone();
         let maintenance_thread = spawn(async move {
             let mut interval = interval(cleanup_interval());
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
             loop {
                 select! {
-                    _ = interval.tick() => sessions_clone.retain(move |_, session| block_on(session.is_alive())),
+                    _ = interval.tick() => {
+                        retain_alive(&sessions_clone, Perishable::liveness).await;
+                        retain_alive(&pinned_clone, move |pinned| pinned.session.liveness()).await;
+                    }
                     item = rx.recv() => {
                         if let Some((id, session)) = item {
                             sessions_clone.insert(id, session);
@@ -169,87 +246,187 @@ impl UnloadingModel {
             }
         });
 
+        let path = model_path.as_ref().to_path_buf();
+        let pinned_path = path.clone();
+
         Self {
-            model: Perishable::with_ttl(inactive_llm_ttl()),
-            path: model_path.as_ref().to_path_buf(),
+            model: Perishable::with_ttl_and_pin(inactive_llm_ttl(), move || {
+                is_pinned(&pinned_path)
+            }),
+            path,
+            device: LoadedDevice::default(),
             sessions,
+            pinned,
             maintenance_thread,
             finished_tx: tx,
+            meter,
         }
     }
 
-    /// Returns **`true`** if this model is currently loaded in system memory, **`false`** otherwise.
-    async fn loaded(&self) -> bool {
-        self.model.is_alive().await
+    /// Returns a handle to check whether this model is currently loaded in system memory.
+    fn liveness(&self) -> Liveness<LlamaModel> {
+        self.model.liveness()
     }
 
     /// Either takes an existing chat [`LlamaSession`] compatible with the provided prompt from the
     /// `sessions` collection, or creates a new one.
     ///
-    /// The matching [`SessionId`] and the new context derived from `prompt` are also returned.
+    /// If the [`CompletionArgs`] have a session [`Uuid`], that session is taken, or created if
+    /// `create_session` is set, instead of the one matching the prompt.
+    ///
+    /// The matching [`SessionKey`] and the new context derived from `prompt` are also returned.
     async fn take_chat_session<'a>(
         &self,
         prompt: &'a str,
-    ) -> (Perishable<LlamaSession>, SessionId, &'a str) {
+        args: &CompletionArgs,
+    ) -> Result<(Perishable<LlamaSession>, SessionKey, &'a str), LLMEndpointError> {
         let (id, new_context) = SessionId::chat(prompt);
 
-        let session_perishable = if let Some((_, session)) = self.sessions.remove(&id) {
-            info!("Matching session found, continuing");
-            session
-        } else {
-            info!("No matching session found, creating new one");
-            Perishable::with_ttl(inactive_llm_session_ttl())
+        let Some(uuid) = args.session else {
+            let key = SessionKey::Chat(id);
+            let session_perishable = if let Some((_, session)) = self.sessions.remove(&key) {
+                info!("Matching session found, continuing");
+                session
+            } else {
+                info!("No matching session found, creating new one");
+                Perishable::with_ttl(inactive_llm_session_ttl())
+            };
+
+            return Ok((session_perishable, key, new_context));
+        };
+
+        let key = SessionKey::Explicit(uuid);
+        if args.create_session {
+            info!("Creating session {uuid}");
+            // The new session holds none of the prompt yet
+            return Ok((
+                Perishable::with_ttl(inactive_llm_session_ttl()),
+                key,
+                prompt,
+            ));
+        }
+
+        match self.sessions.remove(&key) {
+            Some((_, session)) => {
+                info!("Session {uuid} found, continuing");
+                Ok((session, key, new_context))
+            }
+            None => Err(LLMEndpointError::SessionNotFound),
+        }
+    }
+
+    /// Creates the session of a one-shot request for the provided [`CompletionArgs`].
+    ///
+    /// If prompt caching was requested, the session is a copy of the pinned session holding the
+    /// prompt up to the last user message, which is created if needed.
+    ///
+    /// The part of `prompt` the session must still be advanced with is also returned.
+    async fn take_one_shot_session<'a>(
+        &self,
+        model: &LlamaModel,
+        args: &CompletionArgs,
+        prompt: &'a str,
+    ) -> Result<(LlamaSession, &'a str), LLMEndpointError> {
+        let n_ctx = context_size(args.context_hint, args.max_context);
+        let cache = args.cache_prompt.unwrap_or(false) || args.cache_key.is_some();
+        let prefix_len = prompt.rfind(USER_TAG).unwrap_or(0);
+
+        if !cache || prefix_len == 0 {
+            info!("Allocating one-shot LLM session");
+            return Ok((new_session(model, n_ctx, args.no_kv_offload).await?, prompt));
+        }
+
+        let (prefix, new_context) = prompt.split_at(prefix_len);
+        let mut id = SessionId::default();
+        id.advance(prefix);
+        let key = args
+            .cache_key
+            .clone()
+            .unwrap_or_else(|| id.hasher.finalize().to_hex().to_string());
+
+        let existing = self
+            .pinned
+            .get(&key)
+            .map(move |pinned| pinned.value().clone());
+        let pinned = match existing {
+            Some(pinned) if pinned.id == id && pinned.n_ctx == n_ctx => {
+                info!("Cached prompt \"{key}\" found, copying its session");
+                pinned
+            }
+            _ => {
+                info!("Pinning a new session for cached prompt \"{key}\"");
+                Arc::new(PinnedSession {
+                    id,
+                    n_ctx,
+                    session: Perishable::with_ttl(inactive_llm_ttl()),
+                })
+            }
+        };
+
+        let session = {
+            let model = model.clone();
+            let prefix = prefix.to_string();
+            let no_kv_offload = args.no_kv_offload;
+            let (_session_signal, session_guard) = pinned
+                .session
+                .get_or_try_init(move || async move {
+                    let mut session = new_session(&model, n_ctx, no_kv_offload).await?;
+                    session
+                        .advance_context_async(prefix)
+                        .await
+                        .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+                    Ok::<_, LLMEndpointError>(session)
+                })
+                .await?;
+
+            session_guard
+                .deep_copy()
+                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))?
         };
 
-        (session_perishable, id, new_context)
+        self.pinned.insert(key, pinned);
+
+        Ok((session, new_context))
     }
 
     /// Computes the full chat completions for the provided [`CompletionArgs`].
     async fn chat_completions(&self, args: CompletionArgs) -> Result<String, LLMEndpointError> {
-        let (_model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
+        let timings = args.timings.clone().unwrap_or_default();
+        let load_start = Instant::now();
+        let (_model_signal, model_guard) =
+            get_or_init_model(&self.model, &self.path, &self.device).await?;
+        timings.record_load(load_start.elapsed());
+        timings.start_prompt();
 
         let prompt = format!("{}<|ASSISTANT|>", args.messages);
+        check_prompt(&model_guard, &prompt, args.max_context)?;
 
-        if args.one_shot.unwrap_or(false) {
-            info!("Allocating one-shot LLM session");
-            let mut params = SessionParams::default();
-            let threads = SETTINGS.read().await.read().await.auto_threads(false);
+        if args.one_shot.unwrap_or(false) && args.session.is_none() {
+            let (mut session, new_context) = self
+                .take_one_shot_session(&model_guard, &args, &prompt)
+                .await?;
 
-            // TODO handle optional params
-            //params.seed = args.seed;
-            params.n_threads = threads;
-            params.n_threads_batch = threads;
-            params.n_ctx = args.context_hint.unwrap_or(CONTEXT_SIZE);
+            advance(&mut session, &model_guard, new_context, &args).await?;
+            timings.end_prompt();
 
-            let mut session = model_guard
-                .create_session(params)
-                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))?;
-
-            session
-                .advance_context_async(prompt)
-                .await
-                .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
-
-            let sampler = StandardSampler::default();
+            let sampler = build_sampler(&args);
             let handle = session
                 .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
                 .map_err(|e| LLMEndpointError::Advance(e.to_string()))?;
 
-            Ok(handle.into_string_async().await)
+            Ok(complete(handle, &model_guard, &timings, &self.meter).await)
         } else {
-            let (session, mut id, new_context) = self.take_chat_session(&prompt).await;
+            let (session, mut id, new_context) = self.take_chat_session(&prompt, &args).await?;
 
             let (_session_signal, handle) = {
                 let (session_signal, mut session_guard) =
-                    get_or_init_session(&session, model_guard.clone()).await?;
+                    get_or_init_session(&session, model_guard.clone(), &args).await?;
 
-                session_guard
-                    .advance_context_async(new_context)
-                    .await
-                    .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+                advance(&mut session_guard, &model_guard, new_context, &args).await?;
                 id.advance(new_context);
+                timings.end_prompt();
 
-                let sampler = StandardSampler::default();
+                let sampler = build_sampler(&args);
                 let handle = session_guard
                     .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
                     .map_err(|e| LLMEndpointError::Advance(e.to_string()))?;
@@ -257,7 +434,7 @@ impl UnloadingModel {
                 (session_signal, handle)
             };
 
-            let res = handle.into_string_async().await;
+            let res = complete(handle, &model_guard, &timings, &self.meter).await;
 
             self.sessions.insert(id, session);
 
@@ -265,39 +442,72 @@ impl UnloadingModel {
         }
     }
 
+    /// Estimates the cost of the prompt of the provided [`CompletionArgs`], without generating
+    /// anything.
+    async fn estimate_prompt(
+        &self,
+        args: CompletionArgs,
+    ) -> Result<PromptEstimate, LLMEndpointError> {
+        let (_model_signal, model_guard) =
+            get_or_init_model(&self.model, &self.path, &self.device).await?;
+
+        let prompt = format!("{}<|ASSISTANT|>", args.messages);
+        let prompt_tokens = model_guard
+            .tokenize_bytes(&prompt, true, false)
+            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?
+            .len();
+
+        // Only one-shot sessions are sized after the hint, see `take_one_shot_session`
+        let n_ctx = if args.one_shot.unwrap_or(false) && args.session.is_none() {
+            context_size(args.context_hint, args.max_context)
+        } else {
+            context_size(None, args.max_context)
+        };
+        let params = session_params(n_ctx, args.no_kv_offload).await;
+        let usage = model_guard.estimate_session_size(&params);
+
+        Ok(PromptEstimate {
+            prompt_tokens,
+            context_size: n_ctx as usize,
+            session_memory: usage.host_memory + usage.device_memory,
+        })
+    }
+
     /// Return a [`Box`]ed [`Stream`] of chat completions computed for the provided
     /// [`CompletionArgs`].
     async fn stream_chat_completions(
         &self,
         args: CompletionArgs,
     ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
-        let (model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
+        let timings = args.timings.clone().unwrap_or_default();
+        let load_start = Instant::now();
+        let (model_signal, model_guard) =
+            get_or_init_model(&self.model, &self.path, &self.device).await?;
+        timings.record_load(load_start.elapsed());
+        timings.start_prompt();
 
         let prompt = format!("{}<|ASSISTANT|>", args.messages);
+        check_prompt(&model_guard, &prompt, args.max_context)?;
 
-        if args.one_shot.unwrap_or(false) {
-            info!("Allocating one-shot LLM session");
-            let mut params = SessionParams::default();
-            let threads = SETTINGS.read().await.read().await.auto_threads(false);
-
-            // TODO handle optional params
-            //params.seed = args.seed;
-            params.n_threads = threads;
-            params.n_threads_batch = threads;
-            params.n_ctx = args.context_hint.unwrap_or(CONTEXT_SIZE);
-
-            let session = model_guard
-                .create_session(params)
-                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))?;
-            let sampler = StandardSampler::default();
+        if args.one_shot.unwrap_or(false) && args.session.is_none() {
+            let (session, new_context) = self
+                .take_one_shot_session(&model_guard, &args, &prompt)
+                .await?;
 
             Ok(Box::new(
-                CompletionStream::new_oneshot(session, &prompt, model_signal, sampler).await?,
+                CompletionStream::new_oneshot(
+                    session,
+                    new_context,
+                    model_guard.clone(),
+                    model_signal,
+                    &args,
+                    &self.meter,
+                )
+                .await?,
             ))
         } else {
-            let (session, id, new_context) = self.take_chat_session(&prompt).await;
+            let (session, id, new_context) = self.take_chat_session(&prompt, &args).await?;
 
-            let sampler = StandardSampler::default();
             let tx = self.finished_tx.clone();
 
             Ok(Box::new(
@@ -307,25 +517,76 @@ impl UnloadingModel {
                     new_context,
                     model_guard.clone(),
                     model_signal,
-                    sampler,
+                    &args,
                     tx,
+                    &self.meter,
                 )
                 .await?,
             ))
         }
     }
 
-    async fn embeddings(&self, inputs: Vec<String>) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
-        let threads = SETTINGS.read().await.read().await.auto_threads(false);
-        let mut params = EmbeddingsParams::default();
-        params.n_threads = threads;
-        params.n_threads_batch = threads;
+    async fn embeddings(&self, args: EmbeddingsArgs) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+        // `llama.cpp` pools the embeddings of the tokens as the model was trained to
+        if let Some(pooling) = args.pooling {
+            return Err(LLMEndpointError::UnsuitableEndpoint(format!(
+                "{pooling:?} pooling is not supported by llama.cpp models, which always use \
+                 their own pooling"
+            )));
+        }
+        let inputs = args.inputs;
 
-        let (_model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
-        model_guard
-            .embeddings_async(&inputs, params)
-            .await
-            .map_err(move |e| LLMEndpointError::Embeddings(e.to_string()))
+        let (threads, threads_batch, max_batch, max_tokens) = {
+            let settings = SETTINGS.read().await;
+            let settings = settings.read().await;
+            affinity::pin_threads(&settings.process_cpu_affinity);
+            (
+                settings.auto_threads(false),
+                settings.auto_threads_batch(false),
+                settings.embeddings_max_batch.max(1),
+                settings.embeddings_max_input_tokens,
+            )
+        };
+
+        let (_model_signal, model_guard) =
+            get_or_init_model(&self.model, &self.path, &self.device).await?;
+
+        for (index, input) in inputs.iter().enumerate() {
+            let tokens = model_guard
+                .tokenize_bytes(input, true, false)
+                .map_err(move |e| LLMEndpointError::Embeddings(e.to_string()))?
+                .len();
+            if tokens > max_tokens {
+                return Err(LLMEndpointError::InputTooLarge {
+                    index,
+                    tokens,
+                    max: max_tokens,
+                });
+            }
+        }
+
+        // Batches are processed one after the other, so the embeddings are returned in the same
+        // order as the inputs
+        let mut res = Vec::with_capacity(inputs.len());
+        for batch in inputs.chunks(max_batch) {
+            let mut params = EmbeddingsParams::default();
+            params.n_threads = threads;
+            params.n_threads_batch = threads_batch;
+
+            res.extend(
+                model_guard
+                    .embeddings_async(batch, params)
+                    .await
+                    .map_err(move |e| LLMEndpointError::Embeddings(e.to_string()))?,
+            );
+        }
+
+        if args.normalize {
+            res.iter_mut()
+                .for_each(move |embedding| normalize(embedding));
+        }
+
+        Ok(res)
     }
 }
 
@@ -336,28 +597,37 @@ impl Drop for UnloadingModel {
 }
 
 /// Helper function to acquire a read guard to a [`LlamaModel`] (and its associated
-/// [`ActiveSignal`]).
+/// [`ActiveSignal`]), recording the device it is loaded on in `device`.
 async fn get_or_init_model(
     model: &Perishable<LlamaModel>,
     path: impl AsRef<Path>,
+    device: &LoadedDevice,
 ) -> Result<(ActiveSignal, PerishableReadGuard<LlamaModel>), LLMEndpointError> {
     let path = path.as_ref().to_path_buf();
+    let mut args = LlamaParams::default();
+
+    // Read before taking the lock of the model, so that the settings are not locked by the load
+    match SETTINGS.read().await.read().await.gpu_policy {
+        DevicePolicy::AlwaysCpu { .. } => {
+            args.n_gpu_layers = 0;
+        }
+        DevicePolicy::AlwaysDevice { .. } => {
+            args.n_gpu_layers = i32::MAX as u32;
+        }
+        _ => {
+            unimplemented!()
+        }
+    }
+
+    // Layers are only offloaded by builds with a GPU backend, and to the main GPU by default
+    let offloaded =
+        args.n_gpu_layers > 0 && cfg!(any(feature = "cuda", feature = "vulkan", feature = "metal"));
+    let device = device.clone();
+
     model
         .get_or_try_init(move || async move {
             info!("Loading {} into memory", path.to_string_lossy());
-            let mut args = LlamaParams::default();
-
-            match SETTINGS.read().await.read().await.gpu_policy {
-                DevicePolicy::AlwaysCpu { .. } => {
-                    args.n_gpu_layers = 0;
-                }
-                DevicePolicy::AlwaysDevice { .. } => {
-                    args.n_gpu_layers = i32::MAX as u32;
-                }
-                _ => {
-                    unimplemented!()
-                }
-            }
+            device.set(if offloaded { "device:0" } else { "cpu" }.to_string());
 
             LlamaModel::load_from_file_async(path, args)
                 .await
@@ -367,28 +637,205 @@ async fn get_or_init_model(
 }
 
 /// Helper function to acquire a write guard to a [`LlamaSession`] (and its associated
-/// [`ActiveSignal`]).
+/// [`ActiveSignal`]), allocating it for the provided [`CompletionArgs`] if needed.
 async fn get_or_init_session(
     session: &Perishable<LlamaSession>,
     model: LlamaModel,
+    args: &CompletionArgs,
 ) -> Result<(ActiveSignal, PerishableWriteGuard<LlamaSession>), LLMEndpointError> {
+    let n_ctx = context_size(None, args.max_context);
+    let no_kv_offload = args.no_kv_offload;
     session
         .get_or_try_init_mut(move || async move {
             info!("Allocating new LLM session");
-            let mut params = SessionParams::default();
-            let threads = SETTINGS.read().await.read().await.auto_threads(false);
+            new_session(&model, n_ctx, no_kv_offload).await
+        })
+        .await
+}
 
-            // TODO handle optional params
-            //params.seed = args.seed;
-            params.n_threads = threads;
-            params.n_threads_batch = threads;
-            params.n_ctx = CONTEXT_SIZE;
+/// Returns the size of the context of a new session, that is `hint`, or [`CONTEXT_SIZE`] by
+/// default, clamped to `max_context`.
+fn context_size(hint: Option<u32>, max_context: Option<u32>) -> u32 {
+    let n_ctx = hint.unwrap_or(CONTEXT_SIZE);
+    match max_context {
+        Some(max) => n_ctx.min(max),
+        None => n_ctx,
+    }
+}
 
-            model
-                .create_session(params)
-                .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))
-        })
+/// Refuses `prompt` if it has more than `max_context` tokens.
+fn check_prompt(
+    model: &LlamaModel,
+    prompt: &str,
+    max_context: Option<u32>,
+) -> Result<(), LLMEndpointError> {
+    let Some(max) = max_context else {
+        return Ok(());
+    };
+
+    let tokens = model
+        .tokenize_bytes(prompt, true, false)
+        .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?
+        .len();
+    if tokens > max as usize {
+        return Err(LLMEndpointError::ContextTooLarge {
+            tokens,
+            max: max as usize,
+        });
+    }
+    Ok(())
+}
+
+/// Advances `session` with `new_context`.
+///
+/// If `keep_system_prompt` is set in `args`, and the context of `session` is too small for
+/// `new_context` and the `max_tokens` of the completion, the oldest tokens following the system
+/// prompt are evicted, like llama.cpp's context shift with `n_keep` set to the tokens of the system
+/// prompt. The rendered system prompt is the start of every session, so it is never evicted.
+async fn advance(
+    session: &mut LlamaSession,
+    model: &LlamaModel,
+    new_context: &str,
+    args: &CompletionArgs,
+) -> Result<(), LLMEndpointError> {
+    if args.keep_system_prompt {
+        let n_ctx = session.params().n_ctx as usize;
+        let mut tokens = session.context();
+        let incoming = model
+            .tokenize_bytes(new_context, tokens.is_empty(), false)
+            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+        // Never evict the whole conversation to make room for the completion
+        let reserve = (args.max_tokens.unwrap_or(0) as usize).min(n_ctx / 2);
+
+        if tokens.len() + incoming.len() + reserve > n_ctx {
+            let n_keep = model
+                .tokenize_bytes(args.messages.system_prompt(), true, false)
+                .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?
+                .len();
+            tokens.extend(incoming);
+
+            let excess = (tokens.len() + reserve).saturating_sub(n_ctx);
+            let n_keep = n_keep.min(tokens.len());
+            let end = (n_keep + excess).min(tokens.len());
+            info!(
+                "Context full, evicting {} tokens following the system prompt",
+                end - n_keep
+            );
+            tokens.drain(n_keep..end);
+
+            return session
+                .set_context_to_tokens(&tokens)
+                .map_err(move |e| LLMEndpointError::Advance(e.to_string()));
+        }
+    }
+
+    session
+        .advance_context_async(new_context)
         .await
+        .map_err(move |e| LLMEndpointError::Advance(e.to_string()))
+}
+
+/// Helper function to create a new [`LlamaSession`] with a context of `n_ctx` tokens, keeping its
+/// KV cache in host memory if `no_kv_offload` is set.
+async fn new_session(
+    model: &LlamaModel,
+    n_ctx: u32,
+    no_kv_offload: bool,
+) -> Result<LlamaSession, LLMEndpointError> {
+    let params = session_params(n_ctx, no_kv_offload).await;
+    model
+        .create_session(params)
+        .map_err(move |e| LLMEndpointError::SessionCreationFailed(e.to_string()))
+}
+
+/// Helper function to build the [`SessionParams`] of a session with a context of `n_ctx` tokens,
+/// keeping its KV cache in host memory if `no_kv_offload` is set.
+async fn session_params(n_ctx: u32, no_kv_offload: bool) -> SessionParams {
+    let mut params = SessionParams::default();
+    let (threads, threads_batch) = {
+        let settings = SETTINGS.read().await;
+        let settings = settings.read().await;
+        affinity::pin_threads(&settings.process_cpu_affinity);
+        (
+            settings.auto_threads(false),
+            settings.auto_threads_batch(false),
+        )
+    };
+
+    // TODO handle optional params
+    //params.seed = args.seed;
+    params.n_threads = threads;
+    params.n_threads_batch = threads_batch;
+    params.n_ctx = n_ctx;
+    params.offload_kqv = !no_kv_offload;
+    params
+}
+
+/// Generates the whole completion of `handle`, recording its tokens in `timings` and `meter`.
+async fn complete(
+    mut handle: CompletionHandle,
+    model: &LlamaModel,
+    timings: &TimingsRecorder,
+    meter: &ThroughputMeter,
+) -> String {
+    let mut completion = String::new();
+    while let Some(token) = handle.next().await {
+        completion.push_str(&model.token_to_piece(token));
+        timings.record_token();
+        meter.record_token();
+    }
+    completion
+}
+
+/// Builds the sampler of a request from its sampling options.
+///
+/// A temperature of `0` always selects the most likely token.
+fn build_sampler(args: &CompletionArgs) -> StandardSampler {
+    let temperature = args.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+    if temperature <= 0.0 {
+        return StandardSampler::new_greedy();
+    }
+
+    let mut stages = vec![SamplerStage::RepetitionPenalty {
+        repetition_penalty: REPETITION_PENALTY,
+        frequency_penalty: args.frequency_penalty.unwrap_or(0.0),
+        presence_penalty: args.presence_penalty.unwrap_or(0.0),
+        last_n: REPETITION_LAST_N,
+    }];
+
+    let mirostat = args.mirostat.unwrap_or(0);
+    if mirostat == 0 {
+        stages.push(SamplerStage::TopK(DEFAULT_TOP_K));
+        if let Some(typical_p) = args.typical_p {
+            stages.push(SamplerStage::Typical(typical_p));
+        }
+        stages.push(SamplerStage::TopP(args.top_p.unwrap_or(DEFAULT_TOP_P)));
+        stages.push(SamplerStage::MinP(args.min_p.unwrap_or(DEFAULT_MIN_P)));
+    }
+    stages.push(SamplerStage::Temperature(temperature));
+
+    let tau = args.mirostat_tau.unwrap_or(DEFAULT_MIROSTAT_TAU);
+    let eta = args.mirostat_eta.unwrap_or(DEFAULT_MIROSTAT_ETA);
+    match mirostat {
+        0 => StandardSampler::new_softmax(stages, 1),
+        1 => StandardSampler::new_mirostat(stages, 1, tau, eta, MIROSTAT_M),
+        _ => StandardSampler::new_mirostat_v2(stages, 1, tau, eta),
+    }
+}
+
+/// A session holding a cached prompt, copied by the one-shot requests starting with it.
+///
+/// Unlike the chat sessions, pinned sessions are never taken by requests and live as long as
+/// their model.
+struct PinnedSession {
+    /// The [`SessionId`] of the cached prompt.
+    id: SessionId,
+
+    /// The context size of the session.
+    n_ctx: u32,
+
+    /// The session, advanced with the cached prompt.
+    session: Perishable<LlamaSession>,
 }
 
 /// An object representing an unique identifier for a session context.
@@ -480,6 +927,28 @@ impl PartialEq for SessionId {
 
 impl Eq for SessionId {}
 
+/// The key of a chat session in the `sessions` of an [`UnloadingModel`].
+#[derive(Clone, Hash, PartialEq, Eq)]
+enum SessionKey {
+    /// A session found from the messages of the prompts continuing it.
+    Chat(SessionId),
+
+    /// A session created, and continued, explicitly by its [`Uuid`].
+    Explicit(Uuid),
+}
+
+impl SessionKey {
+    /// Advances the context of the session with the provided [`str`] slice, see
+    /// [`SessionId::advance`].
+    ///
+    /// Explicit sessions are identified by their [`Uuid`] alone, so their key never changes.
+    fn advance(&mut self, new_context: &str) {
+        if let SessionKey::Chat(id) = self {
+            id.advance(new_context);
+        }
+    }
+}
+
 /// Helper function that finds the first of several substrings in a string, returning the index if
 /// one was found
 ///
@@ -509,16 +978,31 @@ fn find_any(text: &str, patterns: &[&str]) -> Option<usize> {
 /// A [`Stream`] of [`Token`]s returned by a [`LlamaCppSession::stream_complete`] call.
 struct CompletionStream {
     /// Handle to the model completions handle.
-    handle: TokensToStrings<CompletionHandle>,
+    handle: CompletionHandle,
+
+    /// The model generating the completions, used to turn tokens into text.
+    model: LlamaModel,
+
+    /// The log the generated tokens are recorded in, if any.
+    token_log: Option<TokenLog>,
+
+    /// The recorder of the timings of the completion.
+    timings: TimingsRecorder,
+
+    /// The meter the generated tokens are recorded in.
+    meter: ThroughputMeter,
+
+    /// The object counting this stream as active in `meter`.
+    _active_stream: ActiveStream,
 
     /// The session used for generation completions.
     session: SessionOption,
 
-    /// The `session`'s id.
-    session_id: Option<SessionId>,
+    /// The `session`'s key.
+    session_id: Option<SessionKey>,
 
     /// A sender used to send both `session` and `session_id` once generation is completion
-    finished_tx: Option<UnboundedSender<(SessionId, Perishable<LlamaSession>)>>,
+    finished_tx: Option<UnboundedSender<(SessionKey, Perishable<LlamaSession>)>>,
 
     /// The object signaling that `model` is currently active.
     _model_signal: ActiveSignal,
@@ -532,41 +1016,49 @@ impl CompletionStream {
     ///
     /// ## Arguments
     /// * `session` - The session used to generate completions.
-    /// * `session_id` - The [`SessionId`] associated with `session`.
+    /// * `session_id` - The [`SessionKey`] associated with `session`.
     /// * `new_context` - The context used to advance the session.
     /// * `model` - The [`LlamaModel`] that `session` is associated with.
     /// * `model_signal` - The `model`'s associated [`ActiveSignal`].
-    /// * `sample` - The [`StandardSampler`] used to generate completions.
+    /// * `args` - The [`CompletionArgs`] of the request, used to build the [`StandardSampler`]
+    /// generating completions, and to record its tokens and timings.
     /// * `end_token` - An [`UnboundedSender`] used to send both `session` and `session` once
     /// generation finishes.
+    /// * `meter` - The [`ThroughputMeter`] the generated tokens are recorded in.
     async fn new(
         session: Perishable<LlamaSession>,
-        mut session_id: SessionId,
+        mut session_id: SessionKey,
         new_context: &str,
         model: LlamaModel,
         model_signal: ActiveSignal,
-        sampler: StandardSampler,
-        finished_tx: UnboundedSender<(SessionId, Perishable<LlamaSession>)>,
+        args: &CompletionArgs,
+        finished_tx: UnboundedSender<(SessionKey, Perishable<LlamaSession>)>,
+        meter: &ThroughputMeter,
     ) -> Result<Self, LLMEndpointError> {
+        let timings = args.timings.clone().unwrap_or_default();
         let (session_signal, handle) = {
-            let (session_signal, mut session_guard) = get_or_init_session(&session, model).await?;
+            let (session_signal, mut session_guard) =
+                get_or_init_session(&session, model.clone(), args).await?;
 
-            session_guard
-                .advance_context_async(new_context)
-                .await
-                .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+            advance(&mut session_guard, &model, new_context, args).await?;
             session_id.advance(new_context);
+            timings.end_prompt();
 
             (
                 session_signal,
                 session_guard
-                    .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
+                    .start_completing_with(build_sampler(args), SINGLE_MESSAGE_LIMIT)
                     .map_err(|e| LLMEndpointError::Advance(e.to_string()))?,
             )
         };
 
         Ok(Self {
-            handle: handle.into_strings(),
+            handle,
+            model,
+            token_log: args.token_log.clone(),
+            timings,
+            meter: meter.clone(),
+            _active_stream: meter.start_stream(),
             session: SessionOption::Perishable(session),
             session_id: Some(session_id),
             finished_tx: Some(finished_tx),
@@ -578,19 +1070,25 @@ impl CompletionStream {
     async fn new_oneshot(
         mut session: LlamaSession,
         new_context: &str,
+        model: LlamaModel,
         model_signal: ActiveSignal,
-        sampler: StandardSampler,
+        args: &CompletionArgs,
+        meter: &ThroughputMeter,
     ) -> Result<Self, LLMEndpointError> {
-        session
-            .advance_context_async(new_context)
-            .await
-            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))?;
+        let timings = args.timings.clone().unwrap_or_default();
+        advance(&mut session, &model, new_context, args).await?;
+        timings.end_prompt();
         let handle = session
-            .start_completing_with(sampler, SINGLE_MESSAGE_LIMIT)
+            .start_completing_with(build_sampler(args), SINGLE_MESSAGE_LIMIT)
             .map_err(|e| LLMEndpointError::Advance(e.to_string()))?;
 
         Ok(Self {
-            handle: handle.into_strings(),
+            handle,
+            model,
+            token_log: args.token_log.clone(),
+            timings,
+            meter: meter.clone(),
+            _active_stream: meter.start_stream(),
             session: SessionOption::OneShot(session),
             session_id: None,
             finished_tx: None,
@@ -604,9 +1102,16 @@ impl Stream for CompletionStream {
     type Item = String;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match std::pin::pin!(&mut self.handle).poll_next(cx) {
-            Poll::Ready(Some(val)) => {
-                if let Some(id) = &mut self.session_id {
+        let this = &mut *self;
+        match std::pin::pin!(&mut this.handle).poll_next(cx) {
+            Poll::Ready(Some(token)) => {
+                let val = this.model.token_to_piece(token);
+                this.timings.record_token();
+                this.meter.record_token();
+                if let Some(log) = &this.token_log {
+                    log.push(token.0 as u32, val.len());
+                }
+                if let Some(id) = &mut this.session_id {
                     id.advance(&val);
                 }
                 Poll::Ready(Some(val))
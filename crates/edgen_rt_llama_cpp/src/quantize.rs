@@ -0,0 +1,252 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Re-quantization of GGUF models with `llama.cpp`.
+
+use std::ffi::CString;
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::str::FromStr;
+
+use llama_cpp_sys::{
+    llama_ftype, llama_model_quantize, llama_model_quantize_default_params, LLAMA_FTYPE_ALL_F32,
+    LLAMA_FTYPE_MOSTLY_F16, LLAMA_FTYPE_MOSTLY_Q2_K, LLAMA_FTYPE_MOSTLY_Q3_K_L,
+    LLAMA_FTYPE_MOSTLY_Q3_K_M, LLAMA_FTYPE_MOSTLY_Q3_K_S, LLAMA_FTYPE_MOSTLY_Q4_0,
+    LLAMA_FTYPE_MOSTLY_Q4_1, LLAMA_FTYPE_MOSTLY_Q4_K_M, LLAMA_FTYPE_MOSTLY_Q4_K_S,
+    LLAMA_FTYPE_MOSTLY_Q5_0, LLAMA_FTYPE_MOSTLY_Q5_1, LLAMA_FTYPE_MOSTLY_Q5_K_M,
+    LLAMA_FTYPE_MOSTLY_Q5_K_S, LLAMA_FTYPE_MOSTLY_Q6_K, LLAMA_FTYPE_MOSTLY_Q8_0,
+};
+use thiserror::Error;
+
+/// The quantizations a model can be converted to, named as they are by `llama.cpp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantization {
+    F32,
+    F16,
+    Q4_0,
+    Q4_1,
+    Q5_0,
+    Q5_1,
+    Q8_0,
+    Q2K,
+    Q3KS,
+    Q3KM,
+    Q3KL,
+    Q4KS,
+    Q4KM,
+    Q5KS,
+    Q5KM,
+    Q6K,
+}
+
+impl Quantization {
+    /// Every quantization, from the largest to the smallest models.
+    pub const ALL: &'static [Quantization] = &[
+        Quantization::F32,
+        Quantization::F16,
+        Quantization::Q8_0,
+        Quantization::Q6K,
+        Quantization::Q5_1,
+        Quantization::Q5KM,
+        Quantization::Q5_0,
+        Quantization::Q5KS,
+        Quantization::Q4_1,
+        Quantization::Q4KM,
+        Quantization::Q4_0,
+        Quantization::Q4KS,
+        Quantization::Q3KL,
+        Quantization::Q3KM,
+        Quantization::Q3KS,
+        Quantization::Q2K,
+    ];
+
+    /// The name of this quantization, e.g. `Q4_K_M`, as found in the names of model files.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantization::F32 => "F32",
+            Quantization::F16 => "F16",
+            Quantization::Q4_0 => "Q4_0",
+            Quantization::Q4_1 => "Q4_1",
+            Quantization::Q5_0 => "Q5_0",
+            Quantization::Q5_1 => "Q5_1",
+            Quantization::Q8_0 => "Q8_0",
+            Quantization::Q2K => "Q2_K",
+            Quantization::Q3KS => "Q3_K_S",
+            Quantization::Q3KM => "Q3_K_M",
+            Quantization::Q3KL => "Q3_K_L",
+            Quantization::Q4KS => "Q4_K_S",
+            Quantization::Q4KM => "Q4_K_M",
+            Quantization::Q5KS => "Q5_K_S",
+            Quantization::Q5KM => "Q5_K_M",
+            Quantization::Q6K => "Q6_K",
+        }
+    }
+
+    /// Returns the quantization named in the name of a model file, if any, e.g. `Q4_K_M` for
+    /// `mistral-7b.Q4_K_M.gguf` or `Q5_0` for `ggml-model-q5_0.bin`.
+    pub fn from_file_name(name: &str) -> Option<Quantization> {
+        let stem = Path::new(name).file_stem()?.to_str()?;
+        stem.rsplit(['.', '-'])
+            .find_map(move |segment| segment.parse().ok())
+    }
+
+    fn ftype(&self) -> llama_ftype {
+        match self {
+            Quantization::F32 => LLAMA_FTYPE_ALL_F32,
+            Quantization::F16 => LLAMA_FTYPE_MOSTLY_F16,
+            Quantization::Q4_0 => LLAMA_FTYPE_MOSTLY_Q4_0,
+            Quantization::Q4_1 => LLAMA_FTYPE_MOSTLY_Q4_1,
+            Quantization::Q5_0 => LLAMA_FTYPE_MOSTLY_Q5_0,
+            Quantization::Q5_1 => LLAMA_FTYPE_MOSTLY_Q5_1,
+            Quantization::Q8_0 => LLAMA_FTYPE_MOSTLY_Q8_0,
+            Quantization::Q2K => LLAMA_FTYPE_MOSTLY_Q2_K,
+            Quantization::Q3KS => LLAMA_FTYPE_MOSTLY_Q3_K_S,
+            Quantization::Q3KM => LLAMA_FTYPE_MOSTLY_Q3_K_M,
+            Quantization::Q3KL => LLAMA_FTYPE_MOSTLY_Q3_K_L,
+            Quantization::Q4KS => LLAMA_FTYPE_MOSTLY_Q4_K_S,
+            Quantization::Q4KM => LLAMA_FTYPE_MOSTLY_Q4_K_M,
+            Quantization::Q5KS => LLAMA_FTYPE_MOSTLY_Q5_K_S,
+            Quantization::Q5KM => LLAMA_FTYPE_MOSTLY_Q5_K_M,
+            Quantization::Q6K => LLAMA_FTYPE_MOSTLY_Q6_K,
+        }
+    }
+}
+
+impl Display for Quantization {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Quantization {
+    type Err = QuantizeError;
+
+    /// Parses a quantization from its name, ignoring case, e.g. `Q4_K_M` or `q5_k_s`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Quantization::ALL
+            .iter()
+            .find(move |quant| quant.name().eq_ignore_ascii_case(s.trim()))
+            .copied()
+            .ok_or_else(move || QuantizeError::UnknownQuantization(s.to_string()))
+    }
+}
+
+/// An error quantizing a model.
+#[derive(Debug, Error)]
+pub enum QuantizeError {
+    #[error("unknown quantization {0}, expected one of Q4_0, Q4_K_M, Q5_K_S, Q8_0, etc.")]
+    UnknownQuantization(String),
+    #[error("the model path {0:?} is not valid UTF-8 or contains a nul character")]
+    InvalidPath(String),
+    #[error("llama.cpp failed to quantize the model, with code {0}")]
+    Failed(u32),
+}
+
+/// Quantizes the GGUF model at `input` to `quant`, writing the result to `output`.
+///
+/// This runs for as long as it takes to quantize the model, usually minutes, so it must not be
+/// called from an asynchronous context. If `threads` is `0`, `llama.cpp` uses every core.
+pub fn quantize(
+    input: &Path,
+    output: &Path,
+    quant: Quantization,
+    threads: u32,
+) -> Result<(), QuantizeError> {
+    let input = c_path(input)?;
+    let output = c_path(output)?;
+
+    // SAFETY: this only returns a plain struct of options, without side effects
+    let mut params = unsafe { llama_model_quantize_default_params() };
+    params.ftype = quant.ftype();
+    params.nthread = threads as i32;
+
+    // SAFETY: both paths are nul-terminated strings and `params` is a valid set of options, all of
+    // which outlive the call
+    let result = unsafe { llama_model_quantize(input.as_ptr(), output.as_ptr(), &params) };
+    if result != 0 {
+        return Err(QuantizeError::Failed(result));
+    }
+
+    Ok(())
+}
+
+/// Returns the name of the file `input` is quantized to as `quant`, replacing the quantization in
+/// the name of `input` if it has one, e.g. `mistral-7b.Q8_0.gguf` becomes `mistral-7b.Q4_K_M.gguf`.
+pub fn quantized_file_name(input: &Path, quant: Quantization) -> String {
+    let stem = input
+        .file_stem()
+        .map(move |stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let base = stem
+        .rfind(['.', '-'])
+        .filter(|&i| stem[i + 1..].parse::<Quantization>().is_ok())
+        .map_or(stem.as_str(), |i| &stem[..i]);
+
+    format!("{base}.{quant}.gguf")
+}
+
+fn c_path(path: &Path) -> Result<CString, QuantizeError> {
+    let invalid = move || QuantizeError::InvalidPath(path.to_string_lossy().to_string());
+    let path = path.to_str().ok_or_else(invalid)?;
+    CString::new(path).map_err(move |_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantization_names() {
+        assert_eq!(
+            "Q4_K_M".parse::<Quantization>().unwrap(),
+            Quantization::Q4KM
+        );
+        assert_eq!(
+            "q5_k_s".parse::<Quantization>().unwrap(),
+            Quantization::Q5KS
+        );
+        assert!("Q7_K".parse::<Quantization>().is_err());
+        assert_eq!(
+            Quantization::from_file_name("mistral-7b-instruct.Q4_K_M.gguf"),
+            Some(Quantization::Q4KM)
+        );
+        assert_eq!(
+            Quantization::from_file_name("ggml-model-q5_0.bin"),
+            Some(Quantization::Q5_0)
+        );
+        assert_eq!(Quantization::from_file_name("model.safetensors"), None);
+
+        for quant in Quantization::ALL {
+            assert_eq!(quant.to_string().parse::<Quantization>().unwrap(), *quant);
+        }
+    }
+
+    #[test]
+    fn quantized_file_names() {
+        assert_eq!(
+            quantized_file_name(
+                Path::new("/models/mistral-7b.Q8_0.gguf"),
+                Quantization::Q4KM
+            ),
+            "mistral-7b.Q4_K_M.gguf"
+        );
+        assert_eq!(
+            quantized_file_name(Path::new("tinyllama-f16.gguf"), Quantization::Q5KS),
+            "tinyllama.Q5_K_S.gguf"
+        );
+        assert_eq!(
+            quantized_file_name(Path::new("phi-2.gguf"), Quantization::Q6K),
+            "phi-2.Q6_K.gguf"
+        );
+    }
+}
@@ -0,0 +1,561 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A large language model RT for models distributed as (non-quantized) safetensors files, such
+//! as Phi and Gemma, using a [`candle_core`] backend.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use candle_core::{DType, Device, DeviceLocation, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::{gemma, phi};
+use candle_transformers::utils::apply_repeat_penalty;
+use dashmap::DashMap;
+use futures::Stream;
+use rand::random;
+use thiserror::Error;
+use tokenizers::Tokenizer;
+use tokio::spawn;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio::time::{interval, MissedTickBehavior};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{error, info, info_span, warn};
+
+use edgen_core::cleanup_interval;
+use edgen_core::lazy_task::LazyTask;
+use edgen_core::llm::{
+    inactive_llm_ttl, CompletionArgs, LLMEndpoint, LLMEndpointError, ASSISTANT_TAG,
+};
+use edgen_core::perishable::{retain_alive, ActiveSignal, Perishable};
+use edgen_core::resident::{is_pinned, ResidentModel};
+use edgen_core::settings::{DevicePolicy, SETTINGS};
+
+// TODO this should be in settings
+const SINGLE_MESSAGE_LIMIT: usize = 4096;
+
+/// The penalty applied to recently generated tokens, to keep the model from repeating itself.
+const REPEAT_PENALTY: f32 = 1.1;
+
+/// The number of most recent tokens [`REPEAT_PENALTY`] is applied to.
+const REPEAT_LAST_N: usize = 64;
+
+/// The tokens that mark the end of the generated text in the supported models.
+const EOS_TOKENS: &[&str] = &["<|endoftext|>", "<eos>", "</s>"];
+
+#[derive(Error, Debug)]
+enum CandleLLMError {
+    #[error(transparent)]
+    Candle(#[from] candle_core::Error),
+    #[error("tokenizer error: {0}")]
+    Tokenizer(String),
+    #[error("failed to read model files: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid model configuration: {0}")]
+    Config(#[from] serde_json::Error),
+    #[error("unsupported model architecture: {0}")]
+    UnsupportedArchitecture(String),
+    #[error("no acceleration device available: {0}")]
+    NoDevice(String),
+}
+
+impl From<CandleLLMError> for LLMEndpointError {
+    fn from(value: CandleLLMError) -> Self {
+        match value {
+            CandleLLMError::Candle(_) | CandleLLMError::Tokenizer(_) => {
+                LLMEndpointError::Advance(value.to_string())
+            }
+            CandleLLMError::Io(_)
+            | CandleLLMError::Config(_)
+            | CandleLLMError::UnsupportedArchitecture(_)
+            | CandleLLMError::NoDevice(_) => LLMEndpointError::Load(value.to_string()),
+        }
+    }
+}
+
+/// The supported model architectures.
+enum Architecture {
+    Phi(phi::Model),
+    Gemma(gemma::Model),
+}
+
+impl Architecture {
+    /// Runs the model on the provided tokens, which start at position `offset` of the context,
+    /// returning the logits of the next token.
+    fn forward(&mut self, input: &Tensor, offset: usize) -> candle_core::Result<Tensor> {
+        match self {
+            Architecture::Phi(model) => model.forward(input),
+            Architecture::Gemma(model) => model.forward(input, offset),
+        }
+    }
+
+    fn clear_kv_cache(&mut self) {
+        match self {
+            Architecture::Phi(model) => model.clear_kv_cache(),
+            Architecture::Gemma(model) => model.clear_kv_cache(),
+        }
+    }
+}
+
+/// The sampling parameters of a generation.
+struct GenerationArgs {
+    max_tokens: usize,
+    seed: u64,
+    temperature: f64,
+    top_p: Option<f64>,
+}
+
+impl From<&CompletionArgs> for GenerationArgs {
+    fn from(args: &CompletionArgs) -> Self {
+        Self {
+            max_tokens: args
+                .max_tokens
+                .map(|max| max as usize)
+                .unwrap_or(SINGLE_MESSAGE_LIMIT),
+            seed: args.seed.map(u64::from).unwrap_or_else(random),
+            temperature: args.temperature.unwrap_or(1.0) as f64,
+            top_p: args.top_p.map(f64::from),
+        }
+    }
+}
+
+/// A large language model, loaded into memory.
+struct CandleModel {
+    architecture: Architecture,
+    tokenizer: Tokenizer,
+    eos_token: Option<u32>,
+    device: Device,
+}
+
+impl CandleModel {
+    /// Loads a model into the memory of the provided [`Device`].
+    ///
+    /// The `config.json` and `tokenizer.json` files of the model must be in the same directory as
+    /// its weights.
+    fn load(weights: &Path, device: Device) -> Result<Self, CandleLLMError> {
+        let _span = info_span!("candle_llm_load", weights = ?weights).entered();
+        let dir = weights.parent().unwrap_or(Path::new("."));
+
+        let config: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.join("config.json"))?)?;
+        let tokenizer = Tokenizer::from_file(dir.join("tokenizer.json"))
+            .map_err(|e| CandleLLMError::Tokenizer(e.to_string()))?;
+
+        // Half precision is very slow (when supported at all) on most CPUs
+        let dtype = if device.is_cuda() {
+            DType::BF16
+        } else {
+            DType::F32
+        };
+        let files = weight_files(weights)?;
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&files, dtype, &device)? };
+
+        let model_type = config["model_type"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let architecture = match model_type.as_str() {
+            "phi" => Architecture::Phi(phi::Model::new(&serde_json::from_value(config)?, vb)?),
+            "gemma" => {
+                Architecture::Gemma(gemma::Model::new(&serde_json::from_value(config)?, vb)?)
+            }
+            _ => return Err(CandleLLMError::UnsupportedArchitecture(model_type)),
+        };
+
+        let eos_token = EOS_TOKENS
+            .iter()
+            .find_map(|token| tokenizer.token_to_id(token));
+
+        Ok(Self {
+            architecture,
+            tokenizer,
+            eos_token,
+            device,
+        })
+    }
+
+    /// Generates a completion of the prompt, sending every new piece of text through `tx` as
+    /// soon as it is generated.
+    fn generate(
+        &mut self,
+        prompt: &str,
+        args: GenerationArgs,
+        tx: UnboundedSender<String>,
+    ) -> Result<(), CandleLLMError> {
+        let _span = info_span!("candle_llm_generate", max_tokens = args.max_tokens).entered();
+        self.architecture.clear_kv_cache();
+
+        let mut tokens = self
+            .tokenizer
+            .encode(prompt, true)
+            .map_err(|e| CandleLLMError::Tokenizer(e.to_string()))?
+            .get_ids()
+            .to_vec();
+        let prompt_len = tokens.len();
+        let mut logits_processor =
+            LogitsProcessor::new(args.seed, Some(args.temperature), args.top_p);
+        let mut sent = 0;
+
+        for index in 0..args.max_tokens {
+            // After the first step, the previous tokens are in the KV cache
+            let context = if index == 0 {
+                &tokens[..]
+            } else {
+                &tokens[tokens.len() - 1..]
+            };
+            let offset = tokens.len() - context.len();
+            let input = Tensor::new(context, &self.device)?.unsqueeze(0)?;
+            let logits = self
+                .architecture
+                .forward(&input, offset)?
+                .flatten_all()?
+                .to_dtype(DType::F32)?;
+            let logits = apply_repeat_penalty(
+                &logits,
+                REPEAT_PENALTY,
+                &tokens[tokens.len().saturating_sub(REPEAT_LAST_N)..],
+            )?;
+
+            let next = logits_processor.sample(&logits)?;
+            if Some(next) == self.eos_token {
+                break;
+            }
+            tokens.push(next);
+
+            let text = self
+                .tokenizer
+                .decode(&tokens[prompt_len..], true)
+                .map_err(|e| CandleLLMError::Tokenizer(e.to_string()))?;
+            // Wait until multi-token characters are complete before sending them
+            if text.ends_with('\u{fffd}') {
+                continue;
+            }
+            if let Some(new) = text.get(sent..) {
+                if !new.is_empty() {
+                    if tx.send(new.to_string()).is_err() {
+                        // Nobody is listening anymore
+                        break;
+                    }
+                    sent = text.len();
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the safetensors files with the weights of a model.
+///
+/// The weights of large models are often sharded among several files, in which case `weights`
+/// must be the `model.safetensors.index.json` file listing the shards.
+fn weight_files(weights: &Path) -> Result<Vec<PathBuf>, CandleLLMError> {
+    let dir = weights.parent().unwrap_or(Path::new("."));
+    Ok(shards(weights)?
+        .map(|shards| shards.into_iter().map(|shard| dir.join(shard)).collect())
+        .unwrap_or_else(|| vec![weights.to_path_buf()]))
+}
+
+/// Returns the names of the shards listed in a safetensors index file, or `None` if `weights` is
+/// not an index file.
+fn shards(weights: &Path) -> Result<Option<BTreeSet<String>>, CandleLLMError> {
+    let is_index = weights
+        .extension()
+        .map(|extension| extension == "json")
+        .unwrap_or(false);
+    if !is_index {
+        return Ok(None);
+    }
+
+    let index: serde_json::Value = serde_json::from_slice(&std::fs::read(weights)?)?;
+    let shards = index["weight_map"]
+        .as_object()
+        .map(|map| {
+            map.values()
+                .filter_map(|file| file.as_str().map(|file| file.to_string()))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(Some(shards))
+}
+
+/// Returns the names of the files that must be present in the same directory as the provided
+/// weights file, for the model to be loaded.
+pub fn companion_files(weights: impl AsRef<Path>) -> Result<Vec<String>, LLMEndpointError> {
+    let mut files = vec!["config.json".to_string(), "tokenizer.json".to_string()];
+    if let Some(shards) = shards(weights.as_ref())? {
+        files.extend(shards);
+    }
+    Ok(files)
+}
+
+/// Picks the [`Device`] on which models should be loaded and executed, according to the provided
+/// [`DevicePolicy`].
+fn pick_device(policy: &DevicePolicy) -> Result<Device, CandleLLMError> {
+    match policy {
+        DevicePolicy::AlwaysCpu { .. } => Ok(Device::Cpu),
+        DevicePolicy::AlwaysDevice { overflow_to_cpu } => match accelerator() {
+            Ok(device) => Ok(device),
+            Err(e) if *overflow_to_cpu => {
+                warn!("{e}, executing on CPU");
+                Ok(Device::Cpu)
+            }
+            Err(e) => Err(e),
+        },
+        _ => {
+            warn!("Unknown device policy, executing on CPU");
+            Ok(Device::Cpu)
+        }
+    }
+}
+
+/// Returns the name of a [`Device`], as reported in [`ResidentModel`]s.
+fn device_name(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } | DeviceLocation::Metal { gpu_id } => {
+            format!("device:{gpu_id}")
+        }
+    }
+}
+
+/// Returns the acceleration device models should run on.
+fn accelerator() -> Result<Device, CandleLLMError> {
+    #[cfg(feature = "cuda")]
+    {
+        return Device::new_cuda(0).map_err(|e| CandleLLMError::NoDevice(e.to_string()));
+    }
+
+    #[cfg(all(feature = "metal", not(feature = "cuda")))]
+    {
+        return Device::new_metal(0).map_err(|e| CandleLLMError::NoDevice(e.to_string()));
+    }
+
+    #[cfg(not(any(feature = "cuda", feature = "metal")))]
+    {
+        Err(CandleLLMError::NoDevice(
+            "Edgen was built without CUDA or Metal support".to_string(),
+        ))
+    }
+}
+
+/// A large language model endpoint, implementing [`LLMEndpoint`] using a [`candle_core`] backend.
+pub struct CandleLLMEndpoint {
+    /// A map of the models currently loaded into memory, with their path as the key.
+    models: Arc<DashMap<String, Perishable<Arc<Mutex<CandleModel>>>>>,
+
+    /// The name of the device each model was last loaded on, with its path as the key.
+    devices: Arc<DashMap<String, String>>,
+
+    /// A background thread that periodically removes models from the `models` collection, if they
+    /// are not loaded at the time. It is started along with the first model.
+    cleanup_thread: LazyTask,
+}
+
+impl CandleLLMEndpoint {
+    /// Gets the [`CandleModel`] loaded from the specified path (and its associated
+    /// [`ActiveSignal`]). If the model isn't already loaded, first load it.
+    async fn get(
+        &self,
+        model_path: impl AsRef<Path>,
+    ) -> Result<(ActiveSignal, Arc<Mutex<CandleModel>>), LLMEndpointError> {
+        let path = model_path.as_ref().to_path_buf();
+        let key = path.to_string_lossy().to_string();
+
+        if !self.models.contains_key(&key) {
+            self.cleanup_thread.start();
+            let pinned_path = path.clone();
+            self.models.insert(
+                key.clone(),
+                Perishable::with_ttl_and_pin(inactive_llm_ttl(), move || is_pinned(&pinned_path)),
+            );
+        }
+
+        // PANIC SAFETY: Just inserted the element if it isn't already inside the map, so must be present in the map
+        let perishable = self.models.get(&key).unwrap();
+        let devices = self.devices.clone();
+        let (signal, model) = perishable
+            .get_or_try_init(move || async move {
+                let policy = SETTINGS.read().await.read().await.gpu_policy.clone();
+                let device = pick_device(&policy)?;
+                devices.insert(path.to_string_lossy().to_string(), device_name(&device));
+                info!("Loading {} into memory", path.to_string_lossy());
+                CandleModel::load(&path, device).map(|model| Arc::new(Mutex::new(model)))
+            })
+            .await
+            .map_err(move |e: CandleLLMError| LLMEndpointError::Load(e.to_string()))?;
+
+        Ok((signal, Arc::clone(&model)))
+    }
+
+    /// Starts generating a completion for the provided arguments in a blocking thread, returning
+    /// the handle of that thread and a channel receiving the generated text.
+    async fn start_completion(
+        &self,
+        model_path: impl AsRef<Path>,
+        args: CompletionArgs,
+    ) -> Result<
+        (
+            JoinHandle<Result<(), CandleLLMError>>,
+            UnboundedReceiver<String>,
+        ),
+        LLMEndpointError,
+    > {
+        let (signal, model) = self.get(model_path).await?;
+        let prompt = format!("{}{ASSISTANT_TAG}", args.messages);
+        let args = GenerationArgs::from(&args);
+        let (tx, rx) = unbounded_channel();
+
+        let handle = spawn_blocking(move || {
+            // Keep the model alive for as long as it's generating
+            let _signal = signal;
+            let mut model = match model.lock() {
+                Ok(model) => model,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            model.generate(&prompt, args, tx)
+        });
+
+        Ok((handle, rx))
+    }
+}
+
+#[async_trait::async_trait]
+impl LLMEndpoint for CandleLLMEndpoint {
+    async fn chat_completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: CompletionArgs,
+    ) -> Result<String, LLMEndpointError> {
+        let (handle, mut rx) = self.start_completion(model_path, args).await?;
+
+        let mut res = String::new();
+        while let Some(piece) = rx.recv().await {
+            res.push_str(&piece);
+        }
+        handle
+            .await
+            .map_err(move |e| LLMEndpointError::Advance(e.to_string()))??;
+
+        Ok(res)
+    }
+
+    async fn stream_chat_completions(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: CompletionArgs,
+    ) -> Result<Box<dyn Stream<Item = String> + Unpin + Send>, LLMEndpointError> {
+        let (handle, rx) = self.start_completion(model_path, args).await?;
+
+        spawn(async move {
+            match handle.await {
+                Ok(Err(e)) => error!("Failed to generate completion: {e}"),
+                Err(e) => error!("Completion thread failed: {e}"),
+                Ok(Ok(())) => {}
+            }
+        });
+
+        Ok(Box::new(UnboundedReceiverStream::new(rx)))
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        self.models
+            .iter()
+            .filter(move |model| model.is_resident())
+            .map(|model| {
+                let device = self
+                    .devices
+                    .get(model.key())
+                    .map(move |device| device.clone())
+                    .unwrap_or_else(move || "cpu".to_string());
+                ResidentModel::new(model.key(), device, 0, model.users())
+            })
+            .collect()
+    }
+
+    fn reset(&self) {
+        self.models.clear();
+    }
+}
+
+impl Default for CandleLLMEndpoint {
+    fn default() -> Self {
+        let models: Arc<DashMap<String, Perishable<Arc<Mutex<CandleModel>>>>> = Default::default();
+        let models_clone = models.clone();
+        let cleanup_thread = LazyTask::new(async move {
+            let mut interval = interval(cleanup_interval());
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+                retain_alive(&models_clone, Perishable::liveness).await;
+            }
+        });
+
+        Self {
+            models,
+            devices: Default::default(),
+            cleanup_thread,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sharded_weights() {
+        let dir = std::env::temp_dir().join(format!("edgen_rt_llm_candle_{}", random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let index = dir.join("model.safetensors.index.json");
+        std::fs::write(
+            &index,
+            r#"{"weight_map": {
+                "a": "model-00001-of-00002.safetensors",
+                "b": "model-00002-of-00002.safetensors",
+                "c": "model-00001-of-00002.safetensors"
+            }}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            weight_files(&index).unwrap(),
+            vec![
+                dir.join("model-00001-of-00002.safetensors"),
+                dir.join("model-00002-of-00002.safetensors"),
+            ]
+        );
+        assert_eq!(
+            companion_files(&index).unwrap(),
+            vec![
+                "config.json",
+                "tokenizer.json",
+                "model-00001-of-00002.safetensors",
+                "model-00002-of-00002.safetensors",
+            ]
+        );
+
+        let single = dir.join("model.safetensors");
+        assert_eq!(weight_files(&single).unwrap(), vec![single.clone()]);
+        assert_eq!(
+            companion_files(&single).unwrap(),
+            vec!["config.json", "tokenizer.json"]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
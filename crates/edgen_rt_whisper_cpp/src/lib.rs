@@ -12,22 +12,26 @@
 
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use dashmap::DashMap;
-use futures::executor::block_on;
 use tokio::spawn;
-use tokio::task::JoinHandle;
+use tokio::task::{spawn_blocking, JoinHandle};
 use tokio::time::{interval, MissedTickBehavior};
-use tracing::info;
+use tracing::{debug, info};
 use uuid::Uuid;
 use whisper_cpp::{WhisperModel, WhisperParams, WhisperSampling, WhisperSession};
 
 use edgen_core::cleanup_interval;
-use edgen_core::perishable::{ActiveSignal, Perishable, PerishableReadGuard, PerishableWriteGuard};
+use edgen_core::lazy_task::LazyTask;
+use edgen_core::perishable::{
+    retain_alive, ActiveSignal, Liveness, Perishable, PerishableReadGuard, PerishableWriteGuard,
+};
+use edgen_core::resident::{is_pinned, LoadedDevice, ResidentModel};
 use edgen_core::settings::{DevicePolicy, SETTINGS};
 use edgen_core::whisper::{
-    inactive_whisper_session_ttl, inactive_whisper_ttl, parse, TranscriptionArgs, WhisperEndpoint,
-    WhisperEndpointError,
+    chunk, inactive_whisper_session_ttl, inactive_whisper_ttl, parse, vad, Transcription,
+    TranscriptionArgs, TranscriptionSegment, WhisperEndpoint, WhisperEndpointError,
 };
 
 /// A large language model endpoint, implementing [`WhisperEndpoint`] using a [`whisper_cpp`] backend.
@@ -36,8 +40,8 @@ pub struct WhisperCppEndpoint {
     models: Arc<DashMap<String, UnloadingModel>>,
 
     /// A background thread that periodically removes models from the `models` collection, if they
-    /// are not loaded at the time.
-    cleanup_thread: JoinHandle<()>,
+    /// are not loaded at the time. It is started along with the first model.
+    cleanup_thread: LazyTask,
 }
 
 impl WhisperCppEndpoint {
@@ -50,6 +54,7 @@ impl WhisperCppEndpoint {
         let key = model_path.as_ref().to_string_lossy().to_string();
 
         if !self.models.contains_key(&key) {
+            self.cleanup_thread.start();
             let model = UnloadingModel::new(model_path).await;
             self.models.insert(key.clone(), model);
         }
@@ -65,14 +70,36 @@ impl WhisperEndpoint for WhisperCppEndpoint {
         &self,
         model_path: impl AsRef<Path> + Send,
         args: TranscriptionArgs,
-    ) -> Result<(String, Option<Uuid>), WhisperEndpointError> {
-        let pcm = parse::pcm(&args.file)?;
+    ) -> Result<(Transcription, Option<Uuid>), WhisperEndpointError> {
+        let mut pcm = parse::pcm_of(&args.file)?;
+        if args.vad {
+            pcm = vad::remove_silence(&pcm);
+            // No speech was detected, so there is nothing for the model to transcribe
+            if pcm.is_empty() && !args.create_session {
+                return Ok((Transcription::default(), None));
+            }
+        }
         let model = self.get(model_path).await;
         model
             .transcription(args.create_session, args.session, pcm)
             .await
     }
 
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        self.models
+            .iter()
+            .filter(move |model| model.model.is_resident())
+            .map(move |model| {
+                ResidentModel::new(
+                    &model.path,
+                    model.device.get(),
+                    model.sessions.len(),
+                    model.model.users(),
+                )
+            })
+            .collect()
+    }
+
     fn reset(&self) {
         self.models.clear();
     }
@@ -82,13 +109,13 @@ impl Default for WhisperCppEndpoint {
     fn default() -> Self {
         let models: Arc<DashMap<String, UnloadingModel>> = Default::default();
         let models_clone = models.clone();
-        let cleanup_thread = spawn(async move {
+        let cleanup_thread = LazyTask::new(async move {
             let mut interval = interval(cleanup_interval());
             interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
             loop {
                 interval.tick().await;
-                models_clone.retain(move |_, model| block_on(model.loaded()));
+                retain_alive(&models_clone, UnloadingModel::liveness).await;
             }
         });
 
@@ -99,17 +126,12 @@ impl Default for WhisperCppEndpoint {
     }
 }
 
-impl Drop for WhisperCppEndpoint {
-    fn drop(&mut self) {
-        self.cleanup_thread.abort()
-    }
-}
-
 /// A [`WhisperModel`] (as well as its associated [`WhisperSession`]s) that unloads itself from
 /// memory after not being used for a period of time.
 struct UnloadingModel {
     model: Perishable<WhisperModel>,
     path: PathBuf,
+    device: LoadedDevice,
     sessions: Arc<DashMap<Uuid, Perishable<WhisperSession>>>,
     maintenance_thread: JoinHandle<()>,
 }
@@ -129,37 +151,45 @@ impl UnloadingModel {
 
             loop {
                 interval.tick().await;
-                sessions_clone.retain(move |_, session| block_on(session.is_alive()));
+                retain_alive(&sessions_clone, Perishable::liveness).await;
             }
         });
 
+        let path = model_path.as_ref().to_path_buf();
+        let pinned_path = path.clone();
+
         Self {
-            model: Perishable::with_ttl(inactive_whisper_ttl()),
-            path: model_path.as_ref().to_path_buf(),
+            model: Perishable::with_ttl_and_pin(inactive_whisper_ttl(), move || {
+                is_pinned(&pinned_path)
+            }),
+            path,
+            device: LoadedDevice::default(),
             sessions,
             maintenance_thread,
         }
     }
 
-    /// Returns **`true`** if this model is currently loaded in system memory, **`false`** otherwise.
-    async fn loaded(&self) -> bool {
-        self.model.is_alive().await
+    /// Returns a handle to check whether this model is currently loaded in system memory.
+    fn liveness(&self) -> Liveness<WhisperModel> {
+        self.model.liveness()
     }
 
     /// Computes the full transcription for the provided *PCM*;
+    ///
+    /// Long segments are split into overlapping windows (see [`chunk`]), which are transcribed
+    /// sequentially within the same session, so that the context carries over from one window to
+    /// the next.
     async fn transcription(
         &self,
         create_session: bool,
         uuid: Option<Uuid>,
         pcm: Vec<f32>,
-    ) -> Result<(String, Option<Uuid>), WhisperEndpointError> {
-        let (_model_signal, model_guard) = get_or_init_model(&self.model, &self.path).await?;
+    ) -> Result<(Transcription, Option<Uuid>), WhisperEndpointError> {
+        let (_model_signal, model_guard) =
+            get_or_init_model(&self.model, &self.path, &self.device).await?;
 
-        let mut params = WhisperParams::new(WhisperSampling::default_greedy());
         let threads = SETTINGS.read().await.read().await.auto_threads(false);
 
-        params.thread_count = threads;
-
         let uuid = if let Some(uuid) = uuid {
             Some(uuid)
         } else {
@@ -184,14 +214,7 @@ impl UnloadingModel {
             // Perishable uses a tokio RwLock internally, which guarantees fair access, so we
             // shouldn't have to worry about thread ordering
 
-            params.no_context = false;
-            session_guard
-                .advance(params, &pcm)
-                .await
-                .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
-            let res = session_guard
-                .new_context()
-                .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
+            let res = transcribe_chunks(&mut session_guard, &pcm, threads, false).await?;
 
             if create_session {
                 Ok((res, Some(uuid)))
@@ -205,14 +228,7 @@ impl UnloadingModel {
                 .await
                 .map_err(move |e| WhisperEndpointError::SessionCreationFailed(e.to_string()))?;
 
-            params.no_context = true;
-            session
-                .advance(params, &pcm)
-                .await
-                .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
-            let res = session
-                .new_context()
-                .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
+            let res = transcribe_chunks(&mut session, &pcm, threads, true).await?;
 
             Ok((res, None))
         }
@@ -226,30 +242,92 @@ impl Drop for UnloadingModel {
 }
 
 /// Helper function to acquire a read guard to a [`WhisperModel`] (and its associated
-/// [`ActiveSignal`]).
+/// [`ActiveSignal`]), recording the device it is loaded on in `loaded_device`.
 async fn get_or_init_model(
     model: &Perishable<WhisperModel>,
     path: impl AsRef<Path>,
+    loaded_device: &LoadedDevice,
 ) -> Result<(ActiveSignal, PerishableReadGuard<WhisperModel>), WhisperEndpointError> {
     let path = path.as_ref().to_path_buf();
+    // Read before taking the lock of the model, so that the settings are not locked by the load
+    let device = match SETTINGS.read().await.read().await.gpu_policy {
+        DevicePolicy::AlwaysCpu { .. } => None,
+        DevicePolicy::AlwaysDevice { .. } => Some(0),
+        _ => {
+            unimplemented!()
+        }
+    };
+
+    // Only builds with CUDA support can load models on a GPU
+    let name = match device {
+        Some(index) if cfg!(feature = "cuda") => format!("device:{index}"),
+        _ => "cpu".to_string(),
+    };
+    let loaded_device = loaded_device.clone();
+
     model
         .get_or_try_init(move || async move {
             info!("Loading {} into memory", path.to_string_lossy());
+            loaded_device.set(name);
 
-            let device = match SETTINGS.read().await.read().await.gpu_policy {
-                DevicePolicy::AlwaysCpu { .. } => None,
-                DevicePolicy::AlwaysDevice { .. } => Some(0),
-                _ => {
-                    unimplemented!()
-                }
-            };
-
-            WhisperModel::new_from_file(path, device)
+            // Loaded on a blocking thread, so that it does not stall the loads of other models
+            spawn_blocking(move || WhisperModel::new_from_file(path, device))
+                .await
+                .map_err(move |e| WhisperEndpointError::Load(e.to_string()))?
                 .map_err(move |e| WhisperEndpointError::Load(e.to_string()))
         })
         .await
 }
 
+/// Helper function to transcribe a *PCM* segment window by window, stitching the transcription of
+/// each window onto the previous ones.
+///
+/// The text of each window makes up a single segment, spanning the whole window, whose timestamps
+/// are offset by the start of the window.
+///
+/// If `no_context` is **`true`**, the first window is transcribed without any of the session's
+/// previous context, but every following window still carries over the context of the one before.
+async fn transcribe_chunks(
+    session: &mut WhisperSession,
+    pcm: &[f32],
+    threads: u32,
+    no_context: bool,
+) -> Result<Transcription, WhisperEndpointError> {
+    if pcm.is_empty() {
+        return Ok(Transcription::default());
+    }
+
+    let chunks = chunk::chunks(pcm);
+    if chunks.len() > 1 {
+        info!("Transcribing audio in {} windows", chunks.len());
+    }
+
+    let mut transcription = Transcription::default();
+    for (i, window) in chunks.iter().enumerate() {
+        let mut params = WhisperParams::new(WhisperSampling::default_greedy());
+        params.thread_count = threads;
+        params.no_context = no_context && i == 0;
+
+        debug!("Transcribing window starting at {:?}", window.offset);
+        session
+            .advance(params, window.pcm)
+            .await
+            .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
+        let res = session
+            .new_context()
+            .map_err(move |e| WhisperEndpointError::Advance(e.to_string()))?;
+
+        let segment = TranscriptionSegment {
+            start: Duration::ZERO,
+            end: window.duration(),
+            text: res,
+        };
+        chunk::stitch_window(&mut transcription, window, vec![segment]);
+    }
+
+    Ok(transcription)
+}
+
 /// Helper function to acquire a write guard to a [`WhisperSession`] (and its associated
 /// [`ActiveSignal`]).
 async fn get_or_init_session(
@@ -0,0 +1,182 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A fake model RT for audio transcriptions that answers with predefined strings.
+//!
+//! Transcriptions are keyed by the [`blake3`] hash of the audio file, as printed by `b3sum`, and
+//! can be provided in a YAML or JSON fixture file:
+//!
+//! ```yaml
+//! transcriptions:
+//!   <hash of the audio file>: Hello world.
+//! default: I did not catch that.
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use dashmap::{DashMap, DashSet};
+use serde_derive::Deserialize;
+use tracing::info;
+use uuid::Uuid;
+
+use edgen_core::resident::ResidentModel;
+use edgen_core::settings;
+use edgen_core::whisper::{
+    parse, Transcription, TranscriptionArgs, WhisperEndpoint, WhisperEndpointError,
+};
+
+pub const DEFAULT_TRANSCRIPTION: &str = "This is a fake transcription.";
+
+/// A set of canned transcriptions.
+#[derive(Debug, Default, Deserialize)]
+struct Fixture {
+    /// The transcriptions, keyed by the hex encoded hash of the audio file they belong to.
+    #[serde(default)]
+    transcriptions: HashMap<String, String>,
+
+    /// The transcription returned for unknown audio files, if any.
+    default: Option<String>,
+}
+
+struct WhisperFakerModel {
+    /// The canned transcriptions configured in the settings, if any.
+    fixture: Fixture,
+}
+
+impl WhisperFakerModel {
+    async fn new(_path: impl AsRef<Path>) -> Result<Self, WhisperEndpointError> {
+        let fixture = match settings::whisper_faker_fixture().await {
+            Some(path) => {
+                let contents = tokio::fs::read(&path).await.map_err(|e| {
+                    WhisperEndpointError::Load(format!("failed to read fixture {path:?}: {e}"))
+                })?;
+                parse_fixture(&contents).map_err(|e| {
+                    WhisperEndpointError::Load(format!("invalid fixture {path:?}: {e}"))
+                })?
+            }
+            None => Fixture::default(),
+        };
+        Ok(Self { fixture })
+    }
+
+    fn transcription(&self, file: &[u8]) -> String {
+        info!("faking transcription");
+        let hash = blake3::hash(file).to_hex();
+        self.fixture
+            .transcriptions
+            .iter()
+            .find(move |(key, _)| key.eq_ignore_ascii_case(hash.as_str()))
+            .map(move |(_, text)| text.clone())
+            .or_else(|| self.fixture.default.clone())
+            .unwrap_or_else(|| DEFAULT_TRANSCRIPTION.to_string())
+    }
+}
+
+fn parse_fixture(contents: &[u8]) -> Result<Fixture, serde_yaml::Error> {
+    // JSON is valid YAML, so a single parser handles both
+    serde_yaml::from_slice(contents)
+}
+
+/// Faking a whisper endpoint, implementing [`WhisperEndpoint`].
+pub struct WhisperFakerEndpoint {
+    /// A map of the models currently loaded into memory, with their path as the key.
+    models: Arc<DashMap<String, WhisperFakerModel>>,
+
+    /// The sessions created so far.
+    sessions: Arc<DashSet<Uuid>>,
+}
+
+impl WhisperFakerEndpoint {
+    async fn get(
+        &self,
+        model_path: impl AsRef<Path>,
+    ) -> Result<dashmap::mapref::one::Ref<String, WhisperFakerModel>, WhisperEndpointError> {
+        let key = model_path.as_ref().to_string_lossy().to_string();
+
+        if !self.models.contains_key(&key) {
+            let model = WhisperFakerModel::new(model_path).await?;
+            self.models.insert(key.clone(), model);
+        }
+
+        // PANIC SAFETY: Just inserted the element if it isn't already inside the map, so must be present in the map
+        Ok(self.models.get(&key).unwrap())
+    }
+}
+
+#[async_trait::async_trait]
+impl WhisperEndpoint for WhisperFakerEndpoint {
+    async fn transcription(
+        &self,
+        model_path: impl AsRef<Path> + Send,
+        args: TranscriptionArgs,
+    ) -> Result<(Transcription, Option<Uuid>), WhisperEndpointError> {
+        let session = match args.session {
+            Some(session) if self.sessions.contains(&session) => Some(session),
+            Some(_) => return Err(WhisperEndpointError::SessionNotFound),
+            None if args.create_session => {
+                let session = Uuid::new_v4();
+                self.sessions.insert(session);
+                Some(session)
+            }
+            None => None,
+        };
+
+        let model = self.get(model_path).await?;
+        let text = model.transcription(&args.file.contents()?);
+        // The canned transcription spans the whole file, if it is audio at all
+        let duration = parse::duration_of(&args.file).unwrap_or_default();
+        Ok((Transcription::single(text, duration), session))
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        // Sessions are not bound to a model, so all of them are reported for every model
+        let sessions = self.sessions.len();
+        self.models
+            .iter()
+            .map(move |model| ResidentModel::new(model.key(), "cpu".to_string(), sessions, 0))
+            .collect()
+    }
+
+    fn reset(&self) {
+        self.models.clear();
+        self.sessions.clear();
+    }
+}
+
+impl Default for WhisperFakerEndpoint {
+    fn default() -> Self {
+        Self {
+            models: Default::default(),
+            sessions: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canned_transcriptions() {
+        let hash = blake3::hash(b"hello").to_hex();
+        let fixture = parse_fixture(
+            format!("transcriptions:\n  {}: Hello world.\n", hash.as_str()).as_bytes(),
+        )
+        .unwrap();
+        let model = WhisperFakerModel { fixture };
+
+        assert_eq!(model.transcription(b"hello"), "Hello world.");
+        assert_eq!(model.transcription(b"goodbye"), DEFAULT_TRANSCRIPTION);
+    }
+}
@@ -0,0 +1,203 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Endpoints administering the server, specific to **Edgen**.
+//!
+//! Models can be pinned in memory, so that they are never unloaded for being inactive once
+//! loaded, either with the `pinned_models` setting or through [`pin_model`]. Pins are tracked
+//! per model file by [`edgen_core::resident`], so a model is pinned whenever it was requested by
+//! a pinned identifier.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use axum::response::IntoResponse;
+use axum::Json;
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use edgen_core::resident;
+use edgen_core::settings;
+
+use crate::model::Model;
+
+/// The pins set through [`pin_model`], by lowercase model identifier. These take precedence over
+/// the `pinned_models` setting.
+static PIN_OVERRIDES: Lazy<Mutex<HashMap<String, bool>>> = Lazy::new(Default::default);
+
+/// The identifiers each model file was requested with, so that pins apply to models that are
+/// already loaded.
+static MODEL_IDS: Lazy<Mutex<HashMap<PathBuf, Vec<String>>>> = Lazy::new(Default::default);
+
+/// Records that `model` was requested with `ids`, pinning it in memory if it is pinned under any
+/// of them.
+///
+/// This must be called once the model is preloaded, as its file is only known thereafter.
+pub async fn register_model(ids: &[&str], model: &Model) {
+    let Ok(path) = model.file_path() else {
+        return;
+    };
+
+    let ids = {
+        let mut model_ids = MODEL_IDS.lock().unwrap();
+        let known = model_ids.entry(path.clone()).or_default();
+        for id in ids {
+            let id = id.to_lowercase();
+            if !known.contains(&id) {
+                known.push(id);
+            }
+        }
+        known.clone()
+    };
+
+    resident::set_pinned(&path, is_pinned(&ids).await);
+}
+
+/// Returns `true` if a model is pinned under any of its lowercase `ids`.
+async fn is_pinned(ids: &[String]) -> bool {
+    let overridden = {
+        let overrides = PIN_OVERRIDES.lock().unwrap();
+        ids.iter().find_map(move |id| overrides.get(id).copied())
+    };
+    if let Some(pinned) = overridden {
+        return pinned;
+    }
+
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    settings::model_pinned(&ids).await
+}
+
+/// A request to pin, or unpin, a model in memory.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PinModelRequest {
+    /// The model, identified as it is requested, e.g. `owner/repo/model.gguf`, or by its file name.
+    pub model: String,
+
+    /// Whether the model is pinned. Defaults to `true`.
+    #[serde(default = "default_pinned")]
+    pub pinned: bool,
+}
+
+fn default_pinned() -> bool {
+    true
+}
+
+/// The response to a [`PinModelRequest`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ModelPin {
+    /// The model, as it was provided.
+    pub model: String,
+
+    /// Always `"model.pin"`.
+    pub object: String,
+
+    /// Whether the model is pinned.
+    pub pinned: bool,
+
+    /// The files of the models requested as `model` so far, which the pin applies to right away.
+    /// Any model requested as such later on is pinned as soon as it is loaded.
+    pub paths: Vec<String>,
+}
+
+/// POST `/v1/admin/models/pin`: pins, or unpins, a model in memory.
+///
+/// A pinned model is never unloaded for being inactive once loaded, which guarantees consistent
+/// latencies for the primary model of a deployment. This overrides the `pinned_models` setting
+/// until the server is restarted. This endpoint is specific to **Edgen**.
+#[utoipa::path(
+post,
+path = "/admin/models/pin",
+request_body = PinModelRequest,
+responses(
+(status = 200, description = "OK", body = ModelPin)
+),
+)]
+pub async fn pin_model(Json(req): Json<PinModelRequest>) -> impl IntoResponse {
+    let id = req.model.to_lowercase();
+    PIN_OVERRIDES.lock().unwrap().insert(id.clone(), req.pinned);
+
+    let affected: Vec<(PathBuf, Vec<String>)> = MODEL_IDS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, ids)| ids.contains(&id))
+        .map(move |(path, ids)| (path.clone(), ids.clone()))
+        .collect();
+
+    let mut paths = vec![];
+    for (path, ids) in affected {
+        resident::set_pinned(&path, is_pinned(&ids).await);
+        paths.push(path.to_string_lossy().to_string());
+    }
+    paths.sort();
+
+    Json(ModelPin {
+        model: req.model,
+        object: "model.pin".to_string(),
+        pinned: req.pinned,
+        paths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use edgen_core::settings::SETTINGS;
+
+    use crate::model::ModelKind;
+    use crate::types::Endpoint;
+
+    use super::*;
+
+    async fn init_settings_for_test() {
+        SETTINGS
+            .write()
+            .await
+            .init()
+            .await
+            .expect("Failed to initialise settings");
+    }
+
+    fn pin(model: &str, pinned: bool) -> Json<PinModelRequest> {
+        Json(PinModelRequest {
+            model: model.to_string(),
+            pinned,
+        })
+    }
+
+    #[tokio::test]
+    async fn pins() {
+        init_settings_for_test().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("pinned.gguf"), b"").unwrap();
+
+        let mut model = Model::new(
+            ModelKind::LLM,
+            "pinned.gguf",
+            "owner/repo",
+            &dir.path().to_path_buf(),
+        );
+        model.preload(Endpoint::ChatCompletions).await.unwrap();
+        register_model(&["owner/repo/pinned.gguf", "pinned.gguf"], &model).await;
+
+        let path = model.file_path().unwrap();
+        assert!(!resident::is_pinned(&path));
+
+        pin_model(pin("Owner/Repo/pinned.gguf", true)).await;
+        assert!(resident::is_pinned(&path));
+
+        pin_model(pin("owner/repo/pinned.gguf", false)).await;
+        assert!(!resident::is_pinned(&path));
+    }
+}
@@ -0,0 +1,1035 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A subset of the OpenAI Assistants API: threads, their messages and runs, persisted in the
+//! [`store`](crate::store).
+//!
+//! There are no assistant objects; runs name the chat completions model they use, and their
+//! instructions, directly. A run generates the next assistant message of its thread with the chat
+//! completions backend, in the background, and its progress is polled through its status.
+
+use std::borrow::Cow;
+
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use either::Either;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use edgen_core::llm::{ChatMessage, ChatMessages, CompletionArgs};
+use edgen_core::settings::{KeyModels, RequestCaps};
+
+use crate::openai_shim::{
+    exceeds, generate_chat_completion, request_caps, use_key_model, ChatCompletionError,
+};
+use crate::request_id;
+use crate::store::{self, now, StoreError, ThreadItem};
+
+/// An error condition raised by the assistants API.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum AssistantsError {
+    /// There is no thread with the provided ID.
+    #[error("no such thread: {thread_id}")]
+    NoSuchThread {
+        /// The ID of the thread.
+        thread_id: String,
+    },
+
+    /// There is no run with the provided ID in the thread.
+    #[error("no such run: {run_id}")]
+    NoSuchRun {
+        /// The ID of the run.
+        run_id: String,
+    },
+
+    /// A parameter of the request has an invalid value.
+    #[error("invalid value for parameter {param}: {reason}")]
+    InvalidParam {
+        /// The name of the parameter.
+        param: String,
+
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// The threads could not be accessed in the store.
+    #[error("the store failed: {reason}")]
+    Store {
+        /// A human-readable error message.
+        reason: String,
+    },
+}
+
+impl From<StoreError> for AssistantsError {
+    fn from(e: StoreError) -> Self {
+        AssistantsError::Store {
+            reason: e.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for AssistantsError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::from(e).into()
+    }
+}
+
+impl IntoResponse for AssistantsError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            AssistantsError::NoSuchThread { .. } | AssistantsError::NoSuchRun { .. } => {
+                StatusCode::NOT_FOUND
+            }
+            AssistantsError::InvalidParam { .. } => StatusCode::BAD_REQUEST,
+            AssistantsError::Store { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        request_id::error_response(status, &self)
+    }
+}
+
+/// A thread of messages.
+///
+/// See [the documentation of threads][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/threads/object
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Thread {
+    /// The ID of the thread.
+    pub id: String,
+
+    /// Always `"thread"`.
+    pub object: String,
+
+    /// The UNIX timestamp, in seconds, of when the thread was created.
+    pub created_at: u64,
+
+    /// Arbitrary metadata attached to the thread.
+    pub metadata: serde_json::Value,
+}
+
+/// A request to create a [`Thread`].
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct CreateThreadRequest {
+    /// The messages the thread starts with.
+    #[serde(default)]
+    pub messages: Vec<CreateMessageRequest>,
+
+    /// Arbitrary metadata attached to the thread.
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// A request to add a [`ThreadMessage`] to a thread.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateMessageRequest {
+    /// The role of the author of the message, either `user` or `assistant`.
+    pub role: String,
+
+    /// The text of the message.
+    pub content: String,
+
+    /// Arbitrary metadata attached to the message.
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// A message of a thread.
+///
+/// See [the documentation of messages][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/messages/object
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct ThreadMessage {
+    /// The ID of the message.
+    pub id: String,
+
+    /// Always `"thread.message"`.
+    pub object: String,
+
+    /// The UNIX timestamp, in seconds, of when the message was created.
+    pub created_at: u64,
+
+    /// The ID of the thread the message belongs to.
+    pub thread_id: String,
+
+    /// The role of the author of the message, either `user` or `assistant`.
+    pub role: String,
+
+    /// The content of the message.
+    pub content: Vec<MessageContent>,
+
+    /// The ID of the run that generated the message, if any.
+    pub run_id: Option<String>,
+
+    /// Arbitrary metadata attached to the message.
+    pub metadata: serde_json::Value,
+}
+
+/// A piece of the content of a [`ThreadMessage`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MessageContent {
+    /// Always `"text"`.
+    #[serde(rename = "type")]
+    pub type_: String,
+
+    /// The text of the content.
+    pub text: MessageText,
+}
+
+/// The text of a [`MessageContent`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct MessageText {
+    /// The text itself.
+    pub value: String,
+
+    /// Always empty.
+    pub annotations: Vec<serde_json::Value>,
+}
+
+/// The status of a [`Run`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RunStatus {
+    /// The run has not started yet.
+    Queued,
+
+    /// The run is generating the next message of its thread.
+    InProgress,
+
+    /// The run has added the generated message to its thread.
+    Completed,
+
+    /// The generation failed, as described by the `last_error` of the run.
+    Failed,
+}
+
+/// The error a [`Run`] failed with.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RunError {
+    /// Always `"server_error"`.
+    pub code: String,
+
+    /// A human-readable error message.
+    pub message: String,
+}
+
+/// An execution of a thread, generating its next assistant message.
+///
+/// See [the documentation of runs][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/runs/object
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Run {
+    /// The ID of the run.
+    pub id: String,
+
+    /// Always `"thread.run"`.
+    pub object: String,
+
+    /// The UNIX timestamp, in seconds, of when the run was created.
+    pub created_at: u64,
+
+    /// The ID of the thread the run belongs to.
+    pub thread_id: String,
+
+    /// The ID of the assistant of the run, as provided when creating it. Unused within **Edgen**.
+    pub assistant_id: Option<String>,
+
+    /// The chat completions model used by the run.
+    pub model: String,
+
+    /// The instructions given to the model as a system message, if any.
+    pub instructions: Option<String>,
+
+    /// The status of the run.
+    pub status: RunStatus,
+
+    /// The UNIX timestamp, in seconds, of when the run started.
+    pub started_at: Option<u64>,
+
+    /// The UNIX timestamp, in seconds, of when the run completed.
+    pub completed_at: Option<u64>,
+
+    /// The UNIX timestamp, in seconds, of when the run failed.
+    pub failed_at: Option<u64>,
+
+    /// The error the run failed with, if any.
+    pub last_error: Option<RunError>,
+
+    /// The sampling temperature used by the run.
+    pub temperature: Option<f32>,
+
+    /// The nucleus sampling probability used by the run.
+    pub top_p: Option<f32>,
+
+    /// The maximum number of tokens the run may generate.
+    pub max_completion_tokens: Option<u32>,
+}
+
+/// The API key a [`Run`] was created with, whose models and caps apply to its generation, so that
+/// a run cannot bypass them.
+///
+/// It is stored along with the run, but never returned by the API.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct RunKey {
+    /// The models of the key, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_models: Option<KeyModels>,
+
+    /// The caps of the key, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_caps: Option<RequestCaps>,
+}
+
+/// A run as stored, with the [`RunKey`] it was created with.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredRun<R> {
+    #[serde(flatten)]
+    run: R,
+
+    #[serde(flatten)]
+    key: RunKey,
+}
+
+/// A request to create a [`Run`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateRunRequest {
+    /// The ID of the assistant of the run. Unused within **Edgen**.
+    pub assistant_id: Option<String>,
+
+    /// The chat completions model to use, `default` by default.
+    pub model: Option<String>,
+
+    /// The instructions given to the model as a system message.
+    pub instructions: Option<String>,
+
+    /// The sampling temperature, in `[0.0, 2.0]`.
+    pub temperature: Option<f32>,
+
+    /// The nucleus sampling probability.
+    pub top_p: Option<f32>,
+
+    /// The maximum number of tokens to generate.
+    pub max_completion_tokens: Option<u32>,
+}
+
+/// The return type of [`list_messages`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MessageList {
+    /// Always `"list"`.
+    pub object: String,
+
+    /// The messages of the thread.
+    pub data: Vec<ThreadMessage>,
+}
+
+/// The return type of [`list_runs`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RunList {
+    /// Always `"list"`.
+    pub object: String,
+
+    /// The runs of the thread.
+    pub data: Vec<Run>,
+}
+
+/// The return type of [`delete_thread`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ThreadDeleted {
+    /// The ID of the thread.
+    pub id: String,
+
+    /// Always `"thread.deleted"`.
+    pub object: String,
+
+    /// **`true`** if the thread existed.
+    pub deleted: bool,
+}
+
+/// The order of the objects of a list.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ListOrder {
+    /// Oldest first.
+    Asc,
+
+    /// Newest first.
+    #[default]
+    Desc,
+}
+
+/// The query parameters of the list endpoints.
+#[derive(Debug, Default, Deserialize)]
+pub struct ListQuery {
+    /// The order of the objects, newest first by default.
+    #[serde(default)]
+    pub order: ListOrder,
+
+    /// The maximum number of objects to return, 20 by default.
+    pub limit: Option<usize>,
+}
+
+fn new_id(prefix: &str) -> String {
+    format!("{prefix}_{}", Uuid::new_v4().simple())
+}
+
+/// Sorts and truncates a list of objects, oldest first, as requested.
+fn paginate<T>(mut items: Vec<T>, query: &ListQuery) -> Vec<T> {
+    if let ListOrder::Desc = query.order {
+        items.reverse();
+    }
+    items.truncate(query.limit.unwrap_or(20));
+    items
+}
+
+async fn get_thread(thread_id: &str) -> Result<Thread, AssistantsError> {
+    match store::get()?.thread(thread_id).await? {
+        Some(thread) => Ok(serde_json::from_value(thread)?),
+        None => Err(AssistantsError::NoSuchThread {
+            thread_id: thread_id.to_string(),
+        }),
+    }
+}
+
+async fn put_message(message: &ThreadMessage) -> Result<(), AssistantsError> {
+    store::get()?
+        .put_thread_item(
+            ThreadItem::Message,
+            &message.thread_id,
+            &message.id,
+            &serde_json::to_value(message)?,
+        )
+        .await?;
+    Ok(())
+}
+
+async fn put_run(run: &Run, key: &RunKey) -> Result<(), AssistantsError> {
+    let stored = StoredRun { run, key };
+    store::get()?
+        .put_thread_item(
+            ThreadItem::Run,
+            &run.thread_id,
+            &run.id,
+            &serde_json::to_value(stored)?,
+        )
+        .await?;
+    Ok(())
+}
+
+async fn thread_messages(thread_id: &str) -> Result<Vec<ThreadMessage>, AssistantsError> {
+    store::get()?
+        .thread_items(ThreadItem::Message, thread_id)
+        .await?
+        .into_iter()
+        .map(move |message| Ok(serde_json::from_value(message)?))
+        .collect()
+}
+
+/// Creates a message from a request, checking that its role is valid.
+fn new_message(
+    thread_id: &str,
+    req: CreateMessageRequest,
+    run_id: Option<String>,
+) -> Result<ThreadMessage, AssistantsError> {
+    if req.role != "user" && req.role != "assistant" {
+        return Err(AssistantsError::InvalidParam {
+            param: "role".to_string(),
+            reason: format!(
+                "must be either \"user\" or \"assistant\", got \"{}\"",
+                req.role
+            ),
+        });
+    }
+
+    Ok(ThreadMessage {
+        id: new_id("msg"),
+        object: "thread.message".to_string(),
+        created_at: now(),
+        thread_id: thread_id.to_string(),
+        role: req.role,
+        content: vec![MessageContent {
+            type_: "text".to_string(),
+            text: MessageText {
+                value: req.content,
+                annotations: vec![],
+            },
+        }],
+        run_id,
+        metadata: req
+            .metadata
+            .unwrap_or_else(move || serde_json::Value::Object(Default::default())),
+    })
+}
+
+/// POST `/v1/threads`: creates a thread, optionally with some messages.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/threads/createThread
+///
+/// On failure, may raise a `400 Bad Request` or `500 Internal Server Error` with a JSON-encoded
+/// [`AssistantsError`] to the peer.
+#[utoipa::path(
+post,
+path = "/threads",
+request_body = CreateThreadRequest,
+responses(
+(status = 200, description = "OK", body = Thread),
+(status = 400, description = "invalid request", body = AssistantsError),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn create_thread(
+    req: Option<Json<CreateThreadRequest>>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    let Json(req) = req.unwrap_or_default();
+    let thread = Thread {
+        id: new_id("thread"),
+        object: "thread".to_string(),
+        created_at: now(),
+        metadata: req
+            .metadata
+            .unwrap_or_else(move || serde_json::Value::Object(Default::default())),
+    };
+
+    let messages = req
+        .messages
+        .into_iter()
+        .map(|message| new_message(&thread.id, message, None))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    store::get()?
+        .put_thread(&thread.id, &serde_json::to_value(&thread)?)
+        .await?;
+    for message in &messages {
+        put_message(message).await?;
+    }
+
+    Ok(Json(thread))
+}
+
+/// GET `/v1/threads/{thread_id}`: returns a thread.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/threads/getThread
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`AssistantsError`] to the peer.
+#[utoipa::path(
+get,
+path = "/threads/{thread_id}",
+params(("thread_id" = String, Path, description = "The ID of the thread")),
+responses(
+(status = 200, description = "OK", body = Thread),
+(status = 404, description = "no such thread", body = AssistantsError),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn retrieve_thread(
+    Path(thread_id): Path<String>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    Ok(Json(get_thread(&thread_id).await?))
+}
+
+/// DELETE `/v1/threads/{thread_id}`: deletes a thread, along with its messages and runs.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/threads/deleteThread
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`AssistantsError`]
+/// to the peer.
+#[utoipa::path(
+delete,
+path = "/threads/{thread_id}",
+params(("thread_id" = String, Path, description = "The ID of the thread")),
+responses(
+(status = 200, description = "OK", body = ThreadDeleted),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn delete_thread(
+    Path(thread_id): Path<String>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    let deleted = store::get()?.remove_thread(&thread_id).await?;
+
+    Ok(Json(ThreadDeleted {
+        id: thread_id,
+        object: "thread.deleted".to_string(),
+        deleted,
+    }))
+}
+
+/// POST `/v1/threads/{thread_id}/messages`: adds a message to a thread.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from only supporting text content.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/messages/createMessage
+///
+/// On failure, may raise a `400 Bad Request`, `404 Not Found` or `500 Internal Server Error` with
+/// a JSON-encoded [`AssistantsError`] to the peer.
+#[utoipa::path(
+post,
+path = "/threads/{thread_id}/messages",
+params(("thread_id" = String, Path, description = "The ID of the thread")),
+request_body = CreateMessageRequest,
+responses(
+(status = 200, description = "OK", body = ThreadMessage),
+(status = 400, description = "invalid request", body = AssistantsError),
+(status = 404, description = "no such thread", body = AssistantsError),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn create_message(
+    Path(thread_id): Path<String>,
+    Json(req): Json<CreateMessageRequest>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    get_thread(&thread_id).await?;
+    let message = new_message(&thread_id, req, None)?;
+    put_message(&message).await?;
+
+    Ok(Json(message))
+}
+
+/// GET `/v1/threads/{thread_id}/messages`: lists the messages of a thread.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from only supporting the `order` and `limit` parameters.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/messages/listMessages
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`AssistantsError`] to the peer.
+#[utoipa::path(
+get,
+path = "/threads/{thread_id}/messages",
+params(("thread_id" = String, Path, description = "The ID of the thread")),
+responses(
+(status = 200, description = "OK", body = MessageList),
+(status = 404, description = "no such thread", body = AssistantsError),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn list_messages(
+    Path(thread_id): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    get_thread(&thread_id).await?;
+    let messages = thread_messages(&thread_id).await?;
+
+    Ok(Json(MessageList {
+        object: "list".to_string(),
+        data: paginate(messages, &query),
+    }))
+}
+
+/// POST `/v1/threads/{thread_id}/runs`: creates a run, generating the next assistant message of
+/// the thread in the background.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from runs naming their model directly instead of an assistant.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/runs/createRun
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`AssistantsError`] to the peer.
+#[utoipa::path(
+post,
+path = "/threads/{thread_id}/runs",
+params(("thread_id" = String, Path, description = "The ID of the thread")),
+request_body = CreateRunRequest,
+responses(
+(status = 200, description = "OK", body = Run),
+(status = 400, description = "invalid parameter", body = AssistantsError),
+(status = 404, description = "no such thread", body = AssistantsError),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn create_run(
+    key_models: Option<Extension<KeyModels>>,
+    key_caps: Option<Extension<RequestCaps>>,
+    Path(thread_id): Path<String>,
+    Json(req): Json<CreateRunRequest>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    get_thread(&thread_id).await?;
+
+    let key = RunKey {
+        key_models: key_models.map(move |Extension(models)| models),
+        key_caps: key_caps.map(move |Extension(caps)| caps),
+    };
+    let caps = request_caps(key.key_caps.clone().map(Extension)).await;
+    if let Some(max) = exceeds(req.max_completion_tokens, caps.max_tokens) {
+        return Err(AssistantsError::InvalidParam {
+            param: "max_completion_tokens".to_string(),
+            reason: format!("must be at most {max}"),
+        });
+    }
+
+    let run = Run {
+        id: new_id("run"),
+        object: "thread.run".to_string(),
+        created_at: now(),
+        thread_id,
+        assistant_id: req.assistant_id,
+        model: req.model.unwrap_or_else(move || "default".to_string()),
+        instructions: req.instructions,
+        status: RunStatus::Queued,
+        started_at: None,
+        completed_at: None,
+        failed_at: None,
+        last_error: None,
+        temperature: req.temperature,
+        top_p: req.top_p,
+        max_completion_tokens: req.max_completion_tokens,
+    };
+    put_run(&run, &key).await?;
+    spawn_run(run.clone(), key);
+
+    Ok(Json(run))
+}
+
+/// Executes a run in the background.
+fn spawn_run(run: Run, key: RunKey) {
+    tokio::spawn(async move {
+        let id = run.id.clone();
+        if let Err(e) = execute_run(run, key).await {
+            warn!("Failed to update run {id}: {e}");
+        }
+    });
+}
+
+/// Executes again the runs interrupted by a restart of the server.
+pub async fn resume() {
+    let runs = match store::get() {
+        Ok(store) => store.all_thread_items(ThreadItem::Run).await,
+        Err(e) => Err(e),
+    };
+    let runs = match runs {
+        Ok(runs) => runs,
+        Err(e) => {
+            warn!("Failed to read the stored runs: {e}");
+            return;
+        }
+    };
+
+    for run in runs {
+        let StoredRun { run, key } = match serde_json::from_value::<StoredRun<Run>>(run) {
+            Ok(run) => run,
+            Err(e) => {
+                warn!("Ignoring an invalid stored run: {e}");
+                continue;
+            }
+        };
+        if !matches!(run.status, RunStatus::Queued | RunStatus::InProgress) {
+            continue;
+        }
+
+        info!("Resuming run {}", run.id);
+        spawn_run(run, key);
+    }
+}
+
+/// GET `/v1/threads/{thread_id}/runs`: lists the runs of a thread.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from only supporting the `order` and `limit` parameters.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/runs/listRuns
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`AssistantsError`] to the peer.
+#[utoipa::path(
+get,
+path = "/threads/{thread_id}/runs",
+params(("thread_id" = String, Path, description = "The ID of the thread")),
+responses(
+(status = 200, description = "OK", body = RunList),
+(status = 404, description = "no such thread", body = AssistantsError),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn list_runs(
+    Path(thread_id): Path<String>,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    get_thread(&thread_id).await?;
+    let runs = store::get()?
+        .thread_items(ThreadItem::Run, &thread_id)
+        .await?
+        .into_iter()
+        .map(move |run| Ok(serde_json::from_value(run)?))
+        .collect::<Result<Vec<Run>, AssistantsError>>()?;
+
+    Ok(Json(RunList {
+        object: "list".to_string(),
+        data: paginate(runs, &query),
+    }))
+}
+
+/// GET `/v1/threads/{thread_id}/runs/{run_id}`: returns a run.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/runs/getRun
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`AssistantsError`] to the peer.
+#[utoipa::path(
+get,
+path = "/threads/{thread_id}/runs/{run_id}",
+params(
+("thread_id" = String, Path, description = "The ID of the thread"),
+("run_id" = String, Path, description = "The ID of the run")
+),
+responses(
+(status = 200, description = "OK", body = Run),
+(status = 404, description = "no such thread or run", body = AssistantsError),
+(status = 500, description = "unexpected internal server error", body = AssistantsError)
+),
+)]
+pub async fn retrieve_run(
+    Path((thread_id, run_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AssistantsError> {
+    match store::get()?
+        .thread_item(ThreadItem::Run, &thread_id, &run_id)
+        .await?
+    {
+        Some(run) => Ok(Json(serde_json::from_value::<Run>(run)?)),
+        None => Err(AssistantsError::NoSuchRun { run_id }),
+    }
+}
+
+/// Generates the next assistant message of the thread of a run, with the models and caps of the
+/// API key it was created with, updating the status of the run as it goes.
+async fn execute_run(mut run: Run, key: RunKey) -> Result<(), AssistantsError> {
+    run.status = RunStatus::InProgress;
+    run.started_at = Some(now());
+    put_run(&run, &key).await?;
+
+    let mut model = Cow::Borrowed(run.model.as_str());
+    use_key_model(
+        &mut model,
+        key.key_models
+            .clone()
+            .and_then(move |models| models.chat_completions_model),
+    );
+    let caps = request_caps(key.key_caps.clone().map(Extension)).await;
+
+    let messages = thread_messages(&run.thread_id).await?;
+    let args = CompletionArgs {
+        messages: chat_messages(run.instructions.as_deref(), &messages),
+        frequency_penalty: None,
+        logit_bias: None,
+        max_tokens: run.max_completion_tokens.or(caps.max_tokens),
+        n: None,
+        presence_penalty: None,
+        seed: None,
+        stop: None,
+        temperature: run.temperature,
+        top_p: run.top_p,
+        min_p: None,
+        typical_p: None,
+        mirostat: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
+        one_shot: None,
+        context_hint: None,
+        cache_prompt: None,
+        cache_key: None,
+        create_session: false,
+        session: None,
+        token_log: None,
+        timings: None,
+        no_kv_offload: false,
+        max_context: None,
+        keep_system_prompt: false,
+    };
+
+    let result = match exceeds(run.max_completion_tokens, caps.max_tokens) {
+        Some(max) => Err(ChatCompletionError::ExceedsCap {
+            param: "max_completion_tokens".to_string(),
+            max: max.into(),
+        }),
+        None => generate_chat_completion(&model, args).await,
+    };
+    match result {
+        Ok(content) => {
+            let reply = CreateMessageRequest {
+                role: "assistant".to_string(),
+                content,
+                metadata: None,
+            };
+            put_message(&new_message(&run.thread_id, reply, Some(run.id.clone()))?).await?;
+            run.status = RunStatus::Completed;
+            run.completed_at = Some(now());
+        }
+        Err(e) => {
+            run.status = RunStatus::Failed;
+            run.failed_at = Some(now());
+            run.last_error = Some(RunError {
+                code: "server_error".to_string(),
+                message: e.to_string(),
+            });
+        }
+    }
+
+    put_run(&run, &key).await
+}
+
+/// Converts the messages of a thread to the context of a chat completion, preceded by the
+/// instructions of the run as a system message.
+fn chat_messages(instructions: Option<&str>, messages: &[ThreadMessage]) -> ChatMessages {
+    let mut chat = Vec::with_capacity(messages.len() + 1);
+    if let Some(instructions) = instructions {
+        chat.push(ChatMessage::System {
+            content: Some(instructions.to_string()),
+            name: None,
+        });
+    }
+
+    for message in messages {
+        let content: String = message
+            .content
+            .iter()
+            .map(move |content| content.text.value.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        chat.push(if message.role == "assistant" {
+            ChatMessage::Assistant {
+                content: Some(content),
+                name: None,
+                tool_calls: None,
+            }
+        } else {
+            ChatMessage::User {
+                content: Either::Left(content),
+                name: None,
+            }
+        });
+    }
+
+    ChatMessages(chat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: &str, content: &str) -> ThreadMessage {
+        new_message(
+            "thread",
+            CreateMessageRequest {
+                role: role.to_string(),
+                content: content.to_string(),
+                metadata: None,
+            },
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn thread_to_chat() {
+        let messages = [message("user", "hello"), message("assistant", "hi")];
+        let chat = chat_messages(Some("be brief"), &messages);
+
+        assert_eq!(chat.len(), 3);
+        assert!(
+            matches!(&chat[0], ChatMessage::System { content: Some(c), .. } if c == "be brief")
+        );
+        assert!(
+            matches!(&chat[1], ChatMessage::User { content: Either::Left(c), .. } if c == "hello")
+        );
+        assert!(matches!(&chat[2], ChatMessage::Assistant { content: Some(c), .. } if c == "hi"));
+    }
+
+    #[test]
+    fn invalid_role() {
+        let req = CreateMessageRequest {
+            role: "system".to_string(),
+            content: "hello".to_string(),
+            metadata: None,
+        };
+        assert!(matches!(
+            new_message("thread", req, None),
+            Err(AssistantsError::InvalidParam { .. })
+        ));
+    }
+
+    #[test]
+    fn pagination() {
+        let query = ListQuery {
+            order: ListOrder::Desc,
+            limit: Some(2),
+        };
+        assert_eq!(paginate(vec![1, 2, 3], &query), vec![3, 2]);
+        assert_eq!(
+            paginate(vec![1, 2, 3], &ListQuery::default()),
+            vec![3, 2, 1]
+        );
+
+        let query = ListQuery {
+            order: ListOrder::Asc,
+            limit: None,
+        };
+        assert_eq!(paginate(vec![1, 2, 3], &query), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn stored_key() {
+        let run = Run {
+            id: "run_1".to_string(),
+            object: "thread.run".to_string(),
+            created_at: 0,
+            thread_id: "thread".to_string(),
+            assistant_id: None,
+            model: "default".to_string(),
+            instructions: None,
+            status: RunStatus::Queued,
+            started_at: None,
+            completed_at: None,
+            failed_at: None,
+            last_error: None,
+            temperature: None,
+            top_p: None,
+            max_completion_tokens: None,
+        };
+        let key = RunKey {
+            key_models: Some(KeyModels {
+                chat_completions_model: Some("small".to_string()),
+                embeddings_model: None,
+            }),
+            key_caps: None,
+        };
+
+        let stored = serde_json::to_value(StoredRun { run: &run, key }).unwrap();
+        let StoredRun { run: loaded, key } =
+            serde_json::from_value::<StoredRun<Run>>(stored.clone()).unwrap();
+        assert_eq!(loaded.id, run.id);
+        assert_eq!(
+            key.key_models
+                .and_then(move |models| models.chat_completions_model),
+            Some("small".to_string())
+        );
+
+        // the key is dropped from the runs returned by the API
+        let public = serde_json::to_value(serde_json::from_value::<Run>(stored).unwrap()).unwrap();
+        assert!(public.get("key_models").is_none());
+    }
+}
@@ -0,0 +1,362 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A registry of the runtimes that serve each [`ModelKind`].
+//!
+//! Endpoints look up the backend of a model's kind here instead of matching on it, so adding a
+//! runtime only requires registering it in [`BACKENDS`], which crates embedding the server can also
+//! do to replace the backend of a kind with their own.
+
+use std::sync::{Arc, RwLock};
+
+use futures::Stream;
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+
+use edgen_core::embeddings::EmbeddingsArgs;
+use edgen_core::llm::{CompletionArgs, LLMEndpointError, PromptEstimate, Throughput};
+use edgen_core::resident::ResidentModel;
+use edgen_core::whisper::{Transcription, TranscriptionArgs, WhisperEndpointError};
+
+use crate::image_generation;
+use crate::model::{Model, ModelKind};
+use crate::util::StoppingStream;
+
+/// The registry of the backends built into the server.
+pub static BACKENDS: Lazy<BackendRegistry> = Lazy::new(move || {
+    let registry = BackendRegistry::default();
+    registry.register_chat(ModelKind::LLM, crate::llm::LlamaCppBackend);
+    registry.register_chat(ModelKind::CandleLLM, crate::llm_candle::CandleLLMBackend);
+    registry.register_chat(ModelKind::ChatFaker, crate::chat_faker::ChatFakerBackend);
+    registry.register_embeddings(ModelKind::Embeddings, crate::llm::LlamaCppEmbeddingsBackend);
+    // chat models can also generate embeddings, although dedicated models usually do it better
+    registry.register_embeddings(ModelKind::LLM, crate::llm::LlamaCppEmbeddingsBackend);
+    // fake whisper models usually also match the whisper patterns, so the faker is tried first
+    registry.register_transcription(
+        ModelKind::WhisperFaker,
+        crate::whisper_faker::WhisperFakerBackend,
+    );
+    registry.register_transcription(ModelKind::Whisper, crate::whisper::WhisperCppBackend);
+    registry
+});
+
+/// A stream of chat completion chunks, stopped at any of the chat template tags.
+pub type CompletionStream = StoppingStream<Box<dyn Stream<Item = String> + Unpin + Send>>;
+
+/// A runtime able to generate chat completions with the models of a [`ModelKind`].
+#[async_trait::async_trait]
+pub trait ChatBackend: Send + Sync {
+    /// Generates a complete chat completion.
+    async fn chat_completion(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<String, LLMEndpointError>;
+
+    /// Generates a chat completion, streaming its chunks as they get generated.
+    async fn chat_completion_stream(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<CompletionStream, LLMEndpointError>;
+
+    /// Renders and tokenizes the prompt of a chat completion, estimating its cost without
+    /// generating anything.
+    async fn estimate_prompt(
+        &self,
+        _model: Model,
+        _args: CompletionArgs,
+    ) -> Result<PromptEstimate, LLMEndpointError> {
+        Err(LLMEndpointError::UnsuitableEndpoint(
+            "prompts cannot be estimated by this backend".to_string(),
+        ))
+    }
+
+    /// Returns the models currently loaded into memory.
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        Vec::new()
+    }
+
+    /// Returns the live generation throughput of the backend.
+    fn throughput(&self) -> Throughput {
+        Throughput::default()
+    }
+
+    /// Unloads everything from memory.
+    async fn reset(&self);
+}
+
+/// A runtime able to generate embeddings with the models of a [`ModelKind`].
+#[async_trait::async_trait]
+pub trait EmbeddingsBackend: Send + Sync {
+    /// Generates an embedding for every input.
+    async fn embeddings(
+        &self,
+        model: Model,
+        args: EmbeddingsArgs,
+    ) -> Result<Vec<Vec<f32>>, LLMEndpointError>;
+
+    /// Returns the models currently loaded into memory.
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        Vec::new()
+    }
+
+    /// Unloads everything from memory.
+    async fn reset(&self);
+}
+
+/// A runtime able to transcribe audio with the models of a [`ModelKind`].
+#[async_trait::async_trait]
+pub trait TranscriptionBackend: Send + Sync {
+    /// Transcribes an audio segment, returning the transcription and the session it belongs to,
+    /// if any.
+    async fn transcription(
+        &self,
+        model: Model,
+        args: TranscriptionArgs,
+    ) -> Result<(Transcription, Option<Uuid>), WhisperEndpointError>;
+
+    /// Returns the models currently loaded into memory.
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        Vec::new()
+    }
+
+    /// Unloads everything from memory.
+    async fn reset(&self);
+}
+
+/// The backends registered for each [`ModelKind`], in registration order.
+///
+/// When a model matches several kinds, the kind registered first is preferred.
+#[derive(Default)]
+pub struct BackendRegistry {
+    chat: RwLock<Vec<(ModelKind, Arc<dyn ChatBackend>)>>,
+    embeddings: RwLock<Vec<(ModelKind, Arc<dyn EmbeddingsBackend>)>>,
+    transcription: RwLock<Vec<(ModelKind, Arc<dyn TranscriptionBackend>)>>,
+}
+
+impl BackendRegistry {
+    /// Registers the chat backend of a [`ModelKind`], replacing any previous one.
+    pub fn register_chat(&self, kind: ModelKind, backend: impl ChatBackend + 'static) {
+        register(&self.chat, kind, Arc::new(backend));
+    }
+
+    /// Registers the embeddings backend of a [`ModelKind`], replacing any previous one.
+    pub fn register_embeddings(&self, kind: ModelKind, backend: impl EmbeddingsBackend + 'static) {
+        register(&self.embeddings, kind, Arc::new(backend));
+    }
+
+    /// Registers the transcription backend of a [`ModelKind`], replacing any previous one.
+    pub fn register_transcription(
+        &self,
+        kind: ModelKind,
+        backend: impl TranscriptionBackend + 'static,
+    ) {
+        register(&self.transcription, kind, Arc::new(backend));
+    }
+
+    /// Returns the chat backend of a [`ModelKind`], if any.
+    pub fn chat(&self, kind: &ModelKind) -> Option<Arc<dyn ChatBackend>> {
+        find(&self.chat, kind)
+    }
+
+    /// Returns the embeddings backend of a [`ModelKind`], if any.
+    pub fn embeddings(&self, kind: &ModelKind) -> Option<Arc<dyn EmbeddingsBackend>> {
+        find(&self.embeddings, kind)
+    }
+
+    /// Returns the transcription backend of a [`ModelKind`], if any.
+    pub fn transcription(&self, kind: &ModelKind) -> Option<Arc<dyn TranscriptionBackend>> {
+        find(&self.transcription, kind)
+    }
+
+    /// Returns the kinds that have a chat backend, in order of preference.
+    pub fn chat_kinds(&self) -> Vec<ModelKind> {
+        kinds(&self.chat)
+    }
+
+    /// Returns the kinds that have an embeddings backend, in order of preference.
+    pub fn embeddings_kinds(&self) -> Vec<ModelKind> {
+        kinds(&self.embeddings)
+    }
+
+    /// Returns the kinds that have a transcription backend, in order of preference.
+    pub fn transcription_kinds(&self) -> Vec<ModelKind> {
+        kinds(&self.transcription)
+    }
+
+    /// Returns the models currently loaded into memory by every backend, and by the image
+    /// generation endpoint, with the kind of the backend that loaded them.
+    pub async fn resident_models(&self) -> Vec<(ModelKind, ResidentModel)> {
+        let mut resident = vec![];
+
+        let chat: Vec<_> = self.chat.read().unwrap().clone();
+        for (kind, backend) in chat {
+            for model in backend.resident_models().await {
+                resident.push((kind.clone(), model));
+            }
+        }
+
+        // the same runtime may be registered for several kinds, so its models are only listed
+        // under the first one
+        let embeddings: Vec<_> = self.embeddings.read().unwrap().clone();
+        let mut embedding_models: Vec<(ModelKind, ResidentModel)> = vec![];
+        for (kind, backend) in embeddings {
+            for model in backend.resident_models().await {
+                if !embedding_models.iter().any(|(_, m)| m.path == model.path) {
+                    embedding_models.push((kind.clone(), model));
+                }
+            }
+        }
+        resident.extend(embedding_models);
+
+        let transcription: Vec<_> = self.transcription.read().unwrap().clone();
+        for (kind, backend) in transcription {
+            for model in backend.resident_models().await {
+                resident.push((kind.clone(), model));
+            }
+        }
+
+        for model in image_generation::resident_models().await {
+            resident.push((ModelKind::StableDiffusion, model));
+        }
+
+        resident
+    }
+
+    /// Returns the live generation throughput of all the chat backends together.
+    pub fn chat_throughput(&self) -> Throughput {
+        let chat: Vec<_> = self.chat.read().unwrap().clone();
+        chat.iter()
+            .map(move |(_, backend)| backend.throughput())
+            .fold(Throughput::default(), move |total, throughput| Throughput {
+                tokens_per_second: total.tokens_per_second + throughput.tokens_per_second,
+                active_streams: total.active_streams + throughput.active_streams,
+            })
+    }
+
+    /// Unloads everything from memory in every backend.
+    pub async fn reset(&self) {
+        let chat: Vec<_> = self.chat.read().unwrap().clone();
+        for (_, backend) in chat {
+            backend.reset().await;
+        }
+
+        let embeddings: Vec<_> = self.embeddings.read().unwrap().clone();
+        for (_, backend) in embeddings {
+            backend.reset().await;
+        }
+
+        let transcription: Vec<_> = self.transcription.read().unwrap().clone();
+        for (_, backend) in transcription {
+            backend.reset().await;
+        }
+    }
+}
+
+fn register<T: ?Sized>(
+    backends: &RwLock<Vec<(ModelKind, Arc<T>)>>,
+    kind: ModelKind,
+    backend: Arc<T>,
+) {
+    let mut backends = backends.write().unwrap();
+    match backends.iter_mut().find(move |(k, _)| *k == kind) {
+        Some((_, registered)) => *registered = backend,
+        None => backends.push((kind, backend)),
+    }
+}
+
+fn find<T: ?Sized>(
+    backends: &RwLock<Vec<(ModelKind, Arc<T>)>>,
+    kind: &ModelKind,
+) -> Option<Arc<T>> {
+    backends
+        .read()
+        .unwrap()
+        .iter()
+        .find(move |(k, _)| k == kind)
+        .map(move |(_, backend)| backend.clone())
+}
+
+fn kinds<T: ?Sized>(backends: &RwLock<Vec<(ModelKind, Arc<T>)>>) -> Vec<ModelKind> {
+    backends
+        .read()
+        .unwrap()
+        .iter()
+        .map(move |(kind, _)| kind.clone())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dummy;
+
+    #[async_trait::async_trait]
+    impl ChatBackend for Dummy {
+        async fn chat_completion(
+            &self,
+            _model: Model,
+            _args: CompletionArgs,
+        ) -> Result<String, LLMEndpointError> {
+            Ok("dummy".to_string())
+        }
+
+        async fn chat_completion_stream(
+            &self,
+            _model: Model,
+            _args: CompletionArgs,
+        ) -> Result<CompletionStream, LLMEndpointError> {
+            Err(LLMEndpointError::Advance("dummy".to_string()))
+        }
+
+        async fn reset(&self) {}
+    }
+
+    #[test]
+    fn registration() {
+        let registry = BackendRegistry::default();
+        registry.register_chat(ModelKind::ChatFaker, Dummy);
+        registry.register_chat(ModelKind::LLM, Dummy);
+        assert_eq!(
+            registry.chat_kinds(),
+            [ModelKind::ChatFaker, ModelKind::LLM]
+        );
+        assert!(registry.embeddings_kinds().is_empty());
+        assert!(registry.chat(&ModelKind::LLM).is_some());
+        assert!(registry.chat(&ModelKind::Whisper).is_none());
+
+        // registering a kind again replaces its backend, keeping its position
+        registry.register_chat(ModelKind::ChatFaker, Dummy);
+        assert_eq!(
+            registry.chat_kinds(),
+            [ModelKind::ChatFaker, ModelKind::LLM]
+        );
+    }
+
+    #[test]
+    fn builtin_kinds() {
+        assert_eq!(
+            BACKENDS.chat_kinds(),
+            [ModelKind::LLM, ModelKind::CandleLLM, ModelKind::ChatFaker]
+        );
+        assert_eq!(
+            BACKENDS.embeddings_kinds(),
+            [ModelKind::Embeddings, ModelKind::LLM]
+        );
+        assert_eq!(
+            BACKENDS.transcription_kinds(),
+            [ModelKind::WhisperFaker, ModelKind::Whisper]
+        );
+    }
+}
@@ -0,0 +1,887 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The batch API: bulk chat completions and embeddings requests, read from a JSONL
+//! [file](crate::files) and processed in the background, with their results written to an output
+//! file.
+//!
+//! Batches run one at a time, one request at a time, and only while no other generation is
+//! running, so that they never delay interactive requests. Batches interrupted by a restart are
+//! processed again from the start.
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{Extension, Json};
+use once_cell::sync::Lazy;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use edgen_core::settings::{KeyModels, RequestCaps};
+
+use crate::backends::BACKENDS;
+use crate::files::{self, FileError, FilePurpose};
+use crate::listener::KeyOwner;
+use crate::openai_shim::{self, CreateChatCompletionRequest, CreateEmbeddingsRequest};
+use crate::request_id;
+use crate::store::{self, now, StoreError};
+
+/// How often a batch checks whether the server is idle before sending its next request.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Held by the batch being processed, so that batches run one at a time.
+static RUNNING: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+/// The IDs of the batches asked to be cancelled and not yet stopped.
+static CANCELLING: Lazy<std::sync::Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// The endpoints the requests of a batch can be sent to.
+const ENDPOINTS: &[&str] = &["/v1/chat/completions", "/v1/embeddings"];
+
+/// An error condition raised by the batch API.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum BatchError {
+    /// There is no batch with the provided ID.
+    #[error("no such batch: {batch_id}")]
+    NoSuchBatch {
+        /// The ID of the batch.
+        batch_id: String,
+    },
+
+    /// A parameter of the request has an invalid value.
+    #[error("invalid value for parameter {param}: {reason}")]
+    InvalidParam {
+        /// The name of the parameter.
+        param: String,
+
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// An error raised by the files the batch reads and writes.
+    #[error(transparent)]
+    File(#[from] FileError),
+
+    /// The batch could not be stored.
+    #[error("the batch could not be stored: {reason}")]
+    Store {
+        /// A human-readable error message.
+        reason: String,
+    },
+}
+
+impl From<StoreError> for BatchError {
+    fn from(e: StoreError) -> Self {
+        BatchError::Store {
+            reason: e.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for BatchError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::from(e).into()
+    }
+}
+
+impl IntoResponse for BatchError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            BatchError::NoSuchBatch { .. } => StatusCode::NOT_FOUND,
+            BatchError::InvalidParam { .. } => StatusCode::BAD_REQUEST,
+            BatchError::File(FileError::NoSuchFile { .. })
+            | BatchError::File(FileError::InvalidParam { .. }) => StatusCode::BAD_REQUEST,
+            BatchError::File(FileError::Storage { .. }) | BatchError::Store { .. } => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        request_id::error_response(status, &self)
+    }
+}
+
+/// The status of a [`Batch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    /// The input file is being validated.
+    Validating,
+
+    /// The input file is invalid.
+    Failed,
+
+    /// The requests of the batch are being processed.
+    InProgress,
+
+    /// Every request of the batch has been processed.
+    Completed,
+
+    /// The batch is being cancelled, once its current request completes.
+    Cancelling,
+
+    /// The batch was cancelled.
+    Cancelled,
+}
+
+impl BatchStatus {
+    /// Returns **`true`** if a batch with this status will not be processed any further.
+    fn is_final(self) -> bool {
+        matches!(
+            self,
+            BatchStatus::Failed | BatchStatus::Completed | BatchStatus::Cancelled
+        )
+    }
+}
+
+/// The number of requests of a [`Batch`], by outcome.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct BatchRequestCounts {
+    /// The number of requests of the batch.
+    pub total: usize,
+
+    /// The number of requests that completed successfully.
+    pub completed: usize,
+
+    /// The number of requests that failed.
+    pub failed: usize,
+}
+
+/// An error found validating the input file of a [`Batch`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BatchLineError {
+    /// A machine-readable error code.
+    pub code: String,
+
+    /// A human-readable error message.
+    pub message: String,
+
+    /// The line of the input file the error was found at, starting from 1.
+    pub line: Option<usize>,
+}
+
+/// A batch of requests.
+///
+/// See [the documentation of batches][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/batch/object
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Batch {
+    /// The ID of the batch.
+    pub id: String,
+
+    /// Always `"batch"`.
+    pub object: String,
+
+    /// The endpoint every request of the batch is sent to.
+    pub endpoint: String,
+
+    /// The errors found validating the input file, if any.
+    pub errors: Option<Vec<BatchLineError>>,
+
+    /// The ID of the input file.
+    pub input_file_id: String,
+
+    /// The time frame the batch should be processed in. Only `24h` is accepted, but it isn't
+    /// enforced.
+    pub completion_window: String,
+
+    /// The status of the batch.
+    pub status: BatchStatus,
+
+    /// The ID of the file with the results of the successful requests.
+    pub output_file_id: Option<String>,
+
+    /// The ID of the file with the results of the failed requests.
+    pub error_file_id: Option<String>,
+
+    /// The UNIX timestamp, in seconds, of when the batch was created.
+    pub created_at: u64,
+
+    /// The UNIX timestamp, in seconds, of when the batch started being processed.
+    pub in_progress_at: Option<u64>,
+
+    /// The UNIX timestamp, in seconds, of when the batch completed.
+    pub completed_at: Option<u64>,
+
+    /// The UNIX timestamp, in seconds, of when the batch failed.
+    pub failed_at: Option<u64>,
+
+    /// The UNIX timestamp, in seconds, of when the batch started being cancelled.
+    pub cancelling_at: Option<u64>,
+
+    /// The UNIX timestamp, in seconds, of when the batch was cancelled.
+    pub cancelled_at: Option<u64>,
+
+    /// The number of requests of the batch, by outcome.
+    pub request_counts: BatchRequestCounts,
+
+    /// Arbitrary metadata attached to the batch.
+    #[schema(value_type = Object)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// The API key a [`Batch`] was created with, whose models and caps apply to each of its requests,
+/// so that a batch cannot bypass them.
+///
+/// It is stored along with the batch, but never returned by the API.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct BatchKey {
+    /// The models of the key, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_models: Option<KeyModels>,
+
+    /// The caps of the key, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_caps: Option<RequestCaps>,
+
+    /// The owner of the conversations continued by the requests, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_owner: Option<KeyOwner>,
+}
+
+/// A batch as stored, with the [`BatchKey`] it was created with.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredBatch<B> {
+    #[serde(flatten)]
+    batch: B,
+
+    #[serde(flatten)]
+    key: BatchKey,
+}
+
+/// A request to create a batch.
+///
+/// An `axum` handler, [`create_batch`][create_batch], is provided to handle this request.
+///
+/// [create_batch]: fn.create_batch.html
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateBatchRequest {
+    /// The ID of the JSONL file with the requests, uploaded with the `batch` purpose.
+    pub input_file_id: String,
+
+    /// The endpoint every request is sent to, either `/v1/chat/completions` or `/v1/embeddings`.
+    pub endpoint: String,
+
+    /// The time frame the batch should be processed in. Must be `24h`.
+    pub completion_window: String,
+
+    /// Arbitrary metadata attached to the batch.
+    #[schema(value_type = Object)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// The return type of [`list_batches`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct BatchList {
+    /// Always `"list"`.
+    pub object: String,
+
+    /// The batches.
+    pub data: Vec<Batch>,
+}
+
+/// A line of the input file of a batch.
+#[derive(Debug, Deserialize)]
+struct InputLine {
+    /// An ID chosen by the client, to match the results with the requests.
+    custom_id: String,
+
+    /// Always `POST`.
+    method: String,
+
+    /// The endpoint of the request, which must be the endpoint of the batch.
+    url: String,
+
+    /// The body of the request.
+    body: serde_json::Value,
+}
+
+/// A line of the output and error files of a batch.
+#[derive(Debug, Serialize)]
+struct OutputLine {
+    id: String,
+    custom_id: String,
+    response: Option<OutputResponse>,
+    error: Option<OutputError>,
+}
+
+#[derive(Debug, Serialize)]
+struct OutputResponse {
+    status_code: u16,
+    request_id: String,
+    body: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct OutputError {
+    code: String,
+    message: String,
+}
+
+/// Parses and validates the input file of a batch, returning the requests it holds or the errors
+/// found.
+fn parse_input(contents: &[u8], endpoint: &str) -> Result<Vec<InputLine>, Vec<BatchLineError>> {
+    let mut lines = vec![];
+    let mut errors = vec![];
+
+    let contents = String::from_utf8_lossy(contents);
+    for (index, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let error = move |code: &str, message: String| BatchLineError {
+            code: code.to_string(),
+            message,
+            line: Some(index + 1),
+        };
+        match serde_json::from_str::<InputLine>(line) {
+            Ok(input) if input.method != "POST" => errors.push(error(
+                "invalid_method",
+                format!("the method must be POST, got {}", input.method),
+            )),
+            Ok(input) if input.url != endpoint => errors.push(error(
+                "mismatched_url",
+                format!("the url must be {endpoint}, got {}", input.url),
+            )),
+            Ok(input) => lines.push(input),
+            Err(e) => errors.push(error("invalid_json_line", e.to_string())),
+        }
+    }
+
+    if lines.is_empty() && errors.is_empty() {
+        errors.push(BatchLineError {
+            code: "empty_file".to_string(),
+            message: "the input file has no requests".to_string(),
+            line: None,
+        });
+    }
+
+    if errors.is_empty() {
+        Ok(lines)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Sends a request of a batch to its endpoint with the models and caps of its key, returning the
+/// status and the body of the response.
+async fn send(
+    endpoint: &str,
+    body: &serde_json::Value,
+    key: &BatchKey,
+) -> (StatusCode, serde_json::Value) {
+    let key_models = key.key_models.clone().map(Extension);
+    // the requests borrow from their bodies, so decode them from their text
+    let text = body.to_string();
+    let response = match endpoint {
+        "/v1/chat/completions" => {
+            match serde_json::from_str::<CreateChatCompletionRequest>(&text) {
+                Ok(mut req) => {
+                    req.stream = Some(false);
+                    let key_caps = key.key_caps.clone().map(Extension);
+                    let key_owner = key.key_owner.clone().map(Extension);
+                    openai_shim::chat_completions(key_models, key_caps, key_owner, Json(req))
+                        .await
+                        .into_response()
+                }
+                Err(e) => return invalid_body(e),
+            }
+        }
+        "/v1/embeddings" => match serde_json::from_str::<CreateEmbeddingsRequest>(&text) {
+            Ok(req) => openai_shim::create_embeddings(key_models, Json(req))
+                .await
+                .into_response(),
+            Err(e) => return invalid_body(e),
+        },
+        _ => unreachable!("the endpoint of a batch is validated when it is created"),
+    };
+
+    let status = response.status();
+    let body = match axum::body::to_bytes(response.into_body(), usize::MAX).await {
+        Ok(body) => serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null),
+        Err(e) => {
+            warn!("Failed to read the response to a batch request: {e}");
+            serde_json::Value::Null
+        }
+    };
+
+    (status, body)
+}
+
+fn invalid_body(e: serde_json::Error) -> (StatusCode, serde_json::Value) {
+    (
+        StatusCode::BAD_REQUEST,
+        serde_json::json!({
+            "error": "invalid_body",
+            "reason": e.to_string(),
+        }),
+    )
+}
+
+/// Waits until no generation is running.
+async fn wait_for_idle() {
+    loop {
+        let busy = BACKENDS
+            .resident_models()
+            .await
+            .iter()
+            .any(move |(_, model)| model.in_flight > 0);
+        if !busy {
+            return;
+        }
+        tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+    }
+}
+
+async fn put_batch(batch: &Batch, key: &BatchKey) -> Result<(), BatchError> {
+    let stored = StoredBatch {
+        batch,
+        key: key.clone(),
+    };
+    Ok(store::get()?
+        .put_batch(&batch.id, &serde_json::to_value(stored)?)
+        .await?)
+}
+
+async fn get_batch(batch_id: &str) -> Result<StoredBatch<Batch>, BatchError> {
+    match store::get()?.batch(batch_id).await? {
+        Some(batch) => Ok(serde_json::from_value(batch)?),
+        None => Err(BatchError::NoSuchBatch {
+            batch_id: batch_id.to_string(),
+        }),
+    }
+}
+
+/// Returns **`true`** if the batch has been asked to be cancelled.
+fn cancel_requested(batch_id: &str) -> bool {
+    CANCELLING
+        .lock()
+        .unwrap_or_else(move |e| e.into_inner())
+        .contains(batch_id)
+}
+
+/// Marks a batch as cancelled, after its last request has completed.
+async fn finish_cancel(batch: &mut Batch, key: &BatchKey) -> Result<(), BatchError> {
+    CANCELLING
+        .lock()
+        .unwrap_or_else(move |e| e.into_inner())
+        .remove(&batch.id);
+
+    batch.status = BatchStatus::Cancelled;
+    batch.cancelling_at = batch.cancelling_at.or(Some(now()));
+    batch.cancelled_at = Some(now());
+    put_batch(batch, key).await
+}
+
+/// Encodes the lines of an output file.
+fn jsonl(lines: &[OutputLine]) -> Result<Vec<u8>, BatchError> {
+    let mut contents = vec![];
+    for line in lines {
+        serde_json::to_writer(&mut contents, line)?;
+        contents.push(b'\n');
+    }
+    Ok(contents)
+}
+
+/// Processes a batch, once no other batch is running.
+async fn execute_batch(mut batch: Batch, key: BatchKey) -> Result<(), BatchError> {
+    let _running = RUNNING.lock().await;
+    if cancel_requested(&batch.id) {
+        return finish_cancel(&mut batch, &key).await;
+    }
+
+    let contents = match files::file_contents(&batch.input_file_id).await {
+        Ok(contents) => contents,
+        Err(e) => {
+            batch.status = BatchStatus::Failed;
+            batch.failed_at = Some(now());
+            batch.errors = Some(vec![BatchLineError {
+                code: "invalid_input_file".to_string(),
+                message: e.to_string(),
+                line: None,
+            }]);
+            return put_batch(&batch, &key).await;
+        }
+    };
+    let lines = match parse_input(&contents, &batch.endpoint) {
+        Ok(lines) => lines,
+        Err(errors) => {
+            batch.status = BatchStatus::Failed;
+            batch.failed_at = Some(now());
+            batch.errors = Some(errors);
+            return put_batch(&batch, &key).await;
+        }
+    };
+
+    info!("Processing batch {} of {} requests", batch.id, lines.len());
+    batch.status = BatchStatus::InProgress;
+    batch.in_progress_at = Some(now());
+    batch.request_counts = BatchRequestCounts {
+        total: lines.len(),
+        ..Default::default()
+    };
+    put_batch(&batch, &key).await?;
+
+    let mut outputs = vec![];
+    let mut errors = vec![];
+    for line in lines {
+        wait_for_idle().await;
+        if cancel_requested(&batch.id) {
+            break;
+        }
+
+        let (status, body) = send(&batch.endpoint, &line.body, &key).await;
+        let output = OutputLine {
+            id: format!("batch_req_{}", Uuid::new_v4().simple()),
+            custom_id: line.custom_id,
+            response: Some(OutputResponse {
+                status_code: status.as_u16(),
+                request_id: format!("req_{}", Uuid::new_v4().simple()),
+                body,
+            }),
+            error: None,
+        };
+        if status.is_success() {
+            batch.request_counts.completed += 1;
+            outputs.push(output);
+        } else {
+            batch.request_counts.failed += 1;
+            errors.push(output);
+        }
+        if cancel_requested(&batch.id) {
+            batch.status = BatchStatus::Cancelling;
+            batch.cancelling_at.get_or_insert_with(now);
+        }
+        put_batch(&batch, &key).await?;
+    }
+
+    if !outputs.is_empty() {
+        let filename = format!("{}_output.jsonl", batch.id);
+        let file =
+            files::store_file(&filename, FilePurpose::BatchOutput, &jsonl(&outputs)?).await?;
+        batch.output_file_id = Some(file.id);
+    }
+    if !errors.is_empty() {
+        let filename = format!("{}_error.jsonl", batch.id);
+        let file = files::store_file(&filename, FilePurpose::BatchOutput, &jsonl(&errors)?).await?;
+        batch.error_file_id = Some(file.id);
+    }
+
+    info!(
+        "Batch {} done: {} requests completed, {} failed",
+        batch.id, batch.request_counts.completed, batch.request_counts.failed
+    );
+    if cancel_requested(&batch.id) {
+        return finish_cancel(&mut batch, &key).await;
+    }
+
+    batch.status = BatchStatus::Completed;
+    batch.completed_at = Some(now());
+    put_batch(&batch, &key).await
+}
+
+fn spawn_batch(batch: Batch, key: BatchKey) {
+    tokio::spawn(async move {
+        let id = batch.id.clone();
+        if let Err(e) = execute_batch(batch, key).await {
+            warn!("Failed to process batch {id}: {e}");
+        }
+    });
+}
+
+/// Processes again the batches interrupted by a restart of the server.
+pub async fn resume() {
+    let batches = match store::get() {
+        Ok(store) => store.batches().await,
+        Err(e) => Err(e),
+    };
+    let batches = match batches {
+        Ok(batches) => batches,
+        Err(e) => {
+            warn!("Failed to read the stored batches: {e}");
+            return;
+        }
+    };
+
+    for batch in batches {
+        let StoredBatch { mut batch, key } = match serde_json::from_value(batch) {
+            Ok(batch) => batch,
+            Err(e) => {
+                warn!("Ignoring an invalid stored batch: {e}");
+                continue;
+            }
+        };
+        if batch.status.is_final() {
+            continue;
+        }
+
+        if batch.status == BatchStatus::Cancelling {
+            batch.status = BatchStatus::Cancelled;
+            batch.cancelled_at = Some(now());
+            if let Err(e) = put_batch(&batch, &key).await {
+                warn!("Failed to cancel batch {}: {e}", batch.id);
+            }
+            continue;
+        }
+
+        info!("Resuming batch {}", batch.id);
+        batch.status = BatchStatus::Validating;
+        batch.request_counts = BatchRequestCounts::default();
+        spawn_batch(batch, key);
+    }
+}
+
+/// POST `/v1/batches`: creates a batch of requests, processed in the background.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/batch/create
+///
+/// On failure, may raise a `400 Bad Request` or `500 Internal Server Error` with a JSON-encoded
+/// [`BatchError`] to the peer.
+#[utoipa::path(
+post,
+path = "/batches",
+request_body = CreateBatchRequest,
+responses(
+(status = 200, description = "OK", body = Batch),
+(status = 400, description = "invalid request", body = BatchError),
+(status = 500, description = "unexpected internal server error", body = BatchError)
+),
+)]
+pub async fn create_batch(
+    key_models: Option<Extension<KeyModels>>,
+    key_caps: Option<Extension<RequestCaps>>,
+    key_owner: Option<Extension<KeyOwner>>,
+    Json(req): Json<CreateBatchRequest>,
+) -> Result<impl IntoResponse, BatchError> {
+    if !ENDPOINTS.contains(&req.endpoint.as_str()) {
+        return Err(BatchError::InvalidParam {
+            param: "endpoint".to_string(),
+            reason: format!(
+                "must be one of {}, got \"{}\"",
+                ENDPOINTS.join(", "),
+                req.endpoint
+            ),
+        });
+    }
+    if req.completion_window != "24h" {
+        return Err(BatchError::InvalidParam {
+            param: "completion_window".to_string(),
+            reason: format!("must be \"24h\", got \"{}\"", req.completion_window),
+        });
+    }
+    let input = files::get_file(&req.input_file_id).await?;
+    if input.purpose != FilePurpose::Batch {
+        return Err(BatchError::InvalidParam {
+            param: "input_file_id".to_string(),
+            reason: "the file must have been uploaded with the \"batch\" purpose".to_string(),
+        });
+    }
+
+    let batch = Batch {
+        id: format!("batch_{}", Uuid::new_v4().simple()),
+        object: "batch".to_string(),
+        endpoint: req.endpoint,
+        errors: None,
+        input_file_id: req.input_file_id,
+        completion_window: req.completion_window,
+        status: BatchStatus::Validating,
+        output_file_id: None,
+        error_file_id: None,
+        created_at: now(),
+        in_progress_at: None,
+        completed_at: None,
+        failed_at: None,
+        cancelling_at: None,
+        cancelled_at: None,
+        request_counts: BatchRequestCounts::default(),
+        metadata: req.metadata,
+    };
+    let key = BatchKey {
+        key_models: key_models.map(move |Extension(models)| models),
+        key_caps: key_caps.map(move |Extension(caps)| caps),
+        key_owner: key_owner.map(move |Extension(owner)| owner),
+    };
+    put_batch(&batch, &key).await?;
+    spawn_batch(batch.clone(), key);
+
+    Ok(Json(batch))
+}
+
+/// GET `/v1/batches`: lists the batches, oldest first.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/batch/list
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`BatchError`] to the
+/// peer.
+#[utoipa::path(
+get,
+path = "/batches",
+responses(
+(status = 200, description = "OK", body = BatchList),
+(status = 500, description = "unexpected internal server error", body = BatchError)
+),
+)]
+pub async fn list_batches() -> Result<impl IntoResponse, BatchError> {
+    let batches = store::get()?
+        .batches()
+        .await?
+        .into_iter()
+        .map(move |batch| Ok(serde_json::from_value(batch)?))
+        .collect::<Result<_, BatchError>>()?;
+
+    Ok(Json(BatchList {
+        object: "list".to_string(),
+        data: batches,
+    }))
+}
+
+/// GET `/v1/batches/{batch_id}`: returns a batch.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/batch/retrieve
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`BatchError`] to the peer.
+#[utoipa::path(
+get,
+path = "/batches/{batch_id}",
+params(("batch_id" = String, Path, description = "The ID of the batch")),
+responses(
+(status = 200, description = "OK", body = Batch),
+(status = 404, description = "no such batch", body = BatchError),
+(status = 500, description = "unexpected internal server error", body = BatchError)
+),
+)]
+pub async fn retrieve_batch(Path(batch_id): Path<String>) -> Result<impl IntoResponse, BatchError> {
+    Ok(Json(get_batch(&batch_id).await?.batch))
+}
+
+/// POST `/v1/batches/{batch_id}/cancel`: cancels a batch once its current request completes.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/batch/cancel
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`BatchError`] to the peer.
+#[utoipa::path(
+post,
+path = "/batches/{batch_id}/cancel",
+params(("batch_id" = String, Path, description = "The ID of the batch")),
+responses(
+(status = 200, description = "OK", body = Batch),
+(status = 404, description = "no such batch", body = BatchError),
+(status = 500, description = "unexpected internal server error", body = BatchError)
+),
+)]
+pub async fn cancel_batch(Path(batch_id): Path<String>) -> Result<impl IntoResponse, BatchError> {
+    let StoredBatch { mut batch, key } = get_batch(&batch_id).await?;
+    if !batch.status.is_final() && batch.status != BatchStatus::Cancelling {
+        CANCELLING
+            .lock()
+            .unwrap_or_else(move |e| e.into_inner())
+            .insert(batch_id);
+        batch.status = BatchStatus::Cancelling;
+        batch.cancelling_at = Some(now());
+        put_batch(&batch, &key).await?;
+    }
+
+    Ok(Json(batch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_validation() {
+        let input = concat!(
+            r#"{"custom_id": "a", "method": "POST", "url": "/v1/embeddings", "#,
+            r#""body": {"model": "default", "input": "hello"}}"#,
+            "\n\n",
+            r#"{"custom_id": "b", "method": "POST", "url": "/v1/embeddings", "#,
+            r#""body": {"model": "default", "input": "world"}}"#,
+            "\n",
+        )
+        .as_bytes();
+        let lines = parse_input(input, "/v1/embeddings").unwrap();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].custom_id, "b");
+
+        let errors = parse_input(input, "/v1/chat/completions").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].code, "mismatched_url");
+        assert_eq!(errors[1].line, Some(3));
+
+        let errors = parse_input(b"not json\n", "/v1/embeddings").unwrap_err();
+        assert_eq!(errors[0].code, "invalid_json_line");
+
+        let errors = parse_input(b"\n", "/v1/embeddings").unwrap_err();
+        assert_eq!(errors[0].code, "empty_file");
+    }
+
+    #[test]
+    fn stored_key() {
+        let batch = Batch {
+            id: "batch_test".to_string(),
+            object: "batch".to_string(),
+            endpoint: "/v1/embeddings".to_string(),
+            errors: None,
+            input_file_id: "file_test".to_string(),
+            completion_window: "24h".to_string(),
+            status: BatchStatus::Validating,
+            output_file_id: None,
+            error_file_id: None,
+            created_at: 0,
+            in_progress_at: None,
+            completed_at: None,
+            failed_at: None,
+            cancelling_at: None,
+            cancelled_at: None,
+            request_counts: BatchRequestCounts::default(),
+            metadata: None,
+        };
+        let key = BatchKey {
+            key_models: None,
+            key_caps: Some(RequestCaps {
+                max_tokens: Some(64),
+                ..Default::default()
+            }),
+            key_owner: None,
+        };
+
+        let stored = serde_json::to_value(StoredBatch { batch: &batch, key }).unwrap();
+        let StoredBatch { batch: loaded, key } =
+            serde_json::from_value::<StoredBatch<Batch>>(stored.clone()).unwrap();
+        assert_eq!(loaded.id, batch.id);
+        assert_eq!(key.key_caps.and_then(move |caps| caps.max_tokens), Some(64));
+
+        // the key is dropped from the batches returned by the API
+        let public =
+            serde_json::to_value(serde_json::from_value::<Batch>(stored).unwrap()).unwrap();
+        assert!(public.get("key_caps").is_none());
+    }
+}
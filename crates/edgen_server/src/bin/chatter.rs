@@ -209,6 +209,21 @@ async fn chain_requests(
         user: None,
         one_shot: None,
         context_hint: None,
+        min_p: None,
+        typical_p: None,
+        mirostat: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
+        sampler_profile: None,
+        include_tokens: None,
+        coalesce_ms: None,
+        coalesce_tokens: None,
+        cache_prompt: None,
+        cache_key: None,
+        create_session: None,
+        session: None,
+        conversation_id: None,
+        retrieval: None,
     };
 
     body.messages.push(ChatMessage::System {
@@ -12,71 +12,68 @@
 
 //! Endpoint for the chat faker model RT
 
-use futures::Stream;
 use once_cell::sync::Lazy;
 
 use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError};
+use edgen_core::resident::ResidentModel;
 use edgen_rt_chat_faker::ChatFakerEndpoint;
 
+use crate::backends::{ChatBackend, CompletionStream};
 use crate::model::Model;
 use crate::util::StoppingStream;
 
 static ENDPOINT: Lazy<ChatFakerEndpoint> = Lazy::new(Default::default);
 
-pub async fn chat_completion(
-    model: Model,
-    args: CompletionArgs,
-) -> Result<String, LLMEndpointError> {
-    ENDPOINT
-        .chat_completions(
-            model
-                .file_path()
-                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            args,
-        )
-        .await
-}
+/// The [`ChatBackend`] answering with canned responses, for testing.
+pub struct ChatFakerBackend;
 
-pub async fn chat_completion_stream(
-    model: Model,
-    args: CompletionArgs,
-) -> Result<StoppingStream<Box<dyn Stream<Item = String> + Unpin + Send>>, LLMEndpointError> {
-    let stream = ENDPOINT
-        .stream_chat_completions(
-            model
-                .file_path()
-                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            args,
-        )
-        .await?;
+#[async_trait::async_trait]
+impl ChatBackend for ChatFakerBackend {
+    async fn chat_completion(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<String, LLMEndpointError> {
+        ENDPOINT
+            .chat_completions(
+                model
+                    .file_path()
+                    .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await
+    }
 
-    Ok(StoppingStream::wrap_with_stop_words(
-        stream,
-        vec![
-            "<|ASSISTANT|>".to_string(),
-            "<|USER|>".to_string(),
-            "<|TOOL|>".to_string(),
-            "<|SYSTEM|>".to_string(),
-        ],
-    ))
-}
+    async fn chat_completion_stream(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<CompletionStream, LLMEndpointError> {
+        let stream = ENDPOINT
+            .stream_chat_completions(
+                model
+                    .file_path()
+                    .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await?;
 
-pub async fn embeddings(
-    model: Model,
-    input: Vec<String>,
-) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
-    ENDPOINT
-        .embeddings(
-            model
-                .file_path()
-                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            input,
-        )
-        .await
-}
+        Ok(StoppingStream::wrap_with_stop_words(
+            stream,
+            vec![
+                "<|ASSISTANT|>".to_string(),
+                "<|USER|>".to_string(),
+                "<|TOOL|>".to_string(),
+                "<|SYSTEM|>".to_string(),
+            ],
+        ))
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        ENDPOINT.resident_models().await
+    }
 
-// Not needed. Just for completeness.
-#[allow(dead_code)]
-pub async fn reset_environment() {
-    ENDPOINT.reset()
+    async fn reset(&self) {
+        ENDPOINT.reset()
+    }
 }
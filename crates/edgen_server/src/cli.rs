@@ -11,6 +11,8 @@
  */
 
 //! Command Line Interface
+use std::path::PathBuf;
+
 use once_cell::sync::Lazy;
 
 /// The parsed command-line arguments provided to this program. Lazily initialized.
@@ -46,15 +48,22 @@ pub enum Command {
 
     /// generates the openapi spec and exit.
     Oasgen(Oasgen),
+
+    /// re-quantizes a local GGUF model.
+    Quantize(Quantize),
+
+    /// model-related subcommands.
+    Models(Models),
 }
 
 /// Starts the edgen server. This is the default command when no command is provided.
 #[derive(argh::FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "serve")]
 pub struct Serve {
-    /// if present, one or more URIs/hosts to bind the server to. `unix://` (on Linux), `http://`,
-    /// and `ws://` are supported, e.g.:
+    /// if present, one or more URIs/hosts to bind the server to. `unix://` (on Linux),
+    /// `npipe://` (on Windows), `http://`, and `ws://` are supported, e.g.:
     /// `edgen -b http://127.0.0.1:3000 -b http://192.168.1.1:3000`.
+    /// With port `0`, e.g. `http://127.0.0.1:0`, a free port is assigned and printed to stdout.
     /// For use in scripts, it is recommended to explicitly add this option
     /// to make your scripts future-proof.
     #[argh(option, short = 'b')]
@@ -63,6 +72,25 @@ pub struct Serve {
     /// the default behavior is to start the GUI.
     #[argh(switch, short = 'g')]
     pub nogui: bool,
+    /// if present, edgen detaches from the terminal and keeps serving in the background;
+    /// the process ID of the background server is printed to stdout.
+    #[argh(switch, short = 'd')]
+    pub daemon: bool,
+    /// if present, the file the process ID of the server is written to while it runs.
+    #[argh(option)]
+    pub pidfile: Option<PathBuf>,
+    /// if present, edgen runs as a Windows service. This is meant to be used by the service
+    /// control manager, not from a terminal.
+    #[argh(switch)]
+    pub service: bool,
+    /// if present, the config file, or the directory holding `edgen.conf.yaml`, the settings are
+    /// loaded from instead of the default config directory.
+    #[argh(option, short = 'c')]
+    pub config: Option<PathBuf>,
+    /// if present, the named profile the settings are loaded from, that is, the file
+    /// `profiles/<profile>.yaml` in the config directory, e.g. `edgen serve --profile benchmarking`.
+    #[argh(option, short = 'p')]
+    pub profile: Option<String>,
 }
 
 impl Default for Serve {
@@ -70,6 +98,11 @@ impl Default for Serve {
         Serve {
             uri: Vec::default(),
             nogui: false,
+            daemon: false,
+            pidfile: None,
+            service: false,
+            config: None,
+            profile: None,
         }
     }
 }
@@ -96,6 +129,29 @@ pub enum ConfigCommand {
 #[argh(subcommand, name = "reset")]
 pub struct Reset {}
 
+/// Model-related subcommands.
+#[derive(argh::FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "models")]
+pub struct Models {
+    /// models subcommands
+    #[argh(subcommand)]
+    pub subcommand: ModelsCommand,
+}
+
+/// Model-related subcommands.
+#[derive(argh::FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum ModelsCommand {
+    /// downloads the updated revisions of the configured models
+    Upgrade(Upgrade),
+}
+
+/// Downloads the updated revisions of the configured models, if their upstream repositories have
+/// any
+#[derive(argh::FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "upgrade")]
+pub struct Upgrade {}
+
 /// Prints the edgen version to stdout.
 #[derive(argh::FromArgs, PartialEq, Debug)]
 #[argh(subcommand, name = "version")]
@@ -114,6 +170,32 @@ pub struct Oasgen {
     /// the default behavior is to generate yaml output.
     #[argh(switch, short = 'j')]
     pub json: bool,
+    /// if present, the file the OpenAPI spec is written to;
+    /// the default behavior is to print it to stdout.
+    #[argh(option, short = 'o')]
+    pub output: Option<PathBuf>,
+    /// if present, edgen does not output the OpenAPI spec, but fails if it differs from the spec
+    /// in this file, e.g. a snapshot committed alongside an API client generated from it.
+    #[argh(option)]
+    pub check: Option<PathBuf>,
+}
+
+/// Re-quantizes a local GGUF model with llama.cpp, and registers the result as a chat completions
+/// model.
+#[derive(argh::FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "quantize")]
+pub struct Quantize {
+    /// the path of the GGUF model to quantize.
+    #[argh(positional)]
+    pub input: PathBuf,
+    /// the quantization to convert the model to, e.g. `Q4_K_M`, `Q5_K_S` or `Q8_0`.
+    #[argh(option, short = 'q')]
+    pub quant: String,
+    /// if present, the path the quantized model is written to;
+    /// the default is the chat completions models directory, in a file named after the input
+    /// model and the quantization, e.g. `mistral-7b.Q4_K_M.gguf`.
+    #[argh(option, short = 'o')]
+    pub output: Option<PathBuf>,
 }
 
 #[cfg(test)]
@@ -144,6 +226,18 @@ mod test {
         );
     }
 
+    #[test]
+    fn models_upgrade() {
+        assert_eq!(
+            TopLevel::from_args(&["edgen"], &["models", "upgrade"]).expect("from_args failed"),
+            TopLevel {
+                subcommand: Some(Command::Models(Models {
+                    subcommand: ModelsCommand::Upgrade(Upgrade {})
+                }))
+            }
+        );
+    }
+
     #[test]
     fn oasgen_only() {
         assert_eq!(
@@ -152,6 +246,8 @@ mod test {
                 subcommand: Some(Command::Oasgen(Oasgen{
                     yaml: false,
                     json: false,
+                    output: None,
+                    check: None,
                 }))
             }
         );
@@ -165,6 +261,8 @@ mod test {
                 subcommand: Some(Command::Oasgen(Oasgen{
                     yaml: true,
                     json: false,
+                    output: None,
+                    check: None,
                 }))
             }
         );
@@ -178,6 +276,8 @@ mod test {
                 subcommand: Some(Command::Oasgen(Oasgen{
                     yaml: true,
                     json: false,
+                    output: None,
+                    check: None,
                 }))
             }
         );
@@ -191,6 +291,8 @@ mod test {
                 subcommand: Some(Command::Oasgen(Oasgen{
                     yaml: false,
                     json: true,
+                    output: None,
+                    check: None,
                 }))
             }
         );
@@ -204,6 +306,34 @@ mod test {
                 subcommand: Some(Command::Oasgen(Oasgen{
                     yaml: false,
                     json: true,
+                    output: None,
+                    check: None,
+                }))
+            }
+        );
+    }
+
+    #[test]
+    fn oasgen_output_check() {
+        assert_eq!(
+            TopLevel::from_args(&["edgen"], &["oasgen", "-j", "-o", "spec.json"]).expect("from_args failed"),
+            TopLevel {
+                subcommand: Some(Command::Oasgen(Oasgen{
+                    yaml: false,
+                    json: true,
+                    output: Some(PathBuf::from("spec.json")),
+                    check: None,
+                }))
+            }
+        );
+        assert_eq!(
+            TopLevel::from_args(&["edgen"], &["oasgen", "--check", "spec.yaml"]).expect("from_args failed"),
+            TopLevel {
+                subcommand: Some(Command::Oasgen(Oasgen{
+                    yaml: false,
+                    json: false,
+                    output: None,
+                    check: Some(PathBuf::from("spec.yaml")),
                 }))
             }
         );
@@ -217,6 +347,11 @@ mod test {
                 subcommand: Some(Command::Serve(Serve {
                     uri: [].to_vec(),
                     nogui: false,
+                    daemon: false,
+                    pidfile: None,
+                    service: false,
+                    config: None,
+                    profile: None,
                 }))
             }
         );
@@ -230,6 +365,11 @@ mod test {
                 subcommand: Some(Command::Serve(Serve {
                     uri: [].to_vec(),
                     nogui: true,
+                    daemon: false,
+                    pidfile: None,
+                    service: false,
+                    config: None,
+                    profile: None,
                 }))
             }
         );
@@ -244,6 +384,11 @@ mod test {
                 subcommand: Some(Command::Serve(Serve {
                     uri: ["http://localhost".to_string()].to_vec(),
                     nogui: false,
+                    daemon: false,
+                    pidfile: None,
+                    service: false,
+                    config: None,
+                    profile: None,
                 }))
             }
         );
@@ -276,6 +421,11 @@ mod test {
                     .map(|x| x.to_string())
                     .to_vec(),
                     nogui: false,
+                    daemon: false,
+                    pidfile: None,
+                    service: false,
+                    config: None,
+                    profile: None,
                 }))
             }
         );
@@ -309,6 +459,70 @@ mod test {
                     .map(|x| x.to_string())
                     .to_vec(),
                     nogui: true,
+                    daemon: false,
+                    pidfile: None,
+                    service: false,
+                    config: None,
+                    profile: None,
+                }))
+            }
+        );
+    }
+
+    #[test]
+    fn serve_daemon() {
+        assert_eq!(
+            TopLevel::from_args(
+                &["edgen"],
+                &["serve", "--nogui", "--daemon", "--pidfile", "/run/edgen.pid"]
+            )
+            .expect("from_args failed"),
+            TopLevel {
+                subcommand: Some(Command::Serve(Serve {
+                    uri: [].to_vec(),
+                    nogui: true,
+                    daemon: true,
+                    pidfile: Some(PathBuf::from("/run/edgen.pid")),
+                    service: false,
+                    config: None,
+                    profile: None,
+                }))
+            }
+        );
+    }
+
+    #[test]
+    fn serve_config_profile() {
+        assert_eq!(
+            TopLevel::from_args(
+                &["edgen"],
+                &["serve", "--config", "/etc/edgen", "--profile", "benchmarking"]
+            )
+            .expect("from_args failed"),
+            TopLevel {
+                subcommand: Some(Command::Serve(Serve {
+                    uri: [].to_vec(),
+                    nogui: false,
+                    daemon: false,
+                    pidfile: None,
+                    service: false,
+                    config: Some(PathBuf::from("/etc/edgen")),
+                    profile: Some("benchmarking".to_string()),
+                }))
+            }
+        );
+    }
+
+    #[test]
+    fn quantize() {
+        assert_eq!(
+            TopLevel::from_args(&["edgen"], &["quantize", "model.gguf", "-q", "Q4_K_M"])
+                .expect("from_args failed"),
+            TopLevel {
+                subcommand: Some(Command::Quantize(Quantize{
+                    input: PathBuf::from("model.gguf"),
+                    quant: "Q4_K_M".to_string(),
+                    output: None,
                 }))
             }
         );
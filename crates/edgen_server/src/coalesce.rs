@@ -0,0 +1,100 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Coalescing of the tokens of streamed completions.
+//!
+//! Very fast models generate tokens faster than slow clients can read server-sent events, so the
+//! tokens generated within a short window are sent together, as a single event.
+
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use tokio::select;
+use tokio::time::sleep;
+
+/// Joins the tokens of `stream` generated within `window` of the first token of each chunk, up to
+/// `max_tokens` tokens per chunk, or without a maximum if `max_tokens` is `0`.
+///
+/// A chunk is sent as soon as its window ends, its maximum is reached or `stream` ends, so tokens
+/// are never delayed by more than `window`.
+pub fn coalesce<S>(
+    stream: S,
+    window: Duration,
+    max_tokens: usize,
+) -> impl Stream<Item = String> + Send + 'static
+where
+    S: Stream<Item = String> + Unpin + Send + 'static,
+{
+    futures::stream::unfold((stream, false), move |(mut stream, ended)| async move {
+        if ended {
+            return None;
+        }
+
+        let mut chunk = stream.next().await?;
+        let mut tokens = 1;
+        let deadline = sleep(window);
+        tokio::pin!(deadline);
+
+        while max_tokens == 0 || tokens < max_tokens {
+            select! {
+                next = stream.next() => match next {
+                    Some(token) => {
+                        chunk.push_str(&token);
+                        tokens += 1;
+                    }
+                    None => return Some((chunk, (stream, true))),
+                },
+                _ = &mut deadline => break,
+            }
+        }
+
+        Some((chunk, (stream, false)))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tokens(text: &str) -> impl Stream<Item = String> + Unpin + Send + 'static {
+        futures::stream::iter(text.chars().map(String::from).collect::<Vec<_>>())
+    }
+
+    #[tokio::test]
+    async fn max_tokens() {
+        let chunks: Vec<String> = coalesce(tokens("abcdefg"), Duration::from_secs(60), 3)
+            .collect()
+            .await;
+        assert_eq!(chunks, ["abc", "def", "g"]);
+    }
+
+    #[tokio::test]
+    async fn no_max_tokens() {
+        let chunks: Vec<String> = coalesce(tokens("abcdefg"), Duration::from_secs(60), 0)
+            .collect()
+            .await;
+        assert_eq!(chunks, ["abcdefg"]);
+    }
+
+    #[tokio::test]
+    async fn window() {
+        let slow = tokens("ab").chain(
+            futures::stream::once(async {
+                sleep(Duration::from_millis(200)).await;
+                "c".to_string()
+            })
+            .boxed(),
+        );
+        let chunks: Vec<String> = coalesce(slow, Duration::from_millis(20), 0).collect().await;
+        assert_eq!(chunks, ["ab", "c"]);
+    }
+}
@@ -0,0 +1,241 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Server-side storage of conversations, so that chat completions clients can send a
+//! `conversation_id` and only the newest messages instead of the whole history.
+//!
+//! The history is kept in the [`store`](crate::store), and the generated replies are stored
+//! exactly as they were generated, so the assembled prompts always extend the previous ones and
+//! reuse the sessions of the runtimes.
+//!
+//! A conversation belongs to the API key it was started with, and requests sending another key
+//! are answered with `403 Forbidden`.
+
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::Path;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use futures::{Stream, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::listener::KeyOwner;
+use crate::openai_shim::{ChatCompletionError, ChatMessage, ChatMessages};
+use crate::store::{self, StoreError};
+
+/// An ongoing turn of a stored conversation.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    /// The ID of the conversation.
+    id: String,
+
+    /// The owner of the conversation, the hash of its API key, or empty without one.
+    owner: String,
+
+    /// The messages sent in this turn, stored along with the reply once it has been generated.
+    new_messages: Vec<serde_json::Value>,
+}
+
+impl Conversation {
+    /// Prepends the stored history of conversation `id` to the provided newest messages.
+    ///
+    /// Fails if the conversation belongs to another API key than `key_owner`.
+    pub async fn load(
+        id: &str,
+        key_owner: Option<&KeyOwner>,
+        messages: &mut ChatMessages<'_>,
+    ) -> Result<Self, ChatCompletionError> {
+        let owner = owner_of(key_owner);
+        check_owner(id, &owner).await?;
+
+        let new_messages = messages
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()
+            .map_err(move |e| store_error(StoreError::Serialization(e)))?;
+
+        let history = store::get()
+            .map_err(store_error)?
+            .conversation(id)
+            .await
+            .map_err(store_error)?;
+        let mut history: Vec<ChatMessage> = history
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()
+            .map_err(move |e| store_error(StoreError::Serialization(e)))?;
+
+        history.append(messages);
+        **messages = history;
+
+        Ok(Self {
+            id: id.to_string(),
+            owner,
+            new_messages,
+        })
+    }
+
+    /// Stores the messages of this turn and the generated reply in the conversation.
+    ///
+    /// Failing to do so is only logged, as the reply has already been generated.
+    pub async fn record_reply(mut self, content: &str) {
+        let reply = ChatMessage::Assistant {
+            content: Some(Cow::Borrowed(content)),
+            name: None,
+            tool_calls: None,
+        };
+        match serde_json::to_value(reply) {
+            Ok(reply) => self.new_messages.push(reply),
+            Err(e) => {
+                warn!(
+                    "Failed to encode the reply of conversation {}: {e}",
+                    self.id
+                );
+                return;
+            }
+        }
+
+        let res = match store::get() {
+            Ok(store) => {
+                store
+                    .append_to_conversation(&self.id, &self.owner, self.new_messages)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(true) => {}
+            // Another key started the conversation while the reply was being generated
+            Ok(false) => warn!(
+                "Not storing the reply of conversation {}, which belongs to another API key",
+                self.id
+            ),
+            Err(e) => warn!("Failed to store conversation {}: {e}", self.id),
+        }
+    }
+}
+
+/// Wraps a stream of a generated reply, recording the reply in the conversation, if any, once the
+/// stream has completed.
+///
+/// `content` extracts the piece of the reply carried by each item of the stream.
+pub fn record_stream<S, T, F>(
+    conversation: Option<Conversation>,
+    stream: S,
+    content: F,
+) -> impl Stream<Item = T> + Send + 'static
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Send + 'static,
+    F: Fn(&T) -> Option<String> + Send + 'static,
+{
+    let reply = Arc::new(Mutex::new(String::new()));
+    let written = reply.clone();
+
+    let recorded = stream.inspect(move |item| {
+        if let Some(piece) = content(item) {
+            written
+                .lock()
+                .unwrap_or_else(move |e| e.into_inner())
+                .push_str(&piece);
+        }
+    });
+    let finish = futures::stream::once(async move {
+        if let Some(conversation) = conversation {
+            let reply = reply.lock().unwrap_or_else(move |e| e.into_inner()).clone();
+            conversation.record_reply(&reply).await;
+        }
+    })
+    .filter_map(move |()| futures::future::ready(None));
+
+    recorded.chain(finish)
+}
+
+/// Returns the owner of the conversations of the requests sending `key_owner`.
+fn owner_of(key_owner: Option<&KeyOwner>) -> String {
+    key_owner
+        .map(move |KeyOwner(owner)| owner.clone())
+        .unwrap_or_default()
+}
+
+/// Fails if conversation `id` exists and belongs to another owner than `owner`.
+async fn check_owner(id: &str, owner: &str) -> Result<(), ChatCompletionError> {
+    let stored = store::get()
+        .map_err(store_error)?
+        .conversation_owner(id)
+        .await
+        .map_err(store_error)?;
+    match stored {
+        Some(stored) if stored != owner => Err(ChatCompletionError::ForeignConversation {
+            conversation_id: id.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn store_error(e: StoreError) -> ChatCompletionError {
+    ChatCompletionError::Store {
+        reason: e.to_string(),
+    }
+}
+
+/// The return type of [`delete_conversation`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConversationDeleted {
+    /// The ID of the conversation.
+    pub id: String,
+
+    /// Always `"conversation.deleted"`.
+    pub object: String,
+
+    /// **`true`** if the conversation existed.
+    pub deleted: bool,
+}
+
+/// DELETE `/v1/chat/conversations/{id}`: deletes the stored history of a conversation.
+///
+/// This endpoint is **Edgen** specific.
+///
+/// On failure, may raise a `403 Forbidden` if the conversation belongs to another API key, or a
+/// `500 Internal Server Error`, with a JSON-encoded [`ChatCompletionError`] to the peer.
+#[utoipa::path(
+delete,
+path = "/chat/conversations/{id}",
+params(("id" = String, Path, description = "The ID of the conversation")),
+responses(
+(status = 200, description = "OK", body = ConversationDeleted),
+(status = 403, description = "the conversation belongs to another API key", body = ChatCompletionError),
+(status = 500, description = "unexpected internal server error", body = ChatCompletionError)
+),
+)]
+pub async fn delete_conversation(
+    key_owner: Option<Extension<KeyOwner>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, ChatCompletionError> {
+    let owner = owner_of(key_owner.as_ref().map(move |Extension(owner)| owner));
+    check_owner(&id, &owner).await?;
+
+    let deleted = store::get()
+        .map_err(store_error)?
+        .remove_conversation(&id)
+        .await
+        .map_err(store_error)?;
+
+    Ok(Json(ConversationDeleted {
+        id,
+        object: "conversation.deleted".to_string(),
+        deleted,
+    }))
+}
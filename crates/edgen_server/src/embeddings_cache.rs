@@ -0,0 +1,196 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A persistent, content-addressed cache of generated embeddings.
+//!
+//! Every embedding is stored in its own file, named after the [`blake3`] hash of the model and the
+//! input it was generated from, and containing the little-endian 32-bit floats of the embedding.
+//!
+//! Entries are written to a temporary file first, and then renamed, so that an entry is never read
+//! while being written. Once the entries exceed `embeddings_cache_max_size`, the least recently
+//! used ones, going by the modification time of their files, are removed.
+
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tracing::warn;
+use uuid::Uuid;
+
+use edgen_core::settings::{self, PROJECT_DIRS};
+
+use crate::response_cache::evict;
+
+/// Returns the directory where cached embeddings are stored.
+fn cache_dir() -> PathBuf {
+    PROJECT_DIRS.data_dir().join("cache").join("embeddings")
+}
+
+/// Returns the path of the cache entry of the embedding of `input` generated by `model`.
+fn entry_path(dir: &Path, model: &str, input: &str) -> PathBuf {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(model.as_bytes());
+    // Separate the model from the input, so that different pairs can never hash the same bytes
+    hasher.update(&[0]);
+    hasher.update(input.as_bytes());
+    let hash = hasher.finalize().to_hex();
+
+    // Spread the entries among subdirectories, to keep directories at a reasonable size
+    dir.join(&hash[..2]).join(hash.as_str())
+}
+
+/// Returns the cached embedding of `input` generated by `model`, if any.
+pub async fn get(model: &str, input: &str) -> Option<Vec<f32>> {
+    get_in(&cache_dir(), model, input).await
+}
+
+/// Stores the embeddings of `inputs` generated by `model` in the cache, removing the least
+/// recently used entries if the cache grows over its maximum size.
+///
+/// Failing to do so is not fatal, as the embeddings can always be generated again, so errors are
+/// only logged.
+pub async fn insert(model: &str, inputs: &[String], embeddings: &[Vec<f32>]) {
+    let max_size = settings::embeddings_cache_max_size().await;
+    insert_in(&cache_dir(), model, inputs, embeddings, max_size).await
+}
+
+async fn get_in(dir: &Path, model: &str, input: &str) -> Option<Vec<f32>> {
+    let path = entry_path(dir, model, input);
+    let bytes = tokio::fs::read(&path).await.ok()?;
+    if bytes.is_empty() || bytes.len() % 4 != 0 {
+        return None;
+    }
+
+    // Mark the entry as used, so that it is among the last to be evicted
+    let touched = tokio::task::spawn_blocking(move || {
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now())
+    })
+    .await;
+    if let Ok(Err(e)) = touched {
+        warn!("Failed to update the last use of an embeddings cache entry: {e}");
+    }
+
+    Some(
+        bytes
+            .chunks_exact(4)
+            .map(move |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    )
+}
+
+async fn insert_in(
+    dir: &Path,
+    model: &str,
+    inputs: &[String],
+    embeddings: &[Vec<f32>],
+    max_size: Option<u64>,
+) {
+    for (input, embedding) in inputs.iter().zip(embeddings) {
+        write_entry(&entry_path(dir, model, input), embedding).await;
+    }
+
+    // Evicted once for all the inputs, as indexing may embed many of them at once
+    if let Some(max_size) = max_size {
+        let dir = dir.to_path_buf();
+        match tokio::task::spawn_blocking(move || evict(&dir, max_size)).await {
+            Ok(Err(e)) => warn!("Failed to evict embeddings cache entries: {e}"),
+            Err(e) => warn!("Failed to evict embeddings cache entries: {e}"),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+async fn write_entry(path: &Path, embedding: &[f32]) {
+    if let Some(parent) = path.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            warn!("Failed to create embeddings cache directory {parent:?}: {e}");
+            return;
+        }
+    }
+
+    let bytes: Vec<u8> = embedding
+        .iter()
+        .flat_map(move |value| value.to_le_bytes())
+        .collect();
+    let temp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    let written = match tokio::fs::write(&temp, bytes).await {
+        Ok(()) => tokio::fs::rename(&temp, path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        warn!("Failed to write embeddings cache entry {path:?}: {e}");
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cache_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let embedding = vec![0.25f32, -1.0, 3.5];
+
+        assert_eq!(get_in(dir.path(), "model", "input").await, None);
+
+        insert_in(
+            dir.path(),
+            "model",
+            &["input".to_string()],
+            &[embedding.clone()],
+            None,
+        )
+        .await;
+        assert_eq!(get_in(dir.path(), "model", "input").await, Some(embedding));
+        assert_eq!(get_in(dir.path(), "other-model", "input").await, None);
+        assert_eq!(get_in(dir.path(), "model", "other input").await, None);
+    }
+
+    #[tokio::test]
+    async fn cache_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        // 100 bytes per entry
+        let embedding = vec![vec![1.0f32; 25]];
+        let max_size = Some(250);
+
+        for input in ["first", "second"] {
+            insert_in(
+                dir.path(),
+                "model",
+                &[input.to_string()],
+                &embedding,
+                max_size,
+            )
+            .await;
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+
+        // Using the first entry makes the second one the least recently used
+        assert!(get_in(dir.path(), "model", "first").await.is_some());
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        insert_in(
+            dir.path(),
+            "model",
+            &["third".to_string()],
+            &embedding,
+            max_size,
+        )
+        .await;
+
+        assert!(get_in(dir.path(), "model", "first").await.is_some());
+        assert_eq!(get_in(dir.path(), "model", "second").await, None);
+        assert!(get_in(dir.path(), "model", "third").await.is_some());
+    }
+}
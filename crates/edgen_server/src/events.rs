@@ -0,0 +1,166 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The server event bus, streaming every server event through a single endpoint.
+//!
+//! `/v1/events` multiplexes the [status events](StatusEvent), i.e. the model lifecycle and
+//! download events, with the [server events](ServerEvent): the requests entering and leaving the
+//! server, and the settings being reloaded. Every event is sent with its type as the name of the
+//! server-sent event, so that clients can listen to the types they care about only.
+
+use axum::response::sse::{Event, KeepAlive};
+use axum::response::Sse;
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::status::{self, StatusEvent};
+
+/// An event of the server which is not a change of the service status.
+#[derive(ToSchema, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// A request was received.
+    RequestStarted {
+        /// The ID of the request.
+        id: String,
+        /// The HTTP method of the request.
+        method: String,
+        /// The path of the request, e.g. `/v1/chat/completions`.
+        endpoint: String,
+    },
+    /// A request was done, that is its response was sent, or it was cancelled.
+    RequestFinished {
+        /// The ID of the request.
+        id: String,
+        /// The path of the request, e.g. `/v1/chat/completions`.
+        endpoint: String,
+        /// The end user the request was made for, from the `user` parameter of its body, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+    },
+    /// The settings were changed, and the server is being reset to apply them.
+    SettingsReloaded,
+}
+
+/// An event streamed by [`events`].
+#[derive(ToSchema, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum BusEvent {
+    /// A change of the service status, as streamed by `/v1/status/stream`.
+    Status(StatusEvent),
+    /// Any other event of the server.
+    Server(ServerEvent),
+}
+
+impl BusEvent {
+    /// Returns the type of the event, e.g. `model_loaded`.
+    fn event_type(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|value| Some(value.get("type")?.as_str()?.to_string()))
+            .unwrap_or_default()
+    }
+}
+
+/// How many server events are buffered for slow subscribers before they start missing events.
+const EVENTS_CAPACITY: usize = 256;
+
+static EVENTS: Lazy<broadcast::Sender<ServerEvent>> =
+    Lazy::new(move || broadcast::channel(EVENTS_CAPACITY).0);
+
+/// Sends a server event to every [`events`] subscriber.
+pub(crate) fn publish(event: ServerEvent) {
+    // Failing means there are no subscribers, in which case nobody cares about the event
+    let _ = EVENTS.send(event);
+}
+
+/// GET `/v1/events`: streams every server event as server-sent events.
+///
+/// Every event is a json value BusEvent, named after its `type`. Models being loaded or unloaded
+/// are detected by checking the resident models every second, as for `/v1/status/stream`. This
+/// endpoint is specific to **Edgen**.
+#[utoipa::path(
+get,
+path = "/events",
+responses(
+(status = 200, description = "a stream of server-sent events", body = BusEvent, content_type = "text/event-stream")
+),
+)]
+pub async fn events() -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = futures::stream::unfold(
+        (status::subscribe(), EVENTS.subscribe()),
+        move |(mut statuses, mut servers)| async move {
+            loop {
+                let event = select! {
+                    received = statuses.recv() => match received {
+                        Ok(event) => BusEvent::Status(event),
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("event stream lagging behind, {missed} status events dropped");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    received = servers.recv() => match received {
+                        Ok(event) => BusEvent::Server(event),
+                        Err(RecvError::Lagged(missed)) => {
+                            warn!("event stream lagging behind, {missed} server events dropped");
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                };
+                return Some((event, (statuses, servers)));
+            }
+        },
+    )
+    .map(move |event| Event::default().event(event.event_type()).json_data(event));
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn event_types() {
+        let status = BusEvent::Status(StatusEvent::Download {
+            endpoint: "chat_completions".to_string(),
+            ongoing: true,
+        });
+        assert_eq!(status.event_type(), "download");
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            serde_json::json!({"type": "download", "endpoint": "chat_completions", "ongoing": true})
+        );
+
+        let server = BusEvent::Server(ServerEvent::RequestStarted {
+            id: "req-1".to_string(),
+            method: "POST".to_string(),
+            endpoint: "/v1/chat/completions".to_string(),
+        });
+        assert_eq!(server.event_type(), "request_started");
+
+        let reloaded = BusEvent::Server(ServerEvent::SettingsReloaded);
+        assert_eq!(reloaded.event_type(), "settings_reloaded");
+        assert_eq!(
+            serde_json::to_value(&reloaded).unwrap(),
+            serde_json::json!({"type": "settings_reloaded"})
+        );
+    }
+}
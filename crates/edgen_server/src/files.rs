@@ -0,0 +1,430 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Files uploaded to the server, or generated by it, to be used by other endpoints.
+//!
+//! The contents of every file are kept in the `files` directory of the data directory, named
+//! after the ID of the file, and its metadata in the [`store`](crate::store).
+
+use std::path::PathBuf;
+
+use axum::extract::{Path, Query};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use axum_typed_multipart::{FieldData, TryFromMultipart, TypedMultipart};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use edgen_core::settings::PROJECT_DIRS;
+
+use crate::request_id;
+use crate::store::{self, now, StoreError};
+
+/// What a file is meant to be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FilePurpose {
+    /// A document, for assistants and vector stores.
+    Assistants,
+
+    /// An audio file to transcribe.
+    Audio,
+
+    /// The JSONL input of a batch.
+    Batch,
+
+    /// The JSONL output of a batch, generated by **Edgen**.
+    BatchOutput,
+
+    /// Any other file.
+    UserData,
+}
+
+/// An error condition raised by the files API.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum FileError {
+    /// There is no file with the provided ID.
+    #[error("no such file: {file_id}")]
+    NoSuchFile {
+        /// The ID of the file.
+        file_id: String,
+    },
+
+    /// A parameter of the request has an invalid value.
+    #[error("invalid value for parameter {param}: {reason}")]
+    InvalidParam {
+        /// The name of the parameter.
+        param: String,
+
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// The contents or metadata of the file could not be accessed.
+    #[error("the file could not be accessed: {reason}")]
+    Storage {
+        /// A human-readable error message.
+        reason: String,
+    },
+}
+
+impl From<StoreError> for FileError {
+    fn from(e: StoreError) -> Self {
+        FileError::Storage {
+            reason: e.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for FileError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::from(e).into()
+    }
+}
+
+impl From<std::io::Error> for FileError {
+    fn from(e: std::io::Error) -> Self {
+        FileError::Storage {
+            reason: e.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for FileError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            FileError::NoSuchFile { .. } => StatusCode::NOT_FOUND,
+            FileError::InvalidParam { .. } => StatusCode::BAD_REQUEST,
+            FileError::Storage { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        request_id::error_response(status, &self)
+    }
+}
+
+/// A file stored by **Edgen**.
+///
+/// See [the documentation of files][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/files/object
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct FileObject {
+    /// The ID of the file.
+    pub id: String,
+
+    /// Always `"file"`.
+    pub object: String,
+
+    /// The size of the file, in bytes.
+    pub bytes: u64,
+
+    /// The UNIX timestamp, in seconds, of when the file was created.
+    pub created_at: u64,
+
+    /// The name of the file.
+    pub filename: String,
+
+    /// What the file is meant to be used for.
+    pub purpose: FilePurpose,
+}
+
+/// A request to upload a file.
+///
+/// An `axum` handler, [`create_file`][create_file], is provided to handle this request.
+///
+/// [create_file]: fn.create_file.html
+#[derive(TryFromMultipart, ToSchema)]
+#[try_from_multipart(strict)]
+pub struct CreateFileRequest {
+    /// The file to upload.
+    #[form_data(limit = "unlimited")]
+    #[schema(value_type = Vec < u8 >)]
+    pub file: FieldData<axum::body::Bytes>,
+
+    /// What the file is meant to be used for, one of `assistants`, `audio`, `batch` or
+    /// `user_data`.
+    pub purpose: String,
+}
+
+/// The return type of [`list_files`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FileList {
+    /// Always `"list"`.
+    pub object: String,
+
+    /// The files.
+    pub data: Vec<FileObject>,
+}
+
+/// The return type of [`delete_file`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct FileDeleted {
+    /// The ID of the file.
+    pub id: String,
+
+    /// Always `"file"`.
+    pub object: String,
+
+    /// **`true`** if the file existed.
+    pub deleted: bool,
+}
+
+/// The query parameters of [`list_files`].
+#[derive(Debug, Deserialize)]
+pub struct ListFilesQuery {
+    /// Only list the files with this purpose.
+    pub purpose: Option<FilePurpose>,
+}
+
+/// Returns the directory where the contents of the files are stored.
+fn files_dir() -> PathBuf {
+    PROJECT_DIRS.data_dir().join("files")
+}
+
+/// Parses the purpose of an uploaded file. `batch_output` is reserved to the files generated by
+/// **Edgen**.
+fn parse_purpose(purpose: &str) -> Result<FilePurpose, FileError> {
+    match serde_json::from_value(serde_json::Value::String(purpose.to_string())) {
+        Ok(FilePurpose::BatchOutput) | Err(_) => Err(FileError::InvalidParam {
+            param: "purpose".to_string(),
+            reason: format!(
+                "must be one of \"assistants\", \"audio\", \"batch\" or \"user_data\", \
+                 got \"{purpose}\""
+            ),
+        }),
+        Ok(purpose) => Ok(purpose),
+    }
+}
+
+/// Stores a new file with the provided contents.
+pub(crate) async fn store_file(
+    filename: &str,
+    purpose: FilePurpose,
+    contents: &[u8],
+) -> Result<FileObject, FileError> {
+    let file = FileObject {
+        id: format!("file-{}", Uuid::new_v4().simple()),
+        object: "file".to_string(),
+        bytes: contents.len() as u64,
+        created_at: now(),
+        filename: filename.to_string(),
+        purpose,
+    };
+
+    let dir = files_dir();
+    tokio::fs::create_dir_all(&dir).await?;
+    tokio::fs::write(dir.join(&file.id), contents).await?;
+    store::get()?
+        .put_file(&file.id, &serde_json::to_value(&file)?)
+        .await?;
+
+    Ok(file)
+}
+
+/// Returns the metadata of a file.
+pub(crate) async fn get_file(file_id: &str) -> Result<FileObject, FileError> {
+    match store::get()?.file(file_id).await? {
+        Some(file) => Ok(serde_json::from_value(file)?),
+        None => Err(FileError::NoSuchFile {
+            file_id: file_id.to_string(),
+        }),
+    }
+}
+
+/// Returns the contents of a file.
+pub(crate) async fn file_contents(file_id: &str) -> Result<Vec<u8>, FileError> {
+    get_file(file_id).await?;
+    Ok(tokio::fs::read(files_dir().join(file_id)).await?)
+}
+
+/// POST `/v1/files`: uploads a file.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from the supported purposes.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/files/create
+///
+/// On failure, may raise a `400 Bad Request` or `500 Internal Server Error` with a JSON-encoded
+/// [`FileError`] to the peer.
+#[utoipa::path(
+post,
+path = "/files",
+request_body = CreateFileRequest,
+responses(
+(status = 200, description = "OK", body = FileObject),
+(status = 400, description = "invalid request", body = FileError),
+(status = 500, description = "unexpected internal server error", body = FileError)
+),
+)]
+pub async fn create_file(
+    req: TypedMultipart<CreateFileRequest>,
+) -> Result<impl IntoResponse, FileError> {
+    let purpose = parse_purpose(&req.purpose)?;
+    let filename = req
+        .file
+        .metadata
+        .file_name
+        .clone()
+        .unwrap_or_else(move || "file".to_string());
+
+    Ok(Json(
+        store_file(&filename, purpose, &req.file.contents).await?,
+    ))
+}
+
+/// GET `/v1/files`: lists the files, oldest first.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/files/list
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`FileError`] to the
+/// peer.
+#[utoipa::path(
+get,
+path = "/files",
+params(
+("purpose" = Option<FilePurpose>, Query, description = "Only list the files with this purpose")
+),
+responses(
+(status = 200, description = "OK", body = FileList),
+(status = 500, description = "unexpected internal server error", body = FileError)
+),
+)]
+pub async fn list_files(
+    Query(query): Query<ListFilesQuery>,
+) -> Result<impl IntoResponse, FileError> {
+    let files = store::get()?
+        .files()
+        .await?
+        .into_iter()
+        .map(move |file| Ok(serde_json::from_value(file)?))
+        .collect::<Result<Vec<FileObject>, FileError>>()?
+        .into_iter()
+        .filter(move |file| {
+            query
+                .purpose
+                .map_or(true, |purpose| file.purpose == purpose)
+        })
+        .collect();
+
+    Ok(Json(FileList {
+        object: "list".to_string(),
+        data: files,
+    }))
+}
+
+/// GET `/v1/files/{file_id}`: returns the metadata of a file.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/files/retrieve
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`FileError`] to the peer.
+#[utoipa::path(
+get,
+path = "/files/{file_id}",
+params(("file_id" = String, Path, description = "The ID of the file")),
+responses(
+(status = 200, description = "OK", body = FileObject),
+(status = 404, description = "no such file", body = FileError),
+(status = 500, description = "unexpected internal server error", body = FileError)
+),
+)]
+pub async fn retrieve_file(Path(file_id): Path<String>) -> Result<impl IntoResponse, FileError> {
+    Ok(Json(get_file(&file_id).await?))
+}
+
+/// GET `/v1/files/{file_id}/content`: returns the contents of a file.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/files/retrieve-contents
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`FileError`] to the peer.
+#[utoipa::path(
+get,
+path = "/files/{file_id}/content",
+params(("file_id" = String, Path, description = "The ID of the file")),
+responses(
+(status = 200, description = "OK", body = Vec<u8>, content_type = "application/octet-stream"),
+(status = 404, description = "no such file", body = FileError),
+(status = 500, description = "unexpected internal server error", body = FileError)
+),
+)]
+pub async fn retrieve_file_content(
+    Path(file_id): Path<String>,
+) -> Result<impl IntoResponse, FileError> {
+    let contents = file_contents(&file_id).await?;
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        contents,
+    ))
+}
+
+/// DELETE `/v1/files/{file_id}`: deletes a file.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/files/delete
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`FileError`] to the
+/// peer.
+#[utoipa::path(
+delete,
+path = "/files/{file_id}",
+params(("file_id" = String, Path, description = "The ID of the file")),
+responses(
+(status = 200, description = "OK", body = FileDeleted),
+(status = 500, description = "unexpected internal server error", body = FileError)
+),
+)]
+pub async fn delete_file(Path(file_id): Path<String>) -> Result<impl IntoResponse, FileError> {
+    let deleted = store::get()?.remove_file(&file_id).await?;
+    if deleted {
+        if let Err(e) = tokio::fs::remove_file(files_dir().join(&file_id)).await {
+            warn!("Failed to remove the contents of file {file_id}: {e}");
+        }
+    }
+
+    Ok(Json(FileDeleted {
+        id: file_id,
+        object: "file".to_string(),
+        deleted,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn purposes() {
+        assert_eq!(parse_purpose("batch").unwrap(), FilePurpose::Batch);
+        assert_eq!(parse_purpose("user_data").unwrap(), FilePurpose::UserData);
+        assert!(matches!(
+            parse_purpose("batch_output"),
+            Err(FileError::InvalidParam { .. })
+        ));
+        assert!(matches!(
+            parse_purpose("fine-tune"),
+            Err(FileError::InvalidParam { .. })
+        ));
+    }
+}
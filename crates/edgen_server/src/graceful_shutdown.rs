@@ -11,30 +11,121 @@
  */
 
 //! Mechanisms for shutting down application without destroying anything important.
+//!
+//! Subsystems that must finish something before the application exits, such as saving state, can
+//! register a hook with [`register_hook`]. The hooks run once the listeners have stopped, and must
+//! complete within the grace period set by the `shutdown_grace_period` setting, after which the
+//! application exits regardless.
 
+use std::future::Future;
+use std::sync::Mutex;
+
+use futures::future::{join_all, BoxFuture};
+use futures::FutureExt;
+use once_cell::sync::Lazy;
 use time::{Duration, OffsetDateTime};
 use tokio::signal;
-use tokio::sync::OnceCell;
-use tracing::warn;
+use tokio::sync::{Notify, OnceCell};
+use tracing::{info, warn};
 
-/// The duration between [`global_shutdown_starts`] and [`global_shutdown_ends`].
-pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::seconds(30);
+use edgen_core::settings;
 
 static SHUTDOWN_INVOKED_AT: OnceCell<OffsetDateTime> = OnceCell::const_new();
 
-/// Listens for signals that cause the application to shut down; namely, `CTRL+C`.
+static SHUTDOWN_REQUESTED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// An asynchronous hook run when the application shuts down.
+type ShutdownHook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+/// The registered shutdown hooks, with the name of the subsystem that registered them.
+static HOOKS: Lazy<Mutex<Vec<(String, ShutdownHook)>>> = Lazy::new(Default::default);
+
+/// Registers a hook run when the application shuts down, once the listeners have stopped.
+///
+/// `name` identifies the subsystem registering the hook in the logs.
+pub fn register_hook<F, Fut>(name: &str, hook: F)
+where
+    F: FnOnce() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    HOOKS
+        .lock()
+        .unwrap()
+        .push((name.to_string(), Box::new(move || hook().boxed())));
+}
+
+/// Runs every registered shutdown hook concurrently, and forgets them.
+pub async fn run_hooks() {
+    let hooks: Vec<_> = HOOKS.lock().unwrap().drain(..).collect();
+    join_all(hooks.into_iter().map(move |(name, hook)| async move {
+        info!("Running the shutdown hook of {name}");
+        hook().await;
+    }))
+    .await;
+}
+
+/// Returns `true` if a global shutdown has started.
+pub fn is_shutting_down() -> bool {
+    SHUTDOWN_INVOKED_AT.initialized()
+}
+
+/// Returns the duration between [`global_shutdown_starts`] and [`global_shutdown_ends`].
+async fn grace_period() -> Duration {
+    // Capped so that adding it to a date cannot overflow
+    let secs = settings::shutdown_grace_period().await.as_secs();
+    Duration::seconds(secs.min(u32::MAX as u64) as i64)
+}
+
+/// Starts a global shutdown, as if a shutdown signal had been received.
+///
+/// This is used by service managers that do not stop the application with signals, such as the
+/// Windows service control manager.
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.notify_one();
+}
+
+/// Listens for signals that cause the application to shut down; namely, `CTRL+C`, `SIGTERM` (on
+/// Unix) and [`request_shutdown`].
 async fn signal_listener() -> OffsetDateTime {
-    while signal::ctrl_c().await.is_err() { /* spin */ }
+    tokio::select! {
+        _ = ctrl_c() => {}
+        _ = terminate() => {}
+        _ = SHUTDOWN_REQUESTED.notified() => {}
+    }
 
     warn!(
         "Global shutdown has been invoked at {}, and will result in a hard termination at {}",
         OffsetDateTime::now_utc(),
-        OffsetDateTime::now_utc() + SHUTDOWN_GRACE_PERIOD
+        OffsetDateTime::now_utc() + grace_period().await
     );
 
     OffsetDateTime::now_utc()
 }
 
+async fn ctrl_c() {
+    while signal::ctrl_c().await.is_err() { /* spin */ }
+}
+
+/// Resolves when `SIGTERM` is received, which is how service managers such as systemd stop the
+/// application.
+#[cfg(unix)]
+async fn terminate() {
+    match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            warn!("Cannot listen for SIGTERM: {e}");
+            std::future::pending::<()>().await
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending::<()>().await
+}
+
 /// Resolves when a global shutdown has started.
 ///
 /// All threads **should** start gracefully exiting by this time.
@@ -45,10 +136,10 @@ pub async fn global_shutdown_starts() {
 /// Resolves when the application is about to unconditionally shut down, following
 /// [`global_shutdown_starts`].
 ///
-/// This fires after a grace period of [`SHUTDOWN_GRACE_PERIOD`].
+/// This fires after the grace period set by the `shutdown_grace_period` setting.
 pub async fn global_shutdown_ends() {
-    yield_until(*SHUTDOWN_INVOKED_AT.get_or_init(signal_listener).await + SHUTDOWN_GRACE_PERIOD)
-        .await;
+    let invoked_at = *SHUTDOWN_INVOKED_AT.get_or_init(signal_listener).await;
+    yield_until(invoked_at + grace_period().await).await;
 }
 
 /// Yields until a [`time`]-based [`OffsetDateTime`] has elapsed.
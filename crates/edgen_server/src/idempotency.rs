@@ -0,0 +1,381 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Replay of the responses of retried requests, identified by an `Idempotency-Key` header, so
+//! that a client retrying a request after a timeout does not trigger a second generation.
+//!
+//! The successful response of a `POST` request with an idempotency key is stored in the
+//! [store](crate::store) for a day. Retrying the request with the same key returns the stored
+//! response, with an `Idempotent-Replayed: true` header, instead of handling the request again.
+//! Streamed responses are never stored, as they cannot be replayed.
+//!
+//! Only the routes generating content from a JSON body replay their responses, as their bodies are
+//! read into memory to tell retries apart from different requests. Multipart bodies, such as audio
+//! uploads, are let through. Idempotency keys are scoped by the API key of the request, so that
+//! clients with different API keys never replay each other's responses.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::RequestExt;
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::request_id;
+use crate::store::{self, IdempotentResponse, Store, StoreError};
+
+/// The header carrying the idempotency key of a request.
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+
+/// The header set on replayed responses.
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// The longest idempotency key that is accepted.
+const MAX_KEY_LEN: usize = 255;
+
+/// The number of seconds a response is stored for.
+const KEY_TTL: u64 = 24 * 60 * 60;
+
+/// The scoped idempotency keys of the requests being handled.
+static IN_FLIGHT: Lazy<Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// An error condition raised while handling an idempotency key.
+#[derive(Serialize, Error, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum IdempotencyError {
+    /// The idempotency key is empty or too long.
+    #[error("invalid idempotency key: {reason}")]
+    InvalidKey {
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// A request with the same idempotency key is still being handled.
+    #[error("a request with the idempotency key {key} is still being handled")]
+    InProgress {
+        /// The idempotency key.
+        key: String,
+    },
+
+    /// The idempotency key was already used by a different request.
+    #[error("the idempotency key {key} was already used by a different request")]
+    KeyReused {
+        /// The idempotency key.
+        key: String,
+    },
+
+    /// The request body could not be read.
+    #[error("the request body could not be read: {reason}")]
+    Body {
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// The stored responses could not be accessed.
+    #[error("the stored responses could not be accessed: {reason}")]
+    Store {
+        /// A human-readable error message.
+        reason: String,
+    },
+}
+
+impl From<StoreError> for IdempotencyError {
+    fn from(value: StoreError) -> Self {
+        IdempotencyError::Store {
+            reason: value.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for IdempotencyError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            IdempotencyError::InvalidKey { .. } => StatusCode::BAD_REQUEST,
+            IdempotencyError::InProgress { .. } => StatusCode::CONFLICT,
+            IdempotencyError::KeyReused { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            IdempotencyError::Body { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            IdempotencyError::Store { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        request_id::error_response(status, &self)
+    }
+}
+
+/// Removes an idempotency key from [`IN_FLIGHT`] when the request it belongs to is done, even if
+/// it is cancelled.
+struct InFlight(String);
+
+impl InFlight {
+    /// Marks the request with the scoped idempotency key `scoped` as being handled, `key` being
+    /// the idempotency key it was sent with.
+    fn start(scoped: &str, key: &str) -> Result<Self, IdempotencyError> {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if !in_flight.insert(scoped.to_string()) {
+            return Err(IdempotencyError::InProgress {
+                key: key.to_string(),
+            });
+        }
+        Ok(Self(scoped.to_string()))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// An `axum` middleware that replays the responses of retried requests, as described in the
+/// [module documentation](self).
+///
+/// This must run inside the `DefaultBodyLimit` of its route, so that bodies are limited like the
+/// handler would limit them.
+pub async fn replay(req: Request, next: Next) -> Response {
+    replay_with(store::get(), req, next)
+        .await
+        .unwrap_or_else(move |e| e.into_response())
+}
+
+/// Replays the responses of retried requests using `store`, which is only needed by the
+/// requests with an idempotency key.
+async fn replay_with(
+    store: Result<&Store, StoreError>,
+    req: Request,
+    next: Next,
+) -> Result<Response, IdempotencyError> {
+    if req.method() != Method::POST || is_multipart(req.headers()) {
+        return Ok(next.run(req).await);
+    }
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(next.run(req).await);
+    };
+
+    let key = key
+        .to_str()
+        .map_err(move |e| IdempotencyError::InvalidKey {
+            reason: e.to_string(),
+        })?
+        .to_string();
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(IdempotencyError::InvalidKey {
+            reason: format!("must be between 1 and {MAX_KEY_LEN} bytes long"),
+        });
+    }
+
+    let scoped = scoped_key(req.headers(), &key);
+
+    let (parts, body) = req.with_limited_body().into_parts();
+    let body = to_bytes(body, usize::MAX)
+        .await
+        .map_err(move |e| IdempotencyError::Body {
+            reason: e.to_string(),
+        })?;
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(parts.uri.path().as_bytes());
+    // Separate the path from the body, so that different requests can never hash the same bytes
+    hasher.update(&[0]);
+    hasher.update(&body);
+    let fingerprint = hasher.finalize().to_hex().to_string();
+
+    let store = store?;
+    let _in_flight = InFlight::start(&scoped, &key)?;
+
+    store
+        .remove_idempotent_responses(store::now().saturating_sub(KEY_TTL))
+        .await?;
+    if let Some(stored) = store.idempotent_response(&scoped).await? {
+        if stored.fingerprint != fingerprint {
+            return Err(IdempotencyError::KeyReused { key });
+        }
+        return Ok(replayed(stored));
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(move |value| value.to_str().ok())
+        .map(move |value| value.to_string());
+    let streamed = content_type
+        .as_deref()
+        .is_some_and(move |value| value.starts_with("text/event-stream"));
+    if !response.status().is_success() || streamed {
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Failed to read the response of idempotency key {key}: {e}");
+            return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+        }
+    };
+
+    let stored = IdempotentResponse {
+        fingerprint,
+        status: parts.status.as_u16(),
+        content_type,
+        body: body.to_vec(),
+        created: store::now(),
+    };
+    if let Err(e) = store.put_idempotent_response(&scoped, stored).await {
+        // The request was handled anyway, so only a retry would be affected
+        warn!("Failed to store the response of idempotency key {key}: {e}");
+    }
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+/// Returns `true` if the body of a request with `headers` is multipart, e.g. an uploaded file.
+fn is_multipart(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(move |value| value.to_str().ok())
+        .is_some_and(move |value| value.starts_with("multipart/"))
+}
+
+/// Returns the key the responses of a request with `headers` and the idempotency key `key` are
+/// stored under, scoped by a hash of the API key of the request, so that API keys are not stored.
+fn scoped_key(headers: &HeaderMap, key: &str) -> String {
+    let api_key = headers
+        .get(AUTHORIZATION)
+        .map(move |value| value.as_bytes())
+        .unwrap_or_default();
+    format!("{}:{key}", blake3::hash(api_key).to_hex())
+}
+
+/// Builds the response replaying a stored response.
+fn replayed(stored: IdempotentResponse) -> Response {
+    let status = StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK);
+    let mut response = (status, stored.body).into_response();
+
+    let headers = response.headers_mut();
+    headers.remove(CONTENT_TYPE);
+    if let Some(value) = stored
+        .content_type
+        .and_then(move |value| HeaderValue::from_str(&value).ok())
+    {
+        headers.insert(CONTENT_TYPE, value);
+    }
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use axum::extract::DefaultBodyLimit;
+    use axum::middleware::from_fn;
+    use axum::routing::post;
+    use axum::Router;
+    use axum_test::{TestRequest, TestServer};
+
+    use super::*;
+
+    /// Serves a route counting its calls, replaying its responses, whose requests are limited to
+    /// 16 bytes.
+    fn server(calls: Arc<AtomicUsize>) -> TestServer {
+        let store = Store::open_in_memory().unwrap();
+        let router = Router::new().route(
+            "/generate",
+            post(move || {
+                let counter = calls.clone();
+                async move { format!("call {}", counter.fetch_add(1, Ordering::SeqCst)) }
+            })
+            .layer(from_fn(move |req: Request, next: Next| {
+                let store = store.clone();
+                async move {
+                    replay_with(Ok(&store), req, next)
+                        .await
+                        .unwrap_or_else(move |e| e.into_response())
+                }
+            }))
+            .layer(DefaultBodyLimit::max(16)),
+        );
+        TestServer::new(router).expect("cannot instantiate TestServer")
+    }
+
+    fn request(server: &TestServer, key: &str, body: &str) -> TestRequest {
+        server
+            .post("/generate")
+            .add_header(
+                IDEMPOTENCY_KEY_HEADER.parse().unwrap(),
+                key.parse().unwrap(),
+            )
+            .text(body.to_string())
+    }
+
+    #[tokio::test]
+    async fn replays() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let server = server(calls.clone());
+
+        let first = request(&server, "key", "body").await;
+        assert_eq!(first.text(), "call 0");
+        assert!(first.headers().get(IDEMPOTENT_REPLAYED_HEADER).is_none());
+
+        let retry = request(&server, "key", "body").await;
+        assert_eq!(retry.text(), "call 0");
+        assert_eq!(retry.header(IDEMPOTENT_REPLAYED_HEADER), "true");
+        assert_eq!(retry.header(CONTENT_TYPE), first.header(CONTENT_TYPE));
+
+        request(&server, "key", "other body")
+            .await
+            .assert_status(StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(request(&server, "other key", "body").await.text(), "call 1");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn scoped_by_api_key() {
+        let server = server(Arc::new(AtomicUsize::new(0)));
+        let with_key = |api_key: &str| {
+            request(&server, "key", "body")
+                .add_header(AUTHORIZATION, format!("Bearer {api_key}").parse().unwrap())
+        };
+
+        assert_eq!(with_key("a").await.text(), "call 0");
+        assert_eq!(with_key("b").await.text(), "call 1");
+        assert_eq!(with_key("a").await.text(), "call 0");
+    }
+
+    #[tokio::test]
+    async fn route_limits() {
+        let server = server(Arc::new(AtomicUsize::new(0)));
+
+        request(&server, "key", "a body longer than the limit")
+            .await
+            .assert_status(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn multipart_not_replayed() {
+        let server = server(Arc::new(AtomicUsize::new(0)));
+        let upload = || request(&server, "key", "").content_type("multipart/form-data; boundary=x");
+
+        assert_eq!(upload().await.text(), "call 0");
+        assert_eq!(upload().await.text(), "call 1");
+    }
+}
@@ -1,88 +1,38 @@
 use crate::model_descriptor::{
     ModelDescriptor, ModelDescriptorError, ModelPaths, Quantization, StableDiffusionFiles,
 };
-use axum::http::StatusCode;
+use crate::openai_shim::{self, CreateImageRequest, Image, ImagesResponse};
+use crate::request_id;
+use crate::requests;
+use crate::status;
+use axum::extract::{ConnectInfo, Path as AxumPath};
+use axum::http::header::{CONTENT_TYPE, FORWARDED, HOST};
+use axum::http::{HeaderMap, StatusCode, Uri};
 use axum::response::{IntoResponse, Response};
-use axum::Json;
+use axum::{Extension, Json};
 use dashmap::DashMap;
+use data_encoding::BASE64;
 use edgen_core::image_generation::{
     ImageGenerationArgs, ImageGenerationEndpoint, ImageGenerationEndpointError, ModelFiles,
+    StableDiffusionVersion,
 };
+use edgen_core::resident::ResidentModel;
+use edgen_core::settings::{self, RequestCaps, SafetyCheckerPolicy, PROJECT_DIRS};
 use edgen_rt_image_generation_candle::CandleImageGenerationEndpoint;
 use either::Either;
-use serde_derive::{Deserialize, Serialize};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
 use std::borrow::Cow;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
+use time::OffsetDateTime;
+use tracing::warn;
 use utoipa::ToSchema;
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct Model<'a> {
-    unet_weights: Cow<'a, str>,
-    vae_weights: Cow<'a, str>,
-    clip_weights: Cow<'a, str>,
-    /// Beware that not all models support clip2.
-    clip2_weights: Option<Cow<'a, str>>,
-    tokenizer: Cow<'a, str>,
-}
-
-/// A request to generate images for the provided context.
-/// This request is not at all conformant with OpenAI's API, as that one is very bare-bones, lacking
-/// in many parameters that we need.
-///
-/// An `axum` handler, [`image_generation`][image_generation], is provided to handle this request.
-///
-/// [image_generation]: fn.image_generation.html
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
-pub struct CreateImageGenerationRequest<'a> {
-    /// The prompt to be used to generate the image.
-    pub prompt: Cow<'a, str>,
-
-    /// The model to use for generating completions.
-    pub model: Either<Cow<'a, str>, Model<'a>>,
-
-    /// The width of the generated image.
-    pub width: Option<usize>,
-
-    /// The height of the generated image.
-    pub height: Option<usize>,
-
-    /// The optional unconditional prompt.
-    pub uncond_prompt: Option<Cow<'a, str>>,
-
-    /// The number of steps to be used in the diffusion process.
-    pub steps: Option<usize>,
-
-    /// The number of images to generate.
-    ///
-    /// Default: 1
-    pub images: Option<u32>,
-
-    /// The random number generator seed to use for the generation.
-    ///
-    /// By default, a random seed is used.
-    pub seed: Option<u64>,
-
-    /// The guidance scale to use for generation, that is, how much should the model follow the
-    /// prompt.
-    ///
-    /// Values below 1 disable guidance. (the prompt is ignored)
-    pub guidance_scale: Option<f64>,
-
-    /// The Variational Auto-Encoder scale to use for generation.
-    ///
-    /// Required if `model` is not a pre-made descriptor name.
-    ///
-    /// This value should probably not be set, if `model` is a pre-made descriptor name.
-    pub vae_scale: Option<f64>,
-}
-
-/// This request is not at all conformant with OpenAI's API, as that one returns a URL to the
-/// generated image, and that is not possible for Edgen.
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
-pub struct ImageGenerationResponse {
-    /// A vector containing the byte data of the generated images.
-    pub images: Vec<Vec<u8>>,
-}
+static ENDPOINT: Lazy<CandleImageGenerationEndpoint> = Lazy::new(Default::default);
 
 /// An error condition raised by the image generation API.
 #[derive(Serialize, Error, ToSchema, Debug)]
@@ -101,38 +51,115 @@ pub enum ImageGenerationError {
     /// Some parameter was missing from the request.
     #[error("A parameter was missing from the request: {0}")]
     MissingParam(String),
+    /// Some parameter of the request has an invalid value.
+    #[error("A parameter of the request is invalid: {0}")]
+    InvalidParam(String),
+    /// Some parameter of the request exceeds its maximum in the `request_caps` of the settings or
+    /// of the API key of the request.
+    #[error("A parameter of the request exceeds its maximum: {0}")]
+    ExceedsCap(String),
+    /// The generated images could not be stored in the generated images directory.
+    #[error("Failed to store the generated image: {0}")]
+    Storage(String),
+}
+
+impl ImageGenerationError {
+    /// Returns the status of the response of a request failing with this error.
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ImageGenerationError::MissingParam(_)
+            | ImageGenerationError::InvalidParam(_)
+            | ImageGenerationError::ExceedsCap(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl IntoResponse for ImageGenerationError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
+        request_id::error_response(self.status_code(), &self)
     }
 }
 
-/// POST `/v1/image/generations`: generate image for the provided parameters
+/// POST `/v1/image/generations`: generate images for the provided parameters.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from the **Edgen** specific members of [`CreateImageRequest`].
 ///
-/// The API of this endpoint is not at all conformant with OpenAI's API, as that one is very
-/// bare-bones, lacking  in many parameters that we need, and also returns an URL, which Edgen
-/// cannot do.
+/// [openai]: https://platform.openai.com/docs/api-reference/images/create
 ///
-/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ImageGenerationError`]
-/// to the peer.
+/// On failure, may raise a `400 Bad Request` for invalid parameters, or a
+/// `500 Internal Server Error`, with a JSON-encoded [`ImageGenerationError`] to the peer.
 #[utoipa::path(
 post,
 path = "/image/generations",
-request_body = CreateImageGenerationRequest,
+request_body = CreateImageRequest,
 responses(
-(status = 200, description = "OK", body = ImageGenerationResponse),
+(status = 200, description = "OK", body = ImagesResponse),
+(status = 400, description = "a parameter is missing, invalid or exceeds its maximum", body = ImageGenerationError),
 (status = 500, description = "unexpected internal server error", body = ImageGenerationError)
 ),
 )]
 pub async fn generate_image(
-    Json(req): Json<CreateImageGenerationRequest<'_>>,
+    uri: Uri,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    key_caps: Option<Extension<RequestCaps>>,
+    Json(req): Json<CreateImageRequest<'_>>,
 ) -> Result<impl IntoResponse, ImageGenerationError> {
+    let peer = peer.map(move |ConnectInfo(peer)| peer.ip());
+    let result = serve_image_generation(uri, headers, peer, key_caps, req).await;
+    if let Err(e) = &result {
+        if e.status_code().is_server_error() {
+            status::add_image_generation_error(e).await;
+        }
+    }
+    result
+}
+
+/// Serves a request to [`generate_image`].
+async fn serve_image_generation(
+    uri: Uri,
+    headers: HeaderMap,
+    peer: Option<IpAddr>,
+    key_caps: Option<Extension<RequestCaps>>,
+    req: CreateImageRequest<'_>,
+) -> Result<Json<ImagesResponse>, ImageGenerationError> {
+    let caps = openai_shim::request_caps(key_caps).await;
+    if let Some(max) = openai_shim::exceeds(req.n, caps.images) {
+        return Err(ImageGenerationError::ExceedsCap(format!(
+            "n must be at most {max}"
+        )));
+    }
+
+    let response_format = match req.response_format.as_deref() {
+        None | Some("url") => ResponseFormat::Url,
+        Some("b64_json") => ResponseFormat::Base64,
+        Some(format) => {
+            return Err(ImageGenerationError::InvalidParam(format!(
+                "response_format must be either \"url\" or \"b64_json\", got \"{format}\""
+            )))
+        }
+    };
+
+    let (width, height) = match &req.size {
+        Some(size) => {
+            let (width, height) = parse_size(size).ok_or_else(move || {
+                ImageGenerationError::InvalidParam(format!(
+                    "size must be in the {{width}}x{{height}} form, got \"{size}\""
+                ))
+            })?;
+            (Some(width), Some(height))
+        }
+        None => (None, None),
+    };
+
     let quantization;
     let descriptor = match req.model {
         Either::Left(template) => {
             quantization = Quantization::F16;
+            status::set_image_generation_active_model(template.as_ref()).await;
+            requests::set_model(template.as_ref()).await;
             crate::model_descriptor::get(template.as_ref())?
                 .value()
                 .clone() // Not ideal to clone, but otherwise the code complexity will greatly increase
@@ -144,6 +171,8 @@ pub async fn generate_image(
                 ));
             }
             quantization = Quantization::Default;
+            status::set_image_generation_active_model(custom.unet_weights.as_ref()).await;
+            requests::set_model(custom.unet_weights.as_ref()).await;
             let files = DashMap::new();
             files.insert(
                 quantization,
@@ -159,17 +188,24 @@ pub async fn generate_image(
                 files,
                 steps: 30,
                 vae_scale: req.vae_scale.unwrap(),
+                guidance_scale: 7.5,
+                version: StableDiffusionVersion::V2_1,
             }
         }
     };
-    let model_files;
+    let mut model_files;
     let default_steps;
     let default_vae_scale;
+    let default_guidance_scale;
     if let ModelDescriptor::StableDiffusion {
-        steps, vae_scale, ..
+        steps,
+        vae_scale,
+        guidance_scale,
+        ..
     } = descriptor
     {
         if let ModelPaths::StableDiffusion {
+            version,
             unet_weights,
             vae_weights,
             clip_weights,
@@ -178,38 +214,409 @@ pub async fn generate_image(
         } = descriptor.preload_files(quantization).await?
         {
             model_files = ModelFiles {
+                version,
                 tokenizer,
                 clip_weights,
                 clip2_weights,
                 vae_weights,
                 unet_weights,
+                safety_checker: None,
             };
         } else {
             return Err(ImageGenerationError::Unreachable);
         }
         default_steps = steps;
         default_vae_scale = vae_scale;
+        default_guidance_scale = guidance_scale;
     } else {
         return Err(ImageGenerationError::Unreachable);
     };
 
-    let endpoint = CandleImageGenerationEndpoint {};
-    let images = endpoint
+    let safety_checker = settings::image_generation_safety_checker().await;
+    if safety_checker != SafetyCheckerPolicy::Disabled {
+        model_files.safety_checker = Some(descriptor.preload_safety_checker().await?);
+    }
+
+    let images = ENDPOINT
         .generate_image(
             model_files,
             ImageGenerationArgs {
                 prompt: req.prompt.to_string(),
-                uncond_prompt: req.uncond_prompt.unwrap_or(Cow::from("")).to_string(),
-                width: req.width,
-                height: req.height,
+                uncond_prompt: req.negative_prompt.unwrap_or(Cow::from("")).to_string(),
+                width,
+                height,
                 steps: req.steps.unwrap_or(default_steps),
-                images: req.images.unwrap_or(1),
+                images: req.n.unwrap_or(1),
                 seed: req.seed,
-                guidance_scale: req.guidance_scale.unwrap_or(7.5),
+                guidance_scale: req.guidance_scale.unwrap_or(default_guidance_scale),
                 vae_scale: req.vae_scale.unwrap_or(default_vae_scale),
             },
         )
         .await?;
 
-    Ok(Json(ImageGenerationResponse { images }))
+    let base_path = settings::base_path().await;
+    let proxied = is_trusted_proxy(peer, &settings::trusted_proxies().await);
+    let mut data = vec![];
+    for image in images {
+        let nsfw_content_detected = match safety_checker {
+            SafetyCheckerPolicy::Disabled => None,
+            _ => Some(image.nsfw),
+        };
+        if image.nsfw && safety_checker == SafetyCheckerPolicy::Block {
+            data.push(Image {
+                b64_json: None,
+                url: None,
+                seed: image.seed,
+                nsfw_content_detected,
+            });
+            continue;
+        }
+
+        let image = match response_format {
+            ResponseFormat::Base64 => Image {
+                b64_json: Some(BASE64.encode(&image.data)),
+                url: None,
+                seed: image.seed,
+                nsfw_content_detected,
+            },
+            ResponseFormat::Url => {
+                let name = store_image(&image.data).await?;
+                let path = format!("{base_path}{GENERATED_IMAGES_ROUTE}/{name}");
+                let url = match request_origin(&uri, &headers, proxied) {
+                    Some(origin) => format!("{origin}{path}"),
+                    None => path,
+                };
+                Image {
+                    b64_json: None,
+                    url: Some(url),
+                    seed: image.seed,
+                    nsfw_content_detected,
+                }
+            }
+        };
+        data.push(image);
+    }
+
+    Ok(Json(ImagesResponse {
+        created: OffsetDateTime::now_utc().unix_timestamp(),
+        data,
+    }))
+}
+
+/// Returns the image generation models currently loaded into memory.
+pub async fn resident_models() -> Vec<ResidentModel> {
+    ENDPOINT.resident_models().await
+}
+
+/// The route under which generated images are served, under the `base_path` of the settings.
+const GENERATED_IMAGES_ROUTE: &str = "/v1/image/generations/files";
+
+/// The [`Duration`] for which a generated image remains available through its URL.
+const GENERATED_IMAGE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The format in which generated images are returned to the peer.
+enum ResponseFormat {
+    /// A URL to the image, served by [`get_generated_image`].
+    Url,
+    /// The base64-encoded image data.
+    Base64,
+}
+
+/// Returns the directory where generated images are stored, until their URLs expire.
+fn generated_images_dir() -> PathBuf {
+    PROJECT_DIRS.data_dir().join("generated").join("images")
+}
+
+/// Writes a generated PNG image to the generated images directory, returning its file name.
+///
+/// Any previously generated images whose URLs have expired are removed in the process.
+async fn store_image(image: &[u8]) -> Result<String, ImageGenerationError> {
+    let dir = generated_images_dir();
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(move |e| ImageGenerationError::Storage(e.to_string()))?;
+
+    remove_expired_images(&dir).await;
+
+    let name = format!("{}.png", Uuid::new_v4());
+    tokio::fs::write(dir.join(&name), image)
+        .await
+        .map_err(move |e| ImageGenerationError::Storage(e.to_string()))?;
+
+    Ok(name)
+}
+
+/// Removes every image in `dir` that is older than [`GENERATED_IMAGE_TTL`].
+async fn remove_expired_images(dir: &Path) {
+    let Ok(mut entries) = tokio::fs::read_dir(dir).await else {
+        return;
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if is_expired(&entry.path()).await {
+            if let Err(e) = tokio::fs::remove_file(entry.path()).await {
+                warn!("Failed to remove expired image {:?}: {e}", entry.path());
+            }
+        }
+    }
+}
+
+/// Returns **`true`** if the file at `path` is older than [`GENERATED_IMAGE_TTL`].
+async fn is_expired(path: &Path) -> bool {
+    match tokio::fs::metadata(path).await.and_then(|m| m.modified()) {
+        Ok(modified) => modified.elapsed().unwrap_or_default() > GENERATED_IMAGE_TTL,
+        Err(_) => true,
+    }
+}
+
+/// GET `/v1/image/generations/files/{name}`: returns a previously generated image.
+///
+/// Responds with `404 Not Found` if no image with that name exists, or if its URL has expired.
+#[utoipa::path(
+get,
+path = "/image/generations/files/{name}",
+params(("name" = String, Path, description = "The name of the image")),
+responses(
+(status = 200, description = "the PNG image"),
+(status = 404, description = "no such image, or its URL has expired")
+),
+)]
+pub async fn get_generated_image(AxumPath(name): AxumPath<String>) -> Response {
+    // Only accept the names generated by `store_image`, so that nothing outside of the generated
+    // images directory can be reached.
+    let valid = name
+        .strip_suffix(".png")
+        .map(|uuid| Uuid::parse_str(uuid).is_ok())
+        .unwrap_or(false);
+    if !valid {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let path = generated_images_dir().join(&name);
+    if is_expired(&path).await {
+        let _ = tokio::fs::remove_file(&path).await;
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match tokio::fs::read(&path).await {
+        Ok(image) => ([(CONTENT_TYPE, "image/png")], image).into_response(),
+        Err(_) => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Returns **`true`** if `peer` is one of the `trusted_proxies`.
+fn is_trusted_proxy(peer: Option<IpAddr>, trusted_proxies: &[IpAddr]) -> bool {
+    // IPv4 peers of listeners bound to IPv6 addresses are seen as IPv4-mapped IPv6 addresses
+    peer.is_some_and(move |peer| trusted_proxies.contains(&peer.to_canonical()))
+}
+
+/// Returns the origin the peer sent the request to, e.g. `https://example.com`, or [`None`] if
+/// the request does not tell its host.
+///
+/// If the request was sent by a trusted reverse proxy, the `Forwarded` and `X-Forwarded-*`
+/// headers it set come first, so that the origin is the one the peer sees, and not the one of
+/// **Edgen** behind the proxy. They are ignored otherwise, as any client could send them.
+fn request_origin(uri: &Uri, headers: &HeaderMap, proxied: bool) -> Option<String> {
+    let forwarded_host = proxied
+        .then(|| forwarded(headers, "host").or_else(|| header(headers, "x-forwarded-host")))
+        .flatten();
+    let forwarded_proto = proxied
+        .then(|| forwarded(headers, "proto").or_else(|| header(headers, "x-forwarded-proto")))
+        .flatten();
+
+    let host = forwarded_host
+        .or_else(|| header(headers, HOST.as_str()))
+        .or_else(|| uri.authority().map(|authority| authority.to_string()))?;
+    let scheme = forwarded_proto
+        .or_else(|| uri.scheme_str().map(str::to_string))
+        .unwrap_or_else(|| "http".to_string());
+
+    Some(format!("{scheme}://{host}"))
+}
+
+/// Returns a parameter of the first element of the `Forwarded` header, which was set by the proxy
+/// closest to the peer.
+fn forwarded(headers: &HeaderMap, param: &str) -> Option<String> {
+    let element = headers.get(FORWARDED)?.to_str().ok()?.split(',').next()?;
+    element.split(';').find_map(move |pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        name.eq_ignore_ascii_case(param)
+            .then(move || value.trim_matches('"').to_string())
+    })
+}
+
+/// Returns the first value of a header, which was set by the proxy closest to the peer.
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    let value = headers.get(name)?.to_str().ok()?.split(',').next()?.trim();
+    (!value.is_empty()).then(move || value.to_string())
+}
+
+/// Parses an image size in the `{width}x{height}` form.
+fn parse_size(size: &str) -> Option<(usize, usize)> {
+    let (width, height) = size.split_once('x')?;
+    let width = width.trim().parse().ok()?;
+    let height = height.trim().parse().ok()?;
+
+    if width == 0 || height == 0 {
+        return None;
+    }
+
+    Some((width, height))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use axum::routing::get;
+    use axum::Router;
+    use axum_test::TestServer;
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("512x768"), Some((512, 768)));
+        assert_eq!(parse_size("1024 x 1024"), Some((1024, 1024)));
+        assert_eq!(parse_size("512"), None);
+        assert_eq!(parse_size("0x512"), None);
+        assert_eq!(parse_size("axb"), None);
+    }
+
+    #[test]
+    fn origin() {
+        let headers = |pairs: &[(&'static str, &'static str)]| -> HeaderMap {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+                .collect()
+        };
+        let uri = Uri::from_static("/v1/image/generations");
+
+        assert_eq!(request_origin(&uri, &headers(&[]), true), None);
+        assert_eq!(
+            request_origin(&uri, &headers(&[("host", "localhost:33322")]), false).as_deref(),
+            Some("http://localhost:33322")
+        );
+
+        let x_forwarded = headers(&[
+            ("host", "localhost:33322"),
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "example.com, proxy.internal"),
+        ]);
+        assert_eq!(
+            request_origin(&uri, &x_forwarded, true).as_deref(),
+            Some("https://example.com")
+        );
+        // The forwarding headers of untrusted peers are ignored
+        assert_eq!(
+            request_origin(&uri, &x_forwarded, false).as_deref(),
+            Some("http://localhost:33322")
+        );
+
+        assert_eq!(
+            request_origin(
+                &uri,
+                &headers(&[
+                    ("host", "localhost:33322"),
+                    (
+                        "forwarded",
+                        "for=192.0.2.1;proto=https;host=\"example.com\", for=10.0.0.1"
+                    ),
+                    ("x-forwarded-proto", "http"),
+                ]),
+                true
+            )
+            .as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            request_origin(
+                &Uri::from_static("https://example.com/v1/image/generations"),
+                &headers(&[]),
+                false
+            )
+            .as_deref(),
+            Some("https://example.com")
+        );
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let trusted: Vec<IpAddr> = vec!["127.0.0.1".parse().unwrap()];
+
+        assert!(is_trusted_proxy(
+            Some("127.0.0.1".parse().unwrap()),
+            &trusted
+        ));
+        assert!(is_trusted_proxy(
+            Some("::ffff:127.0.0.1".parse().unwrap()),
+            &trusted
+        ));
+        assert!(!is_trusted_proxy(
+            Some("10.0.0.1".parse().unwrap()),
+            &trusted
+        ));
+        assert!(!is_trusted_proxy(None, &trusted));
+    }
+
+    #[test]
+    fn invalid_param_status() {
+        let response = ImageGenerationError::InvalidParam("size".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = ImageGenerationError::MissingParam("vae_scale".to_string()).into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    // This test downloads a model from huggingface and generates images with it
+    // Therefore, we usually ignore it
+    async fn repeated_generation() {
+        settings::SETTINGS
+            .write()
+            .await
+            .init()
+            .await
+            .expect("Failed to initialise settings");
+
+        // the second generation reuses the model loaded by the first one, on the same device
+        for _ in 0..2 {
+            let req: CreateImageRequest = serde_json::from_value(serde_json::json!({
+                "model": "sdxl-turbo",
+                "prompt": "A lighthouse on a cliff",
+                "size": "512x512",
+                "steps": 1,
+                "seed": 42,
+                "response_format": "b64_json",
+            }))
+            .unwrap();
+            let Json(response) =
+                serve_image_generation(Uri::from_static("/"), HeaderMap::new(), None, None, req)
+                    .await
+                    .expect("image generation failed");
+            assert_eq!(response.data.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn generated_image_not_found() {
+        let router = Router::new().route(
+            "/v1/image/generations/files/:name",
+            get(get_generated_image),
+        );
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let response = server
+            .get(&format!(
+                "/v1/image/generations/files/{}.png",
+                Uuid::new_v4()
+            ))
+            .await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+
+        let response = server
+            .get("/v1/image/generations/files/..%2F..%2Fedgen.conf.yaml")
+            .await;
+        assert_eq!(response.status_code(), StatusCode::NOT_FOUND);
+    }
 }
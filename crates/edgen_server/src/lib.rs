@@ -16,16 +16,18 @@
 #![warn(missing_docs)]
 
 use core::future::IntoFuture;
+use std::net::SocketAddr;
 use std::process::exit;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use axum::extract::DefaultBodyLimit;
 use futures::executor::block_on;
+use futures::future::BoxFuture;
+use futures::FutureExt;
 use tokio::select;
 use tokio::sync::oneshot;
 use tokio::task::JoinSet;
-use tower_http::cors::CorsLayer;
 use tracing::{error, info};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -33,26 +35,58 @@ use utoipa::OpenApi;
 
 use edgen_core::settings;
 use edgen_core::settings::SETTINGS;
+use edgen_rt_llama_cpp::quantize::{self, Quantization};
 use openai_shim as chat;
 use openai_shim as audio;
 
 #[macro_use]
 pub mod misc;
 
+mod admin;
+mod assistants;
+pub mod backends;
+mod batch;
 mod chat_faker;
 pub mod cli;
+mod coalesce;
+mod conversation;
+mod embeddings_cache;
+mod events;
+mod files;
 pub mod graceful_shutdown;
+mod idempotency;
 mod image_generation;
+mod listener;
 mod llm;
-mod model;
+mod llm_candle;
+mod mdns;
+pub mod model;
 mod model_descriptor;
 pub mod model_man;
+mod model_updates;
+mod model_watcher;
+#[cfg(windows)]
+mod npipe;
 pub mod openai_shim;
+mod remote;
+mod request_id;
+mod requests;
+mod rerank;
+mod response_cache;
+mod retrieval;
+mod router;
 mod routes;
+mod service;
 pub mod status;
+pub mod store;
 pub mod types;
 pub mod util;
+mod validation;
+mod vector_storage;
+mod vector_stores;
+mod webhooks;
 mod whisper;
+mod whisper_faker;
 
 #[derive(OpenApi)]
 #[openapi(
@@ -63,7 +97,53 @@ mod whisper;
     paths(
         misc::edgen_version,
         chat::chat_completions,
-        audio::create_transcription
+        chat::chat_completions_estimate,
+        openai_shim::create_embeddings,
+        audio::create_transcription,
+        image_generation::generate_image,
+        image_generation::get_generated_image,
+        rerank::rerank,
+        conversation::delete_conversation,
+        assistants::create_thread,
+        assistants::retrieve_thread,
+        assistants::delete_thread,
+        assistants::create_message,
+        assistants::list_messages,
+        assistants::create_run,
+        assistants::list_runs,
+        assistants::retrieve_run,
+        files::create_file,
+        files::list_files,
+        files::retrieve_file,
+        files::retrieve_file_content,
+        files::delete_file,
+        batch::create_batch,
+        batch::list_batches,
+        batch::retrieve_batch,
+        batch::cancel_batch,
+        vector_stores::create_vector_store,
+        vector_stores::list_vector_stores,
+        vector_stores::retrieve_vector_store,
+        vector_stores::delete_vector_store,
+        vector_stores::create_vector_store_file,
+        vector_stores::list_vector_store_files,
+        vector_stores::delete_vector_store_file,
+        vector_stores::search_vector_store,
+        requests::list_requests,
+        requests::cancel_request,
+        admin::pin_model,
+        status::chat_completions_status,
+        status::audio_transcriptions_status,
+        status::embeddings_status,
+        status::image_generation_status,
+        status::models_status,
+        status::status_stream,
+        events::events,
+        model_man::list_models,
+        model_man::search_models,
+        model_man::retrieve_model,
+        model_man::delete_model,
+        router::workers_status
     ),
     components(schemas(
         misc::Version,
@@ -74,6 +154,9 @@ mod whisper;
         openai_shim::ChatCompletionChunk,
         openai_shim::ChatCompletionChunkDelta,
         openai_shim::ChatCompletionChunkChoice,
+        openai_shim::ChunkToken,
+        openai_shim::ChatCompletionTimings,
+        openai_shim::ChatCompletionEstimate,
         openai_shim::ChatCompletionError,
         openai_shim::ChatMessage,
         openai_shim::ChatMessages,
@@ -88,9 +171,86 @@ mod whisper;
         openai_shim::EmbeddingsUsage,
         openai_shim::CreateTranscriptionRequest,
         openai_shim::TranscriptionResponse,
+        openai_shim::TranscriptionSegment,
         openai_shim::TranscriptionError,
+        openai_shim::ImageModelFiles,
+        openai_shim::CreateImageRequest,
+        openai_shim::ImagesResponse,
+        openai_shim::Image,
+        image_generation::ImageGenerationError,
+        rerank::RerankRequest,
+        rerank::RerankResponse,
+        rerank::RerankResult,
+        rerank::RerankDocument,
+        conversation::ConversationDeleted,
+        assistants::AssistantsError,
+        assistants::Thread,
+        assistants::CreateThreadRequest,
+        assistants::CreateMessageRequest,
+        assistants::ThreadMessage,
+        assistants::MessageContent,
+        assistants::MessageText,
+        assistants::RunStatus,
+        assistants::RunError,
+        assistants::Run,
+        assistants::CreateRunRequest,
+        assistants::MessageList,
+        assistants::RunList,
+        assistants::ThreadDeleted,
+        files::FilePurpose,
+        files::FileError,
+        files::FileObject,
+        files::CreateFileRequest,
+        files::FileList,
+        files::FileDeleted,
+        batch::BatchError,
+        batch::BatchStatus,
+        batch::BatchRequestCounts,
+        batch::BatchLineError,
+        batch::Batch,
+        batch::CreateBatchRequest,
+        batch::BatchList,
+        vector_stores::VectorStoreError,
+        vector_stores::VectorStoreFileCounts,
+        vector_stores::VectorStore,
+        vector_stores::CreateVectorStoreRequest,
+        vector_stores::VectorStoreFile,
+        vector_stores::CreateVectorStoreFileRequest,
+        vector_stores::SearchVectorStoreRequest,
+        vector_stores::SearchResult,
+        vector_stores::SearchResults,
+        vector_stores::VectorStoreList,
+        vector_stores::VectorStoreFileList,
+        vector_stores::VectorStoreDeleted,
+        retrieval::RetrievalOptions,
+        requests::RequestError,
+        requests::RequestInfo,
+        requests::RequestList,
+        requests::RequestCancelled,
+        admin::PinModelRequest,
+        admin::ModelPin,
         model::ModelError,
         model::ModelKind,
+        status::AIStatus,
+        status::DownloadStatus,
+        status::ModelsStatus,
+        status::ResidentModelStatus,
+        status::StatusEvent,
+        events::ServerEvent,
+        events::BusEvent,
+        validation::FieldError,
+        validation::ValidationError,
+        edgen_core::resident::ResidentModel,
+        edgen_core::llm::Throughput,
+        model_man::ModelDesc,
+        model_man::ModelDeletionStatus,
+        model_man::ModelList,
+        model_man::RepoFile,
+        model_man::RepoFileList,
+        router::RouterError,
+        router::WorkerStatus,
+        router::WorkersStatus,
+        listener::ListenerError,
     ))
 )]
 struct ApiDoc;
@@ -106,6 +266,8 @@ pub fn start(command: &cli::TopLevel) -> EdgenResult {
         Some(cli::Command::Config(config_args)) => config(config_args)?,
         Some(cli::Command::Version(_)) => version()?,
         Some(cli::Command::Oasgen(oasgen_args)) => oasgen(oasgen_args)?,
+        Some(cli::Command::Quantize(quantize_args)) => quantize(quantize_args)?,
+        Some(cli::Command::Models(models_args)) => models(models_args)?,
     };
 
     Ok(())
@@ -142,19 +304,155 @@ pub fn config_reset() -> EdgenResult {
 
 /// Generates the OpenAPI Spec.
 pub fn oasgen(args: &cli::Oasgen) -> EdgenResult {
-    if args.json {
-        println!("{}", ApiDoc::openapi().to_pretty_json().unwrap());
+    let spec = if args.json {
+        ApiDoc::openapi().to_pretty_json().unwrap()
     } else {
-        println!("{}", ApiDoc::openapi().to_yaml().unwrap());
+        ApiDoc::openapi().to_yaml().unwrap()
+    };
+
+    if let Some(snapshot) = &args.check {
+        let committed = std::fs::read_to_string(snapshot)?;
+        return match first_difference(&committed, &spec) {
+            None => Ok(()),
+            Some(line) => Err(types::EdgenError::GenericError(format!(
+                "The OpenAPI spec differs from {} from line {line} on; regenerate it with \
+                 `edgen oasgen --output {}`",
+                snapshot.display(),
+                snapshot.display()
+            ))),
+        };
+    }
+
+    match &args.output {
+        Some(output) => std::fs::write(output, spec + "\n")?,
+        None => println!("{spec}"),
     }
 
     Ok(())
 }
 
-// This in-between step appears pointless right now.
-// However, synchronous code that we need before
-// tokio::main should go here.
+/// Returns the number of the first line, starting at 1, that differs between two specs, if any.
+///
+/// Trailing whitespace is ignored, so that specs printed to stdout and written to files compare
+/// equal.
+fn first_difference(committed: &str, generated: &str) -> Option<usize> {
+    let committed: Vec<&str> = committed.trim_end().lines().map(str::trim_end).collect();
+    let generated: Vec<&str> = generated.trim_end().lines().map(str::trim_end).collect();
+    if committed == generated {
+        return None;
+    }
+
+    let same = committed
+        .iter()
+        .zip(&generated)
+        .take_while(|(committed, generated)| committed == generated)
+        .count();
+    Some(same + 1)
+}
+
+/// Re-quantizes a GGUF model, by default into the chat completions models directory, and registers
+/// the result in the store.
+pub fn quantize(args: &cli::Quantize) -> EdgenResult {
+    let quant: Quantization = args
+        .quant
+        .parse()
+        .map_err(move |e| types::EdgenError::GenericError(format!("{e}")))?;
+
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        SETTINGS.write().await.init().await?;
+        settings::create_project_dirs().await?;
+
+        let output = match &args.output {
+            Some(output) => output.clone(),
+            None => std::path::PathBuf::from(settings::chat_completions_dir().await)
+                .join(quantize::quantized_file_name(&args.input, quant)),
+        };
+        if output.exists() {
+            return Err(types::EdgenError::GenericError(format!(
+                "{} already exists",
+                output.display()
+            )));
+        }
+
+        println!(
+            "Quantizing {} to {quant}, this may take a few minutes...",
+            args.input.display()
+        );
+        let input = args.input.clone();
+        let quantized = output.clone();
+        tokio::task::spawn_blocking(move || quantize::quantize(&input, &quantized, quant, 0))
+            .await?
+            .map_err(move |e| types::EdgenError::GenericError(format!("{e}")))?;
+
+        let id = output
+            .file_name()
+            .map(move |name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let entry = serde_json::json!({
+            "path": output,
+            "source": args.input,
+            "quantization": quant.name(),
+            "size": std::fs::metadata(&output)?.len(),
+        });
+        let stored = match store::get() {
+            Ok(store) => store.put_model(&id, &entry).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = stored {
+            eprintln!("Failed to register the quantized model: {e}");
+        }
+
+        println!("{}", output.display());
+        Ok::<(), types::EdgenError>(())
+    })
+}
+
+fn models(models_args: &cli::Models) -> EdgenResult {
+    match &models_args.subcommand {
+        cli::ModelsCommand::Upgrade(_) => models_upgrade()?,
+    };
+
+    Ok(())
+}
+
+/// Downloads the updated revisions of the configured models, if their upstream repositories have
+/// any.
+pub fn models_upgrade() -> EdgenResult {
+    let rt = tokio::runtime::Runtime::new()?;
+    rt.block_on(async {
+        SETTINGS.write().await.init().await?;
+
+        let updates = model_updates::check().await;
+        if updates.is_empty() {
+            println!("All models are up to date");
+        }
+
+        for update in updates {
+            println!(
+                "Downloading {}/{} at revision {}...",
+                update.model.repo, update.model.file, update.upstream
+            );
+            let path = model_updates::upgrade(&update)
+                .await
+                .map_err(move |e| types::EdgenError::GenericError(format!("{e}")))?;
+            println!("{}", path.display());
+        }
+
+        Ok::<(), types::EdgenError>(())
+    })
+}
+
+// Synchronous code that we need before tokio::main goes here.
 fn serve(args: &cli::Serve) -> EdgenResult {
+    if args.service {
+        return service::run_windows_service();
+    }
+
+    if args.daemon {
+        return service::daemonize();
+    }
+
     start_server(args)
 }
 
@@ -174,6 +472,7 @@ async fn start_server(args: &cli::Serve) -> EdgenResult {
         .with(filter)
         .init();
 
+    settings::select_config_file(args.config.as_deref(), args.profile.as_deref())?;
     SETTINGS
         .write()
         .await
@@ -185,10 +484,27 @@ async fn start_server(args: &cli::Serve) -> EdgenResult {
 
     model_descriptor::init();
 
+    if let Err(e) = store::init().await {
+        error!("Failed to open the state store: {e}");
+    }
+    model_updates::spawn_checks().await;
+    status::spawn_residency_poll();
+    batch::resume().await;
+    assistants::resume().await;
+
+    let _pidfile = match &args.pidfile {
+        Some(path) => Some(service::Pidfile::create(path)?),
+        None => None,
+    };
+    let _watchdog = service::spawn_watchdog();
+
     while run_server(args).await? {
-        info!("Settings have been updated, resetting environment")
+        info!("Settings have been updated, resetting environment");
+        service::notify_reloading();
     }
 
+    service::notify_stopping();
+
     Ok(())
 }
 
@@ -216,11 +532,17 @@ async fn run_server(args: &cli::Serve) -> Result<bool, types::EdgenError> {
     status::set_embeddings_active_model(&SETTINGS.read().await.read().await.embeddings_model_name)
         .await;
 
-    let http_app = routes::routes()
-        .layer(CorsLayer::permissive())
-        .layer(DefaultBodyLimit::max(
-            SETTINGS.read().await.read().await.max_request_size,
-        ));
+    model_watcher::watch().await;
+    webhooks::start().await;
+
+    let workers = settings::router_workers().await;
+    let routes = if workers.is_empty() {
+        routes::routes(&settings::request_size_limits().await)
+    } else {
+        router::routes(workers)
+    };
+    let base_path = settings::base_path().await;
+    let max_request_size = SETTINGS.read().await.read().await.max_request_size;
 
     let uri_vector = if !args.uri.is_empty() {
         info!("Overriding default URI");
@@ -232,36 +554,79 @@ async fn run_server(args: &cli::Serve) -> Result<bool, types::EdgenError> {
 
     let mut all_listeners = JoinSet::new();
     let mut reset_channels = vec![];
+    let mut bound_uris = vec![];
 
     for uri in &uri_vector {
-        let listener = match uri {
+        let options = settings::listener_settings(uri).await;
+        let mut http_app =
+            routes::with_base_path(listener::restrict(routes.clone(), &options), &base_path);
+        if let Some(cors) = listener::cors(&options) {
+            http_app = http_app.layer(cors);
+        }
+        let http_app = http_app.layer(DefaultBodyLimit::max(max_request_size));
+
+        // The URI actually bound, with the assigned port if the port of `uri` is 0
+        let (bound_uri, server): (String, BoxFuture<'static, std::io::Result<()>>) = match uri {
             uri if uri.starts_with("unix://") => Err(types::EdgenError::GenericError(
                 "unix:// URIs are not supported".to_string(),
             )),
             uri if uri.starts_with("http://") => {
                 let addr = uri.strip_prefix("http://").unwrap();
-                Ok(tokio::net::TcpListener::bind(addr).await?)
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                let bound_uri = format!("http://{}", listener.local_addr()?);
+                Ok((
+                    bound_uri,
+                    axum::serve(
+                        listener,
+                        http_app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .into_future()
+                    .boxed(),
+                ))
             }
             uri if uri.starts_with("ws://") => {
                 let addr = uri.strip_prefix("ws://").unwrap();
 
-                Ok(tokio::net::TcpListener::bind(addr).await?)
+                let listener = tokio::net::TcpListener::bind(addr).await?;
+                let bound_uri = format!("ws://{}", listener.local_addr()?);
+                Ok((
+                    bound_uri,
+                    axum::serve(
+                        listener,
+                        http_app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .into_future()
+                    .boxed(),
+                ))
+            }
+            #[cfg(windows)]
+            uri if uri.starts_with("npipe://") => {
+                let name = npipe::pipe_name(uri.strip_prefix("npipe://").unwrap());
+                let first = npipe::bind(&name)?;
+                Ok((uri.clone(), npipe::serve(name, first, http_app).boxed()))
             }
+            #[cfg(not(windows))]
+            uri if uri.starts_with("npipe://") => Err(types::EdgenError::GenericError(
+                "npipe:// URIs are only supported on Windows".to_string(),
+            )),
             _ => Err(types::EdgenError::GenericError(format!(
-                "Unsupported URI schema: {uri}. unix://, http://, and ws:// are supported."
+                "Unsupported URI schema: {uri}. unix://, npipe://, http://, and ws:// are \
+                 supported."
             ))),
         }?;
 
-        info!("Listening in on: {uri}");
+        info!("Listening in on: {bound_uri}");
+        // Printed regardless of the log level, for applications launching Edgen on port 0
+        println!("Listening on {bound_uri}");
+        bound_uris.push(bound_uri);
 
-        let http_app = http_app.clone();
         let (reset_tx, reset_rx) = oneshot::channel::<()>();
         reset_channels.push(reset_tx);
 
         all_listeners.spawn(async move {
             let mut reset_rx = reset_rx;
             select! {
-                bind_res = axum::serve(listener, http_app).into_future() => {
+                bind_res = server => {
                     bind_res
                         .unwrap_or_else(|err| {
                             error!("Could not bind HTTP server: {err}");
@@ -273,6 +638,10 @@ async fn run_server(args: &cli::Serve) -> Result<bool, types::EdgenError> {
         });
     }
 
+    mdns::announce(&bound_uris).await;
+    misc::set_listening_uris(bound_uris);
+    service::notify_ready();
+
     let reset_flag = Arc::new(AtomicBool::new(false));
     let flag_clone = reset_flag.clone();
 
@@ -280,9 +649,9 @@ async fn run_server(args: &cli::Serve) -> Result<bool, types::EdgenError> {
         let rt = tokio::runtime::Runtime::new().unwrap();
         let _guard = rt.enter();
         flag_clone.store(true, Ordering::SeqCst);
+        events::publish(events::ServerEvent::SettingsReloaded);
         reset_channels.clear();
-        block_on(crate::llm::reset_environment());
-        block_on(crate::whisper::reset_environment());
+        block_on(crate::backends::BACKENDS.reset());
         block_on(async {
             status::set_chat_completions_active_model(
                 &SETTINGS
@@ -328,6 +697,21 @@ async fn run_server(args: &cli::Serve) -> Result<bool, types::EdgenError> {
         }
     }
 
+    mdns::withdraw();
+    model_watcher::unwatch();
+    webhooks::stop();
+
+    if graceful_shutdown::is_shutting_down() {
+        select! {
+            _ = graceful_shutdown::run_hooks() => {}
+            _ = graceful_shutdown::global_shutdown_ends() => {
+                error!("Global shutdown grace period has ended before the shutdown hooks completed; exiting abnormally");
+
+                exit(1)
+            }
+        }
+    }
+
     Ok(reset_flag.load(Ordering::SeqCst))
 }
 
@@ -337,7 +721,7 @@ mod tests {
     use std::io::Write;
     use std::path::Path;
 
-    use axum::routing::post;
+    use axum::routing::{get, post};
     use axum::Router;
     use axum_test::multipart;
     use axum_test::TestServer;
@@ -345,6 +729,7 @@ mod tests {
     use serde_json::from_str;
 
     use edgen_rt_chat_faker as chat_faker;
+    use edgen_rt_whisper_faker as whisper_faker;
 
     use crate::openai_shim::{ChatCompletion, ChatMessage, TranscriptionResponse};
 
@@ -428,6 +813,16 @@ mod tests {
         }
     }
 
+    async fn create_whisper_fake_model_file() {
+        let path_string = settings::audio_transcriptions_dir().await;
+        let path = Path::new(&path_string).join("fake-whisper.fake");
+        if !path.exists() {
+            let mut file = File::create(path).expect("cannot create fake model");
+            file.write_all(b"this is for testing")
+                .expect("cannot write to fake model");
+        }
+    }
+
     fn poor_mans_stream_processor(stream: &str) -> String {
         let mut answer = String::new();
         let mut next_one = false;
@@ -568,6 +963,103 @@ mod tests {
         assert_eq!(txt.text(), input);
     }
 
+    #[tokio::test]
+    async fn test_axum_fake_transcriptions() {
+        init_settings_for_test().await;
+        create_whisper_fake_model_file().await;
+
+        let router = Router::new().route(
+            "/v1/audio/transcriptions",
+            post(openai_shim::create_transcription),
+        );
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let sound = include_bytes!("../resources/frost.wav");
+        let mp = multipart::MultipartForm::new()
+            .add_text("model", "fake-whisper.fake")
+            .add_part(
+                "file",
+                multipart::Part::bytes(sound.as_slice()).file_name(&"frost.wav"),
+            );
+        let resp = server
+            .post("/v1/audio/transcriptions")
+            .content_type(&"multipart/form-data")
+            .multipart(mp)
+            .await;
+
+        resp.assert_status_ok();
+        let transcription = resp.json::<TranscriptionResponse>();
+        assert_eq!(transcription.text, whisper_faker::DEFAULT_TRANSCRIPTION);
+        assert!(transcription.segments.is_none());
+
+        let mp = multipart::MultipartForm::new()
+            .add_text("model", "fake-whisper.fake")
+            .add_text("response_format", "verbose_json")
+            .add_part(
+                "file",
+                multipart::Part::bytes(sound.as_slice()).file_name(&"frost.wav"),
+            );
+        let resp = server
+            .post("/v1/audio/transcriptions")
+            .content_type(&"multipart/form-data")
+            .multipart(mp)
+            .await;
+
+        resp.assert_status_ok();
+        let segments = resp.json::<TranscriptionResponse>().segments.unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].text, whisper_faker::DEFAULT_TRANSCRIPTION);
+        assert_eq!(segments[0].start, 0.0);
+        assert!(segments[0].end > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_axum_resident_models() {
+        init_settings_for_test().await;
+        create_whisper_fake_model_file().await;
+
+        let router = Router::new()
+            .route(
+                "/v1/audio/transcriptions",
+                post(openai_shim::create_transcription),
+            )
+            .route("/v1/status/models", get(status::models_status));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let sound = include_bytes!("../resources/frost.wav");
+        let mp = multipart::MultipartForm::new()
+            .add_text("model", "fake-whisper.fake")
+            .add_part(
+                "file",
+                multipart::Part::bytes(sound.as_slice()).file_name(&"frost.wav"),
+            );
+        server
+            .post("/v1/audio/transcriptions")
+            .content_type(&"multipart/form-data")
+            .multipart(mp)
+            .await
+            .assert_status_ok();
+
+        let resp = server.get("/v1/status/models").await;
+        resp.assert_status_ok();
+
+        let status: serde_json::Value = resp.json();
+        let models = status["models"].as_array().expect("no models listed");
+        let model = models
+            .iter()
+            .find(move |model| model["kind"] == "WhisperFaker")
+            .expect("fake whisper model not listed");
+        assert!(model["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("fake-whisper.fake"));
+        assert_eq!(model["size"], b"this is for testing".len());
+        assert_eq!(model["device"], "cpu");
+        assert_eq!(model["in_flight"], 0);
+    }
+
     #[tokio::test]
     // Note that the model must exist in the model path,
     // otherwise the test fails.
@@ -614,4 +1106,12 @@ mod tests {
             "Text similarity is less than 90%"
         );
     }
+
+    #[test]
+    fn spec_differences() {
+        assert_eq!(first_difference("a\nb\n", "a\nb"), None);
+        assert_eq!(first_difference("a\nb", "a\nc"), Some(2));
+        assert_eq!(first_difference("a\nb", "a\nb\nc"), Some(3));
+        assert_eq!(first_difference("a", "b"), Some(1));
+    }
 }
@@ -0,0 +1,363 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The options of each listener, as set in the `listeners` setting.
+//!
+//! Every listener serves the same routes, which are restricted according to the options of the
+//! listener by [`guard`], so that e.g. `localhost` is open while a listener exposed to the local
+//! network requires API keys and hides the administration routes.
+
+use std::sync::Arc;
+
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderValue, Method, StatusCode};
+use axum::middleware::{from_fn_with_state, Next};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use serde_derive::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
+use utoipa::ToSchema;
+
+use edgen_core::settings::{ApiKey, CorsPolicy, KeyModels, KeyRole, ListenerSettings, RequestCaps};
+
+use crate::request_id;
+
+/// The path prefixes of the administration routes.
+const ADMIN_PREFIXES: &[&str] = &["/v1/admin", "/v1/requests"];
+
+/// The path prefix of the models, which may only be deleted with [`KeyRole::Admin`] keys.
+const MODELS_PREFIX: &str = "/v1/models";
+
+/// The API key a request was sent with, added to its extensions, so that the objects created with
+/// a key, like stored conversations, can only be accessed with the same key.
+///
+/// It holds the [`blake3`] hash of the key, so that keys are never stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyOwner(pub String);
+
+impl KeyOwner {
+    fn of(api_key: &ApiKey) -> Self {
+        Self(blake3::hash(api_key.key().as_bytes()).to_hex().to_string())
+    }
+}
+
+/// An error condition raised by a listener.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum ListenerError {
+    /// The request does not have one of the API keys of the listener as a bearer token.
+    #[error("a valid API key is required")]
+    Unauthorized,
+    /// The API key of the request does not have the role required by the route.
+    #[error("the API key is not allowed to use this route")]
+    Forbidden,
+}
+
+impl IntoResponse for ListenerError {
+    fn into_response(self) -> Response {
+        match self {
+            ListenerError::Unauthorized => {
+                let mut response = request_id::error_response(StatusCode::UNAUTHORIZED, &self);
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                response
+            }
+            ListenerError::Forbidden => request_id::error_response(StatusCode::FORBIDDEN, &self),
+        }
+    }
+}
+
+/// Restricts `routes` according to the options of their listener.
+///
+/// `routes` must not be nested under the `base_path` yet, as the routes are matched without it.
+pub fn restrict(routes: Router, options: &ListenerSettings) -> Router {
+    if options.api_keys.is_empty() && options.admin && options.allowed_endpoints.is_empty() {
+        return routes;
+    }
+
+    routes.layer(from_fn_with_state(Arc::new(options.clone()), guard))
+}
+
+/// Returns the CORS layer of a listener, if it sends CORS headers.
+pub fn cors(options: &ListenerSettings) -> Option<CorsLayer> {
+    match &options.cors {
+        CorsPolicy::Permissive => Some(CorsLayer::permissive()),
+        CorsPolicy::Disabled => None,
+        CorsPolicy::Origins(origins) => {
+            let origins: Vec<HeaderValue> = origins
+                .iter()
+                .filter_map(move |origin| match HeaderValue::from_str(origin) {
+                    Ok(origin) => Some(origin),
+                    Err(_) => {
+                        warn!("Ignoring invalid CORS origin {origin:?} of {}", options.uri);
+                        None
+                    }
+                })
+                .collect();
+            Some(CorsLayer::permissive().allow_origin(AllowOrigin::list(origins)))
+        }
+    }
+}
+
+/// An `axum` middleware rejecting the requests that the options of the listener do not allow.
+///
+/// Routes that are not served are answered with `404 Not Found`, like unknown routes, so that
+/// they are hidden from the clients of the listener. Requests whose API key has the
+/// [`KeyRole::Inference`] role are answered with `403 Forbidden` on the administration routes.
+/// The [`KeyOwner`], [`KeyModels`] and [`RequestCaps`] of the API key of a request, if any, are
+/// added to its extensions.
+async fn guard(
+    State(options): State<Arc<ListenerSettings>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let path = req.uri().path();
+    if !serves(&options, path) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    if !options.api_keys.is_empty() {
+        let key = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(move |value| value.to_str().ok())
+            .and_then(move |value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+        let Some(api_key) = key.and_then(|key| find_key(&options.api_keys, key)) else {
+            return ListenerError::Unauthorized.into_response();
+        };
+        if api_key.role() == KeyRole::Inference && is_admin(req.method(), path) {
+            return ListenerError::Forbidden.into_response();
+        }
+        req.extensions_mut().insert(KeyOwner::of(api_key));
+        if let Some(models) = api_key.models() {
+            req.extensions_mut().insert(models.clone());
+        }
+        if let Some(caps) = api_key.caps() {
+            req.extensions_mut().insert(caps.clone());
+        }
+    }
+
+    next.run(req).await
+}
+
+/// Returns the API key matching `key`, if any.
+///
+/// Every key is compared in constant time, so that the response time does not reveal how much of
+/// a key a request got right.
+fn find_key<'a>(api_keys: &'a [ApiKey], key: &str) -> Option<&'a ApiKey> {
+    api_keys.iter().fold(None, move |found, api_key| {
+        if bool::from(api_key.key().as_bytes().ct_eq(key.as_bytes())) {
+            Some(api_key)
+        } else {
+            found
+        }
+    })
+}
+
+/// Returns `true` if the listener with `options` serves the route at `path`.
+fn serves(options: &ListenerSettings, path: &str) -> bool {
+    if !options.admin && ADMIN_PREFIXES.iter().any(move |prefix| under(path, prefix)) {
+        return false;
+    }
+
+    options.allowed_endpoints.is_empty()
+        || options
+            .allowed_endpoints
+            .iter()
+            .any(move |prefix| under(path, prefix))
+}
+
+/// Returns `true` if `method` on `path` is an administration request, which only
+/// [`KeyRole::Admin`] keys may send.
+fn is_admin(method: &Method, path: &str) -> bool {
+    ADMIN_PREFIXES.iter().any(move |prefix| under(path, prefix))
+        || method == Method::DELETE && under(path, MODELS_PREFIX)
+}
+
+/// Returns `true` if `path` is `prefix` or below it.
+fn under(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path == prefix || path.starts_with(prefix) && path[prefix.len()..].starts_with('/')
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::{delete, get, post};
+    use axum::Extension;
+    use axum_test::TestServer;
+
+    use edgen_core::settings::ApiKey;
+
+    use super::*;
+
+    fn options() -> ListenerSettings {
+        ListenerSettings {
+            api_keys: vec![
+                ApiKey::Key("secret".to_string()),
+                ApiKey::WithOptions {
+                    key: "app".to_string(),
+                    role: KeyRole::Inference,
+                    models: KeyModels {
+                        chat_completions_model: Some("app-model.gguf".to_string()),
+                        embeddings_model: None,
+                    },
+                    caps: RequestCaps::default(),
+                },
+            ],
+            admin: false,
+            allowed_endpoints: vec!["/v1/chat".to_string(), "/v1/admin".to_string()],
+            ..ListenerSettings::open("http://0.0.0.0:33322")
+        }
+    }
+
+    #[test]
+    fn served_routes() {
+        let options = options();
+        assert!(serves(&options, "/v1/chat/completions"));
+        assert!(!serves(&options, "/v1/chatter"));
+        assert!(!serves(&options, "/v1/embeddings"));
+        assert!(!serves(&options, "/v1/admin/models/pin"));
+        assert!(serves(
+            &ListenerSettings::open("http://127.0.0.1:33322"),
+            "/v1/admin/models/pin"
+        ));
+    }
+
+    #[tokio::test]
+    async fn api_keys() {
+        let router = Router::new().route("/v1/chat/completions", get(|| async { "completion" }));
+        let server =
+            TestServer::new(restrict(router, &options())).expect("cannot instantiate TestServer");
+
+        server
+            .get("/v1/chat/completions")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/v1/chat/completions")
+            .add_header(
+                header::AUTHORIZATION,
+                HeaderValue::from_static("Bearer wrong"),
+            )
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/v1/chat/completions")
+            .add_header(
+                header::AUTHORIZATION,
+                HeaderValue::from_static("Bearer secret"),
+            )
+            .await
+            .assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn key_models() {
+        let router = Router::new().route(
+            "/v1/chat/completions",
+            get(|models: Option<Extension<KeyModels>>| async move {
+                models
+                    .and_then(move |Extension(models)| models.chat_completions_model)
+                    .unwrap_or_default()
+            }),
+        );
+        let server =
+            TestServer::new(restrict(router, &options())).expect("cannot instantiate TestServer");
+
+        server
+            .get("/v1/chat/completions")
+            .add_header(
+                header::AUTHORIZATION,
+                HeaderValue::from_static("Bearer app"),
+            )
+            .await
+            .assert_text("app-model.gguf");
+        server
+            .get("/v1/chat/completions")
+            .add_header(
+                header::AUTHORIZATION,
+                HeaderValue::from_static("Bearer secret"),
+            )
+            .await
+            .assert_text("");
+    }
+
+    #[test]
+    fn key_owners() {
+        let options = options();
+        let secret = KeyOwner::of(&options.api_keys[0]);
+        let app = KeyOwner::of(&options.api_keys[1]);
+
+        assert_ne!(secret, app);
+        assert_eq!(secret, KeyOwner::of(&ApiKey::Key("secret".to_string())));
+        assert!(!secret.0.contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn key_roles() {
+        let router = Router::new()
+            .route("/v1/chat/completions", post(|| async { "completion" }))
+            .route("/v1/admin/models/pin", post(|| async { "pinned" }))
+            .route(
+                "/v1/models/:model",
+                get(|| async { "model" }).merge(delete(|| async { "deleted" })),
+            );
+        let options = ListenerSettings {
+            admin: true,
+            allowed_endpoints: vec![],
+            ..options()
+        };
+        let server =
+            TestServer::new(restrict(router, &options)).expect("cannot instantiate TestServer");
+
+        let inference = HeaderValue::from_static("Bearer app");
+        let admin = HeaderValue::from_static("Bearer secret");
+
+        server
+            .post("/v1/chat/completions")
+            .add_header(header::AUTHORIZATION, inference.clone())
+            .await
+            .assert_status_ok();
+        server
+            .get("/v1/models/chat.gguf")
+            .add_header(header::AUTHORIZATION, inference.clone())
+            .await
+            .assert_status_ok();
+        server
+            .post("/v1/admin/models/pin")
+            .add_header(header::AUTHORIZATION, inference.clone())
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        server
+            .delete("/v1/models/chat.gguf")
+            .add_header(header::AUTHORIZATION, inference)
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+
+        server
+            .post("/v1/admin/models/pin")
+            .add_header(header::AUTHORIZATION, admin.clone())
+            .await
+            .assert_status_ok();
+        server
+            .delete("/v1/models/chat.gguf")
+            .add_header(header::AUTHORIZATION, admin)
+            .await
+            .assert_status_ok();
+    }
+}
@@ -10,69 +10,118 @@
  * limitations under the License.
  */
 
-use futures::Stream;
 use once_cell::sync::Lazy;
 
-use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError};
-use edgen_rt_llama_cpp::LlamaCppEndpoint;
+use edgen_core::embeddings::{EmbeddingsArgs, EmbeddingsEndpoint};
+use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError, PromptEstimate, Throughput};
+use edgen_core::resident::ResidentModel;
+use edgen_rt_llama_cpp::{LlamaCppEmbeddingsEndpoint, LlamaCppEndpoint};
 
+use crate::backends::{ChatBackend, CompletionStream, EmbeddingsBackend};
 use crate::model::Model;
 use crate::util::StoppingStream;
 
 static ENDPOINT: Lazy<LlamaCppEndpoint> = Lazy::new(Default::default);
+static EMBEDDINGS_ENDPOINT: Lazy<LlamaCppEmbeddingsEndpoint> = Lazy::new(Default::default);
 
-pub async fn chat_completion(
-    model: Model,
-    args: CompletionArgs,
-) -> Result<String, LLMEndpointError> {
-    ENDPOINT
-        .chat_completions(
-            model
-                .file_path()
-                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            args,
-        )
-        .await
-}
+/// The [`ChatBackend`] running GGUF models with `llama.cpp`.
+pub struct LlamaCppBackend;
 
-pub async fn chat_completion_stream(
-    model: Model,
-    args: CompletionArgs,
-) -> Result<StoppingStream<Box<dyn Stream<Item = String> + Unpin + Send>>, LLMEndpointError> {
-    let stream = ENDPOINT
-        .stream_chat_completions(
-            model
-                .file_path()
-                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            args,
-        )
-        .await?;
-
-    Ok(StoppingStream::wrap_with_stop_words(
-        stream,
-        vec![
-            "<|ASSISTANT|>".to_string(),
-            "<|USER|>".to_string(),
-            "<|TOOL|>".to_string(),
-            "<|SYSTEM|>".to_string(),
-        ],
-    ))
-}
+#[async_trait::async_trait]
+impl ChatBackend for LlamaCppBackend {
+    async fn chat_completion(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<String, LLMEndpointError> {
+        ENDPOINT
+            .chat_completions(
+                model
+                    .file_path()
+                    .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<CompletionStream, LLMEndpointError> {
+        let stream = ENDPOINT
+            .stream_chat_completions(
+                model
+                    .file_path()
+                    .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await?;
+
+        Ok(StoppingStream::wrap_with_stop_words(
+            stream,
+            vec![
+                "<|ASSISTANT|>".to_string(),
+                "<|USER|>".to_string(),
+                "<|TOOL|>".to_string(),
+                "<|SYSTEM|>".to_string(),
+            ],
+        ))
+    }
+
+    async fn estimate_prompt(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<PromptEstimate, LLMEndpointError> {
+        ENDPOINT
+            .estimate_prompt(
+                model
+                    .file_path()
+                    .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await
+    }
 
-pub async fn embeddings(
-    model: Model,
-    input: Vec<String>,
-) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
-    ENDPOINT
-        .embeddings(
-            model
-                .file_path()
-                .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
-            input,
-        )
-        .await
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        ENDPOINT.resident_models().await
+    }
+
+    fn throughput(&self) -> Throughput {
+        ENDPOINT.throughput()
+    }
+
+    async fn reset(&self) {
+        ENDPOINT.reset()
+    }
 }
 
-pub async fn reset_environment() {
-    ENDPOINT.reset()
+/// The [`EmbeddingsBackend`] running GGUF models with `llama.cpp`.
+pub struct LlamaCppEmbeddingsBackend;
+
+#[async_trait::async_trait]
+impl EmbeddingsBackend for LlamaCppEmbeddingsBackend {
+    async fn embeddings(
+        &self,
+        model: Model,
+        args: EmbeddingsArgs,
+    ) -> Result<Vec<Vec<f32>>, LLMEndpointError> {
+        EMBEDDINGS_ENDPOINT
+            .embeddings(
+                model
+                    .file_path()
+                    .map_err(move |e| LLMEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        EMBEDDINGS_ENDPOINT.resident_models().await
+    }
+
+    async fn reset(&self) {
+        EMBEDDINGS_ENDPOINT.reset()
+    }
 }
@@ -0,0 +1,87 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::PathBuf;
+
+use once_cell::sync::Lazy;
+
+use edgen_core::llm::{CompletionArgs, LLMEndpoint, LLMEndpointError};
+use edgen_core::resident::ResidentModel;
+use edgen_rt_llm_candle::CandleLLMEndpoint;
+
+use crate::backends::{ChatBackend, CompletionStream};
+use crate::model::Model;
+use crate::types::Endpoint;
+use crate::util::StoppingStream;
+
+static ENDPOINT: Lazy<CandleLLMEndpoint> = Lazy::new(Default::default);
+
+/// The [`ChatBackend`] running safetensors models with `candle`.
+pub struct CandleLLMBackend;
+
+#[async_trait::async_trait]
+impl ChatBackend for CandleLLMBackend {
+    async fn chat_completion(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<String, LLMEndpointError> {
+        ENDPOINT
+            .chat_completions(preload_companions(&model).await?, args)
+            .await
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        model: Model,
+        args: CompletionArgs,
+    ) -> Result<CompletionStream, LLMEndpointError> {
+        let stream = ENDPOINT
+            .stream_chat_completions(preload_companions(&model).await?, args)
+            .await?;
+
+        Ok(StoppingStream::wrap_with_stop_words(
+            stream,
+            vec![
+                "<|ASSISTANT|>".to_string(),
+                "<|USER|>".to_string(),
+                "<|TOOL|>".to_string(),
+                "<|SYSTEM|>".to_string(),
+            ],
+        ))
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        ENDPOINT.resident_models().await
+    }
+
+    async fn reset(&self) {
+        ENDPOINT.reset()
+    }
+}
+
+/// Makes sure the configuration, tokenizer and any weight shards of the model are present next to
+/// its weights file, returning the path to the weights file.
+async fn preload_companions(model: &Model) -> Result<PathBuf, LLMEndpointError> {
+    let path = model
+        .file_path()
+        .map_err(move |e| LLMEndpointError::Load(e.to_string()))?;
+
+    for file in edgen_rt_llm_candle::companion_files(&path)? {
+        model
+            .preload_sibling(&file, Endpoint::ChatCompletions)
+            .await
+            .map_err(move |e| LLMEndpointError::Load(e.to_string()))?;
+    }
+
+    Ok(path)
+}
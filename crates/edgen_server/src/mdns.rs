@@ -0,0 +1,179 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Announcement of the listeners on the local network via mDNS/DNS-SD, when `mdns` is enabled.
+//!
+//! Every listener reachable from the network is announced as an `_edgen._tcp` service, whose TXT
+//! record holds the version of Edgen, the base path of the routes and the endpoints that have a
+//! model configured, so that clients can pick an instance without querying it first.
+
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tracing::{info, warn};
+
+use edgen_core::settings;
+use edgen_core::settings::SETTINGS;
+
+use crate::cargo_crate_version;
+
+/// The DNS-SD service type of Edgen.
+const SERVICE_TYPE: &str = "_edgen._tcp.local.";
+
+/// The daemon announcing the listeners, if any.
+static DAEMON: Mutex<Option<ServiceDaemon>> = Mutex::new(None);
+
+/// Announces the listeners bound to `uris` that are reachable from the network, if `mdns` is
+/// enabled, withdrawing the listeners announced previously.
+///
+/// Failing to announce the listeners is not fatal, the errors are logged.
+pub async fn announce(uris: &[String]) {
+    withdraw();
+
+    if !SETTINGS.read().await.read().await.mdns {
+        return;
+    }
+
+    let addrs: Vec<(&str, SocketAddr)> = uris
+        .iter()
+        .filter_map(move |uri| Some((scheme(uri)?, announced_addr(uri)?)))
+        .collect();
+    if addrs.is_empty() {
+        warn!("mdns is enabled, but no listener is reachable from the network");
+        return;
+    }
+
+    let daemon = match ServiceDaemon::new() {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            warn!("Failed to start the mDNS daemon: {e}");
+            return;
+        }
+    };
+
+    let host = match hostname::get() {
+        Ok(host) => host.to_string_lossy().to_string(),
+        Err(e) => {
+            warn!("Failed to get the host name to announce via mDNS: {e}");
+            return;
+        }
+    };
+    let base_path = settings::base_path().await;
+    let capabilities = capabilities().await;
+
+    for (scheme, addr) in addrs {
+        let instance = format!("Edgen on {host} ({})", addr.port());
+        let properties = [
+            ("version", cargo_crate_version!()),
+            ("scheme", scheme),
+            ("base_path", base_path.as_str()),
+            ("capabilities", capabilities.as_str()),
+        ];
+
+        let info = if addr.ip().is_unspecified() {
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &instance,
+                &format!("{host}.local."),
+                "",
+                addr.port(),
+                &properties[..],
+            )
+            .map(ServiceInfo::enable_addr_auto)
+        } else {
+            ServiceInfo::new(
+                SERVICE_TYPE,
+                &instance,
+                &format!("{host}.local."),
+                addr.ip(),
+                addr.port(),
+                &properties[..],
+            )
+        };
+
+        match info.and_then(|info| daemon.register(info)) {
+            Ok(()) => info!("Announcing {scheme}://{addr} via mDNS as {instance:?}"),
+            Err(e) => warn!("Failed to announce {scheme}://{addr} via mDNS: {e}"),
+        }
+    }
+
+    *DAEMON.lock().unwrap() = Some(daemon);
+}
+
+/// Withdraws the announced listeners, if any.
+pub fn withdraw() {
+    if let Some(daemon) = DAEMON.lock().unwrap().take() {
+        if let Err(e) = daemon.shutdown() {
+            warn!("Failed to stop the mDNS daemon: {e}");
+        }
+    }
+}
+
+/// Returns the scheme of `uri` if its listener can be announced.
+fn scheme(uri: &str) -> Option<&'static str> {
+    if uri.starts_with("http://") {
+        Some("http")
+    } else if uri.starts_with("ws://") {
+        Some("ws")
+    } else {
+        None
+    }
+}
+
+/// Returns the address of the listener bound to `uri` if it is reachable from the network, i.e.
+/// it is a TCP listener that is not bound to a loopback address.
+fn announced_addr(uri: &str) -> Option<SocketAddr> {
+    let addr = uri.split_once("://")?.1.trim_end_matches('/');
+    let addr: SocketAddr = addr.parse().ok()?;
+    Some(addr).filter(move |addr| !addr.ip().is_loopback())
+}
+
+/// Returns the endpoints that have a model configured, separated by commas.
+async fn capabilities() -> String {
+    let endpoints = [
+        ("chat_completions", settings::chat_completions_name().await),
+        (
+            "audio_transcriptions",
+            settings::audio_transcriptions_name().await,
+        ),
+        ("embeddings", settings::embeddings_name().await),
+    ];
+
+    endpoints
+        .iter()
+        .filter(|(_, model)| !model.is_empty())
+        .map(move |(endpoint, _)| *endpoint)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announced_addrs() {
+        assert_eq!(
+            announced_addr("http://0.0.0.0:33322"),
+            Some("0.0.0.0:33322".parse().unwrap())
+        );
+        assert_eq!(
+            announced_addr("ws://192.168.1.2:33322/"),
+            Some("192.168.1.2:33322".parse().unwrap())
+        );
+        assert_eq!(announced_addr("http://127.0.0.1:33322"), None);
+        assert_eq!(announced_addr("http://[::1]:33322"), None);
+        assert_eq!(announced_addr("npipe://./pipe/edgen"), None);
+        assert_eq!(scheme("npipe://./pipe/edgen"), None);
+    }
+}
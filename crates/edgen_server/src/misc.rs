@@ -12,12 +12,17 @@
 
 //! Minor Edgen services like version.
 
+use std::path::PathBuf;
+use std::sync::Mutex;
+
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Json, Response};
 use serde::{Deserialize, Serialize};
 use tracing::error;
 use utoipa::ToSchema;
 
+use edgen_core::settings::PROJECT_DIRS;
+
 /// Reads the version defined in Cargo.toml at compile time in the format
 /// `MAJOR.MINOR.PATCH_BUILD`
 #[macro_export]
@@ -34,12 +39,35 @@ pub struct Version {
     minor: u32,
     patch: u32,
     build: String,
+    /// The URIs Edgen is listening on, with the ports assigned to the URIs binding to port `0`.
+    #[serde(default)]
+    uris: Vec<String>,
+}
+
+/// The URIs the listeners are bound to.
+static LISTENING_URIS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Records the URIs the listeners are bound to, returned by the version endpoint and written to
+/// [`uris_file`].
+pub fn set_listening_uris(uris: Vec<String>) {
+    if let Err(e) = std::fs::write(uris_file(), uris.join("\n") + "\n") {
+        error!(
+            "Failed to write the listening URIs to {:?}: {e}",
+            uris_file()
+        );
+    }
+    *LISTENING_URIS.lock().unwrap() = uris;
+}
+
+/// Returns the path of the file listing the URIs Edgen is listening on, one per line.
+pub fn uris_file() -> PathBuf {
+    PROJECT_DIRS.data_dir().join("uris")
 }
 
 /// GET `/v1/misc/version`: returns the current version of edgend.
 ///
 /// The version is returned as json value with major, minor and patch as integer
-/// and build as string (which may be empty).
+/// and build as string (which may be empty), along with the URIs Edgen is listening on.
 /// For any error, the version endpoint returns "internal server error".
 #[utoipa::path(
         get,
@@ -51,7 +79,10 @@ pub struct Version {
 )]
 pub async fn edgen_version() -> Response {
     match string_to_version(cargo_crate_version!()) {
-        Ok(j) => Json(j).into_response(),
+        Ok(mut j) => {
+            j.uris = LISTENING_URIS.lock().unwrap().clone();
+            Json(j).into_response()
+        }
         Err(e) => internal_server_error(&e),
     }
 }
@@ -108,6 +139,7 @@ pub fn string_to_version(vs: &str) -> Result<Version, String> {
         minor: minor,
         patch: patch,
         build: build,
+        uris: vec![],
     })
 }
 
@@ -141,6 +173,7 @@ mod test {
                 minor: 0,
                 patch: 1,
                 build: "".to_string(),
+                uris: vec![],
             })
         )
     }
@@ -154,6 +187,7 @@ mod test {
                 minor: 0,
                 patch: 1,
                 build: "xyz".to_string(),
+                uris: vec![],
             })
         )
     }
@@ -167,6 +201,7 @@ mod test {
                 minor: 0,
                 patch: 1,
                 build: "86_64-special-patch".to_string(),
+                uris: vec![],
             })
         )
     }
@@ -10,18 +10,24 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 use once_cell::sync::Lazy;
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tracing::{info, warn};
 use utoipa::ToSchema;
 
 use edgen_core::settings;
+use edgen_core::settings::{ModelBackend, UntrustedModelPolicy};
 
+use crate::requests;
 use crate::status;
 use crate::types::Endpoint;
 
@@ -35,6 +41,8 @@ pub enum ModelError {
     UnknownModel(ModelKind),
     #[error("unknown model kind for model: ({0:?})")]
     UnknownKind(String),
+    #[error("the runtime pinned to the model cannot serve this endpoint: ({0})")]
+    UnsuitableBackend(String),
     #[error("error checking remote repository: ({0})")]
     API(String),
     /// error resulting from tokio::JoinError
@@ -42,6 +50,16 @@ pub enum ModelError {
     JoinError(String),
     #[error("model was not preloaded before use")]
     NotPreloaded,
+    #[error("the model is not in the trusted models: ({0})")]
+    Untrusted(String),
+    #[error("the file of model {model} has the hash {actual}, while {expected} is expected")]
+    HashMismatch {
+        model: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("failed to hash the model file: ({0})")]
+    Hash(String),
 }
 
 #[derive(Serialize, ToSchema, Debug, Clone, PartialEq, Eq)]
@@ -50,6 +68,44 @@ pub enum ModelKind {
     Whisper,
     ChatFaker,
     StableDiffusion,
+    CandleLLM,
+    WhisperFaker,
+    /// Dedicated embedding models, which cannot chat.
+    Embeddings,
+}
+
+impl ModelKind {
+    /// Returns the kinds of the models served by a [`ModelBackend`], which are none if the backend
+    /// does not run locally.
+    pub fn from_backend(backend: ModelBackend) -> Vec<Self> {
+        match backend {
+            ModelBackend::LlamaCpp => vec![ModelKind::LLM, ModelKind::Embeddings],
+            ModelBackend::Candle => vec![ModelKind::CandleLLM],
+            ModelBackend::Faker => vec![ModelKind::ChatFaker],
+            ModelBackend::Remote => vec![],
+        }
+    }
+}
+
+/// Returns the kind of a model, which is the one of the runtime pinned to it in the settings, if
+/// any, or the top kind inferred from the first of its identifiers by [`MODEL_PATTERNS`].
+pub async fn resolve_model_kind(
+    ids: &[&str],
+    accepted: &[ModelKind],
+) -> Result<ModelKind, ModelError> {
+    match settings::model_backend(ids).await {
+        Some(backend) => {
+            let served = ModelKind::from_backend(backend);
+            accepted
+                .iter()
+                .find(move |kind| served.contains(kind))
+                .cloned()
+                .ok_or_else(move || ModelError::UnsuitableBackend(format!("{backend:?}")))
+        }
+        None => {
+            MODEL_PATTERNS.get_top_model_kind(ids.first().copied().unwrap_or_default(), accepted)
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -62,6 +118,12 @@ pub struct ModelPatterns {
     pub llama: Vec<String>,
     pub whisper: Vec<String>,
     pub chat_faker: Vec<String>,
+    #[serde(default = "default_candle_llm_patterns")]
+    pub candle_llm: Vec<String>,
+    #[serde(default = "default_whisper_faker_patterns")]
+    pub whisper_faker: Vec<String>,
+    #[serde(default = "default_embeddings_patterns")]
+    pub embeddings: Vec<String>,
 }
 
 impl ModelPatterns {
@@ -71,6 +133,9 @@ impl ModelPatterns {
         m.llama = m.llama.iter().map(|s| s.to_lowercase()).collect();
         m.whisper = m.whisper.iter().map(|s| s.to_lowercase()).collect();
         m.chat_faker = m.chat_faker.iter().map(|s| s.to_lowercase()).collect();
+        m.candle_llm = m.candle_llm.iter().map(|s| s.to_lowercase()).collect();
+        m.whisper_faker = m.whisper_faker.iter().map(|s| s.to_lowercase()).collect();
+        m.embeddings = m.embeddings.iter().map(|s| s.to_lowercase()).collect();
         Ok(m)
     }
 
@@ -82,7 +147,14 @@ impl ModelPatterns {
     pub fn get_model_kinds(&self, model_name: &str) -> Vec<ModelKind> {
         self.get_accepted_model_kinds(
             model_name,
-            &[ModelKind::LLM, ModelKind::Whisper, ModelKind::ChatFaker],
+            &[
+                ModelKind::LLM,
+                ModelKind::Whisper,
+                ModelKind::ChatFaker,
+                ModelKind::CandleLLM,
+                ModelKind::WhisperFaker,
+                ModelKind::Embeddings,
+            ],
         )
     }
 
@@ -112,6 +184,9 @@ impl ModelPatterns {
                 ModelKind::LLM => &self.llama,
                 ModelKind::Whisper => &self.whisper,
                 ModelKind::ChatFaker => &self.chat_faker,
+                ModelKind::CandleLLM => &self.candle_llm,
+                ModelKind::WhisperFaker => &self.whisper_faker,
+                ModelKind::Embeddings => &self.embeddings,
                 _ => todo!(),
             };
             find_model_kind(list, kind, &n, &mut v);
@@ -135,10 +210,25 @@ impl Default for ModelPatterns {
             llama: vec!["gguf".to_string()],
             whisper: vec!["distil".to_string(), "whisper".to_string()],
             chat_faker: vec!["fake".to_string()],
+            candle_llm: default_candle_llm_patterns(),
+            whisper_faker: default_whisper_faker_patterns(),
+            embeddings: default_embeddings_patterns(),
         }
     }
 }
 
+fn default_candle_llm_patterns() -> Vec<String> {
+    vec!["safetensors".to_string()]
+}
+
+fn default_whisper_faker_patterns() -> Vec<String> {
+    vec!["fake".to_string()]
+}
+
+fn default_embeddings_patterns() -> Vec<String> {
+    vec!["embed".to_string()]
+}
+
 fn make_model_patterns() -> ModelPatterns {
     let data_dir = settings::PROJECT_DIRS.data_dir();
     let model_dir = data_dir.join("models");
@@ -235,7 +325,36 @@ impl Model {
     }
 
     /// Checks if a file of the model is already present locally, and if not, downloads it.
+    ///
+    /// The model must be trusted by the `trusted_models` setting, and its file must match its hash
+    /// in the `model_hashes` setting, if any.
     pub async fn preload(&mut self, ep: Endpoint) -> Result<(), ModelError> {
+        requests::set_model(&self.name).await;
+
+        let name = self.name.clone();
+        let id = format!("{}/{}", self.repo, name);
+        let ids = if self.repo.is_empty() {
+            vec![name.as_str()]
+        } else {
+            vec![id.as_str(), name.as_str()]
+        };
+
+        if !settings::model_trusted(&ids).await {
+            enforce_trust(ModelError::Untrusted(ids[0].to_string())).await?;
+        }
+
+        self.fetch(ep).await?;
+
+        if let Some(expected) = settings::model_hash(&ids).await {
+            verify_hash(ids[0], &self.path, expected).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks if a file of the model is already present locally, and if not, downloads it,
+    /// without checking its provenance.
+    async fn fetch(&mut self, ep: Endpoint) -> Result<(), ModelError> {
         if self.path.is_file() {
             self.preloaded = true;
             return Ok(());
@@ -262,12 +381,14 @@ impl Model {
             None
         };
 
-        let progress_handle = observe_download(ep, &self.dir, size, download).await;
+        let progress_handle =
+            status::observe_download(ep, &self.repo, &self.name, &self.dir, size, download).await;
 
         let name = self.name.clone();
+        let repo = self.repo.clone();
         let download_handle = tokio::spawn(async move {
             if download {
-                report_start_of_download(ep).await;
+                status::start_download(ep, &repo, &name).await;
             }
 
             let path = api
@@ -275,7 +396,7 @@ impl Model {
                 .map_err(move |e| ModelError::API(e.to_string()));
 
             if download {
-                report_end_of_download(ep).await;
+                status::finish_download(ep, &repo, &name).await;
             }
 
             return path;
@@ -294,6 +415,27 @@ impl Model {
         Ok(())
     }
 
+    /// Checks if another file of the repository of this model is already present locally, next to
+    /// the model file, and if not, downloads it. Returns the path to the local file.
+    ///
+    /// This is needed for models that are made up of several files, such as a configuration and
+    /// a tokenizer alongside the weights.
+    pub async fn preload_sibling(
+        &self,
+        file_name: &str,
+        ep: Endpoint,
+    ) -> Result<PathBuf, ModelError> {
+        let local = self.path.with_file_name(file_name);
+        if local.is_file() {
+            return Ok(local);
+        }
+
+        // The siblings of a model are trusted along with the model
+        let mut sibling = Model::new(self.kind.clone(), file_name, &self.repo, &self.dir);
+        sibling.fetch(ep).await?;
+        sibling.file_path()
+    }
+
     // get size of the remote file when we download.
     async fn get_size(&self, api: &hf_hub::api::sync::ApiRepo) -> Option<u64> {
         match reqwest::Client::new()
@@ -321,49 +463,62 @@ impl Model {
     }
 }
 
-async fn observe_download(
-    ep: Endpoint,
-    dir: &PathBuf,
-    size: Option<u64>,
-    download: bool,
-) -> tokio::task::JoinHandle<()> {
-    match ep {
-        Endpoint::ChatCompletions => {
-            status::observe_chat_completions_progress(dir, size, download).await
-        }
-        Endpoint::AudioTranscriptions => {
-            status::observe_audio_transcriptions_progress(dir, size, download).await
+/// The model files whose hash was verified, with their size and modification time when it was, so
+/// that files are only hashed again once they change.
+static VERIFIED_FILES: Lazy<Mutex<HashMap<PathBuf, (u64, Option<SystemTime>)>>> =
+    Lazy::new(Default::default);
+
+/// Fails with `error` if the `untrusted_models` setting refuses untrusted models, and otherwise
+/// only logs it.
+async fn enforce_trust(error: ModelError) -> Result<(), ModelError> {
+    match settings::SETTINGS
+        .read()
+        .await
+        .read()
+        .await
+        .untrusted_models
+    {
+        UntrustedModelPolicy::Refuse => Err(error),
+        UntrustedModelPolicy::Warn => {
+            warn!("{error}, loading it anyway");
+            Ok(())
         }
-        Endpoint::Embeddings => status::observe_embeddings_progress(dir, size, download).await,
-        _ => tokio::task::spawn(async {}),
     }
 }
 
-async fn report_start_of_download(ep: Endpoint) {
-    match ep {
-        Endpoint::ChatCompletions => status::set_chat_completions_download(true).await,
-        Endpoint::AudioTranscriptions => status::set_audio_transcriptions_download(true).await,
-        Endpoint::Embeddings => status::set_embeddings_download(true).await,
-        _ => {}
+/// Checks that the file of the model `id` at `path` has the SHA-256 hash `expected`.
+async fn verify_hash(id: &str, path: &Path, expected: String) -> Result<(), ModelError> {
+    let metadata = std::fs::metadata(path).map_err(move |e| ModelError::Hash(e.to_string()))?;
+    let stamp = (metadata.len(), metadata.modified().ok());
+    if VERIFIED_FILES.lock().unwrap().get(path) == Some(&stamp) {
+        return Ok(());
     }
-}
 
-async fn report_end_of_download(ep: Endpoint) {
-    match ep {
-        Endpoint::ChatCompletions => {
-            status::set_chat_completions_progress(100).await;
-            status::set_chat_completions_download(false).await;
-        }
-        Endpoint::AudioTranscriptions => {
-            status::set_audio_transcriptions_progress(100).await;
-            status::set_audio_transcriptions_download(false).await;
-        }
-        Endpoint::Embeddings => {
-            status::set_embeddings_progress(100).await;
-            status::set_embeddings_download(false).await;
-        }
-        _ => {}
+    info!("Verifying the hash of {}", path.display());
+    let hashed = path.to_path_buf();
+    let actual = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(hashed)?, &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    })
+    .await
+    .map_err(move |e| ModelError::JoinError(e.to_string()))?
+    .map_err(move |e| ModelError::Hash(e.to_string()))?;
+
+    if actual != expected {
+        return enforce_trust(ModelError::HashMismatch {
+            model: id.to_string(),
+            expected,
+            actual,
+        })
+        .await;
     }
+
+    VERIFIED_FILES
+        .lock()
+        .unwrap()
+        .insert(path.to_path_buf(), stamp);
+    Ok(())
 }
 
 #[cfg(test)]
@@ -416,8 +571,19 @@ mod test {
         assert_eq!(m.file_path(), Err(ModelError::NotPreloaded));
     }
 
+    async fn init_settings_for_test() {
+        settings::SETTINGS
+            .write()
+            .await
+            .init()
+            .await
+            .expect("Failed to initialise settings");
+    }
+
     #[tokio::test]
     async fn preload() {
+        init_settings_for_test().await;
+
         let model = "dummy.gguf";
         let repo = "dummy";
         let dir = PathBuf::from("resources");
@@ -495,6 +661,46 @@ mod test {
         );
     }
 
+    #[test]
+    fn default_model_kinds() {
+        let m = ModelPatterns::default();
+        assert_eq!(
+            m.get_model_kinds("TheBloke/phi-2-GGUF/phi-2.Q4_K_M.gguf"),
+            &[ModelKind::LLM],
+            "expected model to be Llama"
+        );
+        assert_eq!(
+            m.get_model_kinds("microsoft/phi-2/model.safetensors.index.json"),
+            &[ModelKind::CandleLLM],
+            "expected model to be a candle LLM"
+        );
+        assert_eq!(
+            m.get_top_model_kind(
+                "nomic-ai/nomic-embed-text-v1.5-GGUF/nomic-embed-text-v1.5.f16.gguf",
+                &[ModelKind::Embeddings, ModelKind::LLM]
+            ),
+            Ok(ModelKind::Embeddings),
+            "expected model to be an embedding model"
+        );
+    }
+
+    #[test]
+    fn backend_model_kinds() {
+        assert_eq!(
+            ModelKind::from_backend(ModelBackend::LlamaCpp),
+            [ModelKind::LLM, ModelKind::Embeddings]
+        );
+        assert_eq!(
+            ModelKind::from_backend(ModelBackend::Candle),
+            [ModelKind::CandleLLM]
+        );
+        assert_eq!(
+            ModelKind::from_backend(ModelBackend::Faker),
+            [ModelKind::ChatFaker]
+        );
+        assert!(ModelKind::from_backend(ModelBackend::Remote).is_empty());
+    }
+
     #[tokio::test]
     #[ignore]
     // This test tries to connect to huggingface
@@ -2,6 +2,7 @@ use crate::model::{Model, ModelError, ModelKind};
 use crate::openai_shim::{parse_model_param, ParseError};
 use crate::types::Endpoint;
 use dashmap::DashMap;
+use edgen_core::image_generation::StableDiffusionVersion;
 use edgen_core::settings;
 use once_cell::sync::Lazy;
 use serde_derive::Serialize;
@@ -10,6 +11,9 @@ use thiserror::Error;
 
 static MODELS: Lazy<DashMap<String, ModelDescriptor>> = Lazy::new(Default::default);
 
+/// The weights of the safety checker used to classify generated images as NSFW.
+const SAFETY_CHECKER_WEIGHTS: &str = "CompVis/stable-diffusion-safety-checker/model.safetensors";
+
 #[derive(Debug, Error, Serialize)]
 pub enum ModelDescriptorError {
     #[error("The specified quantization level is not available for the model")]
@@ -36,6 +40,12 @@ pub enum ModelDescriptor {
 
         /// The default Variational Auto-Encoder scale for this model.
         vae_scale: f64,
+
+        /// The default guidance scale for this model.
+        guidance_scale: f64,
+
+        /// The architecture of this model.
+        version: StableDiffusionVersion,
     },
 }
 
@@ -56,6 +66,7 @@ pub enum Quantization {
 
 pub enum ModelPaths {
     StableDiffusion {
+        version: StableDiffusionVersion,
         unet_weights: PathBuf,
         vae_weights: PathBuf,
         clip_weights: PathBuf,
@@ -89,12 +100,17 @@ impl ModelDescriptor {
         Ok(file.file_path()?)
     }
 
+    /// Returns the path to the weights of the image safety checker, downloading them if needed.
+    pub async fn preload_safety_checker(&self) -> Result<PathBuf, ModelDescriptorError> {
+        self.get_file(SAFETY_CHECKER_WEIGHTS).await
+    }
+
     pub async fn preload_files(
         &self,
         quantization: Quantization,
     ) -> Result<ModelPaths, ModelDescriptorError> {
         let res = match self {
-            ModelDescriptor::StableDiffusion { files, .. } => {
+            ModelDescriptor::StableDiffusion { files, version, .. } => {
                 let files = files.get(&quantization);
                 if files.is_none() {
                     return Err(ModelDescriptorError::QuantizationUnavailable);
@@ -112,6 +128,7 @@ impl ModelDescriptor {
                 let tokenizer = self.get_file(&files.tokenizer).await?;
 
                 ModelPaths::StableDiffusion {
+                    version: *version,
                     unet_weights: unet,
                     vae_weights: vae,
                     clip_weights: clip,
@@ -160,8 +177,121 @@ pub fn init() {
         files: model_files,
         steps: 30,
         vae_scale: 0.18215,
+        guidance_scale: 7.5,
+        version: StableDiffusionVersion::V2_1,
     };
     MODELS.insert("stable-diffusion-2-1".to_string(), model);
+
+    let model_files = DashMap::new();
+    model_files.insert(
+        Quantization::Default,
+        StableDiffusionFiles {
+            tokenizer: "openai/clip-vit-large-patch14/tokenizer.json".to_string(),
+            clip_weights: "stabilityai/sdxl-turbo/text_encoder/model.safetensors".to_string(),
+            clip2_weights: Some(
+                "stabilityai/sdxl-turbo/text_encoder_2/model.safetensors".to_string(),
+            ),
+            vae_weights: "stabilityai/sdxl-turbo/vae/diffusion_pytorch_model.safetensors"
+                .to_string(),
+            unet_weights: "stabilityai/sdxl-turbo/unet/diffusion_pytorch_model.safetensors"
+                .to_string(),
+        },
+    );
+    model_files.insert(
+        Quantization::F16,
+        StableDiffusionFiles {
+            tokenizer: "openai/clip-vit-large-patch14/tokenizer.json".to_string(),
+            clip_weights: "stabilityai/sdxl-turbo/text_encoder/model.fp16.safetensors".to_string(),
+            clip2_weights: Some(
+                "stabilityai/sdxl-turbo/text_encoder_2/model.fp16.safetensors".to_string(),
+            ),
+            // The original fp16 VAE of SDXL produces NaNs
+            vae_weights: "madebyollin/sdxl-vae-fp16-fix/diffusion_pytorch_model.safetensors"
+                .to_string(),
+            unet_weights: "stabilityai/sdxl-turbo/unet/diffusion_pytorch_model.fp16.safetensors"
+                .to_string(),
+        },
+    );
+    let model = ModelDescriptor::StableDiffusion {
+        files: model_files,
+        steps: 1,
+        vae_scale: 0.13025,
+        // SDXL-Turbo was trained without classifier-free guidance
+        guidance_scale: 0.0,
+        version: StableDiffusionVersion::Turbo,
+    };
+    MODELS.insert("sdxl-turbo".to_string(), model);
+
+    let model_files = DashMap::new();
+    model_files.insert(
+        Quantization::Default,
+        sdxl_files(
+            "stabilityai/stable-diffusion-xl-base-1.0/unet/diffusion_pytorch_model.safetensors",
+            false,
+        ),
+    );
+    model_files.insert(
+        Quantization::F16,
+        sdxl_files(
+            "stabilityai/stable-diffusion-xl-base-1.0/unet/diffusion_pytorch_model.fp16.safetensors",
+            true,
+        ),
+    );
+    let model = ModelDescriptor::StableDiffusion {
+        files: model_files,
+        steps: 30,
+        vae_scale: 0.13025,
+        guidance_scale: 5.0,
+        version: StableDiffusionVersion::Xl,
+    };
+    MODELS.insert("stable-diffusion-xl".to_string(), model);
+
+    let model_files = DashMap::new();
+    model_files.insert(
+        Quantization::Default,
+        sdxl_files(
+            "latent-consistency/lcm-sdxl/diffusion_pytorch_model.safetensors",
+            false,
+        ),
+    );
+    model_files.insert(
+        Quantization::F16,
+        sdxl_files(
+            "latent-consistency/lcm-sdxl/diffusion_pytorch_model.fp16.safetensors",
+            true,
+        ),
+    );
+    let model = ModelDescriptor::StableDiffusion {
+        files: model_files,
+        steps: 4,
+        vae_scale: 0.13025,
+        // Classifier-free guidance would double the cost of every step
+        guidance_scale: 0.0,
+        version: StableDiffusionVersion::XlLcm,
+    };
+    MODELS.insert("lcm-sdxl".to_string(), model);
+}
+
+/// Returns the files of a model built on top of Stable Diffusion XL, with the provided UNet.
+fn sdxl_files(unet_weights: &str, f16: bool) -> StableDiffusionFiles {
+    let base = "stabilityai/stable-diffusion-xl-base-1.0";
+    let suffix = if f16 {
+        "fp16.safetensors"
+    } else {
+        "safetensors"
+    };
+    StableDiffusionFiles {
+        tokenizer: "openai/clip-vit-large-patch14/tokenizer.json".to_string(),
+        clip_weights: format!("{base}/text_encoder/model.{suffix}"),
+        clip2_weights: Some(format!("{base}/text_encoder_2/model.{suffix}")),
+        vae_weights: if f16 {
+            // The original fp16 VAE of SDXL produces NaNs
+            "madebyollin/sdxl-vae-fp16-fix/diffusion_pytorch_model.safetensors".to_string()
+        } else {
+            format!("{base}/vae/diffusion_pytorch_model.safetensors")
+        },
+        unet_weights: unet_weights.to_string(),
+    }
 }
 
 pub fn get(
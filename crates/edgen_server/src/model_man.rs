@@ -23,13 +23,27 @@ use axum::response::{IntoResponse, Json, Response};
 use serde::{Deserialize, Serialize};
 use thiserror;
 use tracing::{info, warn};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 use edgen_core::settings;
+use edgen_rt_llama_cpp::quantize::Quantization;
+
+use crate::model_watcher;
 
 /// GET `/v1/models`: returns a list of model descriptors for all models in all model directories.
 ///
+/// Model files added to the model directories by hand are listed by their file name, and owned by
+/// `local`.
+///
 /// For any error, the endpoint returns "internal server error".
+#[utoipa::path(
+get,
+path = "/models",
+responses(
+(status = 200, description = "OK", body = ModelList),
+(status = 500, description = "unexpected internal server error")
+),
+)]
 pub async fn list_models() -> Response {
     match list_all_models().await {
         Ok(v) => Json(v).into_response(),
@@ -40,6 +54,15 @@ pub async fn list_models() -> Response {
 /// GET `/v1/models{:id}`: returns the model descriptor for the model indicated by 'id'.
 ///
 /// For any error, the endpoint returns "internal server error".
+#[utoipa::path(
+get,
+path = "/models/{model}",
+params(("model" = String, Path, description = "The ID of the model")),
+responses(
+(status = 200, description = "OK", body = ModelDesc),
+(status = 500, description = "unexpected internal server error")
+),
+)]
 pub async fn retrieve_model(extract::Path(id): extract::Path<String>) -> Response {
     match model_id_to_desc(&id).await {
         Ok(d) => Json(d).into_response(),
@@ -52,6 +75,15 @@ pub async fn retrieve_model(extract::Path(id): extract::Path<String>) -> Respons
 /// DELETE `/v1/models{:id}`: deletes the model indicated by 'id'.
 ///
 /// For any error, the endpoint returns "internal server error".
+#[utoipa::path(
+delete,
+path = "/models/{model}",
+params(("model" = String, Path, description = "The ID of the model")),
+responses(
+(status = 200, description = "OK", body = ModelDeletionStatus),
+(status = 500, description = "unexpected internal server error")
+),
+)]
 pub async fn delete_model(extract::Path(id): extract::Path<String>) -> Response {
     match remove_model(&id).await {
         Ok(d) => Json(d).into_response(),
@@ -62,6 +94,104 @@ pub async fn delete_model(extract::Path(id): extract::Path<String>) -> Response
     }
 }
 
+/// GET `/v1/models/search?repo={repo}`: lists the model files of a Hugging Face repository, with
+/// their sizes and quantizations.
+///
+/// Only GGUF models and `.bin` models, such as those of `whisper.cpp`, are listed. This endpoint
+/// is specific to **Edgen**.
+///
+/// If the repository does not exist, or is not accessible, the endpoint returns "not found". For
+/// any other error, the endpoint returns "internal server error".
+#[utoipa::path(
+get,
+path = "/models/search",
+params(SearchQuery),
+responses(
+(status = 200, description = "OK", body = RepoFileList),
+(status = 404, description = "no such repository"),
+(status = 500, description = "unexpected internal server error")
+),
+)]
+pub async fn search_models(extract::Query(query): extract::Query<SearchQuery>) -> Response {
+    let url = format!("{}/api/models/{}?blobs=true", hf_endpoint(), query.repo);
+    let response = match reqwest::get(&url).await {
+        Ok(response) => response,
+        Err(e) => {
+            return internal_server_error(&format!(
+                "model manager: cannot search repository {}: {:?}",
+                query.repo, e
+            ))
+        }
+    };
+
+    let status = response.status();
+    if status == StatusCode::NOT_FOUND || status == StatusCode::UNAUTHORIZED {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    match response.error_for_status() {
+        Ok(response) => match response.json::<RepoInfo>().await {
+            Ok(info) => Json(repo_files(&query.repo, info)).into_response(),
+            Err(e) => internal_server_error(&format!(
+                "model manager: invalid listing of repository {}: {:?}",
+                query.repo, e
+            )),
+        },
+        Err(e) => internal_server_error(&format!(
+            "model manager: cannot search repository {}: {:?}",
+            query.repo, e
+        )),
+    }
+}
+
+/// Returns the Hugging Face endpoint, which can be overridden with `HF_ENDPOINT` like it is for
+/// downloads.
+pub(crate) fn hf_endpoint() -> String {
+    std::env::var("HF_ENDPOINT")
+        .unwrap_or_else(move |_| "https://huggingface.co".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// The files of a repository, as listed by the Hugging Face API.
+#[derive(Deserialize, Debug)]
+struct RepoInfo {
+    siblings: Vec<RepoSibling>,
+}
+
+#[derive(Deserialize, Debug)]
+struct RepoSibling {
+    rfilename: String,
+    size: Option<u64>,
+}
+
+/// Returns the model files of `repo` among the files of `info`, sorted by name.
+fn repo_files(repo: &str, info: RepoInfo) -> RepoFileList {
+    let mut data: Vec<RepoFile> = info
+        .siblings
+        .into_iter()
+        .filter(|sibling| {
+            let name = sibling.rfilename.to_lowercase();
+            name.ends_with(".gguf") || name.ends_with(".bin")
+        })
+        .map(move |sibling| RepoFile {
+            id: format!("{}/{}", repo, sibling.rfilename),
+            object: "model.file".to_string(),
+            repo: repo.to_string(),
+            quantization: Quantization::from_file_name(&sibling.rfilename)
+                .map(move |quant| quant.to_string()),
+            file: sibling.rfilename,
+            size: sibling.size,
+        })
+        .collect();
+    data.sort_by(|a, b| a.file.cmp(&b.file));
+
+    RepoFileList {
+        object: "list".to_string(),
+        data,
+    }
+}
+
 fn internal_server_error(msg: &str) -> Response {
     warn!("{}", msg);
     StatusCode::INTERNAL_SERVER_ERROR.into_response()
@@ -105,6 +235,39 @@ pub struct ModelList {
     pub per_page: usize,
 }
 
+/// The query parameters of [`search_models`].
+#[derive(IntoParams, Deserialize, Debug)]
+pub struct SearchQuery {
+    /// the Hugging Face repository, e.g. `TheBloke/TinyLlama-1.1B-Chat-v1.0-GGUF`
+    pub repo: String,
+}
+
+/// Model file of a Hugging Face repository
+#[derive(ToSchema, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct RepoFile {
+    /// model Id, usable as the `model` of requests, e.g. `owner/repo/model.Q4_K_M.gguf`
+    pub id: String,
+    /// object type, always 'model.file'
+    pub object: String,
+    /// the repository
+    pub repo: String,
+    /// the name of the file in the repository
+    pub file: String,
+    /// the size of the file in bytes, if known
+    pub size: Option<u64>,
+    /// the quantization of the model, e.g. `Q4_K_M`, if its file name tells it
+    pub quantization: Option<String>,
+}
+
+/// List of the model files of a Hugging Face repository
+#[derive(ToSchema, Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct RepoFileList {
+    /// always 'list'
+    pub object: String,
+    /// the files, sorted by name
+    pub data: Vec<RepoFile>,
+}
+
 #[derive(Debug, thiserror::Error)]
 enum PathError {
     Generic(String),
@@ -128,6 +291,12 @@ async fn list_all_models() -> Result<ModelList, PathError> {
 
     list_models_in_dir(Path::new(&completions_dir), &mut v).await?;
     list_models_in_dir(Path::new(&transcriptions_dir), &mut v).await?;
+    for path in model_watcher::local_models() {
+        match file_to_model_desc(&path).await {
+            Ok(m) => v.push(m),
+            Err(e) => info!("model manager: invalid model file {:?}: {:?}", path, e),
+        }
+    }
     let perpage = v.len();
     Ok(ModelList {
         object: "list".to_string(),
@@ -176,6 +345,9 @@ async fn list_models_in_dir(path: &Path, v: &mut Vec<ModelDesc>) -> Result<(), P
 }
 
 async fn model_id_to_desc(id: &str) -> Result<ModelDesc, PathError> {
+    if let Some(path) = model_watcher::local_model(id) {
+        return file_to_model_desc(&path).await;
+    }
     let path = search_model(id).await?;
     path_to_model_desc(path.as_path()).await
 }
@@ -232,6 +404,29 @@ async fn path_to_model_desc(path: &Path) -> Result<ModelDesc, PathError> {
     })
 }
 
+/// Returns the descriptor of a model file added to a model directory, which is identified by its
+/// file name.
+async fn file_to_model_desc(path: &Path) -> Result<ModelDesc, PathError> {
+    let model = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or(PathError::Generic("invalid file name".to_string()))?;
+    let metadata = tokio::fs::metadata(path).await?;
+    let tp = match metadata.created() {
+        Ok(n) => n,
+        Err(_) => SystemTime::UNIX_EPOCH, // unknown
+    };
+
+    let created = tp.duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+
+    Ok(ModelDesc {
+        id: model.to_string(),
+        created: created,
+        object: "model".to_string(),
+        owned_by: "local".to_string(),
+    })
+}
+
 fn to_model_id(owner: &str, repo: &str) -> String {
     format!("{}/{}", owner, repo)
 }
@@ -401,6 +596,39 @@ mod test {
         assert_eq!(parse_model_id(""), Err(ParseError::MissingSeparator));
     }
 
+    // --- Search Repository ----------------------------------------------------------------------
+    #[test]
+    fn list_repo_files() {
+        let info: RepoInfo = serde_json::from_value(serde_json::json!({
+            "siblings": [
+                {"rfilename": "README.md", "size": 100},
+                {"rfilename": "tinyllama.Q5_K_S.gguf", "size": 200},
+                {"rfilename": "tinyllama.Q4_K_M.gguf", "size": 150},
+                {"rfilename": "ggml-model.bin"},
+            ]
+        }))
+        .unwrap();
+
+        let list = repo_files("TheBloke/TinyLlama-GGUF", info);
+        let files: Vec<_> = list
+            .data
+            .iter()
+            .map(|f| (f.file.as_str(), f.size, f.quantization.as_deref()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("ggml-model.bin", None, None),
+                ("tinyllama.Q4_K_M.gguf", Some(150), Some("Q4_K_M")),
+                ("tinyllama.Q5_K_S.gguf", Some(200), Some("Q5_K_S")),
+            ]
+        );
+        assert_eq!(
+            list.data[1].id,
+            "TheBloke/TinyLlama-GGUF/tinyllama.Q4_K_M.gguf"
+        );
+    }
+
     // --- Parse Model Entry ----------------------------------------------------------------------
     #[test]
     fn parse_path_simple_valid() {
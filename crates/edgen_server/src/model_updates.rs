@@ -0,0 +1,250 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Checking of the configured models for updated revisions of their upstream repositories.
+//!
+//! Downloaded models are kept in the layout of the Hugging Face cache, where the revision of a
+//! repository a model was downloaded at is recorded in `models--{owner}--{repo}/refs/main`. A
+//! model is outdated when that revision is not the latest revision of the `main` branch of its
+//! repository.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+use thiserror::Error;
+use tracing::{info, warn};
+
+use edgen_core::settings;
+use edgen_core::settings::SETTINGS;
+
+use crate::model_man::hf_endpoint;
+use crate::status;
+use crate::types::Endpoint;
+
+/// How often the configured models are checked for updates, when enabled.
+const CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// An error checking or downloading model updates.
+#[derive(Debug, Error)]
+pub enum UpdateError {
+    #[error("failed to query the revision of {repo}: {error}")]
+    Query { repo: String, error: String },
+    #[error("failed to download {repo}/{file}: {error}")]
+    Download {
+        repo: String,
+        file: String,
+        error: String,
+    },
+    #[error("failed to join the download task: {0}")]
+    Join(#[from] tokio::task::JoinError),
+}
+
+/// The configured model of an endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfiguredModel {
+    /// The endpoint the model is configured for.
+    pub endpoint: Endpoint,
+    /// The upstream repository of the model.
+    pub repo: String,
+    /// The name of the model file in the repository.
+    pub file: String,
+    /// The directory the model is downloaded to.
+    pub dir: PathBuf,
+}
+
+/// An updated revision of a configured model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModelUpdate {
+    /// The model.
+    pub model: ConfiguredModel,
+    /// The revision the model was downloaded at.
+    pub local: String,
+    /// The latest revision of the repository of the model.
+    pub upstream: String,
+}
+
+/// Returns the configured models of the endpoints downloading theirs from Hugging Face.
+pub async fn configured_models() -> Vec<ConfiguredModel> {
+    let models = [
+        (
+            Endpoint::ChatCompletions,
+            settings::chat_completions_repo().await,
+            settings::chat_completions_name().await,
+            settings::chat_completions_dir().await,
+        ),
+        (
+            Endpoint::AudioTranscriptions,
+            settings::audio_transcriptions_repo().await,
+            settings::audio_transcriptions_name().await,
+            settings::audio_transcriptions_dir().await,
+        ),
+        (
+            Endpoint::Embeddings,
+            settings::embeddings_repo().await,
+            settings::embeddings_name().await,
+            settings::embeddings_dir().await,
+        ),
+    ];
+
+    models
+        .into_iter()
+        .filter(|(_, repo, file, _)| !repo.is_empty() && !file.is_empty())
+        .map(move |(endpoint, repo, file, dir)| ConfiguredModel {
+            endpoint,
+            repo,
+            file,
+            dir: PathBuf::from(dir),
+        })
+        .collect()
+}
+
+/// Returns the revision `repo` was downloaded at into `dir`, if it was.
+fn local_revision(dir: &Path, repo: &str) -> Option<String> {
+    let folder = format!("models--{}", repo.replace('/', "--"));
+    let revision = std::fs::read_to_string(dir.join(folder).join("refs").join("main")).ok()?;
+    Some(revision.trim().to_string())
+}
+
+/// The revision of a repository, as listed by the Hugging Face API.
+#[derive(Deserialize, Debug)]
+struct RevisionInfo {
+    sha: String,
+}
+
+/// Returns the latest revision of the `main` branch of `repo`.
+async fn upstream_revision(repo: &str) -> Result<String, UpdateError> {
+    let query = move |error: reqwest::Error| UpdateError::Query {
+        repo: repo.to_string(),
+        error: error.to_string(),
+    };
+
+    let url = format!("{}/api/models/{}/revision/main", hf_endpoint(), repo);
+    let info: RevisionInfo = reqwest::get(&url)
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(query)?
+        .json()
+        .await
+        .map_err(query)?;
+
+    Ok(info.sha)
+}
+
+/// Returns the update of `model`, if it was downloaded and its repository has a newer revision.
+async fn check_model(model: &ConfiguredModel) -> Result<Option<ModelUpdate>, UpdateError> {
+    let downloaded = hf_hub::Cache::new(model.dir.clone())
+        .model(model.repo.clone())
+        .get(&model.file)
+        .is_some();
+    let Some(local) = local_revision(&model.dir, &model.repo).filter(move |_| downloaded) else {
+        // Models that were never downloaded, or were copied into the models directory by hand,
+        // are not managed by Edgen
+        return Ok(None);
+    };
+
+    let upstream = upstream_revision(&model.repo).await?;
+    if upstream == local {
+        return Ok(None);
+    }
+
+    Ok(Some(ModelUpdate {
+        model: model.clone(),
+        local,
+        upstream,
+    }))
+}
+
+/// Checks every configured model for updates, reporting them in the status of their endpoints.
+///
+/// Models that cannot be checked are skipped, the errors being logged.
+pub async fn check() -> Vec<ModelUpdate> {
+    let mut updates = vec![];
+    for model in configured_models().await {
+        match check_model(&model).await {
+            Ok(update) => {
+                status::set_update_available(model.endpoint, update.is_some()).await;
+                updates.extend(update);
+            }
+            Err(e) => warn!(
+                "Failed to check {}/{} for updates: {e}",
+                model.repo, model.file
+            ),
+        }
+    }
+    updates
+}
+
+/// Spawns the task checking the configured models for updates every [`CHECK_INTERVAL`], if
+/// `check_model_updates` is enabled.
+pub async fn spawn_checks() {
+    if !SETTINGS.read().await.read().await.check_model_updates {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for update in check().await {
+                info!(
+                    "An update of {}/{} is available, run `edgen models upgrade` to download it",
+                    update.model.repo, update.model.file
+                );
+            }
+        }
+    });
+}
+
+/// Downloads the updated revision of a model, returning the path of the downloaded file.
+pub async fn upgrade(update: &ModelUpdate) -> Result<PathBuf, UpdateError> {
+    let ConfiguredModel {
+        repo, file, dir, ..
+    } = update.model.clone();
+    let path = tokio::task::spawn_blocking(move || {
+        let download = |error: hf_hub::api::sync::ApiError| UpdateError::Download {
+            repo: repo.clone(),
+            file: file.clone(),
+            error: error.to_string(),
+        };
+
+        let api = hf_hub::api::sync::ApiBuilder::new()
+            .with_cache_dir(dir)
+            .build()
+            .map_err(download)?;
+        // Unlike `get`, `download` fetches the latest revision even when the file is cached
+        api.model(repo.clone()).download(&file).map_err(download)
+    })
+    .await??;
+
+    status::set_update_available(update.model.endpoint, false).await;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_revisions() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(local_revision(dir.path(), "owner/repo"), None);
+
+        let refs = dir.path().join("models--owner--repo").join("refs");
+        std::fs::create_dir_all(&refs).unwrap();
+        std::fs::write(refs.join("main"), "0123abcd\n").unwrap();
+        assert_eq!(
+            local_revision(dir.path(), "owner/repo"),
+            Some("0123abcd".to_string())
+        );
+    }
+}
@@ -0,0 +1,253 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Watching of the model directories for model files added by hand.
+//!
+//! GGUF, `.bin` and `.safetensors` files placed directly in one of the model directories are
+//! registered once they are fully written, and unregistered as soon as they are removed, so that
+//! `/v1/models` lists them and requests can use them by their file name, whichever endpoint's
+//! directory they are in.
+//!
+//! A file is fully written when it is closed after being written, or moved into the directory.
+//! Since not every platform reports files being closed, a file being written is also registered
+//! once its size stops changing for [`SETTLE_INTERVAL`].
+
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use dashmap::DashMap;
+use notify::event::{AccessKind, AccessMode, ModifyKind};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use once_cell::sync::Lazy;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use edgen_core::settings;
+
+/// The watcher of the model directories, if they are watched.
+static WATCHER: Mutex<Option<RecommendedWatcher>> = Mutex::new(None);
+
+/// The model files found in the model directories, by file name.
+static LOCAL_MODELS: Lazy<DashMap<String, PathBuf>> = Lazy::new(Default::default);
+
+/// How long the size of a model file being written must stay the same for it to be registered.
+const SETTLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The model files being written, with their size when last checked, if they were.
+static PENDING: Lazy<DashMap<PathBuf, Option<u64>>> = Lazy::new(Default::default);
+
+/// The task registering the model files being written once their size settles.
+static SETTLER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Registers the model files in the model directories, and watches the directories for model
+/// files being added or removed, replacing the previous watcher.
+///
+/// Failing to watch a directory is not fatal, the errors are logged.
+pub async fn watch() {
+    unwatch();
+    LOCAL_MODELS.clear();
+
+    let dirs = [
+        settings::chat_completions_dir().await,
+        settings::audio_transcriptions_dir().await,
+        settings::embeddings_dir().await,
+        settings::image_generation_dir().await,
+    ];
+
+    let mut watcher = match notify::recommended_watcher(handle_event) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!("Failed to watch the model directories: {e}");
+            return;
+        }
+    };
+
+    for dir in dirs.iter().map(Path::new) {
+        if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+            warn!("Failed to watch model directory {}: {e}", dir.display());
+            continue;
+        }
+        scan(dir);
+    }
+
+    *WATCHER.lock().unwrap() = Some(watcher);
+    *SETTLER.lock().unwrap() = Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SETTLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            settle();
+        }
+    }));
+}
+
+/// Stops watching the model directories, if they are watched.
+pub fn unwatch() {
+    WATCHER.lock().unwrap().take();
+    if let Some(settler) = SETTLER.lock().unwrap().take() {
+        settler.abort();
+    }
+    PENDING.clear();
+}
+
+/// Returns the path of the model file named `name` found in the model directories, if any.
+pub fn local_model(name: &str) -> Option<PathBuf> {
+    LOCAL_MODELS.get(name).map(move |path| path.clone())
+}
+
+/// Returns the paths of the model files found in the model directories, sorted by file name.
+pub fn local_models() -> Vec<PathBuf> {
+    let mut models: Vec<(String, PathBuf)> = LOCAL_MODELS
+        .iter()
+        .map(move |entry| (entry.key().clone(), entry.value().clone()))
+        .collect();
+    models.sort();
+    models.into_iter().map(move |(_, path)| path).collect()
+}
+
+/// Registers the model files directly in `dir`.
+fn scan(dir: &Path) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Failed to read model directory {}: {e}", dir.display());
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        update(&entry.path());
+    }
+}
+
+fn handle_event(event: notify::Result<Event>) {
+    match event {
+        Ok(event) => {
+            for path in &event.paths {
+                match event.kind {
+                    EventKind::Access(AccessKind::Close(AccessMode::Write))
+                    | EventKind::Modify(ModifyKind::Name(_))
+                    | EventKind::Remove(_) => {
+                        PENDING.remove(path);
+                        update(path);
+                    }
+                    EventKind::Create(_) | EventKind::Modify(_) => track(path),
+                    _ => {}
+                }
+            }
+        }
+        Err(e) => warn!("Failed to watch the model directories: {e}"),
+    }
+}
+
+/// Marks the model file at `path` as being written, to be registered once its size settles.
+fn track(path: &Path) {
+    if is_model_file(path) {
+        PENDING.insert(path.to_path_buf(), None);
+    }
+}
+
+/// Registers the model files being written whose size has not changed since the last check, and
+/// unregisters the ones that were removed.
+fn settle() {
+    PENDING.retain(move |path, size| match std::fs::metadata(path) {
+        Ok(metadata) if *size != Some(metadata.len()) => {
+            *size = Some(metadata.len());
+            true
+        }
+        _ => {
+            update(path);
+            false
+        }
+    });
+}
+
+/// Registers the model file at `path` if it exists, or unregisters it otherwise.
+fn update(path: &Path) {
+    if !is_model_file(path) {
+        return;
+    }
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return;
+    };
+
+    if path.is_file() {
+        if !LOCAL_MODELS.contains_key(name) {
+            info!("Registering model file {}", path.display());
+            LOCAL_MODELS.insert(name.to_string(), path.to_path_buf());
+        }
+    } else if LOCAL_MODELS
+        .remove_if(name, move |_, registered| registered == path)
+        .is_some()
+    {
+        info!("Unregistering model file {}", path.display());
+    }
+}
+
+/// Returns `true` if `path` has the extension of a model file.
+fn is_model_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            ["gguf", "bin", "safetensors"]
+                .iter()
+                .any(move |model_ext| ext.eq_ignore_ascii_case(model_ext))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn model_files() {
+        assert!(is_model_file(Path::new("/models/model.Q4_K_M.gguf")));
+        assert!(is_model_file(Path::new("ggml-distil-small.en.BIN")));
+        assert!(is_model_file(Path::new("unet.safetensors")));
+        assert!(!is_model_file(Path::new("model_patterns.yaml")));
+        assert!(!is_model_file(Path::new("models--owner--repo")));
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("watched-model.gguf");
+
+        std::fs::write(&path, b"").unwrap();
+        update(&path);
+        assert_eq!(local_model("watched-model.gguf"), Some(path.clone()));
+
+        std::fs::remove_file(&path).unwrap();
+        update(&path);
+        assert_eq!(local_model("watched-model.gguf"), None);
+    }
+
+    #[test]
+    fn settle_written_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("written-model.gguf");
+
+        std::fs::write(&path, b"GGUF").unwrap();
+        track(&path);
+        settle();
+        assert_eq!(local_model("written-model.gguf"), None);
+
+        // still being written
+        std::fs::write(&path, b"GGUF v3").unwrap();
+        settle();
+        assert_eq!(local_model("written-model.gguf"), None);
+
+        settle();
+        assert_eq!(local_model("written-model.gguf"), Some(path.clone()));
+        assert!(!PENDING.contains_key(&path));
+    }
+}
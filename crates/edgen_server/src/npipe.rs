@@ -0,0 +1,67 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Serving of the API over Windows named pipes, for `npipe://` URIs.
+//!
+//! Named pipes let local applications talk to the server without opening a TCP port. A pipe
+//! instance only serves a single client, so a new instance is created whenever a client connects
+//! to the last one.
+
+use axum::Router;
+use hyper::server::conn::http1;
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use tokio::net::windows::named_pipe::{NamedPipeServer, ServerOptions};
+use tracing::warn;
+
+/// Returns the name of the pipe of an `npipe://` URI, without its scheme, e.g. `\\.\pipe\edgen`
+/// for both `npipe://./pipe/edgen` and `npipe:////./pipe/edgen`.
+pub fn pipe_name(uri: &str) -> String {
+    let path = uri.trim_start_matches(['/', '\\']).replace('/', "\\");
+    format!(r"\\{path}")
+}
+
+/// Creates the first instance of the pipe `name`, failing if the pipe already exists.
+pub fn bind(name: &str) -> std::io::Result<NamedPipeServer> {
+    ServerOptions::new().first_pipe_instance(true).create(name)
+}
+
+/// Serves `app` over the pipe `name`, of which `first` is the first instance, created by [`bind`].
+pub async fn serve(name: String, first: NamedPipeServer, app: Router) -> std::io::Result<()> {
+    let mut server = first;
+    loop {
+        server.connect().await?;
+        let connected = std::mem::replace(&mut server, ServerOptions::new().create(&name)?);
+
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let connection = http1::Builder::new()
+                .serve_connection(TokioIo::new(connected), service)
+                .with_upgrades();
+            if let Err(e) = connection.await {
+                warn!("Failed to serve a named pipe connection: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipe_names() {
+        assert_eq!(pipe_name("./pipe/edgen"), r"\\.\pipe\edgen");
+        assert_eq!(pipe_name("//./pipe/edgen"), r"\\.\pipe\edgen");
+        assert_eq!(pipe_name(r"\\.\pipe\edgen"), r"\\.\pipe\edgen");
+    }
+}
@@ -17,32 +17,55 @@
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use axum::http::StatusCode;
-use axum::response::sse::Event;
+use axum::response::sse::{Event, KeepAlive};
 use axum::response::{IntoResponse, Response, Sse};
-use axum::Json;
+use axum::{Extension, Json};
 use axum_typed_multipart::{FieldData, TryFromMultipart, TypedMultipart};
+use data_encoding::BASE64;
 use derive_more::{Deref, DerefMut, From};
 use either::Either;
+use futures::stream::BoxStream;
 use futures::{Stream, StreamExt, TryStream};
 use serde_derive::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
 use thiserror::Error;
 use time::OffsetDateTime;
 use tinyvec::{tiny_vec, TinyVec};
-use tracing::error;
+use tracing::{error, info, warn};
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-use edgen_core::llm::{CompletionArgs, LLMEndpointError};
+use edgen_core::embeddings::{normalize, EmbeddingsArgs, Pooling};
+use edgen_core::llm::{
+    CompletionArgs, CompletionTimings, LLMEndpointError, TimingsRecorder, TokenLog,
+};
 use edgen_core::settings;
-use edgen_core::whisper::WhisperEndpointError;
-
-use crate::chat_faker;
-use crate::llm;
-use crate::model::{Model, ModelError, ModelKind, MODEL_PATTERNS};
+use edgen_core::settings::{KeyModels, ModelBackend, RequestCaps, SamplerOptions};
+use edgen_core::whisper::{parse, AudioFile, TranscriptionArgs, WhisperEndpointError};
+
+use crate::admin;
+use crate::backends::{ChatBackend, CompletionStream, BACKENDS};
+use crate::coalesce::coalesce;
+use crate::conversation::{self, Conversation};
+use crate::embeddings_cache;
+use crate::listener::KeyOwner;
+use crate::model::{resolve_model_kind, Model, ModelError, ModelKind};
+use crate::model_watcher;
+use crate::remote;
+use crate::request_id;
+use crate::requests;
+use crate::response_cache;
+use crate::retrieval::{self, RetrievalOptions};
+use crate::status;
+use crate::store::{self, UsageRecord};
 use crate::types::Endpoint;
+use crate::validation::{check_range, FieldError, Validate};
+use crate::vector_stores::SearchResult;
 
 /// The plaintext or image content of a [`ChatMessage`] within a [`CreateChatCompletionRequest`].
 ///
@@ -300,8 +323,9 @@ pub struct CreateChatCompletionRequest<'a> {
     #[schema(value_type = String)]
     pub tool_choice: Option<Either<Cow<'a, str>, ToolStub<'a>>>,
 
-    /// A unique identifier for the _end user_ creating this request. This is used for telemetry
-    /// and user tracking, and is unused within Edgen.
+    /// A unique identifier for the _end user_ creating this request. **Edgen** attributes the
+    /// request to this user in `/v1/requests`, in the `request_finished` events and in the usage
+    /// records, without requiring API keys.
     pub user: Option<Cow<'a, str>>,
 
     /// Indicate if this is an isolated request, with no associated past or future context. This may allow for
@@ -314,6 +338,159 @@ pub struct CreateChatCompletionRequest<'a> {
     /// An unsound hint may severely drop performance and/or inference quality, and in some cases even cause Edgen
     /// to crash. Do not set this value unless you know what you are doing.
     pub context_hint: Option<u32>,
+
+    /// Keep the system messages at the start of the context when it is full, evicting the oldest
+    /// messages following them instead, so that long conversations keep their instructions.
+    /// Default: `false`, failing the completion when the context is full.
+    pub keep_system_prompt: Option<bool>,
+
+    /// Min-p sampling. Tokens whose probability is less than this fraction of the probability of
+    /// the most likely token are not sampled.
+    pub min_p: Option<f32>,
+
+    /// Locally typical sampling. Only the most typical tokens, up to this cumulative probability,
+    /// are sampled.
+    pub typical_p: Option<f32>,
+
+    /// Mirostat sampling, which keeps the perplexity of the output close to `mirostat_tau`: `1`
+    /// for Mirostat, `2` for Mirostat 2.0, or `0` to disable it. When enabled, replaces top-p,
+    /// min-p and typical sampling.
+    pub mirostat: Option<u8>,
+
+    /// The target entropy of Mirostat. Default: `5.0`
+    pub mirostat_tau: Option<f32>,
+
+    /// The learning rate of Mirostat. Default: `0.1`
+    pub mirostat_eta: Option<f32>,
+
+    /// The name of a sampler profile configured in the settings, e.g. `precise` or `creative`,
+    /// providing the sampling options this request does not set.
+    #[schema(value_type = String)]
+    pub sampler_profile: Option<Cow<'a, str>>,
+
+    /// Attach the tokens of every chunk to the chunks of a streamed response, with their IDs and
+    /// byte offsets. Only used when streaming. Default: `false`
+    pub include_tokens: Option<bool>,
+
+    /// The maximum time, in milliseconds, the tokens of a streamed response are held to be sent
+    /// together with the following ones, `0` sending every token as soon as it is generated.
+    /// Overrides the `stream_coalescing` of the settings. Only used when streaming.
+    pub coalesce_ms: Option<u64>,
+
+    /// The maximum number of tokens sent together in a chunk of a streamed response, or `0` for no
+    /// maximum. Overrides the `stream_coalescing` of the settings. Only used when streaming.
+    pub coalesce_tokens: Option<usize>,
+
+    /// Keep the prompt up to the last user message loaded in a pinned session, so that the next
+    /// one-shot requests starting with the same messages only process what follows them. Only
+    /// used by one-shot requests. Default: `false`
+    pub cache_prompt: Option<bool>,
+
+    /// The name of the pinned session holding the cached prompt. Implies `cache_prompt`.
+    ///
+    /// A request with the same key but a different prompt replaces the cached prompt.
+    #[schema(value_type = String)]
+    pub cache_key: Option<Cow<'a, str>>,
+
+    /// Should a new session be created from this request, holding the context of the
+    /// conversation, so that the next requests continue it by its ID instead of by matching
+    /// their messages with the context of the existing sessions.
+    ///
+    /// If `true`, the response will contain a session [`Uuid`].
+    ///
+    /// The value of this member is ignored if `session` has some value.
+    pub create_session: Option<bool>,
+
+    /// The [`Uuid`] of an existing chat session. The messages up to the last assistant message
+    /// are already in its context, so only the following messages are processed. `one_shot` is
+    /// ignored if this is set.
+    pub session: Option<Uuid>,
+
+    /// The ID of a conversation stored by **Edgen**. If set, `messages` only holds the newest
+    /// messages of the conversation, which are appended to its stored history, along with the
+    /// generated reply. Unknown IDs start a new conversation.
+    #[schema(value_type = String)]
+    pub conversation_id: Option<Cow<'a, str>>,
+
+    /// A vector store of **Edgen** to retrieve the context of the completion from. The chunks
+    /// most relevant to the last user message are added to the prompt, and returned in the
+    /// `sources` of the completion.
+    pub retrieval: Option<RetrievalOptions>,
+}
+
+impl Validate for CreateChatCompletionRequest<'_> {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_range(
+            &mut errors,
+            "frequency_penalty",
+            self.frequency_penalty,
+            -2.0,
+            2.0,
+        );
+        check_range(
+            &mut errors,
+            "presence_penalty",
+            self.presence_penalty,
+            -2.0,
+            2.0,
+        );
+        check_range(&mut errors, "temperature", self.temperature, 0.0, 2.0);
+        check_range(&mut errors, "top_p", self.top_p, 0.0, 1.0);
+        check_range(&mut errors, "min_p", self.min_p, 0.0, 1.0);
+        check_range(&mut errors, "typical_p", self.typical_p, 0.0, 1.0);
+        check_range(&mut errors, "mirostat", self.mirostat, 0, 2);
+        check_range(&mut errors, "max_tokens", self.max_tokens, 1, u32::MAX);
+        check_range(&mut errors, "n", self.n, 1, u32::MAX);
+        if let Some(bias) = &self.logit_bias {
+            for (token, bias) in bias {
+                check_range(
+                    &mut errors,
+                    &format!("logit_bias.{token}"),
+                    Some(*bias),
+                    -100.0,
+                    100.0,
+                );
+            }
+        }
+
+        let stream = self.stream.unwrap_or(false);
+        if stream && self.n.unwrap_or(1) > 1 {
+            errors.push(FieldError::new("n", "cannot be more than 1 when streaming"));
+        }
+        for (field, set) in [
+            ("include_tokens", self.include_tokens == Some(true)),
+            ("coalesce_ms", self.coalesce_ms.is_some()),
+            ("coalesce_tokens", self.coalesce_tokens.is_some()),
+        ] {
+            if set && !stream {
+                errors.push(FieldError::new(field, "can only be used when streaming"));
+            }
+        }
+
+        let one_shot = self.one_shot.unwrap_or(false);
+        for (field, set) in [
+            ("cache_prompt", self.cache_prompt == Some(true)),
+            ("cache_key", self.cache_key.is_some()),
+        ] {
+            if set && !one_shot {
+                errors.push(FieldError::new(field, "can only be used with one_shot"));
+            }
+        }
+        if self.session.is_some() {
+            if self.create_session == Some(true) {
+                errors.push(FieldError::new(
+                    "create_session",
+                    "cannot be used with session",
+                ));
+            }
+            if one_shot {
+                errors.push(FieldError::new("one_shot", "cannot be used with session"));
+            }
+        }
+
+        errors
+    }
 }
 
 /// A message in a chat completion.
@@ -381,6 +558,80 @@ pub struct ChatCompletion<'a> {
 
     /// Usage information about this completion.
     pub usage: ChatCompletionUsage,
+
+    /// The chunks added to the prompt, if `retrieval` was set in the request.
+    ///
+    /// This field is **Edgen** specific.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources: Option<Vec<SearchResult>>,
+
+    /// The [`Uuid`] of a newly created session, present only if `create_session` in
+    /// [`CreateChatCompletionRequest`] is set to `true` and `session` is not set.
+    ///
+    /// This field is **Edgen** specific.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<Uuid>,
+
+    /// The timings of the generation of the completion, unless it was cached.
+    ///
+    /// This field is **Edgen** specific.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<ChatCompletionTimings>,
+}
+
+/// The timings of the generation of a chat completion, in milliseconds.
+///
+/// Models whose runtime does not record timings only report the time spent locating their files.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChatCompletionTimings {
+    /// The time spent locating, or downloading, the model and loading it into memory.
+    pub load_ms: f64,
+
+    /// The time the request waited for, besides loading the model, before its prompt started
+    /// being processed.
+    pub queue_ms: f64,
+
+    /// The time spent processing the prompt.
+    pub prompt_ms: f64,
+
+    /// The time spent generating the completion.
+    pub generation_ms: f64,
+
+    /// The number of generated tokens.
+    pub completion_tokens: usize,
+
+    /// The number of tokens generated every second.
+    pub tokens_per_second: f64,
+}
+
+impl ChatCompletionTimings {
+    /// Builds the timings of a request received at `received`, whose model files took `preload`
+    /// to be located, from the timings recorded by its runtime.
+    fn new(received: Instant, preload: Duration, timings: CompletionTimings) -> Self {
+        let load = preload + timings.load;
+        let queue = timings
+            .prompt_start
+            .map(move |start| {
+                start
+                    .saturating_duration_since(received)
+                    .saturating_sub(load)
+            })
+            .unwrap_or_default();
+
+        Self {
+            load_ms: millis(load),
+            queue_ms: millis(queue),
+            prompt_ms: millis(timings.prompt_eval),
+            generation_ms: millis(timings.generation()),
+            completion_tokens: timings.tokens,
+            tokens_per_second: timings.tokens_per_second(),
+        }
+    }
+}
+
+/// Returns a [`Duration`] in fractional milliseconds.
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
 }
 
 /// A delta-encoded difference for an ongoing, stream-mode chat completion.
@@ -432,6 +683,45 @@ pub struct ChatCompletionChunk<'a> {
 
     /// The object type. This is always `text_completion`.
     pub object: Cow<'a, str>,
+
+    /// The chunks added to the prompt, if `retrieval` was set in the request. Only sent in the
+    /// first chunk.
+    ///
+    /// This field is **Edgen** specific.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sources: Option<Vec<SearchResult>>,
+
+    /// The [`Uuid`] of a newly created session, present only if `create_session` in
+    /// [`CreateChatCompletionRequest`] is set to `true` and `session` is not set. Only sent in
+    /// the first chunk.
+    ///
+    /// This field is **Edgen** specific.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<Uuid>,
+
+    /// The tokens the chunk is made of, if `include_tokens` was set in the request. Empty for the
+    /// models that do not report their tokens.
+    ///
+    /// This field is **Edgen** specific.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens: Option<Vec<ChunkToken>>,
+
+    /// The timings of the generation of the completion. Only sent in the last chunk, whose only
+    /// choice has no content.
+    ///
+    /// This field is **Edgen** specific.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timings: Option<ChatCompletionTimings>,
+}
+
+/// A token of a [`ChatCompletionChunk`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChunkToken {
+    /// The ID of the token in the vocabulary of the model.
+    pub id: u32,
+
+    /// The offset, in bytes, of the token in the whole completion.
+    pub offset: usize,
 }
 
 /// An error condition raised by the chat completion API.
@@ -473,14 +763,91 @@ pub enum ChatCompletionError {
     #[error("an error occurred on the other side of a C FFI boundary; check `tracing`")]
     Ffi,
 
+    /// A parameter of the request has an invalid value.
+    #[error("invalid value for parameter {param}: {reason}")]
+    InvalidParam {
+        /// The name of the parameter.
+        param: String,
+
+        /// A human-readable error message.
+        reason: Cow<'static, str>,
+    },
+
+    /// A parameter of the request exceeds its maximum in the `request_caps` of the settings or of
+    /// the API key of the request.
+    #[error("parameter {param} exceeds its maximum of {max}")]
+    ExceedsCap {
+        /// The name of the parameter.
+        param: String,
+
+        /// The maximum of the parameter.
+        max: u64,
+    },
+
     /// An error occurred while processing the request to this endpoint.
     #[error("an error occurred while processing the request: {0}")]
     Endpoint(#[from] LLMEndpointError),
+
+    /// The request could not be forwarded to the upstream API of the remote fallback.
+    #[error("the remote fallback failed: {reason}")]
+    Remote {
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// The stored history of the conversation could not be accessed.
+    #[error("the conversation store failed: {reason}")]
+    Store {
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// The conversation of the request belongs to another API key.
+    #[error("conversation {conversation_id} belongs to another API key")]
+    ForeignConversation {
+        /// The ID of the conversation.
+        conversation_id: String,
+    },
+
+    /// There is no vector store with the ID provided for retrieval.
+    #[error("no such vector store: {vector_store_id}")]
+    NoSuchVectorStore {
+        /// The ID of the vector store.
+        vector_store_id: String,
+    },
+
+    /// The context of the completion could not be retrieved from the vector store.
+    #[error("retrieval failed: {reason}")]
+    Retrieval {
+        /// A human-readable error message.
+        reason: String,
+    },
+}
+
+impl ChatCompletionError {
+    /// Returns the status of the response of a request failing with this error.
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ChatCompletionError::Endpoint(
+                LLMEndpointError::InputTooLarge { .. } | LLMEndpointError::ContextTooLarge { .. },
+            ) => StatusCode::PAYLOAD_TOO_LARGE,
+            ChatCompletionError::Remote { .. } => StatusCode::BAD_GATEWAY,
+            ChatCompletionError::ForeignConversation { .. } => StatusCode::FORBIDDEN,
+            ChatCompletionError::NoSuchVectorStore { .. } => StatusCode::NOT_FOUND,
+            ChatCompletionError::Endpoint(LLMEndpointError::SessionNotFound) => {
+                StatusCode::NOT_FOUND
+            }
+            ChatCompletionError::InvalidParam { .. } | ChatCompletionError::ExceedsCap { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl IntoResponse for ChatCompletionError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
+        request_id::error_response(self.status_code(), &self)
     }
 }
 
@@ -517,6 +884,19 @@ struct ModelId {
     dir: String,
 }
 
+/// Returns the [`ChatBackend`] registered for the provided [`ModelKind`].
+fn chat_backend(
+    kind: &ModelKind,
+    model_name: &str,
+) -> Result<Arc<dyn ChatBackend>, ChatCompletionError> {
+    BACKENDS
+        .chat(kind)
+        .ok_or_else(move || ChatCompletionError::UnknownModelKind {
+            model_name: model_name.to_string(),
+            reason: Cow::Owned(format!("no backend is registered for {kind:?}")),
+        })
+}
+
 async fn get_chat_completions_model_params(name: &str) -> Result<ModelId, &'static str> {
     async fn default_quartet() -> ModelId {
         let name = settings::chat_completions_name().await;
@@ -531,6 +911,9 @@ async fn get_chat_completions_model_params(name: &str) -> Result<ModelId, &'stat
     if name.is_empty() || name.to_ascii_lowercase() == "default" {
         return Ok(default_quartet().await);
     }
+    if Path::new(name).is_absolute() {
+        return get_local_model_params(name).await;
+    }
     get_model_params(name, &settings::chat_completions_dir().await)
 }
 
@@ -548,6 +931,9 @@ async fn get_audio_transcriptions_model_params(name: &str) -> Result<ModelId, &'
     if name.is_empty() || name.to_ascii_lowercase() == "default" {
         return Ok(default_quartet().await);
     }
+    if Path::new(name).is_absolute() {
+        return get_local_model_params(name).await;
+    }
     get_model_params(name, &settings::audio_transcriptions_dir().await)
 }
 
@@ -565,6 +951,9 @@ async fn get_embeddings_model_params(name: &str) -> Result<ModelId, &'static str
     if name.is_empty() || name.to_ascii_lowercase() == "default" {
         return Ok(default_quartet().await);
     }
+    if Path::new(name).is_absolute() {
+        return get_local_model_params(name).await;
+    }
     get_model_params(name, &settings::embeddings_dir().await)
 }
 
@@ -576,15 +965,38 @@ fn get_model_params(model_name: &str, dir: &str) -> Result<ModelId, &'static str
             repo: owner + "/" + &repo,
             dir: dir.to_string(),
         }),
+        // a bare file name may be that of a model file added to any of the model directories
         Err(_) => Ok(ModelId {
             kind_param: model_name.to_string(),
             name: model_name.to_string(),
             repo: "".to_string(),
-            dir: dir.to_string(),
+            dir: model_watcher::local_model(model_name)
+                .and_then(|path| Some(path.parent()?.to_string_lossy().to_string()))
+                .unwrap_or_else(|| dir.to_string()),
         }),
     }
 }
 
+/// Returns the parameters of a model requested by the absolute path of its file, which must be
+/// below one of the `local_model_dirs` of the settings.
+async fn get_local_model_params(path: &str) -> Result<ModelId, &'static str> {
+    if !settings::local_model_allowed(Path::new(path)).await {
+        return Err("The model path is not in any of the local model directories");
+    }
+
+    let path = Path::new(path);
+    let (Some(name), Some(dir)) = (path.file_name(), path.parent()) else {
+        return Err("The model path does not point to a file");
+    };
+
+    Ok(ModelId {
+        kind_param: path.to_string_lossy().to_string(),
+        name: name.to_string_lossy().to_string(),
+        repo: "".to_string(),
+        dir: dir.to_string_lossy().to_string(),
+    })
+}
+
 pub(crate) fn parse_model_param(model: &str) -> Result<(String, String, String), ParseError> {
     let vs = model.split("/").collect::<Vec<&str>>();
     let l = vs.len();
@@ -694,6 +1106,11 @@ impl From<ChatMessages<'_>> for edgen_core::llm::ChatMessages {
 
 impl From<CreateChatCompletionRequest<'_>> for CompletionArgs {
     fn from(value: CreateChatCompletionRequest) -> Self {
+        let create_session = value.session.is_none() && value.create_session.unwrap_or(false);
+        let session = value
+            .session
+            .or_else(move || create_session.then(Uuid::new_v4));
+
         Self {
             messages: value.messages.into(),
             frequency_penalty: value.frequency_penalty,
@@ -708,8 +1125,22 @@ impl From<CreateChatCompletionRequest<'_>> for CompletionArgs {
             }),
             temperature: value.temperature,
             top_p: value.top_p,
+            min_p: value.min_p,
+            typical_p: value.typical_p,
+            mirostat: value.mirostat,
+            mirostat_tau: value.mirostat_tau,
+            mirostat_eta: value.mirostat_eta,
             one_shot: value.one_shot,
             context_hint: value.context_hint,
+            cache_prompt: value.cache_prompt,
+            cache_key: value.cache_key.map(|x| x.to_string()),
+            create_session,
+            session,
+            token_log: None,
+            timings: None,
+            no_kv_offload: false,
+            max_context: None,
+            keep_system_prompt: value.keep_system_prompt.unwrap_or(false),
         }
     }
 }
@@ -748,24 +1179,205 @@ pub enum ParseError {
 ///
 /// [sse]: https://developer.mozilla.org/en-US/docs/Web/API/Server-sent_events
 ///
+/// If `conversation_id` is set, the stored history of the conversation is prepended to the
+/// messages of the request, and the messages and the generated reply are appended to it. A
+/// conversation can only be continued with the API key it was started with.
+///
+/// If the model of a streamed request is not ready within a second, e.g. because it is being
+/// downloaded or loaded, the stream starts right away with a `queued` event and is kept alive with
+/// comments until the generation begins. Errors occurring after that are sent as an `error` event.
+///
+/// If a remote fallback is configured, requests whose model is unavailable, or that wait for the
+/// local runtime longer than allowed, are forwarded to the upstream API, as are requests for models
+/// pinned to the `remote` backend in the settings. The `X-Edgen-Backend`
+/// header of the response tells where the generation ran, either `local` or `remote`.
+///
 /// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ChatCompletionError`]
-/// to the peer.
+/// to the peer, a `403 Forbidden` if the conversation belongs to another API key, or a
+/// `502 Bad Gateway` if the remote fallback failed.
 #[utoipa::path(
 post,
 path = "/chat/completions",
 request_body = CreateChatCompletionRequest,
 responses(
 (status = 200, description = "OK", body = ChatCompletionResponse),
-(status = 500, description = "unexpected internal server error", body = ChatCompletionError)
+(status = 400, description = "a parameter is invalid or exceeds its maximum", body = ChatCompletionError),
+(status = 403, description = "the conversation belongs to another API key", body = ChatCompletionError),
+(status = 413, description = "the prompt exceeds the maximum context of the model", body = ChatCompletionError),
+(status = 500, description = "unexpected internal server error", body = ChatCompletionError),
+(status = 502, description = "the remote fallback failed", body = ChatCompletionError)
 ),
 )]
 pub async fn chat_completions(
+    key_models: Option<Extension<KeyModels>>,
+    key_caps: Option<Extension<RequestCaps>>,
+    key_owner: Option<Extension<KeyOwner>>,
     Json(req): Json<CreateChatCompletionRequest<'_>>,
 ) -> Result<impl IntoResponse, ChatCompletionError> {
-    let params = get_chat_completions_model_params(req.model.as_ref()).await;
+    let result = serve_chat_completions(key_models, key_caps, key_owner, req).await;
+    if let Err(e) = &result {
+        if e.status_code().is_server_error() {
+            status::add_chat_completions_error(e).await;
+        }
+    }
+    result
+}
+
+/// Serves a request to [`chat_completions`].
+async fn serve_chat_completions(
+    key_models: Option<Extension<KeyModels>>,
+    key_caps: Option<Extension<RequestCaps>>,
+    key_owner: Option<Extension<KeyOwner>>,
+    mut req: CreateChatCompletionRequest<'_>,
+) -> Result<Response, ChatCompletionError> {
+    if let Some(user) = &req.user {
+        requests::set_user(user);
+    }
+    use_key_model(
+        &mut req.model,
+        key_models.and_then(move |Extension(models)| models.chat_completions_model),
+    );
+
+    let caps = request_caps(key_caps).await;
+    if let Some(max) = exceeds(req.max_tokens, caps.max_tokens) {
+        return Err(ChatCompletionError::ExceedsCap {
+            param: "max_tokens".to_string(),
+            max: max.into(),
+        });
+    }
+    if let Some(max) = exceeds(req.context_hint, caps.context_hint) {
+        return Err(ChatCompletionError::ExceedsCap {
+            param: "context_hint".to_string(),
+            max: max.into(),
+        });
+    }
+    req.max_tokens = req.max_tokens.or(caps.max_tokens);
+
+    let conversation = match &req.conversation_id {
+        Some(id) => {
+            let key_owner = key_owner.as_ref().map(move |Extension(owner)| owner);
+            Some(Conversation::load(id, key_owner, &mut req.messages).await?)
+        }
+        None => None,
+    };
+    // Retrieved after loading the conversation, so that the retrieved chunks are not stored in it
+    let sources = match &req.retrieval {
+        Some(options) => Some(retrieval::augment(options, &mut req.messages).await?),
+        None => None,
+    };
+
+    let pinned = match get_chat_completions_model_params(req.model.as_ref()).await {
+        Ok(params) => {
+            settings::model_backend(&[params.kind_param.as_str(), params.name.as_str()]).await
+        }
+        Err(_) => None,
+    };
+    if pinned == Some(ModelBackend::Remote) {
+        return Ok(remote::tag_backend(
+            remote::chat_completions(request_body(&req)?, conversation).await?,
+            true,
+        ));
+    }
+
+    if settings::remote_fallback_url().await.is_none() {
+        return Ok(remote::tag_backend(
+            local_chat_completions(req, conversation, sources, true, TimingsRecorder::default())
+                .await?,
+            false,
+        ));
+    }
+
+    // the request is consumed by the local runtime, so keep a copy to forward
+    let body = request_body(&req)?;
+
+    // the stream must not be answered before the local runtime is known to be able to serve it
+    let timings = TimingsRecorder::default();
+    let local = local_chat_completions(req, conversation.clone(), sources, false, timings.clone());
+    let result = match settings::remote_fallback_max_wait().await {
+        Some(max_wait) => {
+            tokio::pin!(local);
+            match tokio::time::timeout(max_wait, &mut local).await {
+                Ok(result) => result,
+                // once the runtime started processing the prompt, the model is ready, so falling
+                // back would only waste the work done locally
+                Err(_) if timings.get().prompt_start.is_some() => local.await,
+                Err(_) => {
+                    info!("Local runtime not ready after {max_wait:?}, falling back to remote");
+                    return Ok(remote::tag_backend(
+                        remote::chat_completions(body, conversation).await?,
+                        true,
+                    ));
+                }
+            }
+        }
+        None => local.await,
+    };
+
+    match result {
+        Ok(response) => Ok(remote::tag_backend(response, false)),
+        Err(
+            e @ (ChatCompletionError::NoSuchModel { .. }
+            | ChatCompletionError::UnknownModelKind { .. }
+            | ChatCompletionError::Endpoint(LLMEndpointError::Load(_))),
+        ) => {
+            info!("Local chat completions unavailable ({e}), falling back to remote");
+            Ok(remote::tag_backend(
+                remote::chat_completions(body, conversation).await?,
+                true,
+            ))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces a request for the `default` model with the model of the API key it was sent with,
+/// if the key has one.
+pub(crate) fn use_key_model(model: &mut Cow<'_, str>, key_model: Option<String>) {
+    if let Some(key_model) = key_model {
+        if model.is_empty() || model.eq_ignore_ascii_case("default") {
+            *model = Cow::Owned(key_model);
+        }
+    }
+}
+
+/// Returns the maximums of the parameters of a request, the tightest of the `request_caps` of the
+/// settings and of the API key it was sent with.
+pub(crate) async fn request_caps(key_caps: Option<Extension<RequestCaps>>) -> RequestCaps {
+    let caps = settings::request_caps().await;
+    match key_caps {
+        Some(Extension(key_caps)) => caps.min(&key_caps),
+        None => caps,
+    }
+}
+
+/// Returns the cap of a parameter if its value exceeds it.
+pub(crate) fn exceeds<T: PartialOrd + Copy>(value: Option<T>, cap: Option<T>) -> Option<T> {
+    match (value, cap) {
+        (Some(value), Some(cap)) if value > cap => Some(cap),
+        _ => None,
+    }
+}
+
+/// Encodes a request to be forwarded to the upstream API.
+fn request_body(
+    req: &CreateChatCompletionRequest<'_>,
+) -> Result<serde_json::Value, ChatCompletionError> {
+    serde_json::to_value(req).map_err(move |e| ChatCompletionError::Remote {
+        reason: e.to_string(),
+    })
+}
+
+/// Resolves and preloads the chat completions model named `model_name`, returning it along with
+/// the backend that runs it and its default sampling options, taken from the sampler profile named
+/// `sampler_profile` if any.
+async fn load_chat_model(
+    model_name: &str,
+    sampler_profile: Option<&str>,
+) -> Result<(Arc<dyn ChatBackend>, Model, ChatModelOptions), ChatCompletionError> {
+    let params = get_chat_completions_model_params(model_name).await;
     if let Err(error) = params {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed(error),
         });
     }
@@ -774,13 +1386,13 @@ pub async fn chat_completions(
 
     if params.name.is_empty() {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed("Empty model name in config"),
         });
     }
     if params.dir.is_empty() {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed("Empty model directory in config"),
         });
     }
@@ -788,17 +1400,22 @@ pub async fn chat_completions(
     // at the moment we care only about the top hit.
     // we can, alternatively, consider all matches and go through them
     // until one backend succeeds.
-    let kind = MODEL_PATTERNS
-        .get_top_model_kind(&params.kind_param, &[ModelKind::LLM, ModelKind::ChatFaker]);
+    let kind = resolve_model_kind(
+        &[params.kind_param.as_str(), params.name.as_str()],
+        &BACKENDS.chat_kinds(),
+    )
+    .await;
     if let Err(error) = kind {
         return Err(ChatCompletionError::UnknownModelKind {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Owned(error.to_string()),
         });
     }
+    let kind = kind.unwrap();
+    let backend = chat_backend(&kind, model_name)?;
 
     let mut model = Model::new(
-        kind.unwrap(),
+        kind,
         &params.name,
         &params.repo,
         &PathBuf::from(&params.dir),
@@ -810,44 +1427,232 @@ pub async fn chat_completions(
         .map_err(move |_| ChatCompletionError::NoSuchModel {
             model_name: params.name.to_string(),
         })?;
+    admin::register_model(&[model_name, params.name.as_str()], &model).await;
 
-    let stream_response = req.stream.unwrap_or(false);
+    let ids = [model_name, params.name.as_str()];
+    let options = ChatModelOptions {
+        sampler: sampler_defaults(&ids, sampler_profile).await?,
+        no_kv_offload: settings::model_no_kv_offload(&ids).await,
+        max_context: settings::model_max_context(&ids).await,
+    };
 
-    let fp = format!("edgen-{}", cargo_crate_version!());
-    let response = if stream_response {
-        let completions_stream = {
-            let result = match model.kind {
-                ModelKind::LLM => llm::chat_completion_stream(model, req.into()).await?,
-                ModelKind::ChatFaker => {
-                    chat_faker::chat_completion_stream(model, req.into()).await?
-                }
-                _ => panic!("we should never get here"),
-            };
-            result.map(move |chunk| {
-                Event::default().json_data(ChatCompletionChunk {
-                    id: Uuid::new_v4().to_string().into(),
-                    choices: tiny_vec![ChatCompletionChunkChoice {
-                        index: 0,
-                        finish_reason: None,
-                        delta: ChatCompletionChunkDelta {
-                            content: Some(Cow::Owned(chunk)),
-                            role: None,
-                        },
-                    }],
-                    created: OffsetDateTime::now_utc().unix_timestamp(),
-                    model: Cow::Borrowed("main"),
-                    system_fingerprint: Cow::Borrowed(&fp),
-                    object: Cow::Borrowed("text_completion"),
-                })
-            })
-        };
-        ChatCompletionResponse::Stream(Sse::new(completions_stream))
+    Ok((backend, model, options))
+}
+
+/// The options of a chat completions model set in the settings.
+struct ChatModelOptions {
+    /// The default sampling options of the model.
+    sampler: SamplerOptions,
+
+    /// Whether the KV cache of the model is kept in host memory.
+    no_kv_offload: bool,
+
+    /// The largest context the model may be given, in tokens.
+    max_context: Option<u32>,
+}
+
+impl ChatModelOptions {
+    /// Completes the arguments of a completion with these options.
+    fn apply(&self, args: CompletionArgs) -> CompletionArgs {
+        let mut args = args.with_sampler_defaults(&self.sampler);
+        args.no_kv_offload = self.no_kv_offload;
+        if let Some(max) = self.max_context {
+            args.context_hint = args.context_hint.map(move |hint| hint.min(max));
+            args.max_context = Some(max);
+        }
+        args
+    }
+}
+
+/// Resolves the default sampling options of a model.
+///
+/// The options of the requested sampler profile come first, then those of `sampler_defaults`, then
+/// those of the profile assigned to the model.
+async fn sampler_defaults(
+    ids: &[&str],
+    sampler_profile: Option<&str>,
+) -> Result<SamplerOptions, ChatCompletionError> {
+    let defaults = settings::sampler_defaults(ids).await;
+
+    if let Some(name) = sampler_profile {
+        let profile = settings::sampler_profile(name).await.ok_or_else(move || {
+            ChatCompletionError::InvalidParam {
+                param: "sampler_profile".to_string(),
+                reason: Cow::Owned(format!("no such sampler profile: \"{name}\"")),
+            }
+        })?;
+        return Ok(profile.or(&defaults));
+    }
+
+    let Some(name) = settings::model_sampler_profile(ids).await else {
+        return Ok(defaults);
+    };
+    match settings::sampler_profile(&name).await {
+        Some(profile) => Ok(defaults.or(&profile)),
+        None => {
+            warn!(
+                "The sampler profile \"{name}\" assigned to model {} does not exist",
+                ids[0]
+            );
+            Ok(defaults)
+        }
+    }
+}
+
+/// Generates a chat completion for the provided arguments with the chat completions model named
+/// `model_name`, without streaming it.
+pub(crate) async fn generate_chat_completion(
+    model_name: &str,
+    args: CompletionArgs,
+) -> Result<String, ChatCompletionError> {
+    let (backend, model, options) = load_chat_model(model_name, None).await?;
+    let args = options.apply(args);
+    Ok(backend.chat_completion(model, args).await?)
+}
+
+/// How long the model of a streamed chat completion may take to be ready before the response is
+/// sent, starting with a `queued` event and kept alive until the generation begins.
+const QUEUED_AFTER: Duration = Duration::from_secs(1);
+
+/// The return type of [`chat_completions_estimate`].
+#[derive(Debug, Serialize, Deserialize, ToSchema, PartialEq, Eq)]
+pub struct ChatCompletionEstimate {
+    /// The model the prompt was rendered and tokenized with.
+    pub model: String,
+
+    /// The number of tokens of the rendered prompt.
+    pub prompt_tokens: usize,
+
+    /// The size of the context the prompt would be processed in, in tokens.
+    pub context_size: usize,
+
+    /// The number of tokens left in the context for the completion. If `0`, the prompt does not
+    /// fit in the context, and the oldest messages should be trimmed.
+    pub remaining_tokens: usize,
+
+    /// The estimated memory, in bytes, the session processing the prompt would take, besides the
+    /// weights of the model.
+    pub session_memory: usize,
+}
+
+/// POST `/v1/chat/completions/estimate`: renders the prompt of a chat completion request and
+/// tokenizes it with the requested model, without generating anything, so that clients can trim
+/// the history of a conversation before submitting it.
+///
+/// The body is a [`CreateChatCompletionRequest`], as sent to `/v1/chat/completions`. Its
+/// `conversation_id` and `retrieval` are not used, only its `messages` are rendered. The model is
+/// loaded if it is not already. This endpoint is specific to **Edgen**.
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ChatCompletionError`]
+/// to the peer, e.g. if the runtime of the model cannot estimate prompts.
+#[utoipa::path(
+post,
+path = "/chat/completions/estimate",
+request_body = CreateChatCompletionRequest,
+responses(
+(status = 200, description = "OK", body = ChatCompletionEstimate),
+(status = 500, description = "unexpected internal server error", body = ChatCompletionError)
+),
+)]
+pub async fn chat_completions_estimate(
+    key_models: Option<Extension<KeyModels>>,
+    Json(mut req): Json<CreateChatCompletionRequest<'_>>,
+) -> Result<Json<ChatCompletionEstimate>, ChatCompletionError> {
+    use_key_model(
+        &mut req.model,
+        key_models.and_then(move |Extension(models)| models.chat_completions_model),
+    );
+
+    let (backend, model, options) =
+        load_chat_model(req.model.as_ref(), req.sampler_profile.as_deref()).await?;
+    let model_name = req.model.to_string();
+    let estimate = backend
+        .estimate_prompt(model, options.apply(req.into()))
+        .await?;
+
+    Ok(Json(ChatCompletionEstimate {
+        model: model_name,
+        prompt_tokens: estimate.prompt_tokens,
+        context_size: estimate.context_size,
+        remaining_tokens: estimate.remaining_tokens(),
+        session_memory: estimate.session_memory,
+    }))
+}
+
+/// Generates a chat completion with the local runtime.
+///
+/// If `keep_alive` is set, streamed completions whose model takes longer than [`QUEUED_AFTER`] to
+/// be ready are answered right away, so that the connection is not dropped by proxies while the
+/// model is downloaded or loaded. Errors occurring after that are sent as an `error` event.
+///
+/// The progress of the generation is recorded in `timings`.
+async fn local_chat_completions(
+    req: CreateChatCompletionRequest<'_>,
+    conversation: Option<Conversation>,
+    sources: Option<Vec<SearchResult>>,
+    keep_alive: bool,
+    timings: TimingsRecorder,
+) -> Result<Response, ChatCompletionError> {
+    let response = if req.stream.unwrap_or(false) {
+        ChatCompletionResponse::Stream(
+            stream_local_chat_completions(req, conversation, sources, keep_alive, timings).await?,
+        )
     } else {
-        let content_str = match model.kind {
-            ModelKind::LLM => llm::chat_completion(model, req.into()).await?,
-            ModelKind::ChatFaker => crate::chat_faker::chat_completion(model, req.into()).await?,
-            _ => panic!("we should never get here"),
+        let received = Instant::now();
+        let (backend, model, options) =
+            load_chat_model(req.model.as_ref(), req.sampler_profile.as_deref()).await?;
+        let preload = received.elapsed();
+
+        let fp = format!("edgen-{}", cargo_crate_version!());
+        // Only requests with a temperature of 0 have a cache key, see `response_cache`. The
+        // completions of sessions depend on their context, which is not part of the request
+        let cache_key = if settings::chat_completions_cache().await
+            && req.session.is_none()
+            && !req.create_session.unwrap_or(false)
+        {
+            let request = request_body(&req)
+                .ok()
+                .and_then(response_cache::request_key);
+            model
+                .file_path()
+                .ok()
+                .map(move |path| path.to_string_lossy().to_string())
+                .zip(request)
+        } else {
+            None
+        };
+        let cached = match &cache_key {
+            Some((model_path, request)) => response_cache::get(model_path, request).await,
+            None => None,
+        };
+
+        let model_name = req.model.to_string();
+        let user = req.user.as_ref().map(move |user| user.to_string());
+        let mut session = None;
+        let (content_str, timings) = match cached {
+            Some(content) => (content, None),
+            None => {
+                let mut args = options.apply(CompletionArgs::from(req));
+                args.timings = Some(timings.clone());
+                session = args.session.filter(|_| args.create_session);
+                let used_session = args.session;
+                let content = backend.chat_completion(model, args).await?;
+                let usage = ChatUsage {
+                    model: model_name,
+                    user,
+                    session: used_session,
+                };
+                usage.record(timings.get().tokens).await;
+                if let Some((model_path, request)) = &cache_key {
+                    response_cache::insert(model_path, request, &content).await;
+                }
+                let timings = ChatCompletionTimings::new(received, preload, timings.get());
+                (content, Some(timings))
+            }
         };
+        if let Some(conversation) = conversation {
+            conversation.record_reply(&content_str).await;
+        }
         let response = ChatCompletion {
             id: Uuid::new_v4().to_string().into(),
             choices: vec![ChatCompletionChoice {
@@ -868,30 +1673,209 @@ pub async fn chat_completions(
                 prompt_tokens: 0,
                 total_tokens: 0,
             },
+            sources,
+            session,
+            timings,
         };
 
         ChatCompletionResponse::Full(Json(response))
     };
 
-    Ok(response)
+    Ok(response.into_response())
 }
 
-/// A request to generate embeddings for one or more pieces of text.
-///
-/// An `axum` handler, [`create_embeddings`][create_embeddings], is provided to handle this request.
-///
-/// See [the documentation for creating transcriptions][openai] for more details.
-///
-/// [embeddings]: fn.create_embeddings.html
-/// [openai]: https://platform.openai.com/docs/api-reference/embeddings/create
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
-pub struct CreateEmbeddingsRequest<'a> {
-    /// The text input to embed as either a string or an array of strings.
-    #[serde(with = "either::serde_untagged")]
-    #[schema(value_type = String)]
-    pub input: Either<Cow<'a, str>, Vec<Cow<'a, str>>>,
+/// What the usage of a chat completion is recorded for.
+struct ChatUsage {
+    /// The model generating the completion.
+    model: String,
 
-    /// ID of the model to use.
+    /// The end user the completion was requested for, if any.
+    user: Option<String>,
+
+    /// The session the completion was generated in, if any.
+    session: Option<Uuid>,
+}
+
+impl ChatUsage {
+    /// Records the usage of the completion, and the session it used, if any.
+    ///
+    /// The runtimes do not count the tokens of prompts, so only the generated tokens are recorded.
+    async fn record(self, completion_tokens: usize) {
+        store::log_usage(UsageRecord {
+            timestamp: store::now(),
+            endpoint: "chat/completions".to_string(),
+            model: self.model.clone(),
+            prompt_tokens: 0,
+            completion_tokens: completion_tokens as u64,
+            user: self.user,
+        })
+        .await;
+        if let Some(session) = self.session {
+            store::log_session(&session.to_string(), &self.model).await;
+        }
+    }
+}
+
+/// Generates a streamed chat completion with the local runtime, see [`local_chat_completions`].
+async fn stream_local_chat_completions(
+    req: CreateChatCompletionRequest<'_>,
+    conversation: Option<Conversation>,
+    sources: Option<Vec<SearchResult>>,
+    keep_alive: bool,
+    timings: TimingsRecorder,
+) -> Result<Sse<BoxStream<'static, Result<Event, axum::Error>>>, ChatCompletionError> {
+    let received = Instant::now();
+    let fp = format!("edgen-{}", cargo_crate_version!());
+
+    let model_name = req.model.to_string();
+    let user = req.user.as_ref().map(move |user| user.to_string());
+    let sampler_profile = req
+        .sampler_profile
+        .as_ref()
+        .map(move |name| name.to_string());
+    let token_log = req.include_tokens.unwrap_or(false).then(TokenLog::default);
+    let coalescing = settings::stream_coalescing().await;
+    let window = Duration::from_millis(req.coalesce_ms.unwrap_or(coalescing.window_ms));
+    let max_tokens = req.coalesce_tokens.unwrap_or(coalescing.max_tokens);
+    let mut args = CompletionArgs::from(req);
+    args.token_log = token_log.clone();
+    args.timings = Some(timings.clone());
+    let mut session = args.session.filter(|_| args.create_session);
+    let usage = ChatUsage {
+        model: model_name.clone(),
+        user,
+        session: args.session,
+    };
+
+    let start = async move {
+        let (backend, model, options) =
+            load_chat_model(&model_name, sampler_profile.as_deref()).await?;
+        let preload = received.elapsed();
+        let result = backend
+            .chat_completion_stream(model, options.apply(args))
+            .await?;
+        Ok::<_, ChatCompletionError>((result, preload))
+    };
+
+    let events = move |(result, preload): (CompletionStream, Duration)| {
+        let result = if window.is_zero() {
+            result.boxed()
+        } else {
+            coalesce(result, window, max_tokens).boxed()
+        };
+        let result =
+            conversation::record_stream(conversation, result, move |chunk| Some(chunk.clone()));
+        let mut sources = sources;
+        let mut offset = 0;
+        let last_fp = fp.clone();
+        let result = result.map(move |chunk| {
+            let tokens = token_log.as_ref().map(|log| {
+                log.take()
+                    .into_iter()
+                    .map(|token| {
+                        let chunk_token = ChunkToken {
+                            id: token.id,
+                            offset,
+                        };
+                        offset += token.len;
+                        chunk_token
+                    })
+                    .collect()
+            });
+            Event::default().json_data(ChatCompletionChunk {
+                id: Uuid::new_v4().to_string().into(),
+                choices: tiny_vec![ChatCompletionChunkChoice {
+                    index: 0,
+                    finish_reason: None,
+                    delta: ChatCompletionChunkDelta {
+                        content: Some(Cow::Owned(chunk)),
+                        role: None,
+                    },
+                }],
+                created: OffsetDateTime::now_utc().unix_timestamp(),
+                model: Cow::Borrowed("main"),
+                system_fingerprint: Cow::Borrowed(&fp),
+                object: Cow::Borrowed("text_completion"),
+                sources: sources.take(),
+                session: session.take(),
+                tokens,
+                timings: None,
+            })
+        });
+
+        result.chain(futures::stream::once(async move {
+            let timings = timings.get();
+            tokio::spawn(usage.record(timings.tokens));
+
+            Event::default().json_data(ChatCompletionChunk {
+                id: Uuid::new_v4().to_string().into(),
+                // An empty choice, so that clients reading the first choice of every
+                // chunk keep working
+                choices: tiny_vec![ChatCompletionChunkChoice {
+                    index: 0,
+                    finish_reason: Some(Cow::Borrowed("stop")),
+                    delta: ChatCompletionChunkDelta {
+                        content: Some(Cow::Borrowed("")),
+                        role: None,
+                    },
+                }],
+                created: OffsetDateTime::now_utc().unix_timestamp(),
+                model: Cow::Borrowed("main"),
+                system_fingerprint: Cow::Owned(last_fp),
+                object: Cow::Borrowed("text_completion"),
+                sources: None,
+                session: None,
+                tokens: None,
+                timings: Some(ChatCompletionTimings::new(received, preload, timings)),
+            })
+        }))
+    };
+
+    let mut start = Box::pin(start);
+    if !keep_alive {
+        return Ok(Sse::new(events(start.await?).boxed()));
+    }
+    if let Ok(started) = tokio::time::timeout(QUEUED_AFTER, &mut start).await {
+        return Ok(Sse::new(events(started?).boxed()));
+    }
+
+    info!("The chat completions model is not ready yet, keeping the stream alive");
+    let queued = futures::stream::once(async move {
+        Event::default()
+            .event("queued")
+            .json_data(serde_json::json!({"status": "queued"}))
+    });
+    let started = futures::stream::once(async move {
+        match start.await {
+            Ok(started) => events(started).left_stream(),
+            Err(e) => {
+                futures::stream::once(async move { Event::default().event("error").json_data(e) })
+                    .right_stream()
+            }
+        }
+    })
+    .flatten();
+
+    let keep_alive = KeepAlive::default().text("keep-alive");
+    Ok(Sse::new(queued.chain(started).boxed()).keep_alive(keep_alive))
+}
+
+/// A request to generate embeddings for one or more pieces of text.
+///
+/// An `axum` handler, [`create_embeddings`][create_embeddings], is provided to handle this request.
+///
+/// See [the documentation for creating transcriptions][openai] for more details.
+///
+/// [embeddings]: fn.create_embeddings.html
+/// [openai]: https://platform.openai.com/docs/api-reference/embeddings/create
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateEmbeddingsRequest<'a> {
+    /// The text input to embed as either a string or an array of strings.
+    #[serde(with = "either::serde_untagged")]
+    #[schema(value_type = String)]
+    pub input: Either<Cow<'a, str>, Vec<Cow<'a, str>>>,
+
+    /// ID of the model to use.
     #[schema(value_type = String)]
     pub model: Cow<'a, str>,
 
@@ -899,8 +1883,41 @@ pub struct CreateEmbeddingsRequest<'a> {
     #[schema(value_type = String)]
     pub encoding_format: Option<Cow<'a, str>>,
 
-    /// The number of dimensions the resulting output embeddings should have. Only supported in some models.
+    /// The number of dimensions the resulting output embeddings should have. Only supported in
+    /// models trained with Matryoshka Representation Learning, such as `nomic-embed-text-v1.5`.
     pub dimensions: Option<usize>,
+
+    /// How the embeddings of the tokens of each input are pooled, `mean`, `cls` or `last`. The
+    /// pooling the model was trained with by default. This member is **not normative** with
+    /// OpenAI's specification, as it is intended for **Edgen** specific functionality.
+    #[schema(value_type = Option<String>)]
+    pub pooling: Option<Pooling>,
+
+    /// Whether the embeddings are normalised to unit length, `false` by default. This member is
+    /// **not normative** with OpenAI's specification, as it is intended for **Edgen** specific
+    /// functionality.
+    pub normalize: Option<bool>,
+
+    /// A unique identifier for the _end user_ creating this request. **Edgen** attributes the
+    /// request to this user in `/v1/requests`, in the `request_finished` events and in the usage
+    /// records, without requiring API keys.
+    pub user: Option<Cow<'a, str>>,
+}
+
+impl Validate for CreateEmbeddingsRequest<'_> {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_range(&mut errors, "dimensions", self.dimensions, 1, usize::MAX);
+        if let Some(format) = self.encoding_format.as_deref() {
+            if format != "float" && format != "base64" {
+                errors.push(FieldError::new(
+                    "encoding_format",
+                    "must be either \"float\" or \"base64\"",
+                ));
+            }
+        }
+        errors
+    }
 }
 
 /// The return type of [`create_embeddings`].
@@ -930,7 +1947,12 @@ pub struct Embedding {
     pub object: String,
 
     /// The embedding vector, which is a list of floats. The length of vector depends on the model.
-    pub embedding: Vec<f32>,
+    ///
+    /// If the `encoding_format` of the request is `base64`, the vector is instead encoded as a
+    /// base64 string of its little-endian 32-bit floats.
+    #[serde(with = "either::serde_untagged")]
+    #[schema(value_type = Vec<f32>)]
+    pub embedding: Either<Vec<f32>, String>,
 
     /// The index of the embedding in the list of embeddings.
     pub index: usize,
@@ -956,23 +1978,113 @@ pub struct EmbeddingsUsage {
 /// [openai]: https://platform.openai.com/docs/api-reference/embeddings/create
 ///
 /// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`ChatCompletionError`]
-/// to the peer.
+/// to the peer, or a `413 Payload Too Large` if any of the inputs has more tokens than allowed by
+/// the settings.
 #[utoipa::path(
 post,
 path = "/embeddings",
 request_body = CreateEmbeddingsRequest,
 responses(
 (status = 200, description = "OK", body = EmbeddingsResponse),
+(status = 400, description = "a parameter is invalid", body = ChatCompletionError),
+(status = 413, description = "an input has too many tokens", body = ChatCompletionError),
 (status = 500, description = "unexpected internal server error", body = ChatCompletionError)
 ),
 )]
 pub async fn create_embeddings(
+    key_models: Option<Extension<KeyModels>>,
     Json(req): Json<CreateEmbeddingsRequest<'_>>,
 ) -> Result<impl IntoResponse, ChatCompletionError> {
-    let params = get_embeddings_model_params(req.model.as_ref()).await;
+    let result = serve_embeddings(key_models, req).await;
+    if let Err(e) = &result {
+        if e.status_code().is_server_error() {
+            status::add_embeddings_error(e).await;
+        }
+    }
+    result
+}
+
+/// Serves a request to [`create_embeddings`].
+async fn serve_embeddings(
+    key_models: Option<Extension<KeyModels>>,
+    mut req: CreateEmbeddingsRequest<'_>,
+) -> Result<Json<EmbeddingsResponse>, ChatCompletionError> {
+    if let Some(user) = &req.user {
+        requests::set_user(user);
+    }
+    use_key_model(
+        &mut req.model,
+        key_models.and_then(move |Extension(models)| models.embeddings_model),
+    );
+
+    let base64 = match req.encoding_format.as_deref() {
+        None | Some("float") => false,
+        Some("base64") => true,
+        Some(format) => {
+            return Err(ChatCompletionError::InvalidParam {
+                param: "encoding_format".to_string(),
+                reason: Cow::Owned(format!(
+                    "must be either \"float\" or \"base64\", got \"{format}\""
+                )),
+            })
+        }
+    };
+
+    let input: Vec<String> = req.input.either(
+        move |s| vec![s.to_string()],
+        move |v| v.iter().map(move |s| s.to_string()).collect(),
+    );
+
+    let args = EmbeddingsArgs {
+        inputs: input,
+        pooling: req.pooling,
+        normalize: req.normalize.unwrap_or(false),
+    };
+    let mut res = generate_embeddings(req.model.as_ref(), args, req.dimensions).await?;
+    store::log_usage(UsageRecord {
+        timestamp: store::now(),
+        endpoint: "embeddings".to_string(),
+        model: req.model.to_string(),
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        user: req.user.as_ref().map(move |user| user.to_string()),
+    })
+    .await;
+
+    Ok(Json(EmbeddingsResponse {
+        object: "list".to_string(),
+        embeddings: res
+            .drain(..)
+            .enumerate()
+            .map(move |(index, embedding)| Embedding {
+                object: "embedding".to_string(),
+                embedding: if base64 {
+                    Either::Right(encode_embedding(&embedding))
+                } else {
+                    Either::Left(embedding)
+                },
+                index,
+            })
+            .collect(),
+        model: req.model.to_string(),
+        usage: EmbeddingsUsage {
+            prompt_tokens: 0,
+            total_tokens: 0,
+        },
+    }))
+}
+
+/// Generates embeddings for every input with the provided embeddings model, optionally truncated
+/// to `dimensions`, using and filling the embeddings cache if it is enabled.
+pub(crate) async fn generate_embeddings(
+    model_name: &str,
+    args: EmbeddingsArgs,
+    dimensions: Option<usize>,
+) -> Result<Vec<Vec<f32>>, ChatCompletionError> {
+    let params = get_embeddings_model_params(model_name).await;
     if let Err(error) = params {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed(error),
         });
     }
@@ -981,26 +2093,57 @@ pub async fn create_embeddings(
 
     if params.name.is_empty() {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed("Empty model name in config"),
         });
     }
     if params.dir.is_empty() {
         return Err(ChatCompletionError::ProhibitedName {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Borrowed("Empty model directory in config"),
         });
     }
 
-    let kind = MODEL_PATTERNS.get_top_model_kind(&params.kind_param, &[ModelKind::LLM]);
+    let kind = resolve_model_kind(
+        &[params.kind_param.as_str(), params.name.as_str()],
+        &BACKENDS.embeddings_kinds(),
+    )
+    .await;
     if let Err(error) = kind {
         return Err(ChatCompletionError::UnknownModelKind {
-            model_name: req.model.to_string(),
+            model_name: model_name.to_string(),
             reason: Cow::Owned(error.to_string()),
         });
     }
+    let kind = kind.unwrap();
+    let backend =
+        BACKENDS
+            .embeddings(&kind)
+            .ok_or_else(move || ChatCompletionError::UnknownModelKind {
+                model_name: model_name.to_string(),
+                reason: Cow::Owned(format!("no embeddings backend is registered for {kind:?}")),
+            })?;
+
+    if let Some(dimensions) = dimensions {
+        if !supports_dimensions(&params.name) && !supports_dimensions(&params.repo) {
+            return Err(ChatCompletionError::InvalidParam {
+                param: "dimensions".to_string(),
+                reason: Cow::Owned(format!(
+                    "model {} does not support reducing the embedding dimensions",
+                    params.name
+                )),
+            });
+        }
+        if dimensions == 0 {
+            return Err(ChatCompletionError::InvalidParam {
+                param: "dimensions".to_string(),
+                reason: Cow::Borrowed("must be greater than zero"),
+            });
+        }
+    }
+
     let mut model = Model::new(
-        kind.unwrap(),
+        kind,
         &params.name,
         &params.repo,
         &PathBuf::from(&params.dir),
@@ -1012,34 +2155,121 @@ pub async fn create_embeddings(
         .map_err(move |_| ChatCompletionError::NoSuchModel {
             model_name: params.name.to_string(),
         })?;
+    admin::register_model(&[model_name, params.name.as_str()], &model).await;
+
+    let EmbeddingsArgs {
+        inputs: input,
+        pooling,
+        normalize,
+    } = args;
+
+    // embeddings pooled or normalised differently are cached separately
+    let cache = settings::embeddings_cache().await;
+    let cache_key = model.file_path().ok().map(move |path| {
+        let path = path.to_string_lossy().to_string();
+        match (pooling, normalize) {
+            (None, false) => path,
+            (pooling, normalize) => format!("{path}#{pooling:?}#{normalize}"),
+        }
+    });
+    let mut cached = vec![];
+    for text in &input {
+        cached.push(match (&cache_key, cache) {
+            (Some(key), true) => embeddings_cache::get(key, text).await,
+            _ => None,
+        });
+    }
 
-    let input = req.input.either(
-        move |s| vec![s.to_string()],
-        move |v| v.iter().map(move |s| s.to_string()).collect(),
-    );
-    let mut res = match model.kind {
-        ModelKind::LLM => llm::embeddings(model, input).await?,
-        ModelKind::ChatFaker => chat_faker::embeddings(model, input).await?,
-        _ => todo!(),
+    let missing: Vec<String> = input
+        .iter()
+        .zip(&cached)
+        .filter(move |(_, embedding)| embedding.is_none())
+        .map(move |(text, _)| text.clone())
+        .collect();
+    let generated = if missing.is_empty() {
+        vec![]
+    } else {
+        let args = EmbeddingsArgs {
+            inputs: missing.clone(),
+            pooling,
+            normalize,
+        };
+        backend.embeddings(model, args).await?
     };
 
-    Ok(Json(EmbeddingsResponse {
-        object: "list".to_string(),
-        embeddings: res
-            .drain(..)
-            .enumerate()
-            .map(move |(index, embedding)| Embedding {
-                object: "embedding".to_string(),
-                embedding,
-                index,
-            })
-            .collect(),
-        model: req.model.to_string(),
-        usage: EmbeddingsUsage {
-            prompt_tokens: 0,
-            total_tokens: 0,
-        },
-    }))
+    // Checked before caching, so that an embedding is never cached for the wrong input
+    if generated.len() != missing.len() {
+        return Err(ChatCompletionError::Endpoint(LLMEndpointError::Embeddings(
+            format!(
+                "the model returned {} embeddings for {} inputs",
+                generated.len(),
+                missing.len()
+            ),
+        )));
+    }
+
+    if let (Some(key), true) = (&cache_key, cache) {
+        embeddings_cache::insert(key, &missing, &generated).await;
+    }
+
+    // Fill the gaps left by the cache with the generated embeddings, keeping the input order
+    let mut generated = generated.into_iter();
+    let mut res: Vec<Vec<f32>> = cached
+        .into_iter()
+        .map(|embedding| embedding.or_else(|| generated.next()))
+        .collect::<Option<_>>()
+        .expect("an embedding was generated for every input missing from the cache");
+
+    if let Some(dimensions) = dimensions {
+        for embedding in &mut res {
+            if dimensions > embedding.len() {
+                return Err(ChatCompletionError::InvalidParam {
+                    param: "dimensions".to_string(),
+                    reason: Cow::Owned(format!(
+                        "the model produces embeddings of {} dimensions, got {dimensions}",
+                        embedding.len()
+                    )),
+                });
+            }
+            truncate_embedding(embedding, dimensions);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Name fragments of the known embedding models trained with Matryoshka Representation Learning,
+/// whose embeddings can be truncated to fewer dimensions.
+const MATRYOSHKA_MODELS: &[&str] = &[
+    "nomic-embed-text-v1.5",
+    "mxbai-embed-large-v1",
+    "snowflake-arctic-embed-m-v1.5",
+    "text-embedding-3",
+];
+
+/// Returns **`true`** if the provided model name (or repo) belongs to a model whose embeddings can
+/// be truncated to fewer dimensions.
+fn supports_dimensions(model: &str) -> bool {
+    let model = model.to_lowercase();
+    MATRYOSHKA_MODELS
+        .iter()
+        .any(move |fragment| model.contains(fragment))
+}
+
+/// Truncates an embedding vector to its first `dimensions` values and re-normalises it to unit
+/// length.
+fn truncate_embedding(embedding: &mut Vec<f32>, dimensions: usize) {
+    embedding.truncate(dimensions);
+    normalize(embedding);
+}
+
+/// Encodes an embedding vector as a base64 string of its little-endian 32-bit floats.
+fn encode_embedding(embedding: &[f32]) -> String {
+    let bytes: Vec<u8> = embedding
+        .iter()
+        .flat_map(move |value| value.to_le_bytes())
+        .collect();
+    BASE64.encode(&bytes)
 }
 
 /// A request to transcribe an audio file into text in either the specified language, or whichever
@@ -1059,9 +2289,12 @@ pub struct CreateTranscriptionRequest {
     /// The audio file object (not file name) to transcribe, in one of the following formats:
     /// **`aac`**, **`flac`**, **`mp3`**, **`m4a`**, **`m4b`**, **`ogg`**, **`oga`**, **`mogg`**,
     /// **`wav`**. TODO check working formats. webm
+    ///
+    /// The upload is streamed to a temporary file, which is decoded as it is read, so that large
+    /// files are never held in memory.
     #[form_data(limit = "unlimited")]
     #[schema(value_type = Vec < u8 >)]
-    pub file: FieldData<axum::body::Bytes>,
+    pub file: FieldData<NamedTempFile>,
 
     /// ID of the model to use.
     pub model: String,
@@ -1075,7 +2308,10 @@ pub struct CreateTranscriptionRequest {
     pub prompt: Option<String>,
 
     /// The format of the transcript output, in one of these options: json, text, srt, verbose_json,
-    /// or vtt. TODO whats this?
+    /// or vtt.
+    ///
+    /// The transcript is currently always returned as JSON. If `verbose_json`, the response also
+    /// contains the timestamped segments the transcript is made of.
     pub response_format: Option<String>,
 
     /// The sampling temperature, between 0 and 1. Higher values like 0.8 will make the output more
@@ -1094,6 +2330,13 @@ pub struct CreateTranscriptionRequest {
 
     /// The [`Uuid`] of an existing audio session.
     pub session: Option<Uuid>,
+
+    /// Should silent stretches of the audio be skipped before transcribing it. This speeds up the
+    /// transcription of long recordings and avoids text being hallucinated over silence.
+    ///
+    /// This additional member is **not normative** with OpenAI's specification, as it is intended
+    /// for **Edgen** specific functionality.
+    pub vad: Option<bool>,
 }
 
 /// The return type of [`create_transcription`].
@@ -1107,6 +2350,29 @@ pub struct TranscriptionResponse {
     /// with OpenAI's specification, as it is intended for **Edgen** specific functionality.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub session: Option<Uuid>,
+
+    /// The segments the transcribed text is made of, present only if `response_format` in
+    /// [`CreateTranscriptionRequest`] is `verbose_json`.
+    ///
+    /// If `vad` is set, the timestamps refer to the audio with its silent stretches removed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segments: Option<Vec<TranscriptionSegment>>,
+}
+
+/// A transcribed stretch of audio, part of a [`TranscriptionResponse`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TranscriptionSegment {
+    /// The index of this segment in the transcription.
+    pub id: usize,
+
+    /// The position of the start of this segment in the audio, in seconds.
+    pub start: f64,
+
+    /// The position of the end of this segment in the audio, in seconds.
+    pub end: f64,
+
+    /// The transcribed text of this segment.
+    pub text: String,
 }
 
 /// POST `/v1/audio/transcriptions`: transcribes audio into text.
@@ -1123,12 +2389,39 @@ path = "/audio/transcriptions",
 request_body = CreateTranscriptionRequest,
 responses(
 (status = 200, description = "OK", body = TranscriptionResponse),
+(status = 400, description = "a parameter exceeds its maximum", body = TranscriptionError),
 (status = 500, description = "unexpected internal server error", body = TranscriptionError)
 ),
 )]
 pub async fn create_transcription(
+    key_caps: Option<Extension<RequestCaps>>,
     req: TypedMultipart<CreateTranscriptionRequest>,
 ) -> Result<impl IntoResponse, TranscriptionError> {
+    let result = serve_transcription(key_caps, req).await;
+    if let Err(e) = &result {
+        if e.status_code().is_server_error() {
+            status::add_audio_transcriptions_error(e).await;
+        }
+    }
+    result
+}
+
+/// Serves a request to [`create_transcription`].
+async fn serve_transcription(
+    key_caps: Option<Extension<RequestCaps>>,
+    req: TypedMultipart<CreateTranscriptionRequest>,
+) -> Result<Json<TranscriptionResponse>, TranscriptionError> {
+    if let Some(max) = request_caps(key_caps).await.audio_duration {
+        let file = AudioFile::Path(req.file.contents.path().to_path_buf());
+        let duration = parse::duration_of(&file).map_err(WhisperEndpointError::from)?;
+        if duration > Duration::from_secs(max) {
+            return Err(TranscriptionError::ExceedsCap {
+                param: "file".to_string(),
+                max,
+            });
+        }
+    }
+
     let params = get_audio_transcriptions_model_params(req.model.as_ref()).await;
     if let Err(error) = params {
         return Err(TranscriptionError::ProhibitedName {
@@ -1152,35 +2445,79 @@ pub async fn create_transcription(
         });
     }
 
-    let kind = MODEL_PATTERNS.get_top_model_kind(&params.kind_param, &[ModelKind::Whisper]);
+    let kind = resolve_model_kind(
+        &[params.kind_param.as_str(), params.name.as_str()],
+        &BACKENDS.transcription_kinds(),
+    )
+    .await;
     if let Err(error) = kind {
         return Err(TranscriptionError::UnknownModelKind {
             model_name: req.model.to_string(),
             reason: Cow::Owned(error.to_string()),
         });
     }
+    let kind = kind.unwrap();
+    let backend =
+        BACKENDS
+            .transcription(&kind)
+            .ok_or_else(|| TranscriptionError::UnknownModelKind {
+                model_name: req.model.to_string(),
+                reason: Cow::Owned(format!("no backend is registered for {kind:?}")),
+            })?;
 
     let mut model = Model::new(
-        kind.unwrap(),
+        kind,
         &params.name,
         &params.repo,
         &PathBuf::from(&params.dir),
     );
 
     model.preload(Endpoint::AudioTranscriptions).await?;
+    admin::register_model(&[req.model.as_ref(), params.name.as_str()], &model).await;
+
+    let args = TranscriptionArgs {
+        // The temporary file lives as long as the request, so until the transcription is done
+        file: AudioFile::Path(req.file.contents.path().to_path_buf()),
+        language: req.language.clone(),
+        prompt: req.prompt.clone(),
+        temperature: req.temperature,
+        create_session: req.create_session.unwrap_or(false),
+        session: req.session,
+        vad: req.vad.unwrap_or(false),
+    };
+    let (transcription, session) = backend.transcription(model, args).await?;
+    store::log_usage(UsageRecord {
+        timestamp: store::now(),
+        endpoint: "audio/transcriptions".to_string(),
+        model: req.model.to_string(),
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        user: None,
+    })
+    .await;
+    if let Some(session) = session.or(req.session) {
+        store::log_session(&session.to_string(), &req.model).await;
+    }
 
-    let (text, session) = crate::whisper::create_transcription(
-        &req.file.contents,
-        model,
-        req.language.as_deref(),
-        req.prompt.as_deref(),
-        req.temperature,
-        req.create_session.unwrap_or(false),
-        req.session,
-    )
-    .await?;
+    let segments = (req.response_format.as_deref() == Some("verbose_json")).then(|| {
+        transcription
+            .segments
+            .into_iter()
+            .enumerate()
+            .map(|(id, segment)| TranscriptionSegment {
+                id,
+                start: segment.start.as_secs_f64(),
+                end: segment.end.as_secs_f64(),
+                text: segment.text,
+            })
+            .collect()
+    });
 
-    Ok(Json(TranscriptionResponse { text, session }))
+    Ok(Json(TranscriptionResponse {
+        text: transcription.text,
+        session,
+        segments,
+    }))
 }
 
 /// An error condition raised by the audio transcription API.
@@ -1229,14 +2566,189 @@ pub enum TranscriptionError {
     /// An error occurred while processing the request to this endpoint.
     #[error("an error occurred while processing the request: {0}")]
     Endpoint(#[from] WhisperEndpointError),
+
+    /// A parameter of the request exceeds its maximum in the `request_caps` of the settings or of
+    /// the API key of the request, e.g. the duration, in seconds, of the audio `file`.
+    #[error("parameter {param} exceeds its maximum of {max}")]
+    ExceedsCap {
+        /// The name of the parameter.
+        param: String,
+
+        /// The maximum of the parameter.
+        max: u64,
+    },
+}
+
+impl TranscriptionError {
+    /// Returns the status of the response of a request failing with this error.
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TranscriptionError::ExceedsCap { .. } => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
 }
 
 impl IntoResponse for TranscriptionError {
     fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
+        request_id::error_response(self.status_code(), &self)
     }
 }
 
+/// The files that make up a custom image generation model, for a [`CreateImageRequest`].
+///
+/// Each file can be a path to a local file, a file inside the image generation models directory or
+/// a huggingface file in the `owner/repo/path/to/file` form.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ImageModelFiles<'a> {
+    /// The UNet weights of the model.
+    #[schema(value_type = String)]
+    pub unet_weights: Cow<'a, str>,
+
+    /// The Variational Auto-Encoder weights of the model.
+    #[schema(value_type = String)]
+    pub vae_weights: Cow<'a, str>,
+
+    /// The CLIP text encoder weights of the model.
+    #[schema(value_type = String)]
+    pub clip_weights: Cow<'a, str>,
+
+    /// The weights of a second CLIP text encoder. Beware that not all models support clip2.
+    #[schema(value_type = String)]
+    pub clip2_weights: Option<Cow<'a, str>>,
+
+    /// The tokenizer of the model.
+    #[schema(value_type = String)]
+    pub tokenizer: Cow<'a, str>,
+}
+
+/// A request to generate images for the provided prompt.
+///
+/// An `axum` handler, [`generate_image`][generate_image], is provided to handle this request.
+///
+/// See [the documentation for creating images][openai] for more details. Besides the members of
+/// OpenAI's specification, this request has several members that are **not normative**, as they
+/// are intended for **Edgen** specific functionality: `negative_prompt`, `steps`, `seed`,
+/// `guidance_scale` and `vae_scale`.
+///
+/// [generate_image]: ../image_generation/fn.generate_image.html
+/// [openai]: https://platform.openai.com/docs/api-reference/images/create
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateImageRequest<'a> {
+    /// A text description of the desired image(s).
+    #[schema(value_type = String)]
+    pub prompt: Cow<'a, str>,
+
+    /// The model to use for generating images, either the name of a pre-made model descriptor
+    /// (like `stable-diffusion-2-1`) or the files of a custom model.
+    #[serde(with = "either::serde_untagged")]
+    #[schema(value_type = String)]
+    pub model: Either<Cow<'a, str>, ImageModelFiles<'a>>,
+
+    /// The number of images to generate.
+    ///
+    /// Default: 1
+    pub n: Option<u32>,
+
+    /// The size of the generated images, in the `{width}x{height}` form, e.g. `512x512`.
+    ///
+    /// By default, the native size of the model is used.
+    #[schema(value_type = String)]
+    pub size: Option<Cow<'a, str>>,
+
+    /// The format in which the generated images are returned. Must be one of `url` or `b64_json`.
+    /// URLs are only valid for an hour after the images have been generated.
+    ///
+    /// Default: `url`
+    #[schema(value_type = String)]
+    pub response_format: Option<Cow<'a, str>>,
+
+    /// A text description of what should **not** be in the generated image(s).
+    #[schema(value_type = String)]
+    pub negative_prompt: Option<Cow<'a, str>>,
+
+    /// The number of steps to be used in the diffusion process.
+    pub steps: Option<usize>,
+
+    /// The random number generator seed to use for the first image. Every following image is
+    /// seeded with the previous seed plus one. The seed of each image is returned in the response.
+    ///
+    /// By default, a random seed is used for each image.
+    pub seed: Option<u64>,
+
+    /// The guidance scale to use for generation, that is, how much should the model follow the
+    /// prompt.
+    ///
+    /// Values below 1 disable guidance. (the prompt is ignored)
+    pub guidance_scale: Option<f64>,
+
+    /// The Variational Auto-Encoder scale to use for generation.
+    ///
+    /// Required if `model` is not a pre-made descriptor name.
+    ///
+    /// This value should probably not be set, if `model` is a pre-made descriptor name.
+    pub vae_scale: Option<f64>,
+}
+
+impl Validate for CreateImageRequest<'_> {
+    fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        check_range(&mut errors, "n", self.n, 1, u32::MAX);
+        check_range(&mut errors, "steps", self.steps, 1, usize::MAX);
+        if let Some(format) = self.response_format.as_deref() {
+            if format != "url" && format != "b64_json" {
+                errors.push(FieldError::new(
+                    "response_format",
+                    "must be either \"url\" or \"b64_json\"",
+                ));
+            }
+        }
+        errors
+    }
+}
+
+/// The return type of [`generate_image`][generate_image].
+///
+/// See [the documentation for images][openai] for more details.
+///
+/// [generate_image]: ../image_generation/fn.generate_image.html
+/// [openai]: https://platform.openai.com/docs/api-reference/images/object
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ImagesResponse {
+    /// The UNIX timestamp at which the images were generated.
+    pub created: i64,
+
+    /// The generated images.
+    pub data: Vec<Image>,
+}
+
+/// An image generated by the image generation endpoint.
+///
+/// Depending on the `response_format` of the [`CreateImageRequest`], only one of the members is
+/// present.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct Image {
+    /// The base64-encoded PNG data of the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub b64_json: Option<String>,
+
+    /// The URL of the image.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+
+    /// The seed used to generate this image, which can be used to reproduce it. This additional
+    /// member is **not normative** with OpenAI's specification, as it is intended for **Edgen**
+    /// specific functionality.
+    pub seed: u64,
+
+    /// Whether the safety checker classified this image as NSFW, if the safety checker is
+    /// enabled. If the safety checker is set to block such images, neither `b64_json` nor `url`
+    /// are present for them. This additional member is **not normative** with OpenAI's
+    /// specification, as it is intended for **Edgen** specific functionality.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nsfw_content_detected: Option<bool>,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -1251,6 +2763,40 @@ mod test {
             .expect("Failed to initialise settings");
     }
 
+    #[test]
+    fn base64_embedding() {
+        let embedding = [1.0f32, -2.5, 0.0];
+        let encoded = encode_embedding(&embedding);
+        let decoded: Vec<f32> = BASE64
+            .decode(encoded.as_bytes())
+            .unwrap()
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        assert_eq!(decoded, embedding);
+    }
+
+    #[test]
+    fn invalid_param_status() {
+        let response = ChatCompletionError::InvalidParam {
+            param: "encoding_format".to_string(),
+            reason: Cow::Borrowed("must be either \"float\" or \"base64\""),
+        }
+        .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn truncated_embedding() {
+        let mut embedding = vec![3.0f32, 4.0, 12.0];
+        truncate_embedding(&mut embedding, 2);
+        assert_eq!(embedding, vec![0.6, 0.8]);
+
+        assert!(supports_dimensions("nomic-embed-text-v1.5.f16.gguf"));
+        assert!(supports_dimensions("nomic-ai/nomic-embed-text-v1.5-GGUF"));
+        assert!(!supports_dimensions("neural-chat-7b-v3-3.Q4_K_M.gguf"));
+    }
+
     #[tokio::test]
     async fn default_chat_model_name() {
         init_settings_for_test().await;
@@ -1443,6 +2989,145 @@ mod test {
         );
     }
 
+    #[test]
+    fn key_model() {
+        let mut model = Cow::Borrowed("default");
+        use_key_model(&mut model, Some("app-model.gguf".to_string()));
+        assert_eq!(model, "app-model.gguf");
+
+        let mut model = Cow::Borrowed("other-model.gguf");
+        use_key_model(&mut model, Some("app-model.gguf".to_string()));
+        assert_eq!(model, "other-model.gguf");
+
+        let mut model = Cow::Borrowed("default");
+        use_key_model(&mut model, None);
+        assert_eq!(model, "default");
+    }
+
+    #[test]
+    fn chat_sessions() {
+        let request = |body: serde_json::Value| -> CompletionArgs {
+            serde_json::from_value::<CreateChatCompletionRequest>(body)
+                .unwrap()
+                .into()
+        };
+        let messages = serde_json::json!([{"role": "user", "content": "Hello"}]);
+
+        let args = request(serde_json::json!({"model": "default", "messages": messages}));
+        assert!(!args.create_session);
+        assert_eq!(args.session, None);
+
+        let args = request(serde_json::json!({
+            "model": "default",
+            "messages": messages,
+            "create_session": true,
+        }));
+        assert!(args.create_session);
+        assert!(args.session.is_some());
+
+        let session = Uuid::new_v4();
+        let args = request(serde_json::json!({
+            "model": "default",
+            "messages": messages,
+            "create_session": true,
+            "session": session,
+        }));
+        assert!(!args.create_session);
+        assert_eq!(args.session, Some(session));
+    }
+
+    #[test]
+    fn max_context() {
+        let args = |context_hint: Option<u32>, max_context: Option<u32>| -> CompletionArgs {
+            let request: CreateChatCompletionRequest = serde_json::from_value(serde_json::json!({
+                "model": "default",
+                "messages": [{"role": "user", "content": "Hello"}],
+                "context_hint": context_hint,
+            }))
+            .unwrap();
+            let options = ChatModelOptions {
+                sampler: SamplerOptions::default(),
+                no_kv_offload: false,
+                max_context,
+            };
+            options.apply(request.into())
+        };
+
+        let unbounded = args(Some(8192), None);
+        assert_eq!(unbounded.context_hint, Some(8192));
+        assert_eq!(unbounded.max_context, None);
+
+        let clamped = args(Some(8192), Some(2048));
+        assert_eq!(clamped.context_hint, Some(2048));
+        assert_eq!(clamped.max_context, Some(2048));
+
+        assert_eq!(args(Some(1024), Some(2048)).context_hint, Some(1024));
+        assert_eq!(args(None, Some(2048)).context_hint, None);
+    }
+
+    #[test]
+    fn chat_validation() {
+        let errors = |body: serde_json::Value| -> Vec<String> {
+            serde_json::from_value::<CreateChatCompletionRequest>(body)
+                .unwrap()
+                .validate()
+                .into_iter()
+                .map(|error| error.field)
+                .collect()
+        };
+        let messages = serde_json::json!([{"role": "user", "content": "Hello"}]);
+
+        assert!(errors(serde_json::json!({
+            "model": "default",
+            "messages": messages,
+            "temperature": 0.7,
+            "stream": true,
+            "coalesce_ms": 20,
+        }))
+        .is_empty());
+        assert_eq!(
+            errors(serde_json::json!({
+                "model": "default",
+                "messages": messages,
+                "temperature": 2.5,
+                "top_p": -0.1,
+                "n": 2,
+                "stream": true,
+                "cache_key": "system",
+            })),
+            ["temperature", "top_p", "n", "cache_key"]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_sampler_profile() {
+        init_settings_for_test().await;
+
+        let error = sampler_defaults(&["default"], Some("no-such-profile"))
+            .await
+            .expect_err("an unknown sampler profile was accepted");
+        assert!(matches!(
+            &error,
+            ChatCompletionError::InvalidParam { param, .. } if param == "sampler_profile"
+        ));
+        assert_eq!(error.into_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn local_model_path_not_allowed() {
+        init_settings_for_test().await;
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("local-model.gguf");
+        std::fs::write(&file, b"").unwrap();
+
+        assert!(
+            get_chat_completions_model_params(&file.to_string_lossy())
+                .await
+                .is_err(),
+            "a model outside of the local model directories was accepted",
+        );
+    }
+
     #[test]
     fn deserialize_chat_completion() {
         let content = r#"
@@ -0,0 +1,229 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Forwarding of requests to an upstream OpenAI-compatible API, for when they cannot be served
+//! locally.
+
+use std::convert::Infallible;
+
+use axum::http::HeaderValue;
+use axum::response::sse::Event;
+use axum::response::{IntoResponse, Response, Sse};
+use axum::Json;
+use futures::StreamExt;
+use reqwest_eventsource::{retry, EventSource};
+use tracing::warn;
+
+use edgen_core::settings;
+
+use crate::conversation::{self, Conversation};
+use crate::openai_shim::ChatCompletionError;
+
+/// The header added to responses to tell where the generation ran, either `local` or `remote`.
+pub const BACKEND_HEADER: &str = "x-edgen-backend";
+
+/// The fields of a chat completions request in OpenAI's specification, which are the only ones
+/// forwarded, so that **Edgen** specific fields never reach the upstream API.
+const OPENAI_FIELDS: &[&str] = &[
+    "model",
+    "messages",
+    "frequency_penalty",
+    "logit_bias",
+    "logprobs",
+    "top_logprobs",
+    "max_tokens",
+    "max_completion_tokens",
+    "n",
+    "presence_penalty",
+    "response_format",
+    "seed",
+    "service_tier",
+    "stop",
+    "stream",
+    "stream_options",
+    "temperature",
+    "top_p",
+    "tools",
+    "tool_choice",
+    "parallel_tool_calls",
+    "user",
+    "functions",
+    "function_call",
+];
+
+/// Adds the [`BACKEND_HEADER`] to a response.
+pub fn tag_backend(mut response: Response, remote: bool) -> Response {
+    let backend = if remote { "remote" } else { "local" };
+    response
+        .headers_mut()
+        .insert(BACKEND_HEADER, HeaderValue::from_static(backend));
+    response
+}
+
+/// Forwards a chat completions request body to the upstream API, returning its response,
+/// streamed if `stream` is set in the body.
+///
+/// The reply is recorded in the conversation, if any.
+pub async fn chat_completions(
+    body: serde_json::Value,
+    conversation: Option<Conversation>,
+) -> Result<Response, ChatCompletionError> {
+    let url =
+        settings::remote_fallback_url()
+            .await
+            .ok_or_else(move || ChatCompletionError::Remote {
+                reason: "no remote fallback is configured".to_string(),
+            })?;
+    let url = format!("{}/chat/completions", url.trim_end_matches('/'));
+    let api_key = settings::remote_fallback_api_key().await;
+    let body = upstream_body(body, settings::remote_fallback_model().await);
+
+    let mut builder = reqwest::Client::new().post(url).json(&body);
+    if !api_key.is_empty() {
+        builder = builder.bearer_auth(api_key);
+    }
+
+    let stream = body
+        .get("stream")
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(false);
+    if stream {
+        stream_chat_completions(builder, conversation).await
+    } else {
+        let response = builder
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(remote_error)?;
+        let completion: serde_json::Value = response.json().await.map_err(remote_error)?;
+        if let Some(conversation) = conversation {
+            let content = completion
+                .pointer("/choices/0/message/content")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default();
+            conversation.record_reply(content).await;
+        }
+        Ok(Json(completion).into_response())
+    }
+}
+
+async fn stream_chat_completions(
+    builder: reqwest::RequestBuilder,
+    conversation: Option<Conversation>,
+) -> Result<Response, ChatCompletionError> {
+    let mut source = EventSource::new(builder).map_err(move |e| ChatCompletionError::Remote {
+        reason: e.to_string(),
+    })?;
+    source.set_retry_policy(Box::new(retry::Never));
+
+    // Wait for the connection to be established, which is always the first event, so that a
+    // failing upstream API can still be reported with an error instead of an empty stream
+    match source.next().await {
+        Some(Ok(_)) => {}
+        Some(Err(e)) => {
+            source.close();
+            return Err(ChatCompletionError::Remote {
+                reason: e.to_string(),
+            });
+        }
+        None => {
+            return Err(ChatCompletionError::Remote {
+                reason: "the upstream API closed the connection".to_string(),
+            })
+        }
+    }
+
+    let messages = futures::stream::unfold(source, move |mut source| async move {
+        loop {
+            match source.next().await? {
+                Ok(reqwest_eventsource::Event::Open) => {}
+                Ok(reqwest_eventsource::Event::Message(message)) => {
+                    return Some((message.data, source));
+                }
+                Err(reqwest_eventsource::Error::StreamEnded) => return None,
+                Err(e) => {
+                    warn!("Remote chat completions stream failed: {e}");
+                    source.close();
+                    return None;
+                }
+            }
+        }
+    });
+    let messages = conversation::record_stream(conversation, messages, move |data| {
+        let chunk: serde_json::Value = serde_json::from_str(data).ok()?;
+        chunk
+            .pointer("/choices/0/delta/content")
+            .and_then(serde_json::Value::as_str)
+            .map(move |content| content.to_string())
+    });
+    let events = messages.map(move |data| Ok::<_, Infallible>(Event::default().data(data)));
+
+    Ok(Sse::new(events).into_response())
+}
+
+/// Prepares a request body to be sent upstream, keeping only the set fields of OpenAI's
+/// specification, and replacing the model if needed.
+fn upstream_body(mut body: serde_json::Value, model: Option<String>) -> serde_json::Value {
+    if let Some(fields) = body.as_object_mut() {
+        fields
+            .retain(move |name, value| !value.is_null() && OPENAI_FIELDS.contains(&name.as_str()));
+        if let Some(model) = model {
+            fields.insert("model".to_string(), serde_json::Value::String(model));
+        }
+    }
+    body
+}
+
+fn remote_error(e: reqwest::Error) -> ChatCompletionError {
+    ChatCompletionError::Remote {
+        reason: e.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn body_sanitising() {
+        let body = json!({
+            "model": "default",
+            "messages": [{"role": "user", "content": "Hello!"}],
+            "seed": null,
+            "one_shot": true,
+            "context_hint": 2048,
+            "create_session": true,
+            "session": "00000000-0000-0000-0000-000000000000",
+            "coalesce_ms": 50,
+            "coalesce_tokens": 8,
+            "keep_system_prompt": true,
+            "some_future_extension": 1,
+        });
+
+        assert_eq!(
+            upstream_body(body.clone(), None),
+            json!({
+                "model": "default",
+                "messages": [{"role": "user", "content": "Hello!"}],
+            })
+        );
+        assert_eq!(
+            upstream_body(body, Some("gpt-4o-mini".to_string())),
+            json!({
+                "model": "gpt-4o-mini",
+                "messages": [{"role": "user", "content": "Hello!"}],
+            })
+        );
+    }
+}
@@ -0,0 +1,125 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Identification of every request with an `X-Request-Id`, so that client issues can be
+//! correlated with the server logs.
+//!
+//! The ID is taken from the request's `X-Request-Id` header, or generated if there is none. It is
+//! attached to a tracing span wrapping the whole request, included in error bodies and echoed in
+//! the response headers.
+
+use axum::extract::Request;
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Serialize;
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+
+/// The header carrying the ID of a request.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The longest request ID that is accepted from a client. Longer IDs are replaced.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// Returns the ID of the request being handled, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(move |id| id.clone()).ok()
+}
+
+/// An `axum` middleware that identifies every request, as described in the [module
+/// documentation](self).
+pub async fn propagate(req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(move |value| value.to_str().ok())
+        .filter(move |id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(move |id| id.to_string())
+        .unwrap_or_else(move || Uuid::new_v4().to_string());
+
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %req.method(),
+        uri = %req.uri()
+    );
+    let mut response = REQUEST_ID
+        .scope(id.clone(), next.run(req))
+        .instrument(span)
+        .await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
+/// Returns an error response with the JSON-encoded error, to which the ID of the request being
+/// handled is added as `request_id`.
+pub fn error_response(status: StatusCode, error: &impl Serialize) -> Response {
+    let mut body = match serde_json::to_value(error) {
+        Ok(body) => body,
+        Err(_) => return status.into_response(),
+    };
+    if let (Some(fields), Some(id)) = (body.as_object_mut(), current()) {
+        fields.insert("request_id".to_string(), serde_json::Value::String(id));
+    }
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::middleware::from_fn;
+    use axum::routing::get;
+    use axum::Router;
+    use axum_test::TestServer;
+
+    use super::*;
+
+    async fn fail() -> Response {
+        error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            &serde_json::json!({"error": "failure"}),
+        )
+    }
+
+    #[tokio::test]
+    async fn request_ids() {
+        let router = Router::new()
+            .route("/fail", get(fail))
+            .layer(from_fn(propagate));
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let resp = server
+            .get("/fail")
+            .add_header(
+                REQUEST_ID_HEADER.parse().unwrap(),
+                "my-request".parse().unwrap(),
+            )
+            .await;
+        assert_eq!(resp.header(REQUEST_ID_HEADER), "my-request");
+        assert_eq!(
+            resp.json::<serde_json::Value>(),
+            serde_json::json!({"error": "failure", "request_id": "my-request"})
+        );
+
+        let resp = server.get("/fail").await;
+        let id = resp.header(REQUEST_ID_HEADER);
+        assert!(Uuid::parse_str(id.to_str().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,488 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Bookkeeping of the requests being handled, identified by their [request
+//! ID](crate::request_id), so that they can be listed and cancelled.
+//!
+//! Cancelling a request drops its handler, which stops the generation of its backend, and ends
+//! its response body, so that streamed responses stop as well.
+//!
+//! Requests are not queued by the server, so every listed request is executing: waiting for its
+//! model to be downloaded or loaded, generating, or sending its response.
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use axum::body::Body;
+use axum::extract::{Path, Request};
+use axum::http::{Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::{Stream, StreamExt};
+use once_cell::sync::Lazy;
+use pin_project::pin_project;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+use utoipa::ToSchema;
+
+use edgen_core::resident::policy_device;
+use edgen_core::settings::SETTINGS;
+
+use crate::events::{self, ServerEvent};
+use crate::request_id;
+use crate::status;
+use crate::store;
+
+/// The requests being handled, by request ID.
+static IN_FLIGHT: Lazy<Mutex<HashMap<String, InFlightRequest>>> = Lazy::new(Default::default);
+
+/// The source of the tokens telling apart requests sharing an ID.
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// A request being handled.
+struct InFlightRequest {
+    /// Tells apart the requests sent by clients with the same ID.
+    token: u64,
+
+    /// Cancelled to cancel the request.
+    cancellation: CancellationToken,
+
+    /// The method of the request.
+    method: Method,
+
+    /// The path of the request, e.g. `/v1/chat/completions`.
+    endpoint: String,
+
+    /// The model used by the request, and the device it runs on, once known.
+    model: Option<(String, String)>,
+
+    /// The end user the request was made for, if the request tells.
+    user: Option<String>,
+
+    /// The UNIX timestamp, in seconds, of when the request was received.
+    created_at: u64,
+
+    /// When the request was received.
+    started: Instant,
+}
+
+/// Removes a request from [`IN_FLIGHT`] when it is done, that is when its response body has been
+/// sent or dropped.
+struct Tracked {
+    id: String,
+    token: u64,
+    endpoint: String,
+}
+
+impl Tracked {
+    fn start(id: String, req: &Request, cancellation: CancellationToken) -> Self {
+        let token = NEXT_TOKEN.fetch_add(1, Ordering::SeqCst);
+        let endpoint = req.uri().path().to_string();
+        IN_FLIGHT.lock().unwrap().insert(
+            id.clone(),
+            InFlightRequest {
+                token,
+                cancellation,
+                method: req.method().clone(),
+                endpoint: endpoint.clone(),
+                model: None,
+                user: None,
+                created_at: store::now(),
+                started: Instant::now(),
+            },
+        );
+        events::publish(ServerEvent::RequestStarted {
+            id: id.clone(),
+            method: req.method().to_string(),
+            endpoint: endpoint.clone(),
+        });
+        Self {
+            id,
+            token,
+            endpoint,
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        // A newer request with the same ID must not be forgotten
+        let own = in_flight.get(&self.id).map(move |request| request.token) == Some(self.token);
+        let user = if own {
+            in_flight
+                .remove(&self.id)
+                .and_then(move |request| request.user)
+        } else {
+            None
+        };
+        drop(in_flight);
+
+        events::publish(ServerEvent::RequestFinished {
+            id: self.id.clone(),
+            endpoint: std::mem::take(&mut self.endpoint),
+            user,
+        });
+    }
+}
+
+/// A response body that ends when its request is cancelled, and keeps the request in
+/// [`IN_FLIGHT`] until then.
+#[pin_project]
+struct TrackedBody<S> {
+    #[pin]
+    inner: S,
+    _tracked: Tracked,
+}
+
+impl<S: Stream> Stream for TrackedBody<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.project().inner.poll_next(cx)
+    }
+}
+
+/// An `axum` middleware that keeps track of the requests being handled, as described in the
+/// [module documentation](self).
+///
+/// This must run inside [`request_id::propagate`], so that the request has an ID.
+pub async fn track(req: Request, next: Next) -> Response {
+    let Some(id) = request_id::current() else {
+        return next.run(req).await;
+    };
+
+    let cancellation = CancellationToken::new();
+    let method = req.method().to_string();
+    let tracked = Tracked::start(id.clone(), &req, cancellation.clone());
+
+    let response = tokio::select! {
+        response = next.run(req) => response,
+        _ = cancellation.cancelled() => {
+            return RequestError::Cancelled { request_id: id }.into_response();
+        }
+    };
+
+    if response.status().is_client_error() || response.status().is_server_error() {
+        status::add_request_failure(&id, &method, &tracked.endpoint, response.status().as_u16());
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = body
+        .into_data_stream()
+        .take_until(cancellation.cancelled_owned());
+    let body = TrackedBody {
+        inner: body,
+        _tracked: tracked,
+    };
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+/// Records the model used by the request being handled, and the device it runs on, unless one was
+/// already recorded.
+///
+/// The first model is kept, so that the files a model is made of are not reported as models.
+pub async fn set_model(model: &str) {
+    let Some(id) = request_id::current() else {
+        return;
+    };
+    let device = policy_device(&SETTINGS.read().await.read().await.gpu_policy);
+
+    if let Some(request) = IN_FLIGHT.lock().unwrap().get_mut(&id) {
+        if request.model.is_none() {
+            request.model = Some((model.to_string(), device));
+        }
+    }
+}
+
+/// Records the end user the request being handled was made for, from the `user` parameter of its
+/// body, so that requests can be attributed to users even without API keys.
+pub fn set_user(user: &str) {
+    let Some(id) = request_id::current() else {
+        return;
+    };
+
+    if let Some(request) = IN_FLIGHT.lock().unwrap().get_mut(&id) {
+        request.user = Some(user.to_string());
+    }
+}
+
+/// An error condition raised by the requests API.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum RequestError {
+    /// There is no request being handled with the provided ID.
+    #[error("no such request: {request_id}")]
+    NoSuchRequest {
+        /// The ID of the request.
+        request_id: String,
+    },
+
+    /// The request was cancelled before its response was ready.
+    #[error("the request {request_id} was cancelled")]
+    Cancelled {
+        /// The ID of the request.
+        request_id: String,
+    },
+}
+
+impl IntoResponse for RequestError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            RequestError::NoSuchRequest { .. } => StatusCode::NOT_FOUND,
+            // The status nginx uses for requests closed by the client
+            RequestError::Cancelled { .. } => {
+                StatusCode::from_u16(499).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+            }
+        };
+        request_id::error_response(status, &self)
+    }
+}
+
+/// A request being handled.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RequestInfo {
+    /// The ID of the request.
+    pub id: String,
+
+    /// Always `"request"`.
+    pub object: String,
+
+    /// The HTTP method of the request.
+    pub method: String,
+
+    /// The path of the request, e.g. `/v1/chat/completions`.
+    pub endpoint: String,
+
+    /// The model used by the request, once known.
+    pub model: Option<String>,
+
+    /// The device the model runs on, e.g. `cpu` or `device:0`, once known.
+    pub device: Option<String>,
+
+    /// The end user the request was made for, from the `user` parameter of its body, if any.
+    pub user: Option<String>,
+
+    /// The UNIX timestamp, in seconds, of when the request was received.
+    pub created_at: u64,
+
+    /// The number of milliseconds since the request was received.
+    pub elapsed_ms: u64,
+}
+
+/// A list of the requests being handled.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RequestList {
+    /// Always `"list"`.
+    pub object: String,
+
+    /// The requests, oldest first.
+    pub data: Vec<RequestInfo>,
+}
+
+/// The response to a request cancellation.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RequestCancelled {
+    /// The ID of the request.
+    pub id: String,
+
+    /// Always `"request"`.
+    pub object: String,
+
+    /// Always `true`.
+    pub cancelled: bool,
+}
+
+/// GET `/v1/requests`: lists the requests being handled, except this one.
+///
+/// This endpoint is specific to **Edgen**.
+#[utoipa::path(
+get,
+path = "/requests",
+responses(
+(status = 200, description = "OK", body = RequestList)
+),
+)]
+pub async fn list_requests() -> impl IntoResponse {
+    let current = request_id::current();
+    let mut requests: Vec<(Instant, RequestInfo)> = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(id, _)| Some(*id) != current.as_ref())
+        .map(move |(id, request)| {
+            let (model, device) = request.model.clone().unzip();
+            let info = RequestInfo {
+                id: id.clone(),
+                object: "request".to_string(),
+                method: request.method.to_string(),
+                endpoint: request.endpoint.clone(),
+                model,
+                device,
+                user: request.user.clone(),
+                created_at: request.created_at,
+                elapsed_ms: request.started.elapsed().as_millis() as u64,
+            };
+            (request.started, info)
+        })
+        .collect();
+    requests.sort_by_key(move |(started, _)| *started);
+
+    Json(RequestList {
+        object: "list".to_string(),
+        data: requests.into_iter().map(move |(_, info)| info).collect(),
+    })
+}
+
+/// POST `/v1/requests/{request_id}/cancel`: cancels a request being handled.
+///
+/// The handler of the request is dropped, which stops its generation, and its response body is
+/// ended, so this works for both streamed and non-streamed requests. This endpoint is specific to
+/// **Edgen**.
+///
+/// On failure, may raise a `404 Not Found` with a JSON-encoded [`RequestError`] to the peer.
+#[utoipa::path(
+post,
+path = "/requests/{request_id}/cancel",
+params(("request_id" = String, Path, description = "The ID of the request")),
+responses(
+(status = 200, description = "OK", body = RequestCancelled),
+(status = 404, description = "no such request", body = RequestError)
+),
+)]
+pub async fn cancel_request(
+    Path(request_id): Path<String>,
+) -> Result<impl IntoResponse, RequestError> {
+    let cancellation = IN_FLIGHT
+        .lock()
+        .unwrap()
+        .get(&request_id)
+        .map(move |request| request.cancellation.clone());
+    let Some(cancellation) = cancellation else {
+        return Err(RequestError::NoSuchRequest { request_id });
+    };
+
+    cancellation.cancel();
+
+    Ok(Json(RequestCancelled {
+        id: request_id,
+        object: "request".to_string(),
+        cancelled: true,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::middleware::from_fn;
+    use axum::routing::{get, post};
+    use axum::Router;
+    use axum_test::TestServer;
+
+    use super::*;
+    use crate::status::StatusEvent;
+
+    async fn slow() -> &'static str {
+        set_user("user-1");
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        "done"
+    }
+
+    #[tokio::test]
+    async fn cancellation() {
+        let router = Router::new()
+            .route("/slow", get(slow))
+            .route("/v1/requests", get(list_requests))
+            .route("/v1/requests/:request_id/cancel", post(cancel_request))
+            .layer(from_fn(track))
+            .layer(from_fn(request_id::propagate));
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        server
+            .post("/v1/requests/unknown/cancel")
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        let request = server.get("/slow").add_header(
+            request_id::REQUEST_ID_HEADER.parse().unwrap(),
+            "slow-request".parse().unwrap(),
+        );
+        let cancel = async {
+            // Wait for the slow request to be handled
+            while IN_FLIGHT
+                .lock()
+                .unwrap()
+                .get("slow-request")
+                .map_or(true, |request| request.user.is_none())
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            let list: RequestList = server.get("/v1/requests").await.json();
+            let endpoints: Vec<_> = list.data.iter().map(|r| r.endpoint.as_str()).collect();
+            assert_eq!(endpoints, vec!["/slow"]);
+            assert_eq!(list.data[0].user.as_deref(), Some("user-1"));
+
+            server
+                .post("/v1/requests/slow-request/cancel")
+                .await
+                .assert_status_ok();
+        };
+
+        let (resp, _) = tokio::join!(async move { request.await }, cancel);
+        assert_eq!(resp.status_code().as_u16(), 499);
+        assert!(!IN_FLIGHT.lock().unwrap().contains_key("slow-request"));
+    }
+
+    #[tokio::test]
+    async fn failures() {
+        let router = Router::new()
+            .route("/v1/requests/:request_id/cancel", post(cancel_request))
+            .layer(from_fn(track))
+            .layer(from_fn(request_id::propagate));
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+        let mut events = status::subscribe();
+
+        server
+            .post("/v1/requests/unknown/cancel")
+            .add_header(
+                request_id::REQUEST_ID_HEADER.parse().unwrap(),
+                "failing-request".parse().unwrap(),
+            )
+            .await
+            .assert_status(StatusCode::NOT_FOUND);
+
+        // Other tests may publish status events concurrently
+        let failure = std::iter::from_fn(move || events.try_recv().ok()).find(|event| {
+            matches!(
+                event,
+                StatusEvent::RequestFailed { request_id, .. } if request_id == "failing-request"
+            )
+        });
+        assert_eq!(
+            failure,
+            Some(StatusEvent::RequestFailed {
+                request_id: "failing-request".to_string(),
+                method: "POST".to_string(),
+                endpoint: "/v1/requests/unknown/cancel".to_string(),
+                status: 404,
+            })
+        );
+    }
+}
@@ -0,0 +1,230 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A document reranking endpoint, scoring the relevance of a set of documents to a query by the
+//! similarity of their embeddings.
+//!
+//! This is a bi-encoder ranking: the query and each document are embedded separately, so it is
+//! cheaper, but usually less accurate, than the cross-encoder models of dedicated reranking APIs,
+//! which read the query and a document together.
+
+use std::borrow::Cow;
+
+use axum::response::IntoResponse;
+use axum::Json;
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use edgen_core::embeddings::EmbeddingsArgs;
+use edgen_core::llm::LLMEndpointError;
+
+use crate::openai_shim::{generate_embeddings, ChatCompletionError};
+
+/// A request to rank a set of documents by the similarity of their embeddings to the embedding of a
+/// query.
+///
+/// An `axum` handler, [`rerank`][rerank], is provided to handle this request.
+///
+/// This endpoint is **Edgen** specific, following the request format commonly used by reranking
+/// APIs, although it scores documents with an embeddings model rather than a cross-encoder.
+///
+/// [rerank]: fn.rerank.html
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankRequest<'a> {
+    /// ID of the embeddings model to use.
+    #[schema(value_type = String)]
+    pub model: Cow<'a, str>,
+
+    /// The query the documents are ranked against.
+    #[schema(value_type = String)]
+    pub query: Cow<'a, str>,
+
+    /// The documents to rank.
+    #[schema(value_type = Vec<String>)]
+    pub documents: Vec<Cow<'a, str>>,
+
+    /// The number of most relevant documents to return. If not provided, every document is
+    /// returned.
+    pub top_n: Option<usize>,
+
+    /// If `true`, the text of each document is included in the results.
+    pub return_documents: Option<bool>,
+}
+
+/// The return type of [`rerank`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankResponse {
+    /// The model used for ranking.
+    pub model: String,
+
+    /// The ranked documents, from most to least relevant.
+    pub results: Vec<RerankResult>,
+}
+
+/// The relevance of a single document of a [`RerankRequest`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankResult {
+    /// The index of the document in the request.
+    pub index: usize,
+
+    /// The cosine similarity of the embedding of the document to the embedding of the query,
+    /// between -1 and 1 (most relevant).
+    pub relevance_score: f32,
+
+    /// The document, if `return_documents` was set in the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub document: Option<RerankDocument>,
+}
+
+/// A document returned in a [`RerankResult`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RerankDocument {
+    /// The text of the document.
+    pub text: String,
+}
+
+/// POST `/v1/rerank`: ranks the provided documents by the similarity of their embeddings to the
+/// embedding of the query.
+///
+/// The query and documents are embedded with the provided embeddings model, and each document is
+/// scored with the cosine similarity of its embedding to the query's.
+///
+/// On failure, may raise a `400 Bad Request` if the query is empty, a `413 Payload Too Large` if
+/// the request or any of the documents is too large, or a `500 Internal Server Error`, with a
+/// JSON-encoded [`ChatCompletionError`] to the peer.
+#[utoipa::path(
+post,
+path = "/rerank",
+request_body = RerankRequest,
+responses(
+(status = 200, description = "OK", body = RerankResponse),
+(status = 400, description = "the query is empty or a parameter is invalid", body = ChatCompletionError),
+(status = 413, description = "the request exceeds the request size limit, or a document has too many tokens", body = ChatCompletionError),
+(status = 500, description = "unexpected internal server error", body = ChatCompletionError)
+),
+)]
+pub async fn rerank(
+    Json(req): Json<RerankRequest<'_>>,
+) -> Result<impl IntoResponse, ChatCompletionError> {
+    if req.query.trim().is_empty() {
+        return Err(ChatCompletionError::InvalidParam {
+            param: "query".to_string(),
+            reason: Cow::Borrowed("must not be empty"),
+        });
+    }
+    if req.documents.is_empty() {
+        return Ok(Json(RerankResponse {
+            model: req.model.to_string(),
+            results: vec![],
+        }));
+    }
+
+    let mut input = Vec::with_capacity(req.documents.len() + 1);
+    input.push(req.query.to_string());
+    input.extend(req.documents.iter().map(move |doc| doc.to_string()));
+
+    let args = EmbeddingsArgs {
+        inputs: input,
+        ..Default::default()
+    };
+    let embeddings = generate_embeddings(req.model.as_ref(), args, None).await?;
+    let (query, documents) = embeddings.split_first().ok_or_else(move || {
+        ChatCompletionError::Endpoint(LLMEndpointError::Embeddings(
+            "the model did not return any embeddings".to_string(),
+        ))
+    })?;
+
+    let scores = documents
+        .iter()
+        .map(move |document| cosine_similarity(query, document))
+        .collect();
+    let return_documents = req.return_documents.unwrap_or(false);
+    let results = rank(scores, req.top_n)
+        .into_iter()
+        .map(move |(index, relevance_score)| RerankResult {
+            index,
+            relevance_score,
+            document: return_documents.then(|| RerankDocument {
+                text: req.documents[index].to_string(),
+            }),
+        })
+        .collect();
+
+    Ok(Json(RerankResponse {
+        model: req.model.to_string(),
+        results,
+    }))
+}
+
+/// Returns the cosine similarity of two vectors, or `0` if either of them is all zeros.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(move |(a, b)| a * b).sum();
+    let norm_a = a.iter().map(move |v| v * v).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(move |v| v * v).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Sorts the scores from highest to lowest, keeping their original indices, and keeps only the
+/// first `top_n` of them.
+fn rank(scores: Vec<f32>, top_n: Option<usize>) -> Vec<(usize, f32)> {
+    let mut ranked: Vec<(usize, f32)> = scores.into_iter().enumerate().collect();
+    ranked.sort_by(move |(_, a), (_, b)| b.total_cmp(a));
+    if let Some(top_n) = top_n {
+        ranked.truncate(top_n);
+    }
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]), -1.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[tokio::test]
+    async fn empty_query() {
+        let req = RerankRequest {
+            model: Cow::Borrowed("default"),
+            query: Cow::Borrowed(" "),
+            documents: vec![Cow::Borrowed("Paris is the capital of France.")],
+            top_n: None,
+            return_documents: None,
+        };
+        let Err(e) = rerank(Json(req)).await else {
+            panic!("an empty query was accepted");
+        };
+        assert_eq!(
+            e.into_response().status(),
+            axum::http::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn ranking() {
+        assert_eq!(
+            rank(vec![0.1, 0.9, 0.5], None),
+            vec![(1, 0.9), (2, 0.5), (0, 0.1)]
+        );
+        assert_eq!(rank(vec![0.1, 0.9, 0.5], Some(2)), vec![(1, 0.9), (2, 0.5)]);
+    }
+}
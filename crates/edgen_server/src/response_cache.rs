@@ -0,0 +1,270 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A persistent, content-addressed cache of deterministic chat completions.
+//!
+//! Only requests with a temperature of `0` are cached, as any other request is expected to get a
+//! different completion every time. Every completion is stored in its own file, named after the
+//! [`blake3`] hash of the model and the parameters of the request.
+//!
+//! Entries are written to a temporary file first, and then renamed, so that an entry is never read
+//! while being written. Once the entries exceed `chat_completions_cache_max_size`, the least
+//! recently used ones, going by the modification time of their files, are removed.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde_json::Value;
+use tracing::warn;
+use uuid::Uuid;
+
+use edgen_core::settings::{self, PROJECT_DIRS};
+
+/// The fields of a request that do not change its completion.
+///
+/// The model is hashed separately, as its file path, and the messages of the conversation and
+/// the retrieved context of a request are already part of its messages.
+const IGNORED_FIELDS: &[&str] = &[
+    "model",
+    "stream",
+    "include_tokens",
+    "coalesce_ms",
+    "coalesce_tokens",
+    "user",
+    "conversation_id",
+    "retrieval",
+];
+
+/// Returns the directory where cached completions are stored.
+fn cache_dir() -> PathBuf {
+    PROJECT_DIRS.data_dir().join("cache").join("completions")
+}
+
+/// Returns the parameters of a request identifying its completion, if it can be cached.
+pub fn request_key(mut request: Value) -> Option<String> {
+    let temperature = request.get("temperature").and_then(Value::as_f64);
+    if temperature != Some(0.0) {
+        return None;
+    }
+
+    let fields = request.as_object_mut()?;
+    // The temperature is always 0, whether it is written `0` or `0.0`
+    fields.remove("temperature");
+    for field in IGNORED_FIELDS {
+        fields.remove(*field);
+    }
+    fields.retain(move |_, value| !value.is_null());
+
+    Some(request.to_string())
+}
+
+/// Returns the path of the cache entry of the completion of `request` generated by `model`.
+fn entry_path(dir: &Path, model: &str, request: &str) -> PathBuf {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(model.as_bytes());
+    // Separate the model from the request, so that different pairs can never hash the same bytes
+    hasher.update(&[0]);
+    hasher.update(request.as_bytes());
+    let hash = hasher.finalize().to_hex();
+
+    // Spread the entries among subdirectories, to keep directories at a reasonable size
+    dir.join(&hash[..2]).join(hash.as_str())
+}
+
+/// Returns the cached completion of `request` generated by `model`, if any.
+pub async fn get(model: &str, request: &str) -> Option<String> {
+    get_in(&cache_dir(), model, request).await
+}
+
+/// Stores the completion of `request` generated by `model` in the cache, removing the least
+/// recently used entries if the cache grows over its maximum size.
+///
+/// Failing to do so is not fatal, as the completion can always be generated again, so errors are
+/// only logged.
+pub async fn insert(model: &str, request: &str, completion: &str) {
+    let max_size = settings::chat_completions_cache_max_size().await;
+    insert_in(&cache_dir(), model, request, completion, max_size).await
+}
+
+async fn get_in(dir: &Path, model: &str, request: &str) -> Option<String> {
+    let path = entry_path(dir, model, request);
+    let completion = tokio::fs::read_to_string(&path).await.ok()?;
+
+    // Mark the entry as used, so that it is among the last to be evicted
+    let touched = tokio::task::spawn_blocking(move || {
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(SystemTime::now())
+    })
+    .await;
+    if let Ok(Err(e)) = touched {
+        warn!("Failed to update the last use of a completions cache entry: {e}");
+    }
+
+    Some(completion)
+}
+
+async fn insert_in(
+    dir: &Path,
+    model: &str,
+    request: &str,
+    completion: &str,
+    max_size: Option<u64>,
+) {
+    let path = entry_path(dir, model, request);
+    if let Some(parent) = path.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(parent).await {
+            warn!("Failed to create completions cache directory {parent:?}: {e}");
+            return;
+        }
+    }
+
+    let temp = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    let written = match tokio::fs::write(&temp, completion).await {
+        Ok(()) => tokio::fs::rename(&temp, &path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = written {
+        warn!("Failed to write completions cache entry {path:?}: {e}");
+        let _ = tokio::fs::remove_file(&temp).await;
+        return;
+    }
+
+    if let Some(max_size) = max_size {
+        let dir = dir.to_path_buf();
+        match tokio::task::spawn_blocking(move || evict(&dir, max_size)).await {
+            Ok(Err(e)) => warn!("Failed to evict completions cache entries: {e}"),
+            Err(e) => warn!("Failed to evict completions cache entries: {e}"),
+            Ok(Ok(())) => {}
+        }
+    }
+}
+
+/// Removes the least recently used entries of the cache in `dir` until their total size is at
+/// most `max_size` bytes.
+pub(crate) fn evict(dir: &Path, max_size: u64) -> io::Result<()> {
+    let mut entries = vec![];
+    for subdir in std::fs::read_dir(dir)? {
+        let subdir = subdir?.path();
+        if !subdir.is_dir() {
+            continue;
+        }
+        for entry in std::fs::read_dir(subdir)? {
+            // Entries may be removed concurrently, by another eviction
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if metadata.is_file() {
+                let used = metadata.modified()?;
+                entries.push((used, metadata.len(), entry.path()));
+            }
+        }
+    }
+
+    let mut size: u64 = entries.iter().map(move |(_, len, _)| len).sum();
+    if size <= max_size {
+        return Ok(());
+    }
+
+    entries.sort();
+    for (_, len, path) in entries {
+        if size <= max_size {
+            break;
+        }
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        size = size.saturating_sub(len);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn request_keys() {
+        let request = json!({
+            "model": "default",
+            "messages": [{"role": "user", "content": "Hello!"}],
+            "temperature": 0.0,
+        });
+
+        assert_eq!(request_key(json!({"temperature": 0.7})), None);
+        assert_eq!(request_key(json!({"messages": []})), None);
+        assert_eq!(
+            request_key(request.clone()),
+            request_key(json!({
+                "model": "other",
+                "messages": [{"role": "user", "content": "Hello!"}],
+                "temperature": 0,
+                "stream": false,
+                "seed": null,
+            }))
+        );
+        assert_ne!(
+            request_key(request),
+            request_key(json!({
+                "messages": [{"role": "user", "content": "Hello!"}],
+                "temperature": 0.0,
+                "max_tokens": 16,
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn cache_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(get_in(dir.path(), "model", "request").await, None);
+
+        insert_in(dir.path(), "model", "request", "completion", None).await;
+        assert_eq!(
+            get_in(dir.path(), "model", "request").await,
+            Some("completion".to_string())
+        );
+        assert_eq!(get_in(dir.path(), "other-model", "request").await, None);
+        assert_eq!(get_in(dir.path(), "model", "other request").await, None);
+    }
+
+    #[tokio::test]
+    async fn cache_eviction() {
+        let dir = tempfile::tempdir().unwrap();
+        let completion = "x".repeat(100);
+        let max_size = Some(250);
+
+        insert_in(dir.path(), "model", "first", &completion, max_size).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        insert_in(dir.path(), "model", "second", &completion, max_size).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        // Using the first entry makes the second one the least recently used
+        assert!(get_in(dir.path(), "model", "first").await.is_some());
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        insert_in(dir.path(), "model", "third", &completion, max_size).await;
+
+        assert!(get_in(dir.path(), "model", "first").await.is_some());
+        assert_eq!(get_in(dir.path(), "model", "second").await, None);
+        assert!(get_in(dir.path(), "model", "third").await.is_some());
+    }
+}
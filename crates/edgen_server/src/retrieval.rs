@@ -0,0 +1,170 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Retrieval-augmented chat completions: the chunks of a [vector store](crate::vector_stores)
+//! most relevant to the last user message are added to the prompt before generation.
+//!
+//! The chunks are added as a system message right before the last user message, so that the
+//! prompt up to the previous turn is unchanged and its session can still be reused.
+
+use std::borrow::Cow;
+
+use either::Either;
+use serde_derive::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::openai_shim::{ChatCompletionError, ChatMessage, ChatMessages, ContentPart};
+use crate::vector_stores::{self, SearchResult, VectorStoreError};
+
+/// The default number of chunks added to the prompt.
+const DEFAULT_TOP_K: usize = 4;
+
+/// The maximum number of chunks added to the prompt.
+const MAX_TOP_K: usize = 50;
+
+/// The vector store to retrieve the context of a chat completion from.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RetrievalOptions {
+    /// The ID of the vector store.
+    pub vector_store_id: String,
+
+    /// The number of chunks to add to the prompt, between 1 and 50. Defaults to 4.
+    pub top_k: Option<usize>,
+}
+
+/// Adds the chunks most relevant to the last user message to the messages, returning them.
+///
+/// Nothing is retrieved if there is no user message.
+pub async fn augment(
+    options: &RetrievalOptions,
+    messages: &mut ChatMessages<'_>,
+) -> Result<Vec<SearchResult>, ChatCompletionError> {
+    let top_k = options.top_k.unwrap_or(DEFAULT_TOP_K);
+    if !(1..=MAX_TOP_K).contains(&top_k) {
+        return Err(ChatCompletionError::InvalidParam {
+            param: "retrieval.top_k".to_string(),
+            reason: Cow::Owned(format!("must be between 1 and {MAX_TOP_K}, got {top_k}")),
+        });
+    }
+
+    let Some((position, query)) = last_user_message(messages) else {
+        return Ok(vec![]);
+    };
+
+    let sources = vector_stores::search(&options.vector_store_id, &query, top_k)
+        .await
+        .map_err(move |e| match e {
+            VectorStoreError::NoSuchVectorStore { vector_store_id } => {
+                ChatCompletionError::NoSuchVectorStore { vector_store_id }
+            }
+            e => ChatCompletionError::Retrieval {
+                reason: e.to_string(),
+            },
+        })?;
+
+    if !sources.is_empty() {
+        messages.insert(
+            position,
+            ChatMessage::System {
+                content: Some(Cow::Owned(context_message(&sources))),
+                name: None,
+            },
+        );
+    }
+
+    Ok(sources)
+}
+
+/// Returns the position and the text of the last user message, if any.
+fn last_user_message(messages: &ChatMessages) -> Option<(usize, String)> {
+    messages
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(move |(position, message)| match message {
+            ChatMessage::User {
+                content: Either::Left(text),
+                ..
+            } => Some((position, text.to_string())),
+            ChatMessage::User {
+                content: Either::Right(parts),
+                ..
+            } => {
+                let text: Vec<&str> = parts
+                    .iter()
+                    .filter_map(move |part| match part {
+                        ContentPart::Text { text } => Some(text.as_ref()),
+                        ContentPart::ImageUrl { .. } => None,
+                    })
+                    .collect();
+                Some((position, text.join("\n")))
+            }
+            _ => None,
+        })
+}
+
+/// Formats the retrieved chunks as the content of a system message.
+fn context_message(sources: &[SearchResult]) -> String {
+    let mut content = "Use the following excerpts to answer the next message. If they are not \
+                       relevant, answer without them.\n"
+        .to_string();
+    for (index, source) in sources.iter().enumerate() {
+        content.push_str(&format!(
+            "\n[{}] {}:\n{}\n",
+            index + 1,
+            source.filename,
+            source.content
+        ));
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query() {
+        let mut messages = ChatMessages::default();
+        assert_eq!(last_user_message(&messages), None);
+
+        messages.push(ChatMessage::User {
+            content: Either::Left(Cow::Borrowed("first")),
+            name: None,
+        });
+        messages.push(ChatMessage::Assistant {
+            content: Some(Cow::Borrowed("reply")),
+            name: None,
+            tool_calls: None,
+        });
+        messages.push(ChatMessage::User {
+            content: Either::Right(vec![
+                ContentPart::Text {
+                    text: Cow::Borrowed("second"),
+                },
+                ContentPart::ImageUrl {
+                    url: Cow::Borrowed("http://localhost/image.png"),
+                    detail: None,
+                },
+                ContentPart::Text {
+                    text: Cow::Borrowed("question"),
+                },
+            ]),
+            name: None,
+        });
+
+        assert_eq!(
+            last_user_message(&messages),
+            Some((2, "second\nquestion".to_string()))
+        );
+    }
+}
@@ -0,0 +1,396 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! The router mode, where the server runs no model itself and forwards every request to the least
+//! busy of a set of worker **Edgen** instances.
+//!
+//! The load of a worker is the number of generations it reports running in
+//! `/v1/status/models`, or the number of requests forwarded to it that have not completed yet, if
+//! that is higher. The reported loads are polled in the background every [`LOAD_POLL_INTERVAL`],
+//! so that forwarding a request does not wait for the workers. Workers that cannot be reached are
+//! skipped until they answer again.
+//!
+//! The URL of a worker is its base URL, including its `base_path` if it has one, e.g.
+//! `http://10.0.0.2:33322/llm`. Requests are forwarded without the `base_path` of the router.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{middleware, Json, Router};
+use futures::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use edgen_core::settings::SETTINGS;
+
+use crate::request_id;
+
+/// How long a worker may take to report its load before being considered unavailable.
+const LOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often the workers are asked for their load.
+const LOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Headers that only concern a single connection, and so are not forwarded.
+const HOP_HEADERS: &[header::HeaderName] = &[
+    header::CONNECTION,
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+    header::TE,
+    header::TRAILER,
+    header::PROXY_AUTHORIZATION,
+];
+
+/// The part of the `/v1/status/models` response of a worker its load is computed from.
+#[derive(Deserialize)]
+struct ReportedModels {
+    models: Vec<ReportedModel>,
+}
+
+#[derive(Deserialize)]
+struct ReportedModel {
+    #[serde(default)]
+    in_flight: usize,
+}
+
+/// A worker instance requests are forwarded to.
+struct Worker {
+    /// The base URL of the worker, e.g. `http://10.0.0.2:33322`.
+    url: String,
+
+    /// The number of generations the worker reported running when last polled, or **`None`** if
+    /// it could not be reached or has not been polled yet.
+    reported: Mutex<Option<usize>>,
+
+    /// The number of requests forwarded to the worker that have not completed yet.
+    forwarded: AtomicUsize,
+}
+
+impl Worker {
+    /// Returns the URL of a route of the worker, e.g. `/v1/status/models`, under its base path.
+    fn route(&self, path: &str) -> String {
+        format!("{}{path}", self.url)
+    }
+
+    /// Returns the number of generations the worker reported running when last polled.
+    fn reported(&self) -> Option<usize> {
+        *self.reported.lock().unwrap()
+    }
+}
+
+/// Decrements the forwarded requests of a worker when dropped, that is, once the response has been
+/// completely sent or the client has gone away.
+struct ForwardGuard(Arc<Worker>);
+
+impl Drop for ForwardGuard {
+    fn drop(&mut self) {
+        self.0.forwarded.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
+struct RouterState {
+    workers: Arc<Vec<Arc<Worker>>>,
+    client: reqwest::Client,
+}
+
+/// An error forwarding a request to a worker.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum RouterError {
+    /// None of the workers could be reached.
+    #[error("no worker is available")]
+    NoWorkerAvailable,
+
+    /// The request could not be forwarded to the chosen worker.
+    #[error("failed to forward the request to {worker}: {reason}")]
+    Forward {
+        /// The URL of the worker.
+        worker: String,
+        /// The reason the request could not be forwarded.
+        reason: String,
+    },
+}
+
+impl IntoResponse for RouterError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            RouterError::NoWorkerAvailable => StatusCode::SERVICE_UNAVAILABLE,
+            RouterError::Forward { .. } => StatusCode::BAD_GATEWAY,
+        };
+        request_id::error_response(status, &self)
+    }
+}
+
+/// The load of a worker, as returned by `/v1/router/workers`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WorkerStatus {
+    /// The base URL of the worker.
+    pub url: String,
+
+    /// **`true`** if the worker reported its load.
+    pub available: bool,
+
+    /// The number of generations the worker reported running.
+    pub in_flight: Option<usize>,
+
+    /// The number of requests forwarded to the worker that have not completed yet.
+    pub forwarded: usize,
+}
+
+/// The return type of `/v1/router/workers`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WorkersStatus {
+    pub workers: Vec<WorkerStatus>,
+}
+
+/// Returns the routes of the router mode, forwarding every request to one of the workers at the
+/// provided base URLs, apart from `/v1/router/workers`, which lists the workers and their load.
+pub fn routes(urls: Vec<String>) -> Router {
+    info!("Routing requests to {} workers: {urls:?}", urls.len());
+
+    let workers = urls
+        .into_iter()
+        .map(move |url| {
+            Arc::new(Worker {
+                url: url.trim_end_matches('/').to_string(),
+                reported: Mutex::new(None),
+                forwarded: AtomicUsize::new(0),
+            })
+        })
+        .collect();
+    let state = RouterState {
+        workers: Arc::new(workers),
+        client: reqwest::Client::new(),
+    };
+    spawn_load_poll(Arc::downgrade(&state.workers), state.client.clone());
+
+    Router::new()
+        .route("/v1/router/workers", get(workers_status))
+        .fallback(forward)
+        .with_state(state)
+        .layer(middleware::from_fn(request_id::propagate))
+}
+
+/// GET `/v1/router/workers`: returns the workers requests are forwarded to, and their load.
+#[utoipa::path(
+get,
+path = "/router/workers",
+responses(
+(status = 200, description = "OK", body = WorkersStatus)
+),
+)]
+pub async fn workers_status(State(state): State<RouterState>) -> impl IntoResponse {
+    let workers = state
+        .workers
+        .iter()
+        .map(move |worker| {
+            let in_flight = worker.reported();
+            WorkerStatus {
+                url: worker.url.clone(),
+                available: in_flight.is_some(),
+                in_flight,
+                forwarded: worker.forwarded.load(Ordering::SeqCst),
+            }
+        })
+        .collect();
+
+    Json(WorkersStatus { workers })
+}
+
+/// Forwards a request to the least busy worker, streaming its response back.
+async fn forward(State(state): State<RouterState>, req: Request) -> Result<Response, RouterError> {
+    let loads = state.workers.iter().map(move |worker| {
+        worker
+            .reported()
+            .map(move |in_flight| in_flight.max(worker.forwarded.load(Ordering::SeqCst)))
+    });
+    let worker = least_busy(loads)
+        .map(|index| state.workers[index].clone())
+        .ok_or(RouterError::NoWorkerAvailable)?;
+
+    worker.forwarded.fetch_add(1, Ordering::SeqCst);
+    let guard = ForwardGuard(worker.clone());
+
+    let forward_error = |reason: String| RouterError::Forward {
+        worker: worker.url.clone(),
+        reason,
+    };
+
+    let (parts, body) = req.into_parts();
+    let max_size = SETTINGS.read().await.read().await.max_request_size;
+    let body = axum::body::to_bytes(body, max_size)
+        .await
+        .map_err(|e| forward_error(e.to_string()))?;
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(move |path| path.as_str())
+        .unwrap_or("/");
+    let url = worker.route(path);
+
+    let mut headers = forwarded_headers(&parts.headers);
+    if let Some(id) = request_id::current() {
+        if let Ok(id) = id.parse() {
+            headers.insert(request_id::REQUEST_ID_HEADER, id);
+        }
+    }
+
+    let response = state
+        .client
+        .request(parts.method, url)
+        .headers(headers)
+        .body(body)
+        .send()
+        .await
+        .map_err(move |e| forward_error(e.to_string()))?;
+
+    let mut builder = Response::builder().status(response.status());
+    if let Some(headers) = builder.headers_mut() {
+        *headers = forwarded_headers(response.headers());
+    }
+
+    // Keep the worker marked as busy until the whole response, which may be an event stream, has
+    // been sent
+    let stream = response.bytes_stream().map(move |chunk| {
+        let _guard = &guard;
+        chunk
+    });
+
+    builder
+        .body(Body::from_stream(stream))
+        .map_err(move |e| forward_error(e.to_string()))
+}
+
+/// Spawns the task asking every worker for the number of generations it is running every
+/// [`LOAD_POLL_INTERVAL`], until the workers are dropped along with the routes.
+fn spawn_load_poll(workers: Weak<Vec<Arc<Worker>>>, client: reqwest::Client) {
+    tokio::spawn(async move {
+        let mut poll = interval(LOAD_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            poll.tick().await;
+            let Some(workers) = workers.upgrade() else {
+                return;
+            };
+
+            let loads = workers.iter().map(|worker| reported_load(&client, worker));
+            let loads = futures::future::join_all(loads).await;
+            for (worker, load) in workers.iter().zip(loads) {
+                *worker.reported.lock().unwrap() = load;
+            }
+        }
+    });
+}
+
+/// Asks a worker for the number of generations it is running, returning **`None`** if it cannot
+/// be reached.
+async fn reported_load(client: &reqwest::Client, worker: &Worker) -> Option<usize> {
+    let url = &worker.url;
+    let response = client
+        .get(worker.route("/v1/status/models"))
+        .timeout(LOAD_TIMEOUT)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status);
+    let status: ReportedModels = match response {
+        Ok(response) => match response.json().await {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Worker {url} reported an invalid status: {e}");
+                return None;
+            }
+        },
+        Err(e) => {
+            warn!("Worker {url} is unavailable: {e}");
+            return None;
+        }
+    };
+
+    Some(status.models.iter().map(move |model| model.in_flight).sum())
+}
+
+/// Returns the index of the available worker with the lowest load, preferring the first ones on
+/// ties.
+fn least_busy(loads: impl Iterator<Item = Option<usize>>) -> Option<usize> {
+    loads
+        .enumerate()
+        .filter_map(move |(index, load)| load.map(move |load| (index, load)))
+        .min_by_key(move |(_, load)| *load)
+        .map(move |(index, _)| index)
+}
+
+/// Copies the headers of a request or response, minus those that only concern a single
+/// connection.
+fn forwarded_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut forwarded = headers.clone();
+    for name in HOP_HEADERS {
+        forwarded.remove(name);
+    }
+    forwarded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn worker_selection() {
+        assert_eq!(least_busy([Some(3), Some(1), Some(2)].into_iter()), Some(1));
+        assert_eq!(least_busy([None, Some(4), Some(4)].into_iter()), Some(1));
+        assert_eq!(least_busy([Some(0), None].into_iter()), Some(0));
+        assert_eq!(least_busy([None, None].into_iter()), None);
+    }
+
+    #[test]
+    fn worker_routes() {
+        let worker = |url: &str| Worker {
+            url: url.trim_end_matches('/').to_string(),
+            reported: Mutex::new(None),
+            forwarded: AtomicUsize::new(0),
+        };
+
+        assert_eq!(
+            worker("http://10.0.0.2:33322").route("/v1/status/models"),
+            "http://10.0.0.2:33322/v1/status/models"
+        );
+        assert_eq!(
+            worker("http://10.0.0.2:33322/llm/").route("/v1/chat/completions?stream=true"),
+            "http://10.0.0.2:33322/llm/v1/chat/completions?stream=true"
+        );
+    }
+
+    #[test]
+    fn hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, "localhost".parse().unwrap());
+        headers.insert(header::CONNECTION, "keep-alive".parse().unwrap());
+        headers.insert(header::AUTHORIZATION, "Bearer key".parse().unwrap());
+
+        let forwarded = forwarded_headers(&headers);
+        assert_eq!(forwarded.len(), 1);
+        assert_eq!(forwarded[header::AUTHORIZATION], "Bearer key");
+    }
+}
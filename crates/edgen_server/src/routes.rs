@@ -13,58 +13,267 @@
 //! Contains all routes served by Edgen
 
 use axum::{
+    extract::DefaultBodyLimit,
     http::{uri::Uri, Method, StatusCode},
+    middleware,
     response::IntoResponse,
-    routing::{delete, get, post},
-    Router,
+    routing::{delete, get, post, MethodRouter},
+    Extension, Router,
 };
 
 use tracing::warn;
+use utoipa::openapi::Server;
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
+use edgen_core::settings::RequestSizeLimits;
+
+use crate::admin;
+use crate::assistants;
+use crate::batch;
+use crate::conversation;
+use crate::events;
+use crate::files;
+use crate::idempotency;
 use crate::model_man;
 use crate::openai_shim;
+use crate::requests;
 use crate::status;
-use crate::{image_generation, misc};
+use crate::types::ApiVersion;
+use crate::validation;
+use crate::vector_stores;
+use crate::{image_generation, misc, request_id, rerank};
+
+pub fn routes(limits: &RequestSizeLimits) -> Router {
+    let mut router = Router::new();
+    for &version in ApiVersion::ALL {
+        router = router.nest(version.prefix(), versioned_routes(version, limits));
+    }
+
+    router
+        // -- Interactive API documentation ------------------------------------
+        .merge(docs())
+        // -- Catch-all route to log all requests ------------------------------
+        .fallback(catch_all)
+        // -- Bookkeeping of the requests being handled, for every route -------
+        .layer(middleware::from_fn(requests::track))
+        // -- Request identification, for every route --------------------------
+        .layer(middleware::from_fn(request_id::propagate))
+}
+
+/// Returns the routes of an API version, relative to its prefix, e.g. `/chat/completions` for
+/// `/v1/chat/completions`.
+///
+/// Every route tree is served side by side, so a version with breaking changes can be added
+/// without changing the behaviour of the previous ones. Handlers shared by several versions can
+/// extract the [`ApiVersion`] of the request to tell them apart.
+fn versioned_routes(version: ApiVersion, limits: &RequestSizeLimits) -> Router {
+    let routes = match version {
+        ApiVersion::V1 => v1_routes(limits),
+    };
+
+    routes.layer(Extension(version))
+}
 
-pub fn routes() -> Router {
+/// The routes of the `/v1` API.
+fn v1_routes(limits: &RequestSizeLimits) -> Router {
     Router::new()
         // -- AI endpoints -----------------------------------------------------
         // ---- Chat -----------------------------------------------------------
-        .route("/v1/chat/completions", post(openai_shim::chat_completions))
+        .route(
+            "/chat/completions",
+            limited(
+                idempotent(
+                    post(openai_shim::chat_completions).layer(middleware::from_fn(
+                        validation::strict::<openai_shim::CreateChatCompletionRequest<'static>>,
+                    )),
+                ),
+                limits.chat_completions,
+            ),
+        )
+        .route(
+            "/chat/completions/estimate",
+            limited(
+                post(openai_shim::chat_completions_estimate).layer(middleware::from_fn(
+                    validation::strict::<openai_shim::CreateChatCompletionRequest<'static>>,
+                )),
+                limits.chat_completions,
+            ),
+        )
+        .route(
+            "/chat/conversations/:id",
+            delete(conversation::delete_conversation),
+        )
         // ---- Embeddings -----------------------------------------------------
-        .route("/v1/embeddings", post(openai_shim::create_embeddings))
+        .route(
+            "/embeddings",
+            limited(
+                idempotent(
+                    post(openai_shim::create_embeddings).layer(middleware::from_fn(
+                        validation::strict::<openai_shim::CreateEmbeddingsRequest<'static>>,
+                    )),
+                ),
+                limits.embeddings,
+            ),
+        )
+        // ---- Rerank ---------------------------------------------------------
+        .route(
+            "/rerank",
+            limited(idempotent(post(rerank::rerank)), limits.embeddings),
+        )
+        // ---- Assistants -----------------------------------------------------
+        .route("/threads", post(assistants::create_thread))
+        .route(
+            "/threads/:thread_id",
+            get(assistants::retrieve_thread).delete(assistants::delete_thread),
+        )
+        .route(
+            "/threads/:thread_id/messages",
+            post(assistants::create_message).get(assistants::list_messages),
+        )
+        .route(
+            "/threads/:thread_id/runs",
+            idempotent(post(assistants::create_run)).get(assistants::list_runs),
+        )
+        .route(
+            "/threads/:thread_id/runs/:run_id",
+            get(assistants::retrieve_run),
+        )
+        // ---- Files ----------------------------------------------------------
+        .route(
+            "/files",
+            limited(post(files::create_file), limits.files).get(files::list_files),
+        )
+        .route(
+            "/files/:file_id",
+            get(files::retrieve_file).delete(files::delete_file),
+        )
+        .route("/files/:file_id/content", get(files::retrieve_file_content))
+        // ---- Batches --------------------------------------------------------
+        .route(
+            "/batches",
+            idempotent(post(batch::create_batch)).get(batch::list_batches),
+        )
+        .route("/batches/:batch_id", get(batch::retrieve_batch))
+        .route("/batches/:batch_id/cancel", post(batch::cancel_batch))
+        // ---- Vector stores --------------------------------------------------
+        .route(
+            "/vector_stores",
+            post(vector_stores::create_vector_store).get(vector_stores::list_vector_stores),
+        )
+        .route(
+            "/vector_stores/:vector_store_id",
+            get(vector_stores::retrieve_vector_store).delete(vector_stores::delete_vector_store),
+        )
+        .route(
+            "/vector_stores/:vector_store_id/files",
+            post(vector_stores::create_vector_store_file)
+                .get(vector_stores::list_vector_store_files),
+        )
+        .route(
+            "/vector_stores/:vector_store_id/files/:file_id",
+            delete(vector_stores::delete_vector_store_file),
+        )
+        .route(
+            "/vector_stores/:vector_store_id/search",
+            post(vector_stores::search_vector_store),
+        )
         // ---- Audio ----------------------------------------------------------
         .route(
-            "/v1/audio/transcriptions",
-            post(openai_shim::create_transcription),
+            "/audio/transcriptions",
+            limited(
+                post(openai_shim::create_transcription),
+                limits.audio_transcriptions,
+            ),
         )
         // ---- Image ----------------------------------------------------------
         .route(
-            "/v1/image/generations",
-            post(image_generation::generate_image),
+            "/image/generations",
+            limited(
+                idempotent(
+                    post(image_generation::generate_image).layer(middleware::from_fn(
+                        validation::strict::<openai_shim::CreateImageRequest<'static>>,
+                    )),
+                ),
+                limits.image_generation,
+            ),
+        )
+        .route(
+            "/image/generations/files/:name",
+            get(image_generation::get_generated_image),
         )
         // -- AI status endpoints ----------------------------------------------
         // ---- Chat -----------------------------------------------------------
         .route(
-            "/v1/chat/completions/status",
+            "/chat/completions/status",
             get(status::chat_completions_status),
         )
         // ---- Audio ----------------------------------------------------------
         .route(
-            "/v1/audio/transcriptions/status",
+            "/audio/transcriptions/status",
             get(status::audio_transcriptions_status),
         )
         // ---- Embeddings -----------------------------------------------------
-        .route("/v1/embeddings/status", get(status::embeddings_status))
+        .route("/embeddings/status", get(status::embeddings_status))
+        // ---- Image ----------------------------------------------------------
+        .route(
+            "/image/generations/status",
+            get(status::image_generation_status),
+        )
+        // ---- Models ---------------------------------------------------------
+        .route("/status/models", get(status::models_status))
+        .route("/status/stream", get(status::status_stream))
+        .route("/events", get(events::events))
         // -- Model Manager ----------------------------------------------------
         // -- Model Manager ----------------------------------------------------
-        .route("/v1/models", get(model_man::list_models))
-        .route("/v1/models/:model", get(model_man::retrieve_model))
-        .route("/v1/models/:model", delete(model_man::delete_model))
+        .route("/models", get(model_man::list_models))
+        .route("/models/search", get(model_man::search_models))
+        .route("/models/:model", get(model_man::retrieve_model))
+        .route("/models/:model", delete(model_man::delete_model))
+        // -- Requests ---------------------------------------------------------
+        .route("/requests", get(requests::list_requests))
+        .route(
+            "/requests/:request_id/cancel",
+            post(requests::cancel_request),
+        )
+        // -- Administration ---------------------------------------------------
+        .route("/admin/models/pin", post(admin::pin_model))
         // -- Miscellaneous services -------------------------------------------
-        .route("/v1/misc/version", get(misc::edgen_version))
-        // -- Catch-all route to log all requests ------------------------------
-        .fallback(catch_all)
+        .route("/misc/version", get(misc::edgen_version))
+}
+
+/// Serves `routes` under `base_path`, e.g. `/llm`, or at the root if `base_path` is empty.
+pub fn with_base_path(routes: Router, base_path: &str) -> Router {
+    if base_path.is_empty() {
+        routes
+    } else {
+        Router::new().nest(base_path, routes)
+    }
+}
+
+/// Serves Swagger UI at `/docs`, and the OpenAPI document it displays at `/docs/openapi.json`.
+fn docs() -> SwaggerUi {
+    let mut doc = crate::ApiDoc::openapi();
+    // Relative to the document, so that requests sent from Swagger UI honour the `base_path`
+    doc.servers = Some(vec![Server::new("../v1")]);
+    SwaggerUi::new("/docs").url("/docs/openapi.json", doc)
+}
+
+/// Replays the responses of `route` to retried requests, see [`idempotency`].
+///
+/// Routes are limited outside of this, so that the bodies read to tell retries apart are limited
+/// like the handler would limit them.
+fn idempotent(route: MethodRouter) -> MethodRouter {
+    route.layer(middleware::from_fn(idempotency::replay))
+}
+
+/// Limits the size of the requests of `route` to `limit` bytes, if set, instead of the
+/// `max_request_size` of the settings.
+fn limited(route: MethodRouter, limit: Option<usize>) -> MethodRouter {
+    match limit {
+        Some(limit) => route.layer(DefaultBodyLimit::max(limit)),
+        None => route,
+    }
 }
 
 async fn catch_all(method: Method, uri: Uri) -> impl IntoResponse {
@@ -77,11 +286,76 @@ async fn catch_all(method: Method, uri: Uri) -> impl IntoResponse {
 
 #[cfg(test)]
 mod test {
-    use super::catch_all;
+    use super::{catch_all, docs, versioned_routes, with_base_path};
+    use crate::types::ApiVersion;
     use axum::http::StatusCode;
+    use axum::routing::get;
     use axum::Router;
     use axum_test::TestServer;
 
+    #[tokio::test]
+    async fn test_base_path() {
+        let router = Router::new()
+            .route("/v1/misc/version", get(|| async { "version" }))
+            .fallback(catch_all);
+        let router = with_base_path(router, "/llm");
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let resp = server.get("/llm/v1/misc/version").await;
+
+        assert_eq!(resp.status_code(), StatusCode::OK);
+
+        let resp = server.get("/v1/misc/version").await;
+
+        assert_eq!(resp.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_versioned_routes() {
+        let router = Router::new()
+            .nest(
+                ApiVersion::V1.prefix(),
+                versioned_routes(ApiVersion::V1, &Default::default()),
+            )
+            .fallback(catch_all);
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let resp = server.get("/v1/misc/version").await;
+
+        assert_eq!(resp.status_code(), StatusCode::OK);
+
+        let resp = server.get("/misc/version").await;
+
+        assert_eq!(resp.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_docs() {
+        let router = Router::new().merge(docs());
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let resp = server.get("/docs/").await;
+
+        assert_eq!(resp.status_code(), StatusCode::OK);
+
+        let doc = server
+            .get("/docs/openapi.json")
+            .await
+            .json::<serde_json::Value>();
+
+        for path in [
+            "/chat/completions",
+            "/embeddings",
+            "/models/{model}",
+            "/status/stream",
+        ] {
+            assert!(doc["paths"].get(path).is_some(), "{path} is not documented");
+        }
+    }
+
     #[tokio::test]
     async fn test_get_any_path() {
         let router = Router::new().fallback(catch_all);
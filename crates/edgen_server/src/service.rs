@@ -0,0 +1,278 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Integration with service managers, so that the server can be supervised on headless machines:
+//! `sd_notify` readiness and watchdog notifications for systemd, a Windows service mode, running
+//! in the background and pidfiles.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::types::EdgenError;
+
+/// Tells the service manager that the server has started and is accepting requests.
+pub fn notify_ready() {
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+}
+
+/// Tells the service manager that the server is reloading its configuration.
+pub fn notify_reloading() {
+    notify("RELOADING=1");
+}
+
+/// Tells the service manager that the server is shutting down.
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+/// If the service manager expects watchdog keep-alive notifications, spawns a task sending them
+/// at half the requested interval.
+pub fn spawn_watchdog() -> Option<JoinHandle<()>> {
+    let interval = watchdog_interval()?;
+    info!("Sending watchdog notifications every {interval:?}");
+
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    }))
+}
+
+/// Returns the interval at which watchdog notifications must be sent, if the service manager
+/// expects them.
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    // The watchdog is meant for this process only, not for its children
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Sends a state notification to systemd, if the server was started by it with `Type=notify`.
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let socket = match UnixDatagram::unbound() {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Cannot create the service manager notification socket: {e}");
+            return;
+        }
+    };
+
+    let sent = match path.to_str().and_then(move |path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            use std::os::unix::net::SocketAddr;
+
+            SocketAddr::from_abstract_name(name)
+                .and_then(move |addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    };
+
+    if let Err(e) = sent {
+        warn!("Cannot notify the service manager: {e}");
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+/// A file holding the process ID of the server, removed when dropped.
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Writes the process ID of the server to the file at `path`.
+    pub fn create(path: &Path) -> Result<Self, EdgenError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!("Cannot remove pidfile {:?}: {e}", self.path);
+        }
+    }
+}
+
+/// Starts the server again in a detached background process, with the same arguments minus the
+/// daemon switch, and prints its process ID.
+pub fn daemonize() -> Result<(), EdgenError> {
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(move |arg| arg != "--daemon" && arg != "-d")
+        .collect();
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    detach(&mut command);
+
+    let child = command.spawn()?;
+    println!("{}", child.id());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    // A new process group keeps the server from receiving the terminal's signals
+    command.process_group(0);
+}
+
+#[cfg(windows)]
+fn detach(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+#[cfg(not(any(unix, windows)))]
+fn detach(_command: &mut Command) {}
+
+/// Runs the server as a Windows service, returning once the service is stopped.
+#[cfg(windows)]
+pub fn run_windows_service() -> Result<(), EdgenError> {
+    windows::run()
+}
+
+/// Runs the server as a Windows service, which is not possible on this platform.
+#[cfg(not(windows))]
+pub fn run_windows_service() -> Result<(), EdgenError> {
+    Err(EdgenError::GenericError(
+        "Windows services are only supported on Windows".to_string(),
+    ))
+}
+
+#[cfg(windows)]
+#[allow(unsafe_code)] // the service entry point generated by `define_windows_service`
+mod windows {
+    use std::ffi::OsString;
+    use std::time::Duration;
+
+    use tracing::error;
+    use windows_service::service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    use crate::cli::{Command, Serve, PARSED_COMMANDS};
+    use crate::graceful_shutdown;
+    use crate::types::EdgenError;
+
+    /// The name the service is registered with.
+    const SERVICE_NAME: &str = "edgen";
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub fn run() -> Result<(), EdgenError> {
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .map_err(move |e| EdgenError::GenericError(e.to_string()))
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = run_service() {
+            error!("Windows service failed: {e}");
+        }
+    }
+
+    fn run_service() -> Result<(), windows_service::Error> {
+        let status_handle =
+            service_control_handler::register(SERVICE_NAME, move |control| match control {
+                ServiceControl::Stop | ServiceControl::Shutdown => {
+                    graceful_shutdown::request_shutdown();
+                    ServiceControlHandlerResult::NoError
+                }
+                ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+                _ => ServiceControlHandlerResult::NotImplemented,
+            })?;
+
+        let status = move |state, accepted, exit_code| ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: accepted,
+            exit_code,
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        };
+
+        status_handle.set_service_status(status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            ServiceExitCode::Win32(0),
+        ))?;
+
+        // The service is started with the arguments it was registered with, not with the service
+        // start parameters
+        let args = match &PARSED_COMMANDS.subcommand {
+            Some(Command::Serve(serve)) => Serve {
+                uri: serve.uri.clone(),
+                pidfile: serve.pidfile.clone(),
+                config: serve.config.clone(),
+                profile: serve.profile.clone(),
+                ..Serve::default()
+            },
+            _ => Serve::default(),
+        };
+        let exit_code = match crate::start_server(&args) {
+            Ok(()) => 0,
+            Err(e) => {
+                error!("Server failed: {e:?}");
+                1
+            }
+        };
+
+        status_handle.set_service_status(status(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            ServiceExitCode::Win32(exit_code),
+        ))
+    }
+}
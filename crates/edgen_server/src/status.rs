@@ -12,47 +12,281 @@
 
 //! Edgen AI service status.
 
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use std::error::Error;
 use std::path::PathBuf;
 use std::time::{Duration, Instant};
 
-use axum::response::{IntoResponse, Json, Response};
+use axum::response::sse::{Event, KeepAlive};
+use axum::response::{IntoResponse, Json, Response, Sse};
+use futures::{Stream, StreamExt};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, RwLock};
+use tokio::time::{interval, MissedTickBehavior};
 use tracing::{error, info, warn};
 use utoipa::ToSchema;
 
+use edgen_core::llm::Throughput;
+use edgen_core::resident::ResidentModel;
+
+use crate::backends::BACKENDS;
+use crate::model::ModelKind;
+use crate::store;
+use crate::types::Endpoint;
+
 /// GET `/v1/chat/completions/status`: returns the current status of the /chat/completions endpoint.
 ///
 /// The status is returned as json value AIStatus.
 /// For any error, the version endpoint returns "internal server error".
+#[utoipa::path(
+get,
+path = "/chat/completions/status",
+responses(
+(status = 200, description = "OK", body = AIStatus)
+),
+)]
 pub async fn chat_completions_status() -> Response {
-    let state = get_chat_completions_status().read().await;
-    Json(state.clone()).into_response()
+    let mut state = get_chat_completions_status().read().await.clone();
+    state.throughput = Some(BACKENDS.chat_throughput());
+    Json(state).into_response()
 }
 
 /// GET `/v1/audio/transcriptions/status`: returns the current status of the /audio/transcriptions endpoint.
 ///
 /// The status is returned as json value AIStatus.
 /// For any error, the version endpoint returns "internal server error".
+#[utoipa::path(
+get,
+path = "/audio/transcriptions/status",
+responses(
+(status = 200, description = "OK", body = AIStatus)
+),
+)]
 pub async fn audio_transcriptions_status() -> Response {
     let state = get_audio_transcriptions_status().read().await;
     Json(state.clone()).into_response()
 }
 
-/// GET `/v1/embeddings`: returns the current status of the /embeddings endpoint.
+/// GET `/v1/embeddings/status`: returns the current status of the /embeddings endpoint.
 ///
 /// The status is returned as json value AIStatus.
 /// For any error, the version endpoint returns "internal server error".
+#[utoipa::path(
+get,
+path = "/embeddings/status",
+responses(
+(status = 200, description = "OK", body = AIStatus)
+),
+)]
 pub async fn embeddings_status() -> Response {
     let state = get_embeddings_status().read().await;
     Json(state.clone()).into_response()
 }
 
+/// GET `/v1/image/generations/status`: returns the current status of the /image/generations endpoint.
+///
+/// The status is returned as json value AIStatus.
+/// For any error, the version endpoint returns "internal server error".
+#[utoipa::path(
+get,
+path = "/image/generations/status",
+responses(
+(status = 200, description = "OK", body = AIStatus)
+),
+)]
+pub async fn image_generation_status() -> Response {
+    let state = get_image_generation_status().read().await;
+    Json(state.clone()).into_response()
+}
+
+/// GET `/v1/status/models`: returns the models currently loaded into memory by every endpoint.
+///
+/// The models are returned as json value ModelsStatus.
+#[utoipa::path(
+get,
+path = "/status/models",
+responses(
+(status = 200, description = "OK", body = ModelsStatus)
+),
+)]
+pub async fn models_status() -> Response {
+    let models = BACKENDS
+        .resident_models()
+        .await
+        .into_iter()
+        .map(move |(kind, model)| ResidentModelStatus { kind, model })
+        .collect();
+    Json(ModelsStatus { models }).into_response()
+}
+
+/// The models currently loaded into memory.
+#[derive(ToSchema, Serialize, Clone, Debug)]
+pub struct ModelsStatus {
+    /// the loaded models, with the kind of the runtime that loaded them
+    pub models: Vec<ResidentModelStatus>,
+}
+
+/// A model currently loaded into memory.
+#[derive(ToSchema, Serialize, Clone, Debug)]
+pub struct ResidentModelStatus {
+    /// the kind of the runtime that loaded the model
+    pub kind: ModelKind,
+    /// where the model is loaded and how much it is used
+    #[serde(flatten)]
+    pub model: ResidentModel,
+}
+
+/// GET `/v1/status/stream`: streams the changes of the service status as server-sent events.
+///
+/// Every event is a json value StatusEvent. Models being loaded or unloaded are detected by
+/// checking the resident models every [`RESIDENCY_POLL_INTERVAL`] (see [`spawn_residency_poll`]).
+#[utoipa::path(
+get,
+path = "/status/stream",
+responses(
+(status = 200, description = "a stream of server-sent events", body = StatusEvent, content_type = "text/event-stream")
+),
+)]
+pub async fn status_stream() -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = futures::stream::unfold(subscribe(), move |mut events| async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => return Some((event, events)),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("status stream lagging behind, {missed} events dropped")
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .map(move |event| Event::default().json_data(event));
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// A change of the service status, as streamed by [`status_stream`].
+#[derive(ToSchema, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StatusEvent {
+    /// The active model of an endpoint changed.
+    ActiveModel { endpoint: String, model: String },
+    /// A model download of an endpoint started or finished.
+    Download { endpoint: String, ongoing: bool },
+    /// The progress of the model download of an endpoint changed.
+    DownloadProgress { endpoint: String, progress: u64 },
+    /// The progress of the download of a single file of an endpoint changed.
+    FileDownloadProgress {
+        endpoint: String,
+        repo: String,
+        file: String,
+        progress: u64,
+    },
+    /// An error occurred in an endpoint.
+    Error { endpoint: String, error: String },
+    /// An updated revision of the configured model of an endpoint became available, or was
+    /// downloaded.
+    UpdateAvailable { endpoint: String, available: bool },
+    /// A model was loaded into memory.
+    ModelLoaded { kind: ModelKind, path: String },
+    /// A model was unloaded from memory.
+    ModelUnloaded { kind: ModelKind, path: String },
+    /// A request was answered with an error status.
+    RequestFailed {
+        request_id: String,
+        method: String,
+        endpoint: String,
+        status: u16,
+    },
+}
+
+/// How often [`spawn_residency_poll`] checks which models are loaded into memory.
+const RESIDENCY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many status events are buffered for slow subscribers before they start missing events.
+const EVENTS_CAPACITY: usize = 256;
+
+static EVENTS: Lazy<broadcast::Sender<StatusEvent>> =
+    Lazy::new(move || broadcast::channel(EVENTS_CAPACITY).0);
+
+/// Sends a status event to every [`status_stream`] subscriber.
+fn publish(event: StatusEvent) {
+    // Failing means there are no subscribers, in which case nobody cares about the event
+    let _ = EVENTS.send(event);
+}
+
+/// Reports a request answered with an error `status`, its `endpoint` being its path, e.g.
+/// `/v1/chat/completions`.
+pub(crate) fn add_request_failure(request_id: &str, method: &str, endpoint: &str, status: u16) {
+    publish(StatusEvent::RequestFailed {
+        request_id: request_id.to_string(),
+        method: method.to_string(),
+        endpoint: endpoint.to_string(),
+        status,
+    });
+}
+
+/// Subscribes to the status events.
+pub(crate) fn subscribe() -> broadcast::Receiver<StatusEvent> {
+    EVENTS.subscribe()
+}
+
+/// Spawns the task publishing the loading and unloading of models, which are found by checking
+/// the resident models every [`RESIDENCY_POLL_INTERVAL`].
+///
+/// A single task polls on behalf of every subscriber of the status events.
+pub fn spawn_residency_poll() {
+    tokio::spawn(async move {
+        let mut poll = interval(RESIDENCY_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut resident = resident_paths().await;
+
+        loop {
+            poll.tick().await;
+            let current = resident_paths().await;
+            for event in residency_changes(&resident, &current) {
+                publish(event);
+            }
+            resident = current;
+        }
+    });
+}
+
+/// Returns the kind and the path of every model currently loaded into memory.
+async fn resident_paths() -> Vec<(ModelKind, String)> {
+    BACKENDS
+        .resident_models()
+        .await
+        .into_iter()
+        .map(move |(kind, model)| (kind, model.path))
+        .collect()
+}
+
+/// Returns the events of the models loaded and unloaded between two sets of resident models.
+fn residency_changes(
+    before: &[(ModelKind, String)],
+    after: &[(ModelKind, String)],
+) -> Vec<StatusEvent> {
+    let loaded = after
+        .iter()
+        .filter(move |model| !before.contains(model))
+        .map(move |(kind, path)| StatusEvent::ModelLoaded {
+            kind: kind.clone(),
+            path: path.clone(),
+        });
+    let unloaded = before
+        .iter()
+        .filter(move |model| !after.contains(model))
+        .map(move |(kind, path)| StatusEvent::ModelUnloaded {
+            kind: kind.clone(),
+            path: path.clone(),
+        });
+    loaded.chain(unloaded).collect()
+}
+
 /// Current Endpoint status.
-#[derive(ToSchema, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[derive(ToSchema, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub struct AIStatus {
     /// currently active model for this endpoint
     pub active_model: String,
@@ -62,6 +296,28 @@ pub struct AIStatus {
     pub download_progress: u64,
     /// last errors that occurred for this endpoint
     pub last_errors: VecDeque<String>,
+    /// files currently being downloaded for this endpoint
+    #[serde(default)]
+    pub downloads: Vec<DownloadStatus>,
+    /// the upstream repository of the configured model has an updated revision, which
+    /// `edgen models upgrade` downloads; only checked if `check_model_updates` is enabled
+    #[serde(default)]
+    pub update_available: bool,
+    /// the live generation throughput of the endpoint, sampled when the status is requested;
+    /// only reported for chat completions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throughput: Option<Throughput>,
+}
+
+/// The download of a single model file.
+#[derive(ToSchema, Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct DownloadStatus {
+    /// the repository the file is downloaded from
+    pub repo: String,
+    /// the name of the file in the repository
+    pub file: String,
+    /// download progress (percentage)
+    pub progress: u64,
 }
 
 impl Default for AIStatus {
@@ -71,6 +327,9 @@ impl Default for AIStatus {
             download_ongoing: false,
             download_progress: 0,
             last_errors: VecDeque::from([]),
+            downloads: vec![],
+            update_available: false,
+            throughput: None,
         }
     }
 }
@@ -82,9 +341,18 @@ static AISTATES: Lazy<AIStates> = Lazy::new(Default::default);
 const EP_CHAT_COMPLETIONS: usize = 0;
 const EP_AUDIO_TRANSCRIPTIONS: usize = 1;
 const EP_EMBEDDINGS: usize = 2;
+const EP_IMAGE_GENERATION: usize = 3;
 
 const MAX_ERRORS: usize = 32;
 
+/// The names of the endpoints, as reported in [`StatusEvent`]s.
+const EP_NAMES: [&str; 4] = [
+    "chat/completions",
+    "audio/transcriptions",
+    "embeddings",
+    "image/generations",
+];
+
 /// Get a protected chat completions status.
 /// Call read() or write() on the returned value to get either read or write access.
 pub fn get_chat_completions_status() -> &'static RwLock<AIStatus> {
@@ -103,6 +371,12 @@ pub fn get_embeddings_status() -> &'static RwLock<AIStatus> {
     get_status(EP_EMBEDDINGS)
 }
 
+/// Get a protected image generation status.
+/// Call read() or write() on the returned value to get either read or write access.
+pub fn get_image_generation_status() -> &'static RwLock<AIStatus> {
+    get_status(EP_IMAGE_GENERATION)
+}
+
 fn get_status(idx: usize) -> &'static RwLock<AIStatus> {
     &AISTATES.endpoints[idx]
 }
@@ -122,6 +396,11 @@ pub async fn reset_embeddings_status() {
     reset_status(EP_EMBEDDINGS).await;
 }
 
+/// Reset the image generation status to its defaults
+pub async fn reset_image_generation_status() {
+    reset_status(EP_IMAGE_GENERATION).await;
+}
+
 async fn reset_status(idx: usize) {
     let mut status = get_status(idx).write().await;
     *status = AIStatus::default();
@@ -142,8 +421,19 @@ pub async fn set_embeddings_active_model(model: &str) {
     set_active_model(EP_EMBEDDINGS, model).await;
 }
 
+/// Set image generation active model
+pub async fn set_image_generation_active_model(model: &str) {
+    set_active_model(EP_IMAGE_GENERATION, model).await;
+}
+
 async fn set_active_model(idx: usize, model: &str) {
     let mut state = get_status(idx).write().await;
+    if state.active_model != model {
+        publish(StatusEvent::ActiveModel {
+            endpoint: EP_NAMES[idx].to_string(),
+            model: model.to_string(),
+        });
+    }
     state.active_model = model.to_string();
 }
 
@@ -177,8 +467,28 @@ pub async fn set_embeddings_download(ongoing: bool) {
     set_download(EP_EMBEDDINGS, ongoing).await;
 }
 
+/// Set image generation download ongoing
+pub async fn set_image_generation_download(ongoing: bool) {
+    if ongoing {
+        info!("starting image generation model download");
+    } else {
+        info!("image generation model download finished");
+    };
+    set_download(EP_IMAGE_GENERATION, ongoing).await;
+}
+
 async fn set_download(idx: usize, ongoing: bool) {
     let mut state = get_status(idx).write().await;
+    update_download(idx, &mut state, ongoing);
+}
+
+fn update_download(idx: usize, state: &mut AIStatus, ongoing: bool) {
+    if state.download_ongoing != ongoing {
+        publish(StatusEvent::Download {
+            endpoint: EP_NAMES[idx].to_string(),
+            ongoing,
+        });
+    }
     state.download_ongoing = ongoing;
 }
 
@@ -197,36 +507,138 @@ pub async fn set_embeddings_progress(progress: u64) {
     set_progress(EP_EMBEDDINGS, progress).await;
 }
 
+/// Set image generation download progress
+pub async fn set_image_generation_progress(progress: u64) {
+    set_progress(EP_IMAGE_GENERATION, progress).await;
+}
+
 async fn set_progress(idx: usize, progress: u64) {
     let mut state = get_status(idx).write().await;
+    update_progress(idx, &mut state, progress);
+}
+
+fn update_progress(idx: usize, state: &mut AIStatus, progress: u64) {
+    if state.download_progress != progress {
+        publish(StatusEvent::DownloadProgress {
+            endpoint: EP_NAMES[idx].to_string(),
+            progress,
+        });
+    }
     state.download_progress = progress;
 }
 
-/// Observe chat completions download progress
-pub async fn observe_chat_completions_progress(
-    datadir: &PathBuf,
-    size: Option<u64>,
-    download: bool,
-) -> tokio::task::JoinHandle<()> {
-    observe_progress(EP_CHAT_COMPLETIONS, datadir, size, download).await
+/// Set whether an updated revision of the configured model of an endpoint is available.
+pub async fn set_update_available(ep: Endpoint, available: bool) {
+    let idx = ep_index(ep);
+    let mut state = get_status(idx).write().await;
+    if state.update_available != available {
+        publish(StatusEvent::UpdateAvailable {
+            endpoint: EP_NAMES[idx].to_string(),
+            available,
+        });
+    }
+    state.update_available = available;
 }
 
-/// Observe audio transcptions download progress
-pub async fn observe_audio_transcriptions_progress(
-    datadir: &PathBuf,
-    size: Option<u64>,
-    download: bool,
-) -> tokio::task::JoinHandle<()> {
-    observe_progress(EP_AUDIO_TRANSCRIPTIONS, datadir, size, download).await
+/// Register the start of the download of a file for an endpoint.
+///
+/// Several files may be downloaded at the same time for the same endpoint, each one tracked
+/// independently in [`AIStatus::downloads`]. `download_ongoing` and `download_progress` summarise
+/// all of them: the endpoint is downloading while any file is, and its progress is the progress of
+/// the least advanced file.
+pub async fn start_download(ep: Endpoint, repo: &str, file: &str) {
+    let idx = ep_index(ep);
+    info!("starting download of {repo}/{file}");
+    persist_download(repo, file, true).await;
+    let mut state = get_status(idx).write().await;
+    if !state
+        .downloads
+        .iter()
+        .any(move |download| download.repo == repo && download.file == file)
+    {
+        state.downloads.push(DownloadStatus {
+            repo: repo.to_string(),
+            file: file.to_string(),
+            progress: 0,
+        });
+    }
+    summarise_downloads(idx, &mut state);
 }
 
-/// Observe embeddings download progress
-pub async fn observe_embeddings_progress(
-    datadir: &PathBuf,
-    size: Option<u64>,
-    download: bool,
-) -> tokio::task::JoinHandle<()> {
-    observe_progress(EP_EMBEDDINGS, datadir, size, download).await
+/// Set the download progress of a file of an endpoint.
+pub async fn set_download_progress(ep: Endpoint, repo: &str, file: &str, progress: u64) {
+    let idx = ep_index(ep);
+    let mut state = get_status(idx).write().await;
+    let Some(download) = state
+        .downloads
+        .iter_mut()
+        .find(move |download| download.repo == repo && download.file == file)
+    else {
+        return;
+    };
+    if download.progress == progress {
+        return;
+    }
+    download.progress = progress;
+    publish(StatusEvent::FileDownloadProgress {
+        endpoint: EP_NAMES[idx].to_string(),
+        repo: repo.to_string(),
+        file: file.to_string(),
+        progress,
+    });
+    summarise_downloads(idx, &mut state);
+}
+
+/// Register the end of the download of a file for an endpoint.
+pub async fn finish_download(ep: Endpoint, repo: &str, file: &str) {
+    let idx = ep_index(ep);
+    info!("download of {repo}/{file} finished");
+    persist_download(repo, file, false).await;
+    let mut state = get_status(idx).write().await;
+    state
+        .downloads
+        .retain(move |download| download.repo != repo || download.file != file);
+    if state.downloads.is_empty() {
+        update_progress(idx, &mut state, 100);
+        update_download(idx, &mut state, false);
+    } else {
+        summarise_downloads(idx, &mut state);
+    }
+}
+
+/// Records the state of a download in the [`store`], so that downloads interrupted by a restart can
+/// be told apart.
+async fn persist_download(repo: &str, file: &str, ongoing: bool) {
+    let res = match store::get() {
+        Ok(store) if ongoing => store.start_download(repo, file).await,
+        Ok(store) => store.finish_download(repo, file).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        warn!("failed to persist the state of download {repo}/{file}: {e}");
+    }
+}
+
+fn summarise_downloads(idx: usize, state: &mut AIStatus) {
+    let Some(progress) = state
+        .downloads
+        .iter()
+        .map(move |download| download.progress)
+        .min()
+    else {
+        return;
+    };
+    update_download(idx, state, true);
+    update_progress(idx, state, progress);
+}
+
+fn ep_index(ep: Endpoint) -> usize {
+    match ep {
+        Endpoint::ChatCompletions => EP_CHAT_COMPLETIONS,
+        Endpoint::AudioTranscriptions => EP_AUDIO_TRANSCRIPTIONS,
+        Endpoint::Embeddings => EP_EMBEDDINGS,
+        Endpoint::ImageGeneration => EP_IMAGE_GENERATION,
+    }
 }
 
 /// Add an error to the last errors in chat completions
@@ -245,6 +657,22 @@ where
     add_error(EP_AUDIO_TRANSCRIPTIONS, e).await;
 }
 
+/// Add an error to the last errors in embeddings
+pub async fn add_embeddings_error<E>(e: E)
+where
+    E: Error,
+{
+    add_error(EP_EMBEDDINGS, e).await;
+}
+
+/// Add an error to the last errors in image generation
+pub async fn add_image_generation_error<E>(e: E)
+where
+    E: Error,
+{
+    add_error(EP_IMAGE_GENERATION, e).await;
+}
+
 async fn add_error<E>(idx: usize, e: E)
 where
     E: Error,
@@ -254,7 +682,12 @@ where
     if state.last_errors.len() > MAX_ERRORS {
         state.last_errors.pop_front();
     }
-    state.last_errors.push_back(format!("{:?}", e));
+    let error = format!("{:?}", e);
+    publish(StatusEvent::Error {
+        endpoint: EP_NAMES[idx].to_string(),
+        error: error.clone(),
+    });
+    state.last_errors.push_back(error);
 }
 
 struct AIStates {
@@ -268,27 +701,42 @@ impl Default for AIStates {
                 RwLock::new(Default::default()),
                 RwLock::new(Default::default()),
                 RwLock::new(Default::default()),
+                RwLock::new(Default::default()),
             ],
         }
     }
 }
 
-// helper function to observe download progress.
+// The temporary files already being observed by a download observer.
+static OBSERVED_TEMPFILES: Lazy<std::sync::Mutex<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// Observe the download progress of a file of a repository for an endpoint.
+///
+/// `datadir` is the cache directory the file is downloaded to. The observer must be started
+/// before the download itself, so that the temporary files of downloads that are already ongoing
+/// are told apart from the one of this download.
+//
 // It spawns a new tokio task which
 // - waits for the tmp directory to appear in dir
-// - waits for the tempfile to appear in that directory
+// - waits for a new tempfile, not observed by another observer, to appear in that directory
 // - repeatedly reads the size of this tempfile
 // -   calculates the percentage relative to size
-// -   sets the percentage in the status.download_progress
-// - until the tempfile disappears or no progress was made for 1 minute.
+// -   sets the percentage in the status.downloads
+// - until the tempfile disappears or no progress was made for 3 minutes.
 // TODO: This code should go to the module manager.
-async fn observe_progress(
-    idx: usize,
+pub async fn observe_download(
+    ep: Endpoint,
+    repo: &str,
+    file: &str,
     datadir: &PathBuf,
     size: Option<u64>,
     download: bool,
 ) -> tokio::task::JoinHandle<()> {
+    let idx = ep_index(ep);
     let tmp = datadir.join("tmp");
+    let repo = repo.to_string();
+    let file = file.to_string();
+    let existing = tempfiles(&tmp);
 
     let progress_handle = tokio::spawn(async move {
         if !download {
@@ -306,14 +754,15 @@ async fn observe_progress(
             return;
         }
 
-        let t = wait_for_tempfile(idx, &tmp).await;
+        let t = wait_for_tempfile(idx, &tmp, &existing).await;
         if t.is_none() {
             return;
         }
 
         let f = t.unwrap();
+        let _observed = ObservedTempfile(f.clone());
 
-        let mut m = tokio::fs::metadata(&f.path()).await;
+        let mut m = tokio::fs::metadata(&f).await;
         let mut last_size = 0;
         let mut timestamp = Instant::now();
         while let Ok(d) = m {
@@ -328,9 +777,9 @@ async fn observe_progress(
                 return;
             };
 
-            set_progress(idx, p).await;
+            set_download_progress(ep, &repo, &file, p).await;
             tokio::time::sleep(std::time::Duration::from_millis(500)).await;
-            m = tokio::fs::metadata(&f.path()).await;
+            m = tokio::fs::metadata(&f).await;
         }
     });
 
@@ -351,9 +800,36 @@ async fn have_tempdir(idx: usize, tmp: &PathBuf) -> bool {
     return tmp.exists();
 }
 
-// TODO: we use the first file we find in the tmp directory.
+// Returns the files currently in the tmp directory.
+fn tempfiles(tmp: &PathBuf) -> HashSet<PathBuf> {
+    std::fs::read_dir(tmp)
+        .map(move |entries| {
+            entries
+                .filter_map(move |entry| entry.ok())
+                .map(move |entry| entry.path())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Releases an observed tempfile when dropped, whichever way the observer finishes.
+struct ObservedTempfile(PathBuf);
+
+impl Drop for ObservedTempfile {
+    fn drop(&mut self) {
+        if let Ok(mut observed) = OBSERVED_TEMPFILES.lock() {
+            observed.remove(&self.0);
+        }
+    }
+}
+
+// TODO: we use the first new file we find in the tmp directory.
 //       we should instead *know* the name of the file.
-async fn wait_for_tempfile(idx: usize, tmp: &PathBuf) -> Option<std::fs::DirEntry> {
+async fn wait_for_tempfile(
+    idx: usize,
+    tmp: &PathBuf,
+    existing: &HashSet<PathBuf>,
+) -> Option<PathBuf> {
     for _ in 0..30 {
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
         let es = std::fs::read_dir(&tmp);
@@ -366,8 +842,16 @@ async fn wait_for_tempfile(idx: usize, tmp: &PathBuf) -> Option<std::fs::DirEntr
             return None;
         };
         for e in es.unwrap() {
-            if e.is_ok() {
-                return Some(e.unwrap());
+            if let Ok(e) = e {
+                let path = e.path();
+                if existing.contains(&path) {
+                    continue;
+                }
+                if let Ok(mut observed) = OBSERVED_TEMPFILES.lock() {
+                    if observed.insert(path.clone()) {
+                        return Some(path);
+                    }
+                }
             }
         }
     }
@@ -388,7 +872,8 @@ mod tests {
         "{\"active_model\":\"unknown\",\
           \"download_ongoing\":false,\
           \"download_progress\":0,\
-          \"last_errors\":[]\
+          \"last_errors\":[],\
+          \"downloads\":[]\
          }"
         .to_string()
     }
@@ -409,6 +894,45 @@ mod tests {
         assert_eq!(state, expected);
     }
 
+    #[test]
+    fn test_residency_changes() {
+        let before = vec![
+            (ModelKind::LLM, "chat.gguf".to_string()),
+            (ModelKind::Whisper, "whisper.bin".to_string()),
+        ];
+        let after = vec![
+            (ModelKind::LLM, "chat.gguf".to_string()),
+            (ModelKind::ChatFaker, "fake.fake".to_string()),
+        ];
+
+        assert_eq!(
+            residency_changes(&before, &after),
+            vec![
+                StatusEvent::ModelLoaded {
+                    kind: ModelKind::ChatFaker,
+                    path: "fake.fake".to_string(),
+                },
+                StatusEvent::ModelUnloaded {
+                    kind: ModelKind::Whisper,
+                    path: "whisper.bin".to_string(),
+                },
+            ]
+        );
+        assert!(residency_changes(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn test_serialize_event() {
+        let event = StatusEvent::DownloadProgress {
+            endpoint: EP_NAMES[EP_EMBEDDINGS].to_string(),
+            progress: 42,
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            "{\"type\":\"download_progress\",\"endpoint\":\"embeddings\",\"progress\":42}"
+        );
+    }
+
     // This test should not be split into sub-tests.
     // The problem is that tests run in parallel
     // and we are testing one common resource, the global status.
@@ -705,4 +1229,103 @@ mod tests {
         assert!(response.text().len() > 0);
         assert_eq!(response.json::<AIStatus>().active_model, model);
     }
+
+    #[tokio::test]
+    async fn test_image_generation_status() {
+        reset_image_generation_status().await;
+
+        // default
+        let mut expected = AIStatus::default();
+
+        {
+            let status = get_image_generation_status().read().await;
+            assert_eq!(*status, AIStatus::default());
+        }
+
+        // download ongoing
+        expected.download_ongoing = true;
+        set_image_generation_download(true).await;
+
+        {
+            let status = get_image_generation_status().read().await;
+            assert_eq!(*status, expected);
+        }
+
+        // download progress
+        expected.download_progress = 42;
+        set_image_generation_progress(42).await;
+
+        {
+            let status = get_image_generation_status().read().await;
+            assert_eq!(*status, expected);
+        }
+
+        // axum router
+        let router =
+            Router::new().route("/v1/image/generations/status", get(image_generation_status));
+
+        let server = TestServer::new(router).expect("cannot instantiate TestServer");
+
+        let response = server.get("/v1/image/generations/status").await;
+
+        response.assert_status_ok();
+        assert!(response.text().len() > 0);
+        assert_eq!(response.json::<AIStatus>().active_model, "unknown");
+
+        let model = "shes-a-model-and-shes-looking-good".to_string();
+        set_image_generation_active_model(&model).await;
+
+        let response = server.get("/v1/image/generations/status").await;
+
+        response.assert_status_ok();
+        assert!(response.text().len() > 0);
+        assert_eq!(response.json::<AIStatus>().active_model, model);
+
+        // concurrent downloads
+        reset_image_generation_status().await;
+        let ep = Endpoint::ImageGeneration;
+        start_download(ep, "repo", "unet.safetensors").await;
+        start_download(ep, "repo", "vae.safetensors").await;
+        set_download_progress(ep, "repo", "unet.safetensors", 80).await;
+        set_download_progress(ep, "repo", "vae.safetensors", 30).await;
+
+        {
+            let status = get_image_generation_status().read().await;
+            assert!(status.download_ongoing);
+            assert_eq!(status.download_progress, 30);
+            assert_eq!(
+                status.downloads,
+                vec![
+                    DownloadStatus {
+                        repo: "repo".to_string(),
+                        file: "unet.safetensors".to_string(),
+                        progress: 80,
+                    },
+                    DownloadStatus {
+                        repo: "repo".to_string(),
+                        file: "vae.safetensors".to_string(),
+                        progress: 30,
+                    },
+                ]
+            );
+        }
+
+        finish_download(ep, "repo", "vae.safetensors").await;
+
+        {
+            let status = get_image_generation_status().read().await;
+            assert!(status.download_ongoing);
+            assert_eq!(status.download_progress, 80);
+            assert_eq!(status.downloads.len(), 1);
+        }
+
+        finish_download(ep, "repo", "unet.safetensors").await;
+
+        {
+            let status = get_image_generation_status().read().await;
+            assert!(!status.download_ongoing);
+            assert_eq!(status.download_progress, 100);
+            assert!(status.downloads.is_empty());
+        }
+    }
 }
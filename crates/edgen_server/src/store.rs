@@ -0,0 +1,1471 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! A persistent store of the operational state of the server, kept in an SQLite database in the
+//! data directory, so that it survives restarts.
+//!
+//! The store holds usage records, a cache of the model registry, the state of ongoing downloads
+//! and session metadata. Its schema is versioned with SQLite's `user_version`, and migrated when
+//! the database is opened.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::OnceCell;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use edgen_core::settings::PROJECT_DIRS;
+
+use crate::graceful_shutdown;
+
+/// The schema migrations, in order. The schema version of a database is the number of migrations
+/// applied to it.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE usage (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        timestamp INTEGER NOT NULL,
+        endpoint TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        completion_tokens INTEGER NOT NULL
+    );
+    CREATE INDEX usage_timestamp ON usage (timestamp);
+
+    CREATE TABLE models (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL,
+        updated INTEGER NOT NULL
+    );
+
+    CREATE TABLE downloads (
+        repo TEXT NOT NULL,
+        file TEXT NOT NULL,
+        started INTEGER NOT NULL,
+        PRIMARY KEY (repo, file)
+    );
+
+    CREATE TABLE sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        model TEXT NOT NULL,
+        created INTEGER NOT NULL,
+        updated INTEGER NOT NULL,
+        metadata TEXT NOT NULL
+    );
+",
+    "
+    CREATE TABLE conversation_messages (
+        conversation TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        message TEXT NOT NULL,
+        PRIMARY KEY (conversation, position)
+    );
+",
+    "
+    CREATE TABLE threads (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+
+    CREATE TABLE thread_items (
+        id TEXT PRIMARY KEY NOT NULL,
+        thread TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX thread_items_thread ON thread_items (thread, kind);
+",
+    "
+    CREATE TABLE files (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+",
+    "
+    CREATE TABLE batches (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+",
+    "
+    CREATE TABLE vector_stores (
+        id TEXT PRIMARY KEY NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE vector_store_files (
+        vector_store TEXT NOT NULL,
+        file TEXT NOT NULL,
+        data TEXT NOT NULL,
+        PRIMARY KEY (vector_store, file)
+    );
+    CREATE TABLE vector_chunks (
+        vector_store TEXT NOT NULL,
+        file TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        embedding BLOB NOT NULL,
+        PRIMARY KEY (vector_store, file, position)
+    );
+",
+    "
+    CREATE TABLE idempotent_responses (
+        key TEXT PRIMARY KEY NOT NULL,
+        fingerprint TEXT NOT NULL,
+        status INTEGER NOT NULL,
+        content_type TEXT,
+        body BLOB NOT NULL,
+        created INTEGER NOT NULL
+    );
+    CREATE INDEX idempotent_responses_created ON idempotent_responses (created);
+",
+    "
+    ALTER TABLE usage ADD COLUMN user TEXT;
+",
+    "
+    CREATE TABLE conversation_owners (
+        conversation TEXT PRIMARY KEY NOT NULL,
+        owner TEXT NOT NULL
+    );
+",
+];
+
+/// The store of the server, opened on first use.
+static STORE: OnceCell<Store> = OnceCell::new();
+
+/// An error accessing the [`Store`].
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error("database error: {0}")]
+    Database(#[from] rusqlite::Error),
+    #[error("failed to create the store directory: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("failed to serialize a stored value: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("the store task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+/// A token usage record of a single request.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// The UNIX timestamp, in seconds, of the request.
+    pub timestamp: u64,
+
+    /// The endpoint the request was sent to, e.g. `chat/completions`.
+    pub endpoint: String,
+
+    /// The model used by the request.
+    pub model: String,
+
+    /// The number of tokens of the prompt.
+    pub prompt_tokens: u64,
+
+    /// The number of generated tokens.
+    pub completion_tokens: u64,
+
+    /// The end user the request was made for, from the `user` parameter of its body, if any.
+    pub user: Option<String>,
+}
+
+/// The metadata of a session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    /// The ID of the session.
+    pub id: String,
+
+    /// The model the session belongs to.
+    pub model: String,
+
+    /// The UNIX timestamp, in seconds, of when the session was created.
+    pub created: u64,
+
+    /// The UNIX timestamp, in seconds, of when the session was last used.
+    pub updated: u64,
+
+    /// Arbitrary JSON metadata of the session.
+    pub metadata: serde_json::Value,
+}
+
+/// A chunk of a file of a vector store, and its similarity with a query.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredChunk {
+    /// The ID of the file the chunk was taken from.
+    pub file: String,
+
+    /// The position of the chunk in the file, starting from 0.
+    pub position: u64,
+
+    /// The text of the chunk.
+    pub content: String,
+
+    /// The cosine similarity between the embedding of the chunk and the query.
+    pub score: f32,
+}
+
+/// A response stored for an idempotency key, replayed when a request is retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdempotentResponse {
+    /// The hash of the request the response was generated for.
+    pub fingerprint: String,
+
+    /// The HTTP status code of the response.
+    pub status: u16,
+
+    /// The `Content-Type` of the response, if any.
+    pub content_type: Option<String>,
+
+    /// The body of the response.
+    pub body: Vec<u8>,
+
+    /// The UNIX timestamp, in seconds, of when the response was stored.
+    pub created: u64,
+}
+
+/// The kinds of objects that belong to a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadItem {
+    Message,
+    Run,
+}
+
+impl ThreadItem {
+    fn as_str(self) -> &'static str {
+        match self {
+            ThreadItem::Message => "message",
+            ThreadItem::Run => "run",
+        }
+    }
+}
+
+/// A handle to an SQLite database holding the operational state of the server.
+///
+/// Every operation runs on the blocking thread pool, so that the database can be used from async
+/// code.
+#[derive(Clone)]
+pub struct Store {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// Returns the store of the server, opening it in the data directory if needed.
+pub fn get() -> Result<&'static Store, StoreError> {
+    STORE.get_or_try_init(move || Store::open(&default_path()))
+}
+
+/// Opens the store of the server, reporting and forgetting the downloads interrupted by the last
+/// exit of the server.
+pub async fn init() -> Result<(), StoreError> {
+    let store = get()?;
+    for (repo, file) in store.downloads().await? {
+        warn!("The download of {repo}/{file} was interrupted, it will be restarted when needed");
+        store.finish_download(&repo, &file).await?;
+    }
+
+    graceful_shutdown::register_hook("the store", move || async move {
+        if let Err(e) = store.checkpoint().await {
+            warn!("Failed to checkpoint the store: {e}");
+        }
+    });
+    Ok(())
+}
+
+/// Records the token usage of a request, logging failures instead of failing the request.
+pub async fn log_usage(record: UsageRecord) {
+    let res = match get() {
+        Ok(store) => store.record_usage(record).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        warn!("Failed to record the usage of a request: {e}");
+    }
+}
+
+/// Records that the session `id` of `model` was used, creating its metadata if it is new, logging
+/// failures instead of failing the request.
+pub async fn log_session(id: &str, model: &str) {
+    let res = async move {
+        let store = get()?;
+        let now = now();
+        let session = match store.session(id).await? {
+            Some(session) => SessionRecord {
+                updated: now,
+                ..session
+            },
+            None => SessionRecord {
+                id: id.to_string(),
+                model: model.to_string(),
+                created: now,
+                updated: now,
+                metadata: serde_json::json!({}),
+            },
+        };
+        store.put_session(session).await
+    }
+    .await;
+    if let Err(e) = res {
+        warn!("Failed to record session {id}: {e}");
+    }
+}
+
+/// Returns the path of the database of the server.
+fn default_path() -> PathBuf {
+    PROJECT_DIRS.data_dir().join("edgen.db")
+}
+
+/// Encodes an embedding as little-endian bytes.
+fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(move |x| x.to_le_bytes()).collect()
+}
+
+fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(move |x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect()
+}
+
+/// Returns the cosine similarity of two vectors, or 0 if either of them is null.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(move |(a, b)| a * b).sum();
+    let norm = |v: &[f32]| v.iter().map(move |x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// Returns the current UNIX timestamp, in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(move |d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl Store {
+    /// Opens, or creates, the database at `path`, migrating it to the latest schema.
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+        // Concurrent readers should not block the server while it writes
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        Self::from_connection(conn)
+    }
+
+    /// Writes the changes kept in the write-ahead log back into the database, so that it is
+    /// complete by itself.
+    pub async fn checkpoint(&self) -> Result<(), StoreError> {
+        self.with(move |conn| {
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], move |_| Ok(()))?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Opens a store that only lives in memory, for tests.
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<Self, StoreError> {
+        migrate(&mut conn)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` with the connection to the database on the blocking thread pool.
+    async fn with<T, F>(&self, f: F) -> Result<T, StoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, StoreError> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            // A panic while holding the lock cannot leave the database itself inconsistent
+            let conn = conn.lock().unwrap_or_else(move |e| e.into_inner());
+            f(&conn)
+        })
+        .await?
+    }
+
+    /// Records the token usage of a request.
+    pub async fn record_usage(&self, record: UsageRecord) -> Result<(), StoreError> {
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO usage
+                 (timestamp, endpoint, model, prompt_tokens, completion_tokens, user)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    record.timestamp,
+                    record.endpoint,
+                    record.model,
+                    record.prompt_tokens,
+                    record.completion_tokens,
+                    record.user
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the usage records of the requests made at or after the `since` UNIX timestamp,
+    /// oldest first.
+    pub async fn usage(&self, since: u64) -> Result<Vec<UsageRecord>, StoreError> {
+        self.with(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT timestamp, endpoint, model, prompt_tokens, completion_tokens, user
+                 FROM usage WHERE timestamp >= ?1 ORDER BY timestamp, id",
+            )?;
+            let records = statement
+                .query_map(params![since], move |row| {
+                    Ok(UsageRecord {
+                        timestamp: row.get(0)?,
+                        endpoint: row.get(1)?,
+                        model: row.get(2)?,
+                        prompt_tokens: row.get(3)?,
+                        completion_tokens: row.get(4)?,
+                        user: row.get(5)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            Ok(records)
+        })
+        .await
+    }
+
+    /// Caches the registry entry of a model, replacing any previous one.
+    pub async fn put_model(&self, id: &str, data: &serde_json::Value) -> Result<(), StoreError> {
+        let id = id.to_string();
+        let data = serde_json::to_string(data)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO models (id, data, updated) VALUES (?1, ?2, ?3)",
+                params![id, data, now()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the cached registry entry of a model, if any.
+    pub async fn model(&self, id: &str) -> Result<Option<serde_json::Value>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let data: Option<String> = conn
+                .query_row(
+                    "SELECT data FROM models WHERE id = ?1",
+                    params![id],
+                    move |row| row.get(0),
+                )
+                .optional()?;
+            Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+        })
+        .await
+    }
+
+    /// Removes the cached registry entry of a model.
+    pub async fn remove_model(&self, id: &str) -> Result<(), StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            conn.execute("DELETE FROM models WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Records that the download of a file has started.
+    pub async fn start_download(&self, repo: &str, file: &str) -> Result<(), StoreError> {
+        let (repo, file) = (repo.to_string(), file.to_string());
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO downloads (repo, file, started) VALUES (?1, ?2, ?3)",
+                params![repo, file, now()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Records that the download of a file has finished, successfully or not.
+    pub async fn finish_download(&self, repo: &str, file: &str) -> Result<(), StoreError> {
+        let (repo, file) = (repo.to_string(), file.to_string());
+        self.with(move |conn| {
+            conn.execute(
+                "DELETE FROM downloads WHERE repo = ?1 AND file = ?2",
+                params![repo, file],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the `(repo, file)` pairs of the downloads that have started but not finished,
+    /// which, right after starting the server, are the downloads interrupted by its last exit.
+    pub async fn downloads(&self) -> Result<Vec<(String, String)>, StoreError> {
+        self.with(move |conn| {
+            let mut statement =
+                conn.prepare("SELECT repo, file FROM downloads ORDER BY started")?;
+            let downloads = statement
+                .query_map([], move |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?;
+            Ok(downloads)
+        })
+        .await
+    }
+
+    /// Creates or updates the metadata of a session.
+    pub async fn put_session(&self, session: SessionRecord) -> Result<(), StoreError> {
+        let metadata = serde_json::to_string(&session.metadata)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO sessions (id, model, created, updated, metadata)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    session.id,
+                    session.model,
+                    session.created,
+                    session.updated,
+                    metadata
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the metadata of a session, if any.
+    pub async fn session(&self, id: &str) -> Result<Option<SessionRecord>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let row: Option<(String, String, u64, u64, String)> = conn
+                .query_row(
+                    "SELECT id, model, created, updated, metadata FROM sessions WHERE id = ?1",
+                    params![id],
+                    move |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                        ))
+                    },
+                )
+                .optional()?;
+            let Some((id, model, created, updated, metadata)) = row else {
+                return Ok(None);
+            };
+
+            Ok(Some(SessionRecord {
+                id,
+                model,
+                created,
+                updated,
+                metadata: serde_json::from_str(&metadata)?,
+            }))
+        })
+        .await
+    }
+
+    /// Removes the metadata of a session.
+    pub async fn remove_session(&self, id: &str) -> Result<(), StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            conn.execute("DELETE FROM sessions WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the messages of a conversation, oldest first, or an empty list if there is no such
+    /// conversation.
+    pub async fn conversation(&self, id: &str) -> Result<Vec<serde_json::Value>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT message FROM conversation_messages WHERE conversation = ?1
+                 ORDER BY position",
+            )?;
+            let messages = statement
+                .query_map(params![id], move |row| row.get::<_, String>(0))?
+                .map(move |message| Ok(serde_json::from_str(&message?)?))
+                .collect::<Result<_, StoreError>>()?;
+            Ok(messages)
+        })
+        .await
+    }
+
+    /// Returns the owner of a conversation, if there is such a conversation and it has one.
+    pub async fn conversation_owner(&self, id: &str) -> Result<Option<String>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT owner FROM conversation_owners WHERE conversation = ?1",
+                    params![id],
+                    move |row| row.get(0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    /// Appends messages to a conversation of `owner`, creating it if needed.
+    ///
+    /// Returns **`false`**, without appending anything, if the conversation belongs to another
+    /// owner.
+    pub async fn append_to_conversation(
+        &self,
+        id: &str,
+        owner: &str,
+        messages: Vec<serde_json::Value>,
+    ) -> Result<bool, StoreError> {
+        let (id, owner) = (id.to_string(), owner.to_string());
+        let messages = messages
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.with(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "INSERT INTO conversation_owners (conversation, owner) VALUES (?1, ?2)
+                 ON CONFLICT (conversation) DO NOTHING",
+                params![id, owner],
+            )?;
+            let stored_owner: String = tx.query_row(
+                "SELECT owner FROM conversation_owners WHERE conversation = ?1",
+                params![id],
+                move |row| row.get(0),
+            )?;
+            if stored_owner != owner {
+                return Ok(false);
+            }
+
+            let next: u64 = tx.query_row(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM conversation_messages
+                 WHERE conversation = ?1",
+                params![id],
+                move |row| row.get(0),
+            )?;
+            for (position, message) in (next..).zip(messages) {
+                tx.execute(
+                    "INSERT INTO conversation_messages (conversation, position, message)
+                     VALUES (?1, ?2, ?3)",
+                    params![id, position, message],
+                )?;
+            }
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    /// Creates or replaces a thread.
+    pub async fn put_thread(&self, id: &str, data: &serde_json::Value) -> Result<(), StoreError> {
+        let id = id.to_string();
+        let data = serde_json::to_string(data)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO threads (id, data) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                params![id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns a thread, if any.
+    pub async fn thread(&self, id: &str) -> Result<Option<serde_json::Value>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let data: Option<String> = conn
+                .query_row(
+                    "SELECT data FROM threads WHERE id = ?1",
+                    params![id],
+                    move |row| row.get(0),
+                )
+                .optional()?;
+            Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+        })
+        .await
+    }
+
+    /// Removes a thread and everything that belongs to it, returning **`true`** if it existed.
+    pub async fn remove_thread(&self, id: &str) -> Result<bool, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute("DELETE FROM thread_items WHERE thread = ?1", params![id])?;
+            let removed = tx.execute("DELETE FROM threads WHERE id = ?1", params![id])?;
+            tx.commit()?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    /// Creates or replaces an object of a thread. Replaced objects keep their position in the
+    /// thread.
+    pub async fn put_thread_item(
+        &self,
+        kind: ThreadItem,
+        thread: &str,
+        id: &str,
+        data: &serde_json::Value,
+    ) -> Result<(), StoreError> {
+        let (thread, id) = (thread.to_string(), id.to_string());
+        let data = serde_json::to_string(data)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO thread_items (id, thread, kind, data) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                params![id, thread, kind.as_str(), data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns an object of a thread, if any.
+    pub async fn thread_item(
+        &self,
+        kind: ThreadItem,
+        thread: &str,
+        id: &str,
+    ) -> Result<Option<serde_json::Value>, StoreError> {
+        let (thread, id) = (thread.to_string(), id.to_string());
+        self.with(move |conn| {
+            let data: Option<String> = conn
+                .query_row(
+                    "SELECT data FROM thread_items WHERE id = ?1 AND thread = ?2 AND kind = ?3",
+                    params![id, thread, kind.as_str()],
+                    move |row| row.get(0),
+                )
+                .optional()?;
+            Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+        })
+        .await
+    }
+
+    /// Returns the objects of a kind of a thread, oldest first.
+    pub async fn thread_items(
+        &self,
+        kind: ThreadItem,
+        thread: &str,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let thread = thread.to_string();
+        self.with(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT data FROM thread_items WHERE thread = ?1 AND kind = ?2 ORDER BY rowid",
+            )?;
+            let items = statement
+                .query_map(params![thread, kind.as_str()], move |row| {
+                    row.get::<_, String>(0)
+                })?
+                .map(move |item| Ok(serde_json::from_str(&item?)?))
+                .collect::<Result<_, StoreError>>()?;
+            Ok(items)
+        })
+        .await
+    }
+
+    /// Returns the objects of a kind of every thread, oldest first.
+    pub async fn all_thread_items(
+        &self,
+        kind: ThreadItem,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        self.with(move |conn| {
+            let mut statement =
+                conn.prepare("SELECT data FROM thread_items WHERE kind = ?1 ORDER BY rowid")?;
+            let items = statement
+                .query_map(params![kind.as_str()], move |row| row.get::<_, String>(0))?
+                .map(move |item| Ok(serde_json::from_str(&item?)?))
+                .collect::<Result<_, StoreError>>()?;
+            Ok(items)
+        })
+        .await
+    }
+
+    /// Creates or replaces the metadata of a file.
+    pub async fn put_file(&self, id: &str, data: &serde_json::Value) -> Result<(), StoreError> {
+        let id = id.to_string();
+        let data = serde_json::to_string(data)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO files (id, data) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                params![id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the metadata of a file, if any.
+    pub async fn file(&self, id: &str) -> Result<Option<serde_json::Value>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let data: Option<String> = conn
+                .query_row(
+                    "SELECT data FROM files WHERE id = ?1",
+                    params![id],
+                    move |row| row.get(0),
+                )
+                .optional()?;
+            Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+        })
+        .await
+    }
+
+    /// Returns the metadata of every file, oldest first.
+    pub async fn files(&self) -> Result<Vec<serde_json::Value>, StoreError> {
+        self.with(move |conn| {
+            let mut statement = conn.prepare("SELECT data FROM files ORDER BY rowid")?;
+            let files = statement
+                .query_map([], move |row| row.get::<_, String>(0))?
+                .map(move |file| Ok(serde_json::from_str(&file?)?))
+                .collect::<Result<_, StoreError>>()?;
+            Ok(files)
+        })
+        .await
+    }
+
+    /// Removes the metadata of a file, returning **`true`** if it existed.
+    pub async fn remove_file(&self, id: &str) -> Result<bool, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let removed = conn.execute("DELETE FROM files WHERE id = ?1", params![id])?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    /// Creates or replaces a batch.
+    pub async fn put_batch(&self, id: &str, data: &serde_json::Value) -> Result<(), StoreError> {
+        let id = id.to_string();
+        let data = serde_json::to_string(data)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO batches (id, data) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                params![id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns a batch, if any.
+    pub async fn batch(&self, id: &str) -> Result<Option<serde_json::Value>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let data: Option<String> = conn
+                .query_row(
+                    "SELECT data FROM batches WHERE id = ?1",
+                    params![id],
+                    move |row| row.get(0),
+                )
+                .optional()?;
+            Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+        })
+        .await
+    }
+
+    /// Returns every batch, oldest first.
+    pub async fn batches(&self) -> Result<Vec<serde_json::Value>, StoreError> {
+        self.with(move |conn| {
+            let mut statement = conn.prepare("SELECT data FROM batches ORDER BY rowid")?;
+            let batches = statement
+                .query_map([], move |row| row.get::<_, String>(0))?
+                .map(move |batch| Ok(serde_json::from_str(&batch?)?))
+                .collect::<Result<_, StoreError>>()?;
+            Ok(batches)
+        })
+        .await
+    }
+
+    /// Creates or replaces a vector store.
+    pub async fn put_vector_store(
+        &self,
+        id: &str,
+        data: &serde_json::Value,
+    ) -> Result<(), StoreError> {
+        let id = id.to_string();
+        let data = serde_json::to_string(data)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO vector_stores (id, data) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+                params![id, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns a vector store, if any.
+    pub async fn vector_store(&self, id: &str) -> Result<Option<serde_json::Value>, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let data: Option<String> = conn
+                .query_row(
+                    "SELECT data FROM vector_stores WHERE id = ?1",
+                    params![id],
+                    move |row| row.get(0),
+                )
+                .optional()?;
+            Ok(data.map(|data| serde_json::from_str(&data)).transpose()?)
+        })
+        .await
+    }
+
+    /// Returns every vector store, oldest first.
+    pub async fn vector_stores(&self) -> Result<Vec<serde_json::Value>, StoreError> {
+        self.with(move |conn| {
+            let mut statement = conn.prepare("SELECT data FROM vector_stores ORDER BY rowid")?;
+            let stores = statement
+                .query_map([], move |row| row.get::<_, String>(0))?
+                .map(move |store| Ok(serde_json::from_str(&store?)?))
+                .collect::<Result<_, StoreError>>()?;
+            Ok(stores)
+        })
+        .await
+    }
+
+    /// Removes a vector store along with its files, returning **`true`** if it existed.
+    ///
+    /// Its chunks are removed separately, with [`remove_chunks`](Self::remove_chunks).
+    pub async fn remove_vector_store(&self, id: &str) -> Result<bool, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "DELETE FROM vector_store_files WHERE vector_store = ?1",
+                params![id],
+            )?;
+            let removed = tx.execute("DELETE FROM vector_stores WHERE id = ?1", params![id])?;
+            tx.commit()?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    /// Adds a file to a vector store, or replaces it.
+    pub async fn put_vector_store_file(
+        &self,
+        vector_store: &str,
+        file: &str,
+        data: &serde_json::Value,
+    ) -> Result<(), StoreError> {
+        let (vector_store, file) = (vector_store.to_string(), file.to_string());
+        let data = serde_json::to_string(data)?;
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT INTO vector_store_files (vector_store, file, data) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (vector_store, file) DO UPDATE SET data = excluded.data",
+                params![vector_store, file, data],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Replaces the chunks of a file of a vector store, along with their embeddings.
+    pub async fn put_chunks(
+        &self,
+        vector_store: &str,
+        file: &str,
+        chunks: Vec<(String, Vec<f32>)>,
+    ) -> Result<(), StoreError> {
+        let (vector_store, file) = (vector_store.to_string(), file.to_string());
+        self.with(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            tx.execute(
+                "DELETE FROM vector_chunks WHERE vector_store = ?1 AND file = ?2",
+                params![vector_store, file],
+            )?;
+            for (position, (content, embedding)) in chunks.into_iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO vector_chunks (vector_store, file, position, content, embedding)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        vector_store,
+                        file,
+                        position as u64,
+                        content,
+                        encode_vector(&embedding)
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the files of a vector store, oldest first.
+    pub async fn vector_store_files(
+        &self,
+        vector_store: &str,
+    ) -> Result<Vec<serde_json::Value>, StoreError> {
+        let vector_store = vector_store.to_string();
+        self.with(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT data FROM vector_store_files WHERE vector_store = ?1 ORDER BY rowid",
+            )?;
+            let files = statement
+                .query_map(params![vector_store], move |row| row.get::<_, String>(0))?
+                .map(move |file| Ok(serde_json::from_str(&file?)?))
+                .collect::<Result<_, StoreError>>()?;
+            Ok(files)
+        })
+        .await
+    }
+
+    /// Removes a file from a vector store, returning **`true`** if it was there.
+    pub async fn remove_vector_store_file(
+        &self,
+        vector_store: &str,
+        file: &str,
+    ) -> Result<bool, StoreError> {
+        let (vector_store, file) = (vector_store.to_string(), file.to_string());
+        self.with(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM vector_store_files WHERE vector_store = ?1 AND file = ?2",
+                params![vector_store, file],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    /// Removes the chunks of a file of a vector store, or of every file if `file` is **`None`**.
+    pub async fn remove_chunks(
+        &self,
+        vector_store: &str,
+        file: Option<&str>,
+    ) -> Result<(), StoreError> {
+        let (vector_store, file) = (vector_store.to_string(), file.map(str::to_string));
+        self.with(move |conn| {
+            conn.execute(
+                "DELETE FROM vector_chunks WHERE vector_store = ?1 AND (?2 IS NULL OR file = ?2)",
+                params![vector_store, file],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the `limit` chunks of a vector store most similar to `query`, most similar first.
+    ///
+    /// The search is exact: every chunk of the vector store is compared with the query.
+    pub async fn nearest_chunks(
+        &self,
+        vector_store: &str,
+        query: Vec<f32>,
+        limit: usize,
+    ) -> Result<Vec<ScoredChunk>, StoreError> {
+        let vector_store = vector_store.to_string();
+        self.with(move |conn| {
+            let mut statement = conn.prepare(
+                "SELECT file, position, content, embedding FROM vector_chunks
+                 WHERE vector_store = ?1",
+            )?;
+            let mut chunks = statement
+                .query_map(params![vector_store], move |row| {
+                    let embedding: Vec<u8> = row.get(3)?;
+                    Ok(ScoredChunk {
+                        file: row.get(0)?,
+                        position: row.get(1)?,
+                        content: row.get(2)?,
+                        score: cosine_similarity(&query, &decode_vector(&embedding)),
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+
+            chunks.sort_by(move |a, b| b.score.total_cmp(&a.score));
+            chunks.truncate(limit);
+            Ok(chunks)
+        })
+        .await
+    }
+
+    /// Stores the response generated for an idempotency key, replacing any previous one.
+    pub async fn put_idempotent_response(
+        &self,
+        key: &str,
+        response: IdempotentResponse,
+    ) -> Result<(), StoreError> {
+        let key = key.to_string();
+        self.with(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO idempotent_responses
+                 (key, fingerprint, status, content_type, body, created)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    key,
+                    response.fingerprint,
+                    response.status,
+                    response.content_type,
+                    response.body,
+                    response.created
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the response stored for an idempotency key, if any.
+    pub async fn idempotent_response(
+        &self,
+        key: &str,
+    ) -> Result<Option<IdempotentResponse>, StoreError> {
+        let key = key.to_string();
+        self.with(move |conn| {
+            let response = conn
+                .query_row(
+                    "SELECT fingerprint, status, content_type, body, created
+                     FROM idempotent_responses WHERE key = ?1",
+                    params![key],
+                    move |row| {
+                        Ok(IdempotentResponse {
+                            fingerprint: row.get(0)?,
+                            status: row.get(1)?,
+                            content_type: row.get(2)?,
+                            body: row.get(3)?,
+                            created: row.get(4)?,
+                        })
+                    },
+                )
+                .optional()?;
+            Ok(response)
+        })
+        .await
+    }
+
+    /// Removes the responses stored before `before`, a UNIX timestamp in seconds.
+    pub async fn remove_idempotent_responses(&self, before: u64) -> Result<(), StoreError> {
+        self.with(move |conn| {
+            conn.execute(
+                "DELETE FROM idempotent_responses WHERE created < ?1",
+                params![before],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Removes a conversation, returning **`true`** if it existed.
+    pub async fn remove_conversation(&self, id: &str) -> Result<bool, StoreError> {
+        let id = id.to_string();
+        self.with(move |conn| {
+            let tx = conn.unchecked_transaction()?;
+            let removed = tx.execute(
+                "DELETE FROM conversation_messages WHERE conversation = ?1",
+                params![id],
+            )?;
+            tx.execute(
+                "DELETE FROM conversation_owners WHERE conversation = ?1",
+                params![id],
+            )?;
+            tx.commit()?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+}
+
+/// Applies the migrations that have not been applied to the database yet.
+fn migrate(conn: &mut Connection) -> Result<(), StoreError> {
+    let version: usize = conn.pragma_query_value(None, "user_version", move |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn usage() {
+        let store = Store::open_in_memory().unwrap();
+        let record = move |timestamp| UsageRecord {
+            timestamp,
+            endpoint: "chat/completions".to_string(),
+            model: "model".to_string(),
+            prompt_tokens: 10,
+            completion_tokens: 20,
+            user: (timestamp > 150).then(|| "user-1".to_string()),
+        };
+
+        store.record_usage(record(100)).await.unwrap();
+        store.record_usage(record(200)).await.unwrap();
+
+        assert_eq!(
+            store.usage(0).await.unwrap(),
+            vec![record(100), record(200)]
+        );
+        assert_eq!(store.usage(150).await.unwrap(), vec![record(200)]);
+    }
+
+    #[tokio::test]
+    async fn state() {
+        let store = Store::open_in_memory().unwrap();
+
+        store.put_model("model", &json!({"size": 1})).await.unwrap();
+        assert_eq!(
+            store.model("model").await.unwrap(),
+            Some(json!({"size": 1}))
+        );
+        store.remove_model("model").await.unwrap();
+        assert_eq!(store.model("model").await.unwrap(), None);
+
+        store.start_download("repo", "a").await.unwrap();
+        store.start_download("repo", "b").await.unwrap();
+        store.finish_download("repo", "a").await.unwrap();
+        assert_eq!(
+            store.downloads().await.unwrap(),
+            vec![("repo".to_string(), "b".to_string())]
+        );
+
+        let session = SessionRecord {
+            id: "session".to_string(),
+            model: "model".to_string(),
+            created: 1,
+            updated: 2,
+            metadata: json!({"messages": 3}),
+        };
+        store.put_session(session.clone()).await.unwrap();
+        assert_eq!(store.session("session").await.unwrap(), Some(session));
+        store.remove_session("session").await.unwrap();
+        assert_eq!(store.session("session").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn conversations() {
+        let store = Store::open_in_memory().unwrap();
+
+        assert!(store
+            .append_to_conversation("a", "owner", vec![json!(1), json!(2)])
+            .await
+            .unwrap());
+        assert!(store
+            .append_to_conversation("a", "owner", vec![json!(3)])
+            .await
+            .unwrap());
+        // Another owner cannot append to the conversation
+        assert!(!store
+            .append_to_conversation("a", "other", vec![json!(4)])
+            .await
+            .unwrap());
+        assert_eq!(
+            store.conversation("a").await.unwrap(),
+            vec![json!(1), json!(2), json!(3)]
+        );
+        assert_eq!(
+            store.conversation_owner("a").await.unwrap(),
+            Some("owner".to_string())
+        );
+        assert!(store.conversation("b").await.unwrap().is_empty());
+        assert_eq!(store.conversation_owner("b").await.unwrap(), None);
+
+        assert!(store.remove_conversation("a").await.unwrap());
+        assert!(!store.remove_conversation("a").await.unwrap());
+        assert_eq!(store.conversation_owner("a").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn threads() {
+        let store = Store::open_in_memory().unwrap();
+
+        store.put_thread("thread", &json!({"a": 1})).await.unwrap();
+        for id in ["m1", "m2"] {
+            store
+                .put_thread_item(ThreadItem::Message, "thread", id, &json!(id))
+                .await
+                .unwrap();
+        }
+        store
+            .put_thread_item(ThreadItem::Run, "thread", "r1", &json!("queued"))
+            .await
+            .unwrap();
+        // Replacing an item keeps its position
+        store
+            .put_thread_item(ThreadItem::Message, "thread", "m1", &json!("m1 edited"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store
+                .thread_items(ThreadItem::Message, "thread")
+                .await
+                .unwrap(),
+            vec![json!("m1 edited"), json!("m2")]
+        );
+        assert_eq!(
+            store
+                .thread_item(ThreadItem::Run, "thread", "r1")
+                .await
+                .unwrap(),
+            Some(json!("queued"))
+        );
+        assert_eq!(
+            store.all_thread_items(ThreadItem::Run).await.unwrap(),
+            vec![json!("queued")]
+        );
+        assert_eq!(
+            store
+                .thread_item(ThreadItem::Run, "thread", "m1")
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert!(store.remove_thread("thread").await.unwrap());
+        assert_eq!(store.thread("thread").await.unwrap(), None);
+        assert!(store
+            .thread_items(ThreadItem::Message, "thread")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn vector_stores() {
+        let store = Store::open_in_memory().unwrap();
+
+        store
+            .put_vector_store("vs", &json!({"a": 1}))
+            .await
+            .unwrap();
+        for file in ["f1", "f2"] {
+            store
+                .put_vector_store_file("vs", file, &json!(file))
+                .await
+                .unwrap();
+        }
+        store
+            .put_chunks(
+                "vs",
+                "f1",
+                vec![
+                    ("east".to_string(), vec![1.0, 0.0]),
+                    ("north".to_string(), vec![0.0, 1.0]),
+                ],
+            )
+            .await
+            .unwrap();
+        store
+            .put_chunks("vs", "f2", vec![("north-east".to_string(), vec![1.0, 1.0])])
+            .await
+            .unwrap();
+
+        let nearest = store.nearest_chunks("vs", vec![0.9, 0.1], 2).await.unwrap();
+        let contents: Vec<_> = nearest.iter().map(|c| c.content.as_str()).collect();
+        assert_eq!(contents, vec!["east", "north-east"]);
+        assert_eq!((nearest[0].file.as_str(), nearest[0].position), ("f1", 0));
+
+        assert!(store.remove_vector_store_file("vs", "f1").await.unwrap());
+        store.remove_chunks("vs", Some("f1")).await.unwrap();
+        assert_eq!(
+            store.vector_store_files("vs").await.unwrap(),
+            vec![json!("f2")]
+        );
+        assert_eq!(
+            store
+                .nearest_chunks("vs", vec![1.0, 0.0], 2)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert!(store.remove_vector_store("vs").await.unwrap());
+        store.remove_chunks("vs", None).await.unwrap();
+        assert!(store
+            .nearest_chunks("vs", vec![1.0, 0.0], 2)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn idempotent_responses() {
+        let store = Store::open_in_memory().unwrap();
+        let response = move |created| IdempotentResponse {
+            fingerprint: "hash".to_string(),
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            body: b"{}".to_vec(),
+            created,
+        };
+
+        store
+            .put_idempotent_response("a", response(100))
+            .await
+            .unwrap();
+        store
+            .put_idempotent_response("b", response(200))
+            .await
+            .unwrap();
+        assert_eq!(
+            store.idempotent_response("a").await.unwrap(),
+            Some(response(100))
+        );
+
+        store.remove_idempotent_responses(150).await.unwrap();
+        assert_eq!(store.idempotent_response("a").await.unwrap(), None);
+        assert_eq!(
+            store.idempotent_response("b").await.unwrap(),
+            Some(response(200))
+        );
+    }
+
+    #[tokio::test]
+    async fn persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("edgen.db");
+
+        Store::open(&path)
+            .unwrap()
+            .start_download("repo", "file")
+            .await
+            .unwrap();
+
+        // Reopening an existing database must not apply the migrations again
+        let store = Store::open(&path).unwrap();
+        assert_eq!(
+            store.downloads().await.unwrap(),
+            vec![("repo".to_string(), "file".to_string())]
+        );
+    }
+}
@@ -52,6 +52,31 @@ impl Display for Endpoint {
     }
 }
 
+/// Version of the API, selected by the prefix of the routes of the requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiVersion {
+    /// The current API, served under `/v1`.
+    V1,
+}
+
+impl ApiVersion {
+    /// Every version of the API served, from the oldest to the newest.
+    pub const ALL: &'static [ApiVersion] = &[ApiVersion::V1];
+
+    /// The prefix of the routes of this version, e.g. `/v1`.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "/v1",
+        }
+    }
+}
+
+impl Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.prefix().trim_start_matches('/'))
+    }
+}
+
 /// Abstraction over all errors that we can handle in edgen.
 /// This allows using '?' error handling everywhere for all known error types.
 #[derive(Debug, thiserror::Error)]
@@ -0,0 +1,204 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Strict validation of the request bodies, enabled by the `strict_requests` setting.
+//!
+//! By default, like OpenAI's API, unknown request parameters are ignored, and parameters out of
+//! their range are used as they are, which can silently produce surprising results. In strict
+//! mode, a request with unknown parameters, parameters out of their range or conflicting
+//! parameters is refused with a `400 Bad Request`, listing every offending parameter.
+
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::RequestExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use edgen_core::settings;
+
+use crate::request_id;
+
+/// An offending parameter of a request.
+#[derive(Serialize, ToSchema, Clone, Debug, PartialEq, Eq)]
+pub struct FieldError {
+    /// The name of the parameter, e.g. `temperature`.
+    pub field: String,
+    /// A human-readable error message.
+    pub message: String,
+}
+
+impl FieldError {
+    /// Creates an error for the parameter `field`.
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        Self {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+/// An error condition raised by the strict validation of a request.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum ValidationError {
+    /// Some parameters of the request are unknown, out of their range or conflicting.
+    #[error("the request has {} invalid parameter(s)", errors.len())]
+    InvalidRequest {
+        /// Every offending parameter.
+        errors: Vec<FieldError>,
+    },
+}
+
+impl IntoResponse for ValidationError {
+    fn into_response(self) -> Response {
+        request_id::error_response(StatusCode::BAD_REQUEST, &self)
+    }
+}
+
+/// A request body whose parameters can be checked against each other and against their ranges.
+pub trait Validate {
+    /// Returns every parameter out of its range, or conflicting with another parameter.
+    fn validate(&self) -> Vec<FieldError> {
+        Vec::new()
+    }
+}
+
+/// Adds an error to `errors` if `value` is set and out of `[min, max]`.
+pub fn check_range<T>(errors: &mut Vec<FieldError>, field: &str, value: Option<T>, min: T, max: T)
+where
+    T: PartialOrd + std::fmt::Display,
+{
+    match value {
+        // Written so that NaN is out of range
+        Some(value) if !(value >= min && value <= max) => errors.push(FieldError::new(
+            field,
+            format!("must be between {min} and {max}, got {value}"),
+        )),
+        _ => {}
+    }
+}
+
+/// An `axum` middleware refusing the requests whose body is a `T` with unknown, out of range or
+/// conflicting parameters, if the `strict_requests` setting is enabled.
+///
+/// Bodies that are not a `T` at all are let through, so that the handler refuses them as usual.
+pub async fn strict<T>(req: Request, next: Next) -> Response
+where
+    T: DeserializeOwned + Serialize + Validate,
+{
+    if !settings::strict_requests().await {
+        return next.run(req).await;
+    }
+
+    // Limited like the handler would limit it, by the `DefaultBodyLimit` of the route
+    let (parts, body) = req.with_limited_body().into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+    };
+
+    let errors = validate::<T>(&body);
+    if !errors.is_empty() {
+        return ValidationError::InvalidRequest { errors }.into_response();
+    }
+
+    next.run(Request::from_parts(parts, Body::from(body))).await
+}
+
+/// Returns the offending parameters of `body`, if it is a `T`.
+fn validate<T>(body: &[u8]) -> Vec<FieldError>
+where
+    T: DeserializeOwned + Serialize + Validate,
+{
+    let Ok(value) = serde_json::from_slice::<serde_json::Value>(body) else {
+        return Vec::new();
+    };
+    let Ok(parsed) = serde_json::from_value::<T>(value.clone()) else {
+        return Vec::new();
+    };
+
+    let mut errors = Vec::new();
+    // Every known parameter is serialized back, so the parameters missing are the unknown ones
+    if let (Some(sent), Ok(serde_json::Value::Object(known))) =
+        (value.as_object(), serde_json::to_value(&parsed))
+    {
+        errors.extend(
+            sent.iter()
+                .filter(|(name, value)| !value.is_null() && !known.contains_key(*name))
+                .map(|(name, _)| FieldError::new(name, "unknown parameter")),
+        );
+    }
+    errors.extend(parsed.validate());
+    errors
+}
+
+#[cfg(test)]
+mod test {
+    use serde_derive::Deserialize;
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Sampling {
+        temperature: Option<f32>,
+        stream: Option<bool>,
+        n: Option<u32>,
+    }
+
+    impl Validate for Sampling {
+        fn validate(&self) -> Vec<FieldError> {
+            let mut errors = Vec::new();
+            check_range(&mut errors, "temperature", self.temperature, 0.0, 2.0);
+            if self.n.unwrap_or(1) > 1 && self.stream.unwrap_or(false) {
+                errors.push(FieldError::new("n", "cannot be used with stream"));
+            }
+            errors
+        }
+    }
+
+    fn errors(body: serde_json::Value) -> Vec<FieldError> {
+        validate::<Sampling>(body.to_string().as_bytes())
+    }
+
+    #[test]
+    fn unknown_fields() {
+        assert_eq!(errors(serde_json::json!({"temperature": 1.0})), []);
+        assert_eq!(
+            errors(serde_json::json!({"temperature": 1.0, "temprature": 1.0})),
+            [FieldError::new("temprature", "unknown parameter")]
+        );
+    }
+
+    #[test]
+    fn ranges_and_conflicts() {
+        assert_eq!(
+            errors(serde_json::json!({"temperature": 3.0, "n": 2, "stream": true})),
+            [
+                FieldError::new("temperature", "must be between 0 and 2, got 3"),
+                FieldError::new("n", "cannot be used with stream"),
+            ]
+        );
+    }
+
+    #[test]
+    fn not_validated() {
+        // Left to the handler, which refuses them with its own error
+        assert_eq!(validate::<Sampling>(b"not json"), []);
+        assert_eq!(errors(serde_json::json!({"temperature": "hot"})), []);
+    }
+}
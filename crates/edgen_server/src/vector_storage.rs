@@ -0,0 +1,449 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Where the chunks of the [vector stores](crate::vector_stores), and their embeddings, are kept:
+//! the embedded [`store`](crate::store) by default, or an external vector database for corpora
+//! larger than it can comfortably search.
+//!
+//! The backing storage is chosen with the `vector_storage` setting when it is first used.
+
+use std::sync::Arc;
+
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use postgres_native_tls::MakeTlsConnector;
+use serde_derive::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tokio::sync::OnceCell;
+use tracing::info;
+use uuid::Uuid;
+
+use edgen_core::settings::{self, VectorStorage as VectorStorageSettings};
+
+use crate::store::{self, ScoredChunk, Store, StoreError};
+
+static STORAGE: OnceCell<Arc<dyn VectorStorage>> = OnceCell::const_new();
+
+/// An error accessing the storage of the chunks of the vector stores.
+#[derive(Debug, Error)]
+pub enum VectorStorageError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("the Qdrant request failed: {0}")]
+    Qdrant(#[from] reqwest::Error),
+    #[error("the PostgreSQL query failed: {0}")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[error("failed to connect to PostgreSQL: {0}")]
+    PostgresPool(#[from] deadpool_postgres::PoolError),
+    #[error("failed to create the PostgreSQL connection pool: {0}")]
+    PostgresPoolBuild(#[from] deadpool_postgres::BuildError),
+    #[error("failed to set up TLS: {0}")]
+    Tls(#[from] native_tls::Error),
+}
+
+/// A storage of the chunks of the vector stores, and their embeddings.
+#[async_trait::async_trait]
+pub trait VectorStorage: Send + Sync {
+    /// Replaces the chunks of a file of a vector store.
+    async fn put_chunks(
+        &self,
+        vector_store: &str,
+        file: &str,
+        chunks: Vec<(String, Vec<f32>)>,
+    ) -> Result<(), VectorStorageError>;
+
+    /// Removes the chunks of a file of a vector store.
+    async fn remove_file(&self, vector_store: &str, file: &str) -> Result<(), VectorStorageError>;
+
+    /// Removes every chunk of a vector store.
+    async fn remove_vector_store(&self, vector_store: &str) -> Result<(), VectorStorageError>;
+
+    /// Returns the `limit` chunks of a vector store most similar to `query`, most similar first.
+    async fn nearest(
+        &self,
+        vector_store: &str,
+        query: Vec<f32>,
+        limit: usize,
+    ) -> Result<Vec<ScoredChunk>, VectorStorageError>;
+}
+
+/// Returns the storage of the chunks of the vector stores, connecting to it on first use.
+pub async fn get() -> Result<Arc<dyn VectorStorage>, VectorStorageError> {
+    STORAGE
+        .get_or_try_init(move || async move {
+            let storage: Arc<dyn VectorStorage> = match settings::vector_storage().await {
+                VectorStorageSettings::Embedded => Arc::new(Embedded(store::get()?)),
+                VectorStorageSettings::Qdrant { url, api_key } => {
+                    info!("Keeping the vector store chunks in Qdrant at {url}");
+                    Arc::new(Qdrant {
+                        url: url.trim_end_matches('/').to_string(),
+                        api_key,
+                        client: reqwest::Client::new(),
+                    })
+                }
+                VectorStorageSettings::Pgvector { url } => {
+                    info!("Keeping the vector store chunks in PostgreSQL");
+                    Arc::new(Pgvector::connect(&url).await?)
+                }
+            };
+            Ok::<_, VectorStorageError>(storage)
+        })
+        .await
+        .cloned()
+}
+
+/// The embedded [`Store`].
+struct Embedded(&'static Store);
+
+#[async_trait::async_trait]
+impl VectorStorage for Embedded {
+    async fn put_chunks(
+        &self,
+        vector_store: &str,
+        file: &str,
+        chunks: Vec<(String, Vec<f32>)>,
+    ) -> Result<(), VectorStorageError> {
+        Ok(self.0.put_chunks(vector_store, file, chunks).await?)
+    }
+
+    async fn remove_file(&self, vector_store: &str, file: &str) -> Result<(), VectorStorageError> {
+        Ok(self.0.remove_chunks(vector_store, Some(file)).await?)
+    }
+
+    async fn remove_vector_store(&self, vector_store: &str) -> Result<(), VectorStorageError> {
+        Ok(self.0.remove_chunks(vector_store, None).await?)
+    }
+
+    async fn nearest(
+        &self,
+        vector_store: &str,
+        query: Vec<f32>,
+        limit: usize,
+    ) -> Result<Vec<ScoredChunk>, VectorStorageError> {
+        Ok(self.0.nearest_chunks(vector_store, query, limit).await?)
+    }
+}
+
+/// A Qdrant server, accessed through its REST API, with a collection per vector store.
+struct Qdrant {
+    url: String,
+    api_key: String,
+    client: reqwest::Client,
+}
+
+/// The payload of the points of a collection.
+#[derive(Deserialize)]
+struct QdrantPayload {
+    file: String,
+    position: u64,
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct QdrantPoint {
+    score: f32,
+    payload: QdrantPayload,
+}
+
+#[derive(Deserialize)]
+struct QdrantSearch {
+    result: Vec<QdrantPoint>,
+}
+
+impl Qdrant {
+    fn collection(&self, vector_store: &str) -> String {
+        format!("{}/collections/edgen_{vector_store}", self.url)
+    }
+
+    fn request(&self, method: reqwest::Method, url: String) -> reqwest::RequestBuilder {
+        let request = self.client.request(method, url);
+        if self.api_key.is_empty() {
+            request
+        } else {
+            request.header("api-key", &self.api_key)
+        }
+    }
+
+    /// Creates the collection of a vector store, if it does not exist yet.
+    async fn ensure_collection(
+        &self,
+        vector_store: &str,
+        dimensions: usize,
+    ) -> Result<(), VectorStorageError> {
+        let collection = self.collection(vector_store);
+        let response = self
+            .request(reqwest::Method::GET, collection.clone())
+            .send()
+            .await?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+            return Ok(());
+        }
+
+        self.request(reqwest::Method::PUT, collection)
+            .json(&json!({"vectors": {"size": dimensions, "distance": "Cosine"}}))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl VectorStorage for Qdrant {
+    async fn put_chunks(
+        &self,
+        vector_store: &str,
+        file: &str,
+        chunks: Vec<(String, Vec<f32>)>,
+    ) -> Result<(), VectorStorageError> {
+        let Some(dimensions) = chunks.first().map(move |(_, embedding)| embedding.len()) else {
+            return self.remove_file(vector_store, file).await;
+        };
+        self.ensure_collection(vector_store, dimensions).await?;
+        self.remove_file(vector_store, file).await?;
+
+        let points: Vec<_> = chunks
+            .into_iter()
+            .enumerate()
+            .map(move |(position, (content, embedding))| {
+                json!({
+                    "id": Uuid::new_v4().to_string(),
+                    "vector": embedding,
+                    "payload": {"file": file, "position": position, "content": content},
+                })
+            })
+            .collect();
+        self.request(
+            reqwest::Method::PUT,
+            format!("{}/points?wait=true", self.collection(vector_store)),
+        )
+        .json(&json!({ "points": points }))
+        .send()
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
+    async fn remove_file(&self, vector_store: &str, file: &str) -> Result<(), VectorStorageError> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                format!("{}/points/delete?wait=true", self.collection(vector_store)),
+            )
+            .json(&json!({"filter": {"must": [{"key": "file", "match": {"value": file}}]}}))
+            .send()
+            .await?;
+        // Vector stores without chunks have no collection
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    async fn remove_vector_store(&self, vector_store: &str) -> Result<(), VectorStorageError> {
+        let response = self
+            .request(reqwest::Method::DELETE, self.collection(vector_store))
+            .send()
+            .await?;
+        if response.status() != reqwest::StatusCode::NOT_FOUND {
+            response.error_for_status()?;
+        }
+        Ok(())
+    }
+
+    async fn nearest(
+        &self,
+        vector_store: &str,
+        query: Vec<f32>,
+        limit: usize,
+    ) -> Result<Vec<ScoredChunk>, VectorStorageError> {
+        let response = self
+            .request(
+                reqwest::Method::POST,
+                format!("{}/points/search", self.collection(vector_store)),
+            )
+            .json(&json!({"vector": query, "limit": limit, "with_payload": true}))
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+
+        let search: QdrantSearch = response.error_for_status()?.json().await?;
+        Ok(search
+            .result
+            .into_iter()
+            .map(move |point| ScoredChunk {
+                file: point.payload.file,
+                position: point.payload.position,
+                content: point.payload.content,
+                score: point.score,
+            })
+            .collect())
+    }
+}
+
+/// The maximum number of connections to the PostgreSQL database.
+const PGVECTOR_MAX_CONNECTIONS: usize = 16;
+
+/// Removes the chunks of a file of a vector store from the `edgen_vector_chunks` table.
+const PGVECTOR_DELETE_FILE: &str =
+    "DELETE FROM edgen_vector_chunks WHERE vector_store = $1 AND file = $2";
+
+/// A PostgreSQL database with the `pgvector` extension, keeping every chunk in the
+/// `edgen_vector_chunks` table.
+///
+/// Connections are pooled, and use TLS if the server supports it, or if the `sslmode` of the
+/// connection string requires it.
+struct Pgvector {
+    pool: Pool,
+}
+
+impl Pgvector {
+    async fn connect(url: &str) -> Result<Self, VectorStorageError> {
+        let config: tokio_postgres::Config = url.parse()?;
+        let tls = MakeTlsConnector::new(native_tls::TlsConnector::new()?);
+        let manager = Manager::from_config(
+            config,
+            tls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager)
+            .max_size(PGVECTOR_MAX_CONNECTIONS)
+            .build()?;
+
+        pool.get()
+            .await?
+            .batch_execute(
+                "CREATE EXTENSION IF NOT EXISTS vector;
+                 CREATE TABLE IF NOT EXISTS edgen_vector_chunks (
+                     vector_store TEXT NOT NULL,
+                     file TEXT NOT NULL,
+                     position BIGINT NOT NULL,
+                     content TEXT NOT NULL,
+                     embedding vector NOT NULL,
+                     PRIMARY KEY (vector_store, file, position)
+                 );",
+            )
+            .await?;
+
+        Ok(Self { pool })
+    }
+}
+
+/// Formats a vector as a `pgvector` literal, e.g. `[1,0.5,2]`.
+fn vector_literal(vector: &[f32]) -> String {
+    let components: Vec<String> = vector.iter().map(move |x| x.to_string()).collect();
+    format!("[{}]", components.join(","))
+}
+
+#[async_trait::async_trait]
+impl VectorStorage for Pgvector {
+    async fn put_chunks(
+        &self,
+        vector_store: &str,
+        file: &str,
+        chunks: Vec<(String, Vec<f32>)>,
+    ) -> Result<(), VectorStorageError> {
+        // Replaced in a single transaction, so that searches never see the file half-written
+        let mut client = self.pool.get().await?;
+        let transaction = client.transaction().await?;
+        transaction
+            .execute(PGVECTOR_DELETE_FILE, &[&vector_store, &file])
+            .await?;
+        let insert = transaction
+            .prepare_cached(
+                "INSERT INTO edgen_vector_chunks (vector_store, file, position, content, embedding)
+                 VALUES ($1, $2, $3, $4, $5::text::vector)",
+            )
+            .await?;
+        for (position, (content, embedding)) in chunks.into_iter().enumerate() {
+            let position = position as i64;
+            let embedding = vector_literal(&embedding);
+            transaction
+                .execute(
+                    &insert,
+                    &[&vector_store, &file, &position, &content, &embedding],
+                )
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn remove_file(&self, vector_store: &str, file: &str) -> Result<(), VectorStorageError> {
+        self.pool
+            .get()
+            .await?
+            .execute(PGVECTOR_DELETE_FILE, &[&vector_store, &file])
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_vector_store(&self, vector_store: &str) -> Result<(), VectorStorageError> {
+        self.pool
+            .get()
+            .await?
+            .execute(
+                "DELETE FROM edgen_vector_chunks WHERE vector_store = $1",
+                &[&vector_store],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn nearest(
+        &self,
+        vector_store: &str,
+        query: Vec<f32>,
+        limit: usize,
+    ) -> Result<Vec<ScoredChunk>, VectorStorageError> {
+        let query = vector_literal(&query);
+        let limit = limit as i64;
+        let rows = self
+            .pool
+            .get()
+            .await?
+            .query(
+                "SELECT file, position, content,
+                        (1 - (embedding <=> $2::text::vector))::real AS score
+                 FROM edgen_vector_chunks WHERE vector_store = $1
+                 ORDER BY embedding <=> $2::text::vector LIMIT $3",
+                &[&vector_store, &query, &limit],
+            )
+            .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(move |row| ScoredChunk {
+                file: row.get(0),
+                position: row.get::<_, i64>(1) as u64,
+                content: row.get(2),
+                score: row.get(3),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pgvector_literal() {
+        assert_eq!(vector_literal(&[1.0, 0.5, -2.0]), "[1,0.5,-2]");
+        assert_eq!(vector_literal(&[]), "[]");
+    }
+}
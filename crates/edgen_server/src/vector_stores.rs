@@ -0,0 +1,829 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Vector stores: text [files](crate::files) split into chunks, embedded with the embeddings
+//! endpoint and kept in the [`vector_storage`](crate::vector_storage), to be searched by
+//! similarity with a query. The vector stores and their files are kept in the
+//! [`store`](crate::store).
+//!
+//! Files are chunked and embedded when they are added to a vector store, so adding a file only
+//! returns once its chunks can be searched.
+
+use std::collections::HashMap;
+
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use edgen_core::embeddings::EmbeddingsArgs;
+
+use crate::files::{self, FileError};
+use crate::openai_shim::generate_embeddings;
+use crate::request_id;
+use crate::store::{self, now, StoreError};
+use crate::vector_storage::{self, VectorStorageError};
+
+/// The default maximum length of a chunk, in characters.
+const DEFAULT_CHUNK_SIZE: usize = 2000;
+
+/// The default number of characters shared by consecutive chunks.
+const DEFAULT_CHUNK_OVERLAP: usize = 400;
+
+/// The default number of chunks returned by a search.
+const DEFAULT_MAX_RESULTS: usize = 10;
+
+/// The maximum number of chunks returned by a search.
+const MAX_RESULTS: usize = 50;
+
+/// The number of chunks embedded with each call to the embeddings model.
+const EMBEDDINGS_BATCH: usize = 32;
+
+/// An error condition raised by the vector stores API.
+#[derive(Serialize, Error, ToSchema, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "error")]
+pub enum VectorStoreError {
+    /// There is no vector store with the provided ID.
+    #[error("no such vector store: {vector_store_id}")]
+    NoSuchVectorStore {
+        /// The ID of the vector store.
+        vector_store_id: String,
+    },
+
+    /// A parameter of the request has an invalid value.
+    #[error("invalid value for parameter {param}: {reason}")]
+    InvalidParam {
+        /// The name of the parameter.
+        param: String,
+
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// An error raised by the files added to the vector store.
+    #[error(transparent)]
+    File(#[from] FileError),
+
+    /// The chunks of a file or the query could not be embedded.
+    #[error("failed to generate embeddings: {reason}")]
+    Embeddings {
+        /// A human-readable error message.
+        reason: String,
+    },
+
+    /// The vector store could not be stored.
+    #[error("the vector store could not be stored: {reason}")]
+    Store {
+        /// A human-readable error message.
+        reason: String,
+    },
+}
+
+impl From<StoreError> for VectorStoreError {
+    fn from(e: StoreError) -> Self {
+        VectorStoreError::Store {
+            reason: e.to_string(),
+        }
+    }
+}
+
+impl From<VectorStorageError> for VectorStoreError {
+    fn from(e: VectorStorageError) -> Self {
+        VectorStoreError::Store {
+            reason: e.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for VectorStoreError {
+    fn from(e: serde_json::Error) -> Self {
+        StoreError::from(e).into()
+    }
+}
+
+impl IntoResponse for VectorStoreError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            VectorStoreError::NoSuchVectorStore { .. } => StatusCode::NOT_FOUND,
+            VectorStoreError::InvalidParam { .. }
+            | VectorStoreError::File(FileError::NoSuchFile { .. })
+            | VectorStoreError::File(FileError::InvalidParam { .. }) => StatusCode::BAD_REQUEST,
+            VectorStoreError::File(FileError::Storage { .. })
+            | VectorStoreError::Embeddings { .. }
+            | VectorStoreError::Store { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        request_id::error_response(status, &self)
+    }
+}
+
+/// The number of files of a [`VectorStore`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, ToSchema)]
+pub struct VectorStoreFileCounts {
+    /// The number of files that have been chunked and embedded.
+    pub completed: usize,
+
+    /// The number of files of the vector store.
+    pub total: usize,
+}
+
+/// A vector store.
+///
+/// See [the documentation of vector stores][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores/object
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct VectorStore {
+    /// The ID of the vector store.
+    pub id: String,
+
+    /// Always `"vector_store"`.
+    pub object: String,
+
+    /// The name of the vector store.
+    pub name: Option<String>,
+
+    /// The UNIX timestamp, in seconds, of when the vector store was created.
+    pub created_at: u64,
+
+    /// The embeddings model the chunks and queries are embedded with.
+    ///
+    /// This field is **Edgen** specific.
+    pub model: String,
+
+    /// The maximum length of a chunk, in characters.
+    ///
+    /// This field is **Edgen** specific.
+    pub chunk_size: usize,
+
+    /// The number of characters shared by consecutive chunks.
+    ///
+    /// This field is **Edgen** specific.
+    pub chunk_overlap: usize,
+
+    /// The number of files of the vector store.
+    pub file_counts: VectorStoreFileCounts,
+
+    /// Arbitrary metadata attached to the vector store.
+    #[schema(value_type = Object)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// A request to create a vector store.
+///
+/// An `axum` handler, [`create_vector_store`][create_vector_store], is provided to handle this
+/// request.
+///
+/// [create_vector_store]: fn.create_vector_store.html
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateVectorStoreRequest {
+    /// The name of the vector store.
+    pub name: Option<String>,
+
+    /// The IDs of the files to add to the vector store.
+    #[serde(default)]
+    pub file_ids: Vec<String>,
+
+    /// The embeddings model the chunks and queries are embedded with, the default embeddings
+    /// model if omitted.
+    ///
+    /// This field is **Edgen** specific.
+    pub model: Option<String>,
+
+    /// The maximum length of a chunk, in characters.
+    ///
+    /// This field is **Edgen** specific.
+    pub chunk_size: Option<usize>,
+
+    /// The number of characters shared by consecutive chunks, which must be lower than
+    /// `chunk_size`.
+    ///
+    /// This field is **Edgen** specific.
+    pub chunk_overlap: Option<usize>,
+
+    /// Arbitrary metadata attached to the vector store.
+    #[schema(value_type = Object)]
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// A file of a vector store.
+///
+/// See [the documentation of vector store files][openai] for more details.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores-files/file-object
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct VectorStoreFile {
+    /// The ID of the file.
+    pub id: String,
+
+    /// Always `"vector_store.file"`.
+    pub object: String,
+
+    /// The ID of the vector store.
+    pub vector_store_id: String,
+
+    /// The UNIX timestamp, in seconds, of when the file was added to the vector store.
+    pub created_at: u64,
+
+    /// Always `"completed"`, as files are chunked and embedded when they are added.
+    pub status: String,
+
+    /// The name of the file.
+    ///
+    /// This field is **Edgen** specific.
+    pub filename: String,
+
+    /// The number of chunks the file was split into.
+    ///
+    /// This field is **Edgen** specific.
+    pub chunks: usize,
+}
+
+/// A request to add a file to a vector store.
+///
+/// An `axum` handler, [`create_vector_store_file`][create_vector_store_file], is provided to
+/// handle this request.
+///
+/// [create_vector_store_file]: fn.create_vector_store_file.html
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateVectorStoreFileRequest {
+    /// The ID of the file, which must hold UTF-8 text.
+    pub file_id: String,
+}
+
+/// A request to search a vector store.
+///
+/// An `axum` handler, [`search_vector_store`][search_vector_store], is provided to handle this
+/// request.
+///
+/// [search_vector_store]: fn.search_vector_store.html
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SearchVectorStoreRequest {
+    /// The text to search the chunks most similar to.
+    pub query: String,
+
+    /// The maximum number of chunks to return, between 1 and 50. Defaults to 10.
+    pub max_num_results: Option<usize>,
+}
+
+/// A chunk found by a search.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SearchResult {
+    /// The ID of the file the chunk was taken from.
+    pub file_id: String,
+
+    /// The name of the file the chunk was taken from.
+    pub filename: String,
+
+    /// The position of the chunk in the file, starting from 0.
+    pub position: u64,
+
+    /// The cosine similarity between the chunk and the query.
+    pub score: f32,
+
+    /// The text of the chunk.
+    pub content: String,
+}
+
+/// The return type of [`search_vector_store`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SearchResults {
+    /// Always `"vector_store.search_results.page"`.
+    pub object: String,
+
+    /// The query of the search.
+    pub search_query: String,
+
+    /// The chunks found, most similar first.
+    pub data: Vec<SearchResult>,
+}
+
+/// The return type of [`list_vector_stores`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VectorStoreList {
+    /// Always `"list"`.
+    pub object: String,
+
+    /// The vector stores.
+    pub data: Vec<VectorStore>,
+}
+
+/// The return type of [`list_vector_store_files`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VectorStoreFileList {
+    /// Always `"list"`.
+    pub object: String,
+
+    /// The files of the vector store.
+    pub data: Vec<VectorStoreFile>,
+}
+
+/// The return type of [`delete_vector_store`] and [`delete_vector_store_file`].
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VectorStoreDeleted {
+    /// The ID of the vector store or file.
+    pub id: String,
+
+    /// Either `"vector_store.deleted"` or `"vector_store.file.deleted"`.
+    pub object: String,
+
+    /// **`true`** if the vector store or file existed.
+    pub deleted: bool,
+}
+
+/// Splits a text into chunks of at most `size` characters, consecutive chunks sharing `overlap`
+/// characters. Chunks are cut at whitespace where possible.
+fn chunk_text(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = vec![];
+
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = (start + size).min(chars.len());
+        if end < chars.len() {
+            // Prefer cutting at the last whitespace of the second half of the chunk
+            if let Some(space) = (start + size / 2..end)
+                .rev()
+                .find(|&i| chars[i].is_whitespace())
+            {
+                end = space;
+            }
+        }
+
+        let chunk: String = chars[start..end].iter().collect();
+        let chunk = chunk.trim();
+        if !chunk.is_empty() {
+            chunks.push(chunk.to_string());
+        }
+
+        if end == chars.len() {
+            break;
+        }
+        start = end.saturating_sub(overlap).max(start + 1);
+    }
+
+    chunks
+}
+
+/// Embeds texts with the provided embeddings model, a few at a time.
+async fn embed(model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>, VectorStoreError> {
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(EMBEDDINGS_BATCH) {
+        let args = EmbeddingsArgs {
+            inputs: batch.to_vec(),
+            ..Default::default()
+        };
+        let generated = generate_embeddings(model, args, None)
+            .await
+            .map_err(move |e| VectorStoreError::Embeddings {
+                reason: e.to_string(),
+            })?;
+        embeddings.extend(generated);
+    }
+    Ok(embeddings)
+}
+
+async fn put_vector_store(vector_store: &VectorStore) -> Result<(), VectorStoreError> {
+    Ok(store::get()?
+        .put_vector_store(&vector_store.id, &serde_json::to_value(vector_store)?)
+        .await?)
+}
+
+/// Returns a vector store.
+pub(crate) async fn get_vector_store(
+    vector_store_id: &str,
+) -> Result<VectorStore, VectorStoreError> {
+    match store::get()?.vector_store(vector_store_id).await? {
+        Some(vector_store) => Ok(serde_json::from_value(vector_store)?),
+        None => Err(VectorStoreError::NoSuchVectorStore {
+            vector_store_id: vector_store_id.to_string(),
+        }),
+    }
+}
+
+async fn vector_store_files(
+    vector_store_id: &str,
+) -> Result<Vec<VectorStoreFile>, VectorStoreError> {
+    store::get()?
+        .vector_store_files(vector_store_id)
+        .await?
+        .into_iter()
+        .map(move |file| Ok(serde_json::from_value(file)?))
+        .collect()
+}
+
+/// Updates the file counts of a vector store.
+async fn count_files(vector_store: &mut VectorStore) -> Result<(), VectorStoreError> {
+    let files = vector_store_files(&vector_store.id).await?.len();
+    vector_store.file_counts = VectorStoreFileCounts {
+        completed: files,
+        total: files,
+    };
+    put_vector_store(vector_store).await
+}
+
+/// Chunks and embeds a file, adding it to a vector store.
+async fn add_file(
+    vector_store: &VectorStore,
+    file_id: &str,
+) -> Result<VectorStoreFile, VectorStoreError> {
+    let file = files::get_file(file_id).await?;
+    let contents = files::file_contents(file_id).await?;
+    let text = String::from_utf8(contents).map_err(move |_| VectorStoreError::InvalidParam {
+        param: "file_id".to_string(),
+        reason: format!("file {file_id} is not UTF-8 text"),
+    })?;
+
+    let chunks = chunk_text(&text, vector_store.chunk_size, vector_store.chunk_overlap);
+    let embeddings = embed(&vector_store.model, &chunks).await?;
+
+    let vector_store_file = VectorStoreFile {
+        id: file.id,
+        object: "vector_store.file".to_string(),
+        vector_store_id: vector_store.id.clone(),
+        created_at: now(),
+        status: "completed".to_string(),
+        filename: file.filename,
+        chunks: chunks.len(),
+    };
+    vector_storage::get()
+        .await?
+        .put_chunks(
+            &vector_store.id,
+            &vector_store_file.id,
+            chunks.into_iter().zip(embeddings).collect(),
+        )
+        .await?;
+    store::get()?
+        .put_vector_store_file(
+            &vector_store.id,
+            &vector_store_file.id,
+            &serde_json::to_value(&vector_store_file)?,
+        )
+        .await?;
+
+    Ok(vector_store_file)
+}
+
+/// Returns the `limit` chunks of a vector store most similar to `query`, most similar first.
+pub(crate) async fn search(
+    vector_store_id: &str,
+    query: &str,
+    limit: usize,
+) -> Result<Vec<SearchResult>, VectorStoreError> {
+    let vector_store = get_vector_store(vector_store_id).await?;
+    let query_embedding = embed(&vector_store.model, &[query.to_string()])
+        .await?
+        .pop()
+        .unwrap_or_default();
+
+    let filenames: HashMap<String, String> = vector_store_files(vector_store_id)
+        .await?
+        .into_iter()
+        .map(move |file| (file.id, file.filename))
+        .collect();
+    let chunks = vector_storage::get()
+        .await?
+        .nearest(vector_store_id, query_embedding, limit)
+        .await?;
+
+    Ok(chunks
+        .into_iter()
+        .map(move |chunk| SearchResult {
+            filename: filenames.get(&chunk.file).cloned().unwrap_or_default(),
+            file_id: chunk.file,
+            position: chunk.position,
+            score: chunk.score,
+            content: chunk.content,
+        })
+        .collect())
+}
+
+/// POST `/v1/vector_stores`: creates a vector store, chunking and embedding the provided files.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from the chunking parameters.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores/create
+///
+/// On failure, may raise a `400 Bad Request` or `500 Internal Server Error` with a JSON-encoded
+/// [`VectorStoreError`] to the peer.
+#[utoipa::path(
+post,
+path = "/vector_stores",
+request_body = CreateVectorStoreRequest,
+responses(
+(status = 200, description = "OK", body = VectorStore),
+(status = 400, description = "invalid request", body = VectorStoreError),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn create_vector_store(
+    Json(req): Json<CreateVectorStoreRequest>,
+) -> Result<impl IntoResponse, VectorStoreError> {
+    let chunk_size = req.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let chunk_overlap = req
+        .chunk_overlap
+        .unwrap_or(DEFAULT_CHUNK_OVERLAP.min(chunk_size / 2));
+    if chunk_size == 0 {
+        return Err(VectorStoreError::InvalidParam {
+            param: "chunk_size".to_string(),
+            reason: "must be positive".to_string(),
+        });
+    }
+    if chunk_overlap >= chunk_size {
+        return Err(VectorStoreError::InvalidParam {
+            param: "chunk_overlap".to_string(),
+            reason: format!("must be lower than the chunk size, {chunk_size}"),
+        });
+    }
+
+    let mut vector_store = VectorStore {
+        id: format!("vs_{}", Uuid::new_v4().simple()),
+        object: "vector_store".to_string(),
+        name: req.name,
+        created_at: now(),
+        model: req.model.unwrap_or_else(move || "default".to_string()),
+        chunk_size,
+        chunk_overlap,
+        file_counts: VectorStoreFileCounts::default(),
+        metadata: req.metadata,
+    };
+    put_vector_store(&vector_store).await?;
+
+    for file_id in &req.file_ids {
+        add_file(&vector_store, file_id).await?;
+    }
+    count_files(&mut vector_store).await?;
+
+    Ok(Json(vector_store))
+}
+
+/// GET `/v1/vector_stores`: lists the vector stores, oldest first.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores/list
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`VectorStoreError`]
+/// to the peer.
+#[utoipa::path(
+get,
+path = "/vector_stores",
+responses(
+(status = 200, description = "OK", body = VectorStoreList),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn list_vector_stores() -> Result<impl IntoResponse, VectorStoreError> {
+    let vector_stores = store::get()?
+        .vector_stores()
+        .await?
+        .into_iter()
+        .map(move |vector_store| Ok(serde_json::from_value(vector_store)?))
+        .collect::<Result<_, VectorStoreError>>()?;
+
+    Ok(Json(VectorStoreList {
+        object: "list".to_string(),
+        data: vector_stores,
+    }))
+}
+
+/// GET `/v1/vector_stores/{vector_store_id}`: returns a vector store.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores/retrieve
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`VectorStoreError`] to the peer.
+#[utoipa::path(
+get,
+path = "/vector_stores/{vector_store_id}",
+params(("vector_store_id" = String, Path, description = "The ID of the vector store")),
+responses(
+(status = 200, description = "OK", body = VectorStore),
+(status = 404, description = "no such vector store", body = VectorStoreError),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn retrieve_vector_store(
+    Path(vector_store_id): Path<String>,
+) -> Result<impl IntoResponse, VectorStoreError> {
+    Ok(Json(get_vector_store(&vector_store_id).await?))
+}
+
+/// DELETE `/v1/vector_stores/{vector_store_id}`: deletes a vector store and its chunks. The files
+/// themselves are kept.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores/delete
+///
+/// On failure, may raise a `500 Internal Server Error` with a JSON-encoded [`VectorStoreError`]
+/// to the peer.
+#[utoipa::path(
+delete,
+path = "/vector_stores/{vector_store_id}",
+params(("vector_store_id" = String, Path, description = "The ID of the vector store")),
+responses(
+(status = 200, description = "OK", body = VectorStoreDeleted),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn delete_vector_store(
+    Path(vector_store_id): Path<String>,
+) -> Result<impl IntoResponse, VectorStoreError> {
+    vector_storage::get()
+        .await?
+        .remove_vector_store(&vector_store_id)
+        .await?;
+    let deleted = store::get()?.remove_vector_store(&vector_store_id).await?;
+
+    Ok(Json(VectorStoreDeleted {
+        id: vector_store_id,
+        object: "vector_store.deleted".to_string(),
+        deleted,
+    }))
+}
+
+/// POST `/v1/vector_stores/{vector_store_id}/files`: chunks and embeds a file, adding it to a
+/// vector store.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores-files/createFile
+///
+/// On failure, may raise a `400 Bad Request`, `404 Not Found` or `500 Internal Server Error` with
+/// a JSON-encoded [`VectorStoreError`] to the peer.
+#[utoipa::path(
+post,
+path = "/vector_stores/{vector_store_id}/files",
+params(("vector_store_id" = String, Path, description = "The ID of the vector store")),
+request_body = CreateVectorStoreFileRequest,
+responses(
+(status = 200, description = "OK", body = VectorStoreFile),
+(status = 400, description = "invalid request", body = VectorStoreError),
+(status = 404, description = "no such vector store", body = VectorStoreError),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn create_vector_store_file(
+    Path(vector_store_id): Path<String>,
+    Json(req): Json<CreateVectorStoreFileRequest>,
+) -> Result<impl IntoResponse, VectorStoreError> {
+    let mut vector_store = get_vector_store(&vector_store_id).await?;
+    let file = add_file(&vector_store, &req.file_id).await?;
+    count_files(&mut vector_store).await?;
+
+    Ok(Json(file))
+}
+
+/// GET `/v1/vector_stores/{vector_store_id}/files`: lists the files of a vector store, oldest
+/// first.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores-files/listFiles
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`VectorStoreError`] to the peer.
+#[utoipa::path(
+get,
+path = "/vector_stores/{vector_store_id}/files",
+params(("vector_store_id" = String, Path, description = "The ID of the vector store")),
+responses(
+(status = 200, description = "OK", body = VectorStoreFileList),
+(status = 404, description = "no such vector store", body = VectorStoreError),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn list_vector_store_files(
+    Path(vector_store_id): Path<String>,
+) -> Result<impl IntoResponse, VectorStoreError> {
+    get_vector_store(&vector_store_id).await?;
+
+    Ok(Json(VectorStoreFileList {
+        object: "list".to_string(),
+        data: vector_store_files(&vector_store_id).await?,
+    }))
+}
+
+/// DELETE `/v1/vector_stores/{vector_store_id}/files/{file_id}`: removes a file and its chunks
+/// from a vector store. The file itself is kept.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores-files/deleteFile
+///
+/// On failure, may raise a `404 Not Found` or `500 Internal Server Error` with a JSON-encoded
+/// [`VectorStoreError`] to the peer.
+#[utoipa::path(
+delete,
+path = "/vector_stores/{vector_store_id}/files/{file_id}",
+params(
+("vector_store_id" = String, Path, description = "The ID of the vector store"),
+("file_id" = String, Path, description = "The ID of the file")
+),
+responses(
+(status = 200, description = "OK", body = VectorStoreDeleted),
+(status = 404, description = "no such vector store", body = VectorStoreError),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn delete_vector_store_file(
+    Path((vector_store_id, file_id)): Path<(String, String)>,
+) -> Result<impl IntoResponse, VectorStoreError> {
+    let mut vector_store = get_vector_store(&vector_store_id).await?;
+    vector_storage::get()
+        .await?
+        .remove_file(&vector_store_id, &file_id)
+        .await?;
+    let deleted = store::get()?
+        .remove_vector_store_file(&vector_store_id, &file_id)
+        .await?;
+    count_files(&mut vector_store).await?;
+
+    Ok(Json(VectorStoreDeleted {
+        id: file_id,
+        object: "vector_store.file.deleted".to_string(),
+        deleted,
+    }))
+}
+
+/// POST `/v1/vector_stores/{vector_store_id}/search`: returns the chunks of a vector store most
+/// similar to a query.
+///
+/// See [the original OpenAI API specification][openai], which this endpoint is compatible with,
+/// apart from filters and ranking options.
+///
+/// [openai]: https://platform.openai.com/docs/api-reference/vector-stores/search
+///
+/// On failure, may raise a `400 Bad Request`, `404 Not Found` or `500 Internal Server Error` with
+/// a JSON-encoded [`VectorStoreError`] to the peer.
+#[utoipa::path(
+post,
+path = "/vector_stores/{vector_store_id}/search",
+params(("vector_store_id" = String, Path, description = "The ID of the vector store")),
+request_body = SearchVectorStoreRequest,
+responses(
+(status = 200, description = "OK", body = SearchResults),
+(status = 400, description = "invalid request", body = VectorStoreError),
+(status = 404, description = "no such vector store", body = VectorStoreError),
+(status = 500, description = "unexpected internal server error", body = VectorStoreError)
+),
+)]
+pub async fn search_vector_store(
+    Path(vector_store_id): Path<String>,
+    Json(req): Json<SearchVectorStoreRequest>,
+) -> Result<impl IntoResponse, VectorStoreError> {
+    let limit = req.max_num_results.unwrap_or(DEFAULT_MAX_RESULTS);
+    if !(1..=MAX_RESULTS).contains(&limit) {
+        return Err(VectorStoreError::InvalidParam {
+            param: "max_num_results".to_string(),
+            reason: format!("must be between 1 and {MAX_RESULTS}, got {limit}"),
+        });
+    }
+
+    let data = search(&vector_store_id, &req.query, limit).await?;
+
+    Ok(Json(SearchResults {
+        object: "vector_store.search_results.page".to_string(),
+        search_query: req.query,
+        data,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunking() {
+        assert!(chunk_text("", 10, 2).is_empty());
+        assert_eq!(chunk_text("short text", 100, 10), vec!["short text"]);
+
+        // Chunks are cut at whitespace, and start 4 characters before the end of the previous one
+        assert_eq!(
+            chunk_text("one two three four five six", 10, 4),
+            vec!["one two", "two three", "hree four", "four five", "five six"]
+        );
+
+        // Texts without whitespace are cut anywhere
+        let chunks = chunk_text("abcdefghij", 4, 1);
+        assert_eq!(chunks, vec!["abcd", "defg", "ghij"]);
+    }
+}
@@ -0,0 +1,168 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Notification of the server events to the `webhooks` of the settings.
+//!
+//! Every [`StatusEvent`] whose type a webhook subscribed to is posted to it as JSON, the same
+//! payload `/v1/status/stream` sends, so that orchestration systems can react to downloads
+//! finishing or models being loaded without polling the status. If the webhook has a secret, the
+//! payload is signed with HMAC-SHA256, and the signature is sent in the `X-Edgen-Signature` header
+//! as `sha256=<hex digest>`.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::spawn;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use edgen_core::settings::{self, WebhookSettings};
+
+use crate::status::{self, StatusEvent};
+
+/// The header holding the type of the event.
+const EVENT_HEADER: &str = "X-Edgen-Event";
+
+/// The header holding the signature of the payload.
+const SIGNATURE_HEADER: &str = "X-Edgen-Signature";
+
+/// How long a webhook is given to answer.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The task notifying the webhooks, if any.
+static TASK: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+
+/// Starts notifying the `webhooks` of the settings of the server events, stopping the previous
+/// notifications.
+pub async fn start() {
+    stop();
+
+    let webhooks = settings::webhooks().await;
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Failed to create the webhooks client: {e}");
+            return;
+        }
+    };
+
+    info!("Notifying {} webhook(s) of server events", webhooks.len());
+    let task = spawn(async move {
+        let mut events = status::subscribe();
+        loop {
+            match events.recv().await {
+                Ok(event) => notify(&client, &webhooks, &event),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("webhooks lagging behind, {missed} events dropped")
+                }
+                Err(RecvError::Closed) => return,
+            }
+        }
+    });
+
+    *TASK.lock().unwrap() = Some(task);
+}
+
+/// Stops notifying the webhooks, if they are notified.
+pub fn stop() {
+    if let Some(task) = TASK.lock().unwrap().take() {
+        task.abort();
+    }
+}
+
+/// Posts `event` to every webhook subscribed to its type, in the background.
+fn notify(client: &reqwest::Client, webhooks: &[WebhookSettings], event: &StatusEvent) {
+    let payload = match serde_json::to_string(event) {
+        Ok(payload) => payload,
+        Err(e) => {
+            warn!("Failed to encode the webhook payload: {e}");
+            return;
+        }
+    };
+    let kind = event_type(&payload);
+
+    for webhook in webhooks {
+        if !webhook.events.iter().any(|subscribed| *subscribed == kind) {
+            continue;
+        }
+
+        let mut request = client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, kind.as_str())
+            .body(payload.clone());
+        if !webhook.secret.is_empty() {
+            request = request.header(SIGNATURE_HEADER, sign(&webhook.secret, &payload));
+        }
+
+        let url = webhook.url.clone();
+        spawn(async move {
+            let sent = request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(e) = sent {
+                warn!("Failed to notify webhook {url}: {e}");
+            }
+        });
+    }
+}
+
+/// Returns the `type` of an encoded [`StatusEvent`].
+fn event_type(payload: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|value| Some(value.get("type")?.as_str()?.to_string()))
+        .unwrap_or_default()
+}
+
+/// Returns the value of the signature header of `payload`, signed with `secret`.
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC-SHA256 accepts keys of any length");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", HEXLOWER.encode(&mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signature() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn types() {
+        let event = StatusEvent::ModelLoaded {
+            kind: crate::model::ModelKind::LLM,
+            path: "chat.gguf".to_string(),
+        };
+        assert_eq!(
+            event_type(&serde_json::to_string(&event).unwrap()),
+            "model_loaded"
+        );
+    }
+}
@@ -13,43 +13,44 @@
 use once_cell::sync::Lazy;
 use uuid::Uuid;
 
-use edgen_core::whisper::{TranscriptionArgs, WhisperEndpoint, WhisperEndpointError};
+use edgen_core::resident::ResidentModel;
+use edgen_core::whisper::{
+    Transcription, TranscriptionArgs, WhisperEndpoint, WhisperEndpointError,
+};
 use edgen_rt_whisper_cpp::WhisperCppEndpoint;
 
+use crate::backends::TranscriptionBackend;
 use crate::model::Model;
 
 static ENDPOINT: Lazy<WhisperCppEndpoint> = Lazy::new(Default::default);
 
-pub async fn create_transcription(
-    file: &[u8],
-    model: Model,
-    language: Option<&str>,
-    prompt: Option<&str>,
-    temperature: Option<f32>,
-    create_session: bool,
-    session: Option<Uuid>,
-) -> Result<(String, Option<Uuid>), WhisperEndpointError> {
-    let args = TranscriptionArgs {
-        file: file.to_vec(),
-        language: language.map(move |s| s.to_string()),
-        prompt: prompt.map(move |s| s.to_string()),
-        temperature,
-        create_session,
-        session,
-    };
-
-    ENDPOINT
-        .transcription(
-            model
-                .file_path()
-                .map_err(move |e| WhisperEndpointError::Load(e.to_string()))?,
-            args,
-        )
-        .await
-}
+/// The [`TranscriptionBackend`] running models with `whisper.cpp`.
+pub struct WhisperCppBackend;
+
+#[async_trait::async_trait]
+impl TranscriptionBackend for WhisperCppBackend {
+    async fn transcription(
+        &self,
+        model: Model,
+        args: TranscriptionArgs,
+    ) -> Result<(Transcription, Option<Uuid>), WhisperEndpointError> {
+        ENDPOINT
+            .transcription(
+                model
+                    .file_path()
+                    .map_err(move |e| WhisperEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await
+    }
 
-pub async fn reset_environment() {
-    ENDPOINT.reset()
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        ENDPOINT.resident_models().await
+    }
+
+    async fn reset(&self) {
+        ENDPOINT.reset()
+    }
 }
 
 #[cfg(test)]
@@ -58,6 +59,7 @@ mod tests {
     use crate::model::{Model, ModelKind};
     use crate::types::Endpoint;
     use edgen_core::settings::SETTINGS;
+    use edgen_core::whisper::AudioFile;
     use levenshtein;
     use std::path::PathBuf;
 
@@ -95,12 +97,22 @@ mod tests {
         assert!(model.preload(Endpoint::AudioTranscriptions).await.is_ok());
 
         let sound = include_bytes!("../resources/frost.wav");
-        let response = create_transcription(sound, model, None, None, None, true, None).await;
+        let args = TranscriptionArgs {
+            file: AudioFile::Bytes(sound.to_vec()),
+            language: None,
+            prompt: None,
+            temperature: None,
+            create_session: true,
+            session: None,
+            vad: false,
+        };
+        let response = WhisperCppBackend.transcription(model, args).await;
 
         assert!(response.is_ok(), "cannot create transcription");
 
         let expected_text = frost();
-        let (actual_text, session) = response.unwrap();
+        let (transcription, session) = response.unwrap();
+        let actual_text = transcription.text;
 
         println!("{:?}", session);
 
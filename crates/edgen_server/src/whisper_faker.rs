@@ -0,0 +1,56 @@
+/* Copyright 2023- The Binedge, Lda team. All rights reserved.
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+//! Endpoint for the whisper faker model RT
+
+use once_cell::sync::Lazy;
+use uuid::Uuid;
+
+use edgen_core::resident::ResidentModel;
+use edgen_core::whisper::{
+    Transcription, TranscriptionArgs, WhisperEndpoint, WhisperEndpointError,
+};
+use edgen_rt_whisper_faker::WhisperFakerEndpoint;
+
+use crate::backends::TranscriptionBackend;
+use crate::model::Model;
+
+static ENDPOINT: Lazy<WhisperFakerEndpoint> = Lazy::new(Default::default);
+
+/// The [`TranscriptionBackend`] answering with canned transcriptions, for testing.
+pub struct WhisperFakerBackend;
+
+#[async_trait::async_trait]
+impl TranscriptionBackend for WhisperFakerBackend {
+    async fn transcription(
+        &self,
+        model: Model,
+        args: TranscriptionArgs,
+    ) -> Result<(Transcription, Option<Uuid>), WhisperEndpointError> {
+        ENDPOINT
+            .transcription(
+                model
+                    .file_path()
+                    .map_err(move |e| WhisperEndpointError::Load(e.to_string()))?,
+                args,
+            )
+            .await
+    }
+
+    async fn resident_models(&self) -> Vec<ResidentModel> {
+        ENDPOINT.resident_models().await
+    }
+
+    async fn reset(&self) {
+        ENDPOINT.reset()
+    }
+}
@@ -250,11 +250,18 @@ export const navigation: Array<NavGroup> = [
   {
     title: 'API Reference',
     links: [
+      { title: 'Admin', href: '/api-reference/admin' },
+      { title: 'Assistants', href: '/api-reference/assistants' },
       { title: 'Audio', href: '/api-reference/audio' },
+      { title: 'Batches', href: '/api-reference/batches' },
       { title: 'Chat', href: '/api-reference/chat' },
       { title: 'Embeddings', href: '/api-reference/embeddings' },
+      { title: 'Files', href: '/api-reference/files' },
       { title: 'Models', href: '/api-reference/models' },
       { title: 'Image', href: '/api-reference/image' },
+      { title: 'Rerank', href: '/api-reference/rerank' },
+      { title: 'Requests', href: '/api-reference/requests' },
+      { title: 'Vector stores', href: '/api-reference/vector-stores' },
     ],
   },
 ]
@@ -10,6 +10,6 @@
 # --------------------------------------------------------------------------------------------------------------------
 # (c) Binedge 2023
 # =====================================================================================================================
-cargo run --bin edgend -- -o > openapi-docs/edgen-api-spec.yaml
+cargo run --bin edgen -- oasgen --output openapi-docs/edgen-api-spec.yaml
 swagger-cli bundle -o openapi-docs/edgen-api-spec.json openapi-docs/edgen-api-spec.yaml
 